@@ -2,65 +2,103 @@ use std::io::Write;
 
 extern crate shaderc;
 
-fn main() {
-    // Create folder for compiled binaries if it doesn't already exist
-    std::fs::create_dir_all("shaders/spv").unwrap();
+fn compile_shader(
+    compiler: &shaderc::Compiler,
+    options: &shaderc::CompileOptions,
+    source_path: &str,
+    kind: shaderc::ShaderKind,
+    output_path: &str,
+) {
+    println!("cargo:rerun-if-changed={}", source_path);
 
-    let compiler = shaderc::Compiler::new().unwrap();
-    let options = shaderc::CompileOptions::new().unwrap();
+    let source = std::fs::read_to_string(source_path)
+        .unwrap_or_else(|_| panic!("Failed to read shader {}.", source_path));
 
-    let vertex_source =
-        std::fs::read_to_string("shaders/shader.vert").expect("Failed to read vertex shader.");
-    let fragment_source =
-        std::fs::read_to_string("shaders/shader.frag").expect("Failed to read fragment shader.");
+    let binary_result = compiler
+        .compile_into_spirv(&source, kind, source_path, "main", Some(options))
+        .unwrap();
+
+    let mut output = std::fs::File::create(output_path).unwrap();
+    output.write_all(binary_result.as_binary_u8()).unwrap();
+    println!("{} written", output_path);
+}
 
-    println!("Read vertex_source and fragment_source");
+/// Resolves `#include "name"` to `shaders/name`.
+fn include_shader(
+    requested_source: &str,
+    _include_type: shaderc::IncludeType,
+    _requesting_source: &str,
+    _include_depth: usize,
+) -> shaderc::IncludeCallbackResult {
+    let path = format!("shaders/{}", requested_source);
+    println!("cargo:rerun-if-changed={}", path);
 
-    let vertex_binary_result = compiler
-        .compile_into_spirv(
-            &vertex_source,
-            shaderc::ShaderKind::Vertex,
-            "shaders/shader.vert",
-            "main",
-            Some(&options),
-        )
-        .unwrap();
+    let content = std::fs::read_to_string(&path)
+        .map_err(|error| format!("Failed to read {}: {}", path, error))?;
+    Ok(shaderc::ResolvedInclude {
+        resolved_name: path,
+        content,
+    })
+}
 
-    // let text_result = compiler
-    //     .compile_into_spirv_assembly(
-    //         &vertex_source,
-    //         shaderc::ShaderKind::Vertex,
-    //         "shaders/shader.vert",
-    //         "main",
-    //         Some(&options),
-    //     )
-    //     .unwrap();
+fn main() {
+    // Create folder for compiled binaries if it doesn't already exist
+    std::fs::create_dir_all("shaders/spv").unwrap();
 
-    // std::fs::write("shaders/spv/shader.vert.spv", text_result.as_text()).unwrap();
-    // std::fs::write(
-    //     "shaders/spv/shader.vert.spv",
-    //     vertex_binary_result.as_text(),
-    // )
-    // .unwrap();
+    let compiler = shaderc::Compiler::new().unwrap();
+    let mut options = shaderc::CompileOptions::new().unwrap();
+    options.set_include_callback(include_shader);
 
-    let mut output = std::fs::File::create("shaders/spv/shader.vert.spv").unwrap();
-    output
-        .write_all(vertex_binary_result.as_binary_u8())
-        .unwrap();
-    println!("VBR written");
+    compile_shader(
+        &compiler,
+        &options,
+        "shaders/shader.vert",
+        shaderc::ShaderKind::Vertex,
+        "shaders/spv/shader.vert.spv",
+    );
+    compile_shader(
+        &compiler,
+        &options,
+        "shaders/shader.frag",
+        shaderc::ShaderKind::Fragment,
+        "shaders/spv/shader.frag.spv",
+    );
+    compile_shader(
+        &compiler,
+        &options,
+        "shaders/trace.comp",
+        shaderc::ShaderKind::Compute,
+        "shaders/spv/trace.comp.spv",
+    );
 
-    let fragment_binary_result = compiler
-        .compile_into_spirv(
-            &fragment_source,
-            shaderc::ShaderKind::Fragment,
-            "shaders/shader.frag",
-            "main",
-            Some(&options),
-        )
-        .unwrap();
+    // Ray tracing stages need SPIR-V 1.4, which is core in Vulkan 1.2
+    let mut ray_tracing_options = shaderc::CompileOptions::new().unwrap();
+    ray_tracing_options.set_include_callback(include_shader);
+    ray_tracing_options.set_target_env(
+        shaderc::TargetEnv::Vulkan,
+        shaderc::EnvVersion::Vulkan1_2 as u32,
+    );
+    ray_tracing_options.set_target_spirv(shaderc::SpirvVersion::V1_4);
 
-    let mut output = std::fs::File::create("shaders/spv/shader.frag.spv").unwrap();
-    output
-        .write_all(fragment_binary_result.as_binary_u8())
-        .unwrap();
+    compile_shader(
+        &compiler,
+        &ray_tracing_options,
+        "shaders/raytrace.rgen",
+        shaderc::ShaderKind::RayGeneration,
+        "shaders/spv/raytrace.rgen.spv",
+    );
+    compile_shader(
+        &compiler,
+        &ray_tracing_options,
+        "shaders/raytrace.rmiss",
+        shaderc::ShaderKind::Miss,
+        "shaders/spv/raytrace.rmiss.spv",
+    );
+    compile_shader(
+        &compiler,
+        &ray_tracing_options,
+        "shaders/raytrace.rchit",
+        shaderc::ShaderKind::ClosestHit,
+        "shaders/spv/raytrace.rchit.spv",
+    );
 }
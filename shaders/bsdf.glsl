@@ -0,0 +1,343 @@
+// The materials and their BSDFs, ported from `engine::bsdf`, which has the
+// tests. Directions are in the shading frame, with the geometric normal along
+// +Z, and point away from the surface. Opaque materials scatter the same on
+// both sides, dielectrics refract into the side `wo` is not on. Internally
+// `wo` is flipped above the surface.
+
+const float PI = 3.14159265358979;
+
+// Matches `engine::material::GpuMaterial`
+struct Material {
+    vec3 color;
+    uint kind;
+    vec3 emission;
+    float roughness;
+    float ior;
+    float metallic;
+    // Indices into the texture array, or NO_TEXTURE
+    uint baseColorTexture;
+    uint metallicRoughnessTexture;
+    uint normalTexture;
+    uint emissiveTexture;
+    vec2 padding;
+};
+
+// Texture slots of a material that has no texture there
+const uint NO_TEXTURE = 0xffffffffu;
+
+// Values of `Material::kind`
+const uint KIND_LAMBERT = 0;
+const uint KIND_CONDUCTOR = 1;
+const uint KIND_DIELECTRIC = 2;
+const uint KIND_EMISSIVE = 3;
+const uint KIND_METALLIC_ROUGHNESS = 4;
+
+// Smallest GGX alpha of the microfacet lobes. Dielectrics smoother than this
+// are perfectly smooth.
+const float MIN_ALPHA = 1e-3;
+
+struct BsdfSample {
+    vec3 direction;
+    // eval * |cos| / pdf
+    vec3 weight;
+    // For specular samples the probability of picking them
+    float pdf;
+    bool isSpecular;
+};
+
+vec3 flipDirection(vec3 w, bool isFlipped) {
+    return isFlipped ? vec3(w.xy, -w.z) : w;
+}
+
+float ggxAlpha(float roughness) {
+    return max(roughness * roughness, MIN_ALPHA);
+}
+
+// Scattering helpers
+
+vec3 reflectDirection(vec3 w, vec3 normal) {
+    return 2.0 * dot(w, normal) * normal - w;
+}
+
+// False on total internal reflection
+bool refractDirection(vec3 w, vec3 normal, float eta, out vec3 direction) {
+    const float cosIncident = dot(w, normal);
+    const float sin2Transmitted = max(0.0, 1.0 - cosIncident * cosIncident) / (eta * eta);
+    if (sin2Transmitted >= 1.0) {
+        return false;
+    }
+
+    const float cosTransmitted = sqrt(1.0 - sin2Transmitted);
+    direction = -w / eta + (cosIncident / eta - cosTransmitted) * normal;
+    return true;
+}
+
+float fresnelDielectric(float cosIncident, float eta) {
+    const float sin2Transmitted = max(0.0, 1.0 - cosIncident * cosIncident) / (eta * eta);
+    if (sin2Transmitted >= 1.0) {
+        return 1.0;
+    }
+
+    const float cosTransmitted = sqrt(1.0 - sin2Transmitted);
+    const float parallel = (eta * cosIncident - cosTransmitted) / (eta * cosIncident + cosTransmitted);
+    const float perpendicular = (cosIncident - eta * cosTransmitted) / (cosIncident + eta * cosTransmitted);
+    return (parallel * parallel + perpendicular * perpendicular) / 2.0;
+}
+
+vec3 fresnelSchlick(vec3 reflectance, float cosTheta) {
+    return reflectance + (1.0 - reflectance) * pow(clamp(1.0 - cosTheta, 0.0, 1.0), 5.0);
+}
+
+float ggxDistribution(float alpha, vec3 halfVector) {
+    const float alpha2 = alpha * alpha;
+    const float denominator = halfVector.z * halfVector.z * (alpha2 - 1.0) + 1.0;
+    return alpha2 / (PI * denominator * denominator);
+}
+
+float ggxLambda(float alpha, vec3 w) {
+    const float cos2 = w.z * w.z;
+    if (cos2 == 0.0) {
+        return 1e30;
+    }
+    return (sqrt(1.0 + alpha * alpha * (1.0 - cos2) / cos2) - 1.0) / 2.0;
+}
+
+// Height-correlated masking and shadowing
+float ggxMasking(float alpha, vec3 wo, vec3 wi) {
+    return 1.0 / (1.0 + ggxLambda(alpha, wo) + ggxLambda(alpha, wi));
+}
+
+float ggxVisibleNormalPdf(float alpha, vec3 wo, vec3 halfVector) {
+    const float masking = 1.0 / (1.0 + ggxLambda(alpha, wo));
+    return masking * max(dot(wo, halfVector), 0.0) * ggxDistribution(alpha, halfVector) / wo.z;
+}
+
+// "Sampling the GGX Distribution of Visible Normals" by Heitz
+vec3 sampleGgxVisibleNormal(float alpha, vec3 wo, float u1, float u2) {
+    const vec3 view = normalize(vec3(alpha * wo.x, alpha * wo.y, wo.z));
+    const float lengthSquared = view.x * view.x + view.y * view.y;
+    const vec3 tangent = lengthSquared > 0.0 ? vec3(-view.y, view.x, 0.0) / sqrt(lengthSquared) : vec3(1.0, 0.0, 0.0);
+    const vec3 bitangent = cross(view, tangent);
+
+    const float radius = sqrt(u1);
+    const float phi = 2.0 * PI * u2;
+    const float t1 = radius * cos(phi);
+    const float s = 0.5 * (1.0 + view.z);
+    const float t2 = (1.0 - s) * sqrt(1.0 - t1 * t1) + s * radius * sin(phi);
+    const vec3 normal = t1 * tangent + t2 * bitangent + sqrt(max(0.0, 1.0 - t1 * t1 - t2 * t2)) * view;
+
+    return normalize(vec3(alpha * normal.x, alpha * normal.y, max(normal.z, 0.0)));
+}
+
+vec3 sampleCosineHemisphere(float u1, float u2) {
+    const float radius = sqrt(u1);
+    const float phi = 2.0 * PI * u2;
+    return vec3(radius * cos(phi), radius * sin(phi), sqrt(max(0.0, 1.0 - u1)));
+}
+
+// Opaque materials, with `wo` above the surface
+
+vec3 conductorEval(vec3 color, float alpha, vec3 wo, vec3 wi) {
+    const vec3 halfVector = normalize(wo + wi);
+    return fresnelSchlick(color, dot(wi, halfVector)) * ggxDistribution(alpha, halfVector) * ggxMasking(alpha, wo, wi) / (4.0 * wo.z * wi.z);
+}
+
+float conductorPdf(float alpha, vec3 wo, vec3 wi) {
+    const vec3 halfVector = normalize(wo + wi);
+    return ggxVisibleNormalPdf(alpha, wo, halfVector) / (4.0 * dot(wo, halfVector));
+}
+
+vec3 opaqueEval(Material material, vec3 wo, vec3 wi) {
+    if (wo.z <= 0.0 || wi.z <= 0.0) {
+        return vec3(0.0);
+    }
+
+    const float alpha = ggxAlpha(material.roughness);
+    switch (material.kind) {
+    case KIND_LAMBERT:
+        return material.color / PI;
+    case KIND_CONDUCTOR:
+        return conductorEval(material.color, alpha, wo, wi);
+    case KIND_METALLIC_ROUGHNESS:
+        return (1.0 - material.metallic) * material.color / PI + material.metallic * conductorEval(material.color, alpha, wo, wi);
+    default:
+        return vec3(0.0);
+    }
+}
+
+float opaquePdf(Material material, vec3 wo, vec3 wi) {
+    if (wo.z <= 0.0 || wi.z <= 0.0) {
+        return 0.0;
+    }
+
+    const float alpha = ggxAlpha(material.roughness);
+    switch (material.kind) {
+    case KIND_LAMBERT:
+        return wi.z / PI;
+    case KIND_CONDUCTOR:
+        return conductorPdf(alpha, wo, wi);
+    case KIND_METALLIC_ROUGHNESS:
+        return (1.0 - material.metallic) * wi.z / PI + material.metallic * conductorPdf(alpha, wo, wi);
+    default:
+        return 0.0;
+    }
+}
+
+bool opaqueSample(Material material, vec3 wo, vec3 u, out BsdfSample bsdfSample) {
+    if (wo.z <= 0.0) {
+        return false;
+    }
+
+    const float alpha = ggxAlpha(material.roughness);
+    vec3 direction;
+    if (material.kind == KIND_LAMBERT) {
+        direction = sampleCosineHemisphere(u.y, u.z);
+    } else if (material.kind == KIND_CONDUCTOR || (material.kind == KIND_METALLIC_ROUGHNESS && u.x < material.metallic)) {
+        direction = reflectDirection(wo, sampleGgxVisibleNormal(alpha, wo, u.y, u.z));
+    } else if (material.kind == KIND_METALLIC_ROUGHNESS) {
+        direction = sampleCosineHemisphere(u.y, u.z);
+    } else {
+        return false;
+    }
+
+    const float pdf = opaquePdf(material, wo, direction);
+    if (direction.z <= 0.0 || pdf <= 0.0) {
+        return false;
+    }
+    bsdfSample.direction = direction;
+    bsdfSample.weight = opaqueEval(material, wo, direction) * direction.z / pdf;
+    bsdfSample.pdf = pdf;
+    bsdfSample.isSpecular = false;
+    return true;
+}
+
+// Dielectrics, with `eta` the relative index of refraction
+
+// The front-facing microfacet normal that scatters `wo` into `wi`
+bool dielectricHalfVector(float eta, vec3 wo, vec3 wi, out vec3 halfVector) {
+    const vec3 unnormalized = wi.z > 0.0 ? wo + wi : wo + wi * eta;
+    if (wo.z == 0.0 || wi.z == 0.0 || dot(unnormalized, unnormalized) == 0.0) {
+        return false;
+    }
+
+    halfVector = normalize(unnormalized);
+    if (halfVector.z < 0.0) {
+        halfVector = -halfVector;
+    }
+    return dot(halfVector, wi) * wi.z >= 0.0 && dot(halfVector, wo) * wo.z >= 0.0;
+}
+
+vec3 dielectricEval(Material material, float eta, vec3 wo, vec3 wi) {
+    const float alpha = material.roughness * material.roughness;
+    vec3 halfVector;
+    if (alpha < MIN_ALPHA || !dielectricHalfVector(eta, wo, wi, halfVector)) {
+        return vec3(0.0);
+    }
+
+    const float fresnel = fresnelDielectric(dot(wo, halfVector), eta);
+    const float microfacets = ggxDistribution(alpha, halfVector) * ggxMasking(alpha, wo, wi);
+    if (wi.z > 0.0) {
+        return vec3(fresnel * microfacets / (4.0 * wo.z * wi.z));
+    }
+
+    // Radiance is not scaled by the squared ratio of the indices of
+    // refraction, which cancels out for closed objects
+    const float sum = dot(wi, halfVector) + dot(wo, halfVector) / eta;
+    const float denominator = sum * sum * wi.z * wo.z;
+    return material.color * ((1.0 - fresnel) * microfacets * abs(dot(wi, halfVector) * dot(wo, halfVector) / denominator));
+}
+
+float dielectricPdf(Material material, float eta, vec3 wo, vec3 wi) {
+    const float alpha = material.roughness * material.roughness;
+    vec3 halfVector;
+    if (alpha < MIN_ALPHA || !dielectricHalfVector(eta, wo, wi, halfVector)) {
+        return 0.0;
+    }
+
+    const float fresnel = fresnelDielectric(dot(wo, halfVector), eta);
+    const float visiblePdf = ggxVisibleNormalPdf(alpha, wo, halfVector);
+    if (wi.z > 0.0) {
+        return fresnel * visiblePdf / (4.0 * dot(wo, halfVector));
+    }
+
+    const float sum = dot(wi, halfVector) + dot(wo, halfVector) / eta;
+    const float jacobian = abs(dot(wi, halfVector)) / (sum * sum);
+    return (1.0 - fresnel) * visiblePdf * jacobian;
+}
+
+bool dielectricSample(Material material, float eta, vec3 wo, vec3 u, out BsdfSample bsdfSample) {
+    const float alpha = material.roughness * material.roughness;
+    if (alpha < MIN_ALPHA) {
+        const float fresnel = fresnelDielectric(wo.z, eta);
+        bsdfSample.isSpecular = true;
+        if (u.x < fresnel) {
+            bsdfSample.direction = vec3(-wo.xy, wo.z);
+            bsdfSample.weight = vec3(1.0);
+            bsdfSample.pdf = fresnel;
+            return true;
+        }
+        bsdfSample.weight = material.color;
+        bsdfSample.pdf = 1.0 - fresnel;
+        return refractDirection(wo, vec3(0.0, 0.0, 1.0), eta, bsdfSample.direction);
+    }
+
+    // Reflect or refract through a visible microfacet, by its reflectance
+    const vec3 halfVector = sampleGgxVisibleNormal(alpha, wo, u.y, u.z);
+    vec3 direction;
+    if (u.x < fresnelDielectric(dot(wo, halfVector), eta)) {
+        direction = reflectDirection(wo, halfVector);
+        if (direction.z <= 0.0) {
+            return false;
+        }
+    } else if (!refractDirection(wo, halfVector, eta, direction) || direction.z >= 0.0) {
+        return false;
+    }
+
+    const float pdf = dielectricPdf(material, eta, wo, direction);
+    if (pdf <= 0.0) {
+        return false;
+    }
+    bsdfSample.direction = direction;
+    bsdfSample.weight = dielectricEval(material, eta, wo, direction) * abs(direction.z) / pdf;
+    bsdfSample.pdf = pdf;
+    bsdfSample.isSpecular = false;
+    return true;
+}
+
+// The BSDF for light arriving from `wi` and leaving towards `wo`, without the
+// cosine term
+vec3 evalBsdf(Material material, vec3 wo, vec3 wi) {
+    const bool isFlipped = wo.z < 0.0;
+    wo = flipDirection(wo, isFlipped);
+    wi = flipDirection(wi, isFlipped);
+    if (material.kind == KIND_DIELECTRIC) {
+        return dielectricEval(material, isFlipped ? 1.0 / material.ior : material.ior, wo, wi);
+    }
+    return opaqueEval(material, wo, wi);
+}
+
+float pdfBsdf(Material material, vec3 wo, vec3 wi) {
+    const bool isFlipped = wo.z < 0.0;
+    wo = flipDirection(wo, isFlipped);
+    wi = flipDirection(wi, isFlipped);
+    if (material.kind == KIND_DIELECTRIC) {
+        return dielectricPdf(material, isFlipped ? 1.0 / material.ior : material.ior, wo, wi);
+    }
+    return opaquePdf(material, wo, wi);
+}
+
+// Picks the direction light arrives from with three random numbers: the
+// first chooses between lobes, the others the direction within it. False
+// when the path ends.
+bool sampleBsdf(Material material, vec3 wo, vec3 u, out BsdfSample bsdfSample) {
+    const bool isFlipped = wo.z < 0.0;
+    wo = flipDirection(wo, isFlipped);
+    bool isSampled;
+    if (material.kind == KIND_DIELECTRIC) {
+        isSampled = dielectricSample(material, isFlipped ? 1.0 / material.ior : material.ior, wo, u, bsdfSample);
+    } else {
+        isSampled = opaqueSample(material, wo, u, bsdfSample);
+    }
+    bsdfSample.direction = flipDirection(bsdfSample.direction, isFlipped);
+    return isSampled;
+}
@@ -0,0 +1,105 @@
+// Light arriving from outside the scene, ported from `engine::environment`,
+// which has the tests. Included by path_tracing.glsl after `textures`.
+
+// `engine::environment::environment_buffer_data`, the same binding in both
+// backends
+layout(std430, binding = 8, set = 0) readonly buffer Environment {
+    // Index into `textures`, or NO_TEXTURE for the default sky
+    uint environmentTexture;
+    float environmentIntensity;
+    // About +Y, in radians
+    float environmentRotation;
+    uint environmentWidth;
+    uint environmentHeight;
+    // The marginal CDF over rows, environmentHeight + 1 values, then the
+    // conditional CDF of each row, environmentWidth + 1 values each
+    float environmentCdf[];
+};
+
+bool hasEnvironmentMap() {
+    return environmentTexture != NO_TEXTURE;
+}
+
+// Rows go from +Y down to -Y, columns from +X towards +Z
+vec2 directionToUv(vec3 direction) {
+    const float phi = atan(direction.z, direction.x) - environmentRotation;
+    return vec2(fract(phi / (2.0 * PI)), acos(clamp(direction.y, -1.0, 1.0)) / PI);
+}
+
+vec3 uvToDirection(vec2 uv) {
+    const float phi = uv.x * 2.0 * PI + environmentRotation;
+    const float theta = uv.y * PI;
+    return vec3(sin(theta) * cos(phi), cos(theta), sin(theta) * sin(phi));
+}
+
+// Light arriving from rays that leave the scene
+vec3 environmentRadiance(vec3 direction) {
+    if (!hasEnvironmentMap()) {
+        const float height = 0.5 * (direction.y + 1.0);
+        return mix(vec3(1.0), vec3(0.5, 0.7, 1.0), height);
+    }
+    return environmentIntensity * textureLod(textures[nonuniformEXT(environmentTexture)], directionToUv(direction), 0.0).rgb;
+}
+
+// The interval of the `count` + 1 CDF values from `first` on that `u` falls
+// into, skipping empty ones
+uint findInterval(uint first, uint count, float u) {
+    uint low = 0u;
+    uint high = count;
+    while (low + 1u < high) {
+        const uint middle = (low + high) / 2u;
+        if (environmentCdf[first + middle] <= u) {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    return low;
+}
+
+// How far into interval `index` of the CDF at `first` `u` lies
+float intervalOffset(uint first, uint index, float u) {
+    const float lower = environmentCdf[first + index];
+    const float width = environmentCdf[first + index + 1u] - lower;
+    return width > 0.0 ? clamp((u - lower) / width, 0.0, 1.0) : 0.0;
+}
+
+uint conditionalCdfStart(uint row) {
+    return environmentHeight + 1u + row * (environmentWidth + 1u);
+}
+
+// Density over texture coordinates within a texel
+float texelPdf(uint column, uint row) {
+    const uint conditional = conditionalCdfStart(row);
+    const float rowProbability = environmentCdf[row + 1u] - environmentCdf[row];
+    const float columnProbability = environmentCdf[conditional + column + 1u] - environmentCdf[conditional + column];
+    return rowProbability * columnProbability * float(environmentWidth * environmentHeight);
+}
+
+float solidAnglePdf(float uvPdf, vec3 direction) {
+    const float sinTheta = sqrt(max(1.0 - direction.y * direction.y, 0.0));
+    return sinTheta > 0.0 ? uvPdf / (2.0 * PI * PI * sinTheta) : 0.0;
+}
+
+// Picks a direction in proportion to the light arriving from it, with its
+// density per solid angle. Requires an environment map.
+vec3 sampleEnvironment(vec2 u, out float pdf) {
+    const uint row = findInterval(0u, environmentHeight, u.y);
+    const float rowOffset = intervalOffset(0u, row, u.y);
+    const uint conditional = conditionalCdfStart(row);
+    const uint column = findInterval(conditional, environmentWidth, u.x);
+    const float columnOffset = intervalOffset(conditional, column, u.x);
+
+    const vec2 uv = vec2((float(column) + columnOffset) / float(environmentWidth), (float(row) + rowOffset) / float(environmentHeight));
+    const vec3 direction = uvToDirection(uv);
+    pdf = solidAnglePdf(texelPdf(column, row), direction);
+    return direction;
+}
+
+// The density with which `sampleEnvironment` picks `direction`
+float environmentPdf(vec3 direction) {
+    const vec2 uv = directionToUv(direction);
+    const uint column = min(uint(uv.x * float(environmentWidth)), environmentWidth - 1u);
+    const uint row = min(uint(uv.y * float(environmentHeight)), environmentHeight - 1u);
+    return solidAnglePdf(texelPdf(column, row), direction);
+}
@@ -0,0 +1,118 @@
+// Lights for next-event estimation, gathered by `engine::light::LightList`,
+// which has the tests. Included by path_tracing.glsl.
+
+// Values of `Light::kind`
+const uint LIGHT_POINT = 0;
+const uint LIGHT_SPOT = 1;
+const uint LIGHT_DIRECTIONAL = 2;
+const uint LIGHT_TRIANGLE = 3;
+
+// Matches `engine::light::GpuLight`
+struct Light {
+    // Point and spot light position, or the first corner of a triangle
+    vec3 position;
+    uint kind;
+    // Where spot lights point and directional light travels, or the first
+    // edge of a triangle
+    vec3 direction;
+    float cosInner;
+    // The second edge of a triangle
+    vec3 edge;
+    float cosOuter;
+    // Intensity, illuminance or radiance, times the color
+    vec3 color;
+    float probability;
+    float aliasProbability;
+    uint alias;
+    uvec2 padding;
+};
+
+// `engine::light::LightList::buffer_data`, the same binding in both backends
+layout(std430, binding = 9, set = 0) readonly buffer Lights {
+    uint lightCount;
+    float totalLightPower;
+    uvec2 lightsPadding;
+    Light lights[];
+};
+
+// Light arriving at a point from one light
+struct LightSample {
+    // Towards the light
+    vec3 direction;
+    float distance;
+    // Arriving radiance divided by the density of picking the light and the
+    // direction
+    vec3 weightedRadiance;
+    // Density per solid angle of picking the direction, zero for lights that
+    // are only a point or a direction and can't be hit by rays
+    float pdf;
+};
+
+float luminance(vec3 color) {
+    return dot(color, vec3(0.2126, 0.7152, 0.0722));
+}
+
+// The alias method, with one random number
+uint pickLight(float u) {
+    const float scaled = u * float(lightCount);
+    uint index = min(uint(scaled), lightCount - 1u);
+    if (scaled - float(index) >= lights[index].aliasProbability) {
+        index = lights[index].alias;
+    }
+    return index;
+}
+
+// Picks a light in proportion to its power and a direction towards it from
+// `point`. Requires at least one light.
+LightSample sampleLight(vec3 point, vec3 u) {
+    const Light light = lights[pickLight(u.x)];
+    LightSample lightSample;
+    lightSample.pdf = 0.0;
+
+    if (light.kind == LIGHT_DIRECTIONAL) {
+        lightSample.direction = -light.direction;
+        lightSample.distance = 1e30;
+        lightSample.weightedRadiance = light.color / light.probability;
+        return lightSample;
+    }
+
+    if (light.kind == LIGHT_TRIANGLE) {
+        // Uniform over the triangle's area
+        const float root = sqrt(u.y);
+        const vec3 target = light.position + root * (1.0 - u.z) * light.direction + root * u.z * light.edge;
+        const vec3 normal = cross(light.direction, light.edge);
+        const float area = 0.5 * length(normal);
+        const vec3 toLight = target - point;
+        lightSample.distance = length(toLight);
+        lightSample.direction = toLight / lightSample.distance;
+        const float cosLight = abs(dot(lightSample.direction, normal)) / (2.0 * area);
+        if (cosLight <= 0.0) {
+            lightSample.weightedRadiance = vec3(0.0);
+            return lightSample;
+        }
+        lightSample.pdf = light.probability * lightSample.distance * lightSample.distance / (cosLight * area);
+        lightSample.weightedRadiance = light.color / lightSample.pdf;
+        return lightSample;
+    }
+
+    const vec3 toLight = light.position - point;
+    lightSample.distance = length(toLight);
+    lightSample.direction = toLight / lightSample.distance;
+    float falloff = 1.0;
+    if (light.kind == LIGHT_SPOT) {
+        const float cosAngle = dot(-lightSample.direction, light.direction);
+        falloff = light.cosInner > light.cosOuter ? smoothstep(light.cosOuter, light.cosInner, cosAngle) : step(light.cosOuter, cosAngle);
+    }
+    lightSample.weightedRadiance = falloff * light.color / (light.probability * lightSample.distance * lightSample.distance);
+    return lightSample;
+}
+
+// The density per solid angle with which `sampleLight` picks a point on an
+// emissive triangle with `emission`, seen at `distance` and `cosLight`. Lights
+// are picked in proportion to their power, so the triangle's area cancels out.
+float emissiveTrianglePdf(vec3 emission, float distance, float cosLight) {
+    if (totalLightPower <= 0.0 || cosLight <= 0.0) {
+        return 0.0;
+    }
+    return 2.0 * PI * luminance(emission) * distance * distance / (cosLight * totalLightPower);
+}
@@ -0,0 +1,350 @@
+// Path tracing shared by raytrace.rgen and trace.comp: the per-frame push
+// constants, the materials and their textures, random numbers, camera rays,
+// sampling and the accumulation of samples into the output image. Includers
+// enable GL_EXT_nonuniform_qualifier and provide the images named below and
+// the `traceClosestHit` and `isUnoccluded` functions.
+
+#include "bsdf.glsl"
+
+// Matches `engine::path_tracing::FrameConstants`
+layout(push_constant) uniform FrameConstants {
+    vec4 cameraPosition;
+    // Scaled so that the image plane spans -1 to 1 along right and up
+    vec4 cameraForward;
+    vec4 cameraRight;
+    vec4 cameraUp;
+    // Seeds the random numbers, different every frame
+    uint frameIndex;
+    // Samples already in the accumulation image, zero to start over
+    uint accumulatedSamples;
+    // Samples to add this frame, zero to only display the accumulation
+    uint samplesPerFrame;
+    uint maxBounces;
+    // Paths may be terminated at random from this bounce on
+    uint russianRouletteDepth;
+    // Zero for a pinhole camera
+    float lensRadius;
+    float focusDistance;
+    // Zero for a round aperture
+    uint apertureBlades;
+    float apertureRotation;
+} frame;
+
+// `engine::material::MaterialTable::materials`, the same binding in both
+// backends
+layout(std430, binding = 4, set = 0) readonly buffer Materials {
+    Material materials[];
+};
+
+// `engine::texture::TextureArray`, indexed by the texture slots of the
+// materials
+layout(binding = 6, set = 0) uniform sampler2D textures[];
+
+#include "environment.glsl"
+#include "lights.glsl"
+
+// PCG hash, from "Hash Functions for GPU Rendering" by Jarzynski and Olano
+uint pcgHash(uint value) {
+    uint state = value * 747796405u + 2891336453u;
+    uint word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+uint rngState;
+
+void initRandom(uvec2 pixel, uint width) {
+    rngState = pcgHash(pixel.y * width + pixel.x) ^ pcgHash(frame.frameIndex);
+}
+
+// Uniform in [0, 1)
+float random() {
+    rngState = pcgHash(rngState);
+    return float(rngState >> 8) / 16777216.0;
+}
+
+// Angle between the rays through neighbouring pixels, which widens the cone
+// each path stands for
+float pixelSpreadAngle;
+
+// Uniform on an aperture of radius 1, a disk or a regular polygon with a
+// corner at `apertureRotation`. Ported from
+// `engine::path_tracing::sample_aperture`, which has the tests.
+vec2 sampleAperture(vec2 u) {
+    if (frame.apertureBlades == 0u) {
+        // The concentric mapping by Shirley and Chiu
+        const vec2 offset = 2.0 * u - 1.0;
+        if (offset.x == 0.0 && offset.y == 0.0) {
+            return vec2(0.0);
+        }
+        float radius;
+        float angle;
+        if (abs(offset.x) > abs(offset.y)) {
+            radius = offset.x;
+            angle = 0.25 * PI * (offset.y / offset.x);
+        } else {
+            radius = offset.y;
+            angle = 0.5 * PI - 0.25 * PI * (offset.x / offset.y);
+        }
+        return radius * vec2(cos(angle), sin(angle));
+    }
+
+    // The triangle between the center and two neighbouring corners
+    const float scaled = u.x * float(frame.apertureBlades);
+    const uint blade = min(uint(scaled), frame.apertureBlades - 1u);
+    const float remainder = scaled - float(blade);
+    const float firstAngle = frame.apertureRotation + 2.0 * PI * float(blade) / float(frame.apertureBlades);
+    const float secondAngle = frame.apertureRotation + 2.0 * PI * float(blade + 1u) / float(frame.apertureBlades);
+    const vec2 first = vec2(cos(firstAngle), sin(firstAngle));
+    const vec2 second = vec2(cos(secondAngle), sin(secondAngle));
+    return sqrt(remainder) * mix(first, second, u.y);
+}
+
+// Jittered inside the pixel, so the accumulation antialiases the image, and
+// across the lens. Ported from `engine::path_tracing::CameraView::ray`.
+void cameraRay(uvec2 pixel, uvec2 size, out vec3 origin, out vec3 direction) {
+    const vec2 uv = (vec2(pixel) + vec2(random(), random())) / vec2(size);
+    // Image rows go down, the camera's up vector goes up
+    const vec2 ndc = vec2(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0);
+
+    origin = frame.cameraPosition.xyz;
+    direction = normalize(frame.cameraForward.xyz + ndc.x * frame.cameraRight.xyz + ndc.y * frame.cameraUp.xyz);
+    if (frame.lensRadius <= 0.0) {
+        return;
+    }
+
+    // Every ray through the pixel meets on the plane in focus
+    const vec3 focusPoint = origin + direction * (frame.focusDistance / dot(direction, frame.cameraForward.xyz));
+    const vec2 lensOffset = frame.lensRadius * sampleAperture(vec2(random(), random()));
+    origin += lensOffset.x * normalize(frame.cameraRight.xyz) + lensOffset.y * normalize(frame.cameraUp.xyz);
+    direction = normalize(focusPoint - origin);
+}
+
+// Tangents completing `normal` to an orthonormal basis, from "Building an
+// Orthonormal Basis, Revisited" by Duff et al.
+void orthonormalBasis(vec3 normal, out vec3 tangent, out vec3 bitangent) {
+    const float s = normal.z >= 0.0 ? 1.0 : -1.0;
+    const float a = -1.0 / (s + normal.z);
+    const float b = normal.x * normal.y * a;
+    tangent = vec3(1.0 + s * normal.x * normal.x * a, s * b, -s * normal.x);
+    bitangent = vec3(b, s + normal.y * normal.y * a, -normal.y);
+}
+
+// The closest surface along a ray
+struct SurfaceHit {
+    float t;
+    // Geometric normal, on the side the front face is on
+    vec3 normal;
+    // Direction along the surface in which the texture coordinate u grows,
+    // with the handedness of the texture coordinates in w. All zero when the
+    // triangle's texture coordinates are degenerate.
+    vec4 tangent;
+    vec2 uv;
+    // Texture coordinate area per world space area of the triangle
+    float uvDensity;
+    uint material;
+};
+
+// Implemented by the includer. Returns false when nothing is hit.
+bool traceClosestHit(vec3 origin, vec3 direction, out SurfaceHit hit);
+
+// Implemented by the includer. Whether the ray gets `tMax` far without
+// hitting anything.
+bool isUnoccluded(vec3 origin, vec3 direction, float tMax);
+
+// Weighs a sample by how much better its strategy is at finding it than the
+// other strategy is, from "Optimally Combining Sampling Techniques for Monte
+// Carlo Rendering" by Veach and Guibas
+float powerHeuristic(float pdf, float otherPdf) {
+    const float square = pdf * pdf;
+    const float otherSquare = otherPdf * otherPdf;
+    return square + otherSquare > 0.0 ? square / (square + otherSquare) : 0.0;
+}
+
+// Starts a ray leaving a surface with geometric normal `normal` on the side
+// `direction` points to
+vec3 offsetOrigin(vec3 point, vec3 normal, vec3 direction) {
+    return point + (dot(direction, normal) > 0.0 ? 1e-4 : -1e-4) * normal;
+}
+
+// A point light scatters at, with its shading frame
+struct ShadingPoint {
+    vec3 position;
+    vec3 geometricNormal;
+    vec3 tangent;
+    vec3 bitangent;
+    vec3 normal;
+    // Where the light leaves to, in the shading frame
+    vec3 wo;
+};
+
+// Light arriving along `direction` from up to `distance` away that scatters
+// towards `wo`, unless something is in the way. `weightedRadiance` is
+// already divided by `lightPdf`, which is weighted against finding the same
+// direction by sampling the BSDF unless it's zero.
+vec3 scatterLight(ShadingPoint shading, Material material, vec3 direction, float distance, vec3 weightedRadiance, float lightPdf) {
+    const vec3 wi = vec3(dot(direction, shading.tangent), dot(direction, shading.bitangent), dot(direction, shading.normal));
+    const vec3 scattered = evalBsdf(material, shading.wo, wi) * abs(wi.z) * weightedRadiance;
+    if (max(scattered.r, max(scattered.g, scattered.b)) <= 0.0
+            || !isUnoccluded(offsetOrigin(shading.position, shading.geometricNormal, direction), direction, distance)) {
+        return vec3(0.0);
+    }
+
+    const float weight = lightPdf > 0.0 ? powerHeuristic(lightPdf, pdfBsdf(material, shading.wo, wi)) : 1.0;
+    return scattered * weight;
+}
+
+// Next-event estimation: light from a direction picked from the environment
+// map and from a point on a light
+vec3 sampleDirectLight(ShadingPoint shading, Material material) {
+    vec3 radiance = vec3(0.0);
+    if (hasEnvironmentMap()) {
+        float pdf;
+        const vec3 direction = sampleEnvironment(vec2(random(), random()), pdf);
+        if (pdf > 0.0) {
+            radiance += scatterLight(shading, material, direction, 1e30, environmentRadiance(direction) / pdf, pdf);
+        }
+    }
+    if (lightCount > 0u) {
+        const LightSample lightSample = sampleLight(shading.position, vec3(random(), random(), random()));
+        // Stop short of the light's own surface
+        radiance += scatterLight(shading, material, lightSample.direction, 0.999 * lightSample.distance, lightSample.weightedRadiance, lightSample.pdf);
+    }
+    return radiance;
+}
+
+// Filters texture `index` over `footprint`, the width of the ray cone at the
+// hit point in texture coordinates. Ray shaders have no derivatives to pick
+// mip levels with, so the cone stands in for them.
+vec4 sampleTexture(uint index, vec2 uv, float footprint) {
+    const vec2 size = vec2(textureSize(textures[nonuniformEXT(index)], 0));
+    const float lod = log2(max(footprint, 1e-10)) + 0.5 * log2(size.x * size.y);
+    return textureLod(textures[nonuniformEXT(index)], uv, lod);
+}
+
+// Scales the factors of `material` by its textures at the hit point and bends
+// `shadingNormal` with its normal map. Metallic-roughness textures hold
+// roughness in green and metalness in blue.
+void applyTextures(SurfaceHit hit, float footprint, inout Material material, inout vec3 shadingNormal) {
+    if (material.baseColorTexture != NO_TEXTURE) {
+        material.color *= sampleTexture(material.baseColorTexture, hit.uv, footprint).rgb;
+    }
+    if (material.metallicRoughnessTexture != NO_TEXTURE) {
+        const vec4 texel = sampleTexture(material.metallicRoughnessTexture, hit.uv, footprint);
+        material.roughness *= texel.g;
+        material.metallic *= texel.b;
+    }
+    if (material.emissiveTexture != NO_TEXTURE) {
+        material.emission *= sampleTexture(material.emissiveTexture, hit.uv, footprint).rgb;
+    }
+    if (material.normalTexture != NO_TEXTURE && hit.tangent.w != 0.0) {
+        const vec3 texel = sampleTexture(material.normalTexture, hit.uv, footprint).xyz * 2.0 - 1.0;
+        const vec3 tangent = normalize(hit.tangent.xyz - dot(hit.tangent.xyz, hit.normal) * hit.normal);
+        const vec3 bitangent = hit.tangent.w * cross(hit.normal, tangent);
+        const vec3 bent = texel.x * tangent + texel.y * bitangent + texel.z * hit.normal;
+        if (dot(bent, bent) > 0.0) {
+            shadingNormal = normalize(bent);
+        }
+    }
+}
+
+vec3 tracePath(vec3 origin, vec3 direction) {
+    vec3 radiance = vec3(0.0);
+    vec3 throughput = vec3(1.0);
+    // Width of the ray cone, grown with the distance travelled only, which
+    // underestimates it after bounces and errs towards sharper textures
+    float coneWidth = 0.0;
+    // Density with which the last bounce picked `direction`, zero for camera
+    // rays and specular bounces, which light sampling can't find
+    float bsdfPdf = 0.0;
+
+    for (uint bounce = 0; bounce <= frame.maxBounces; bounce++) {
+        SurfaceHit hit;
+        if (!traceClosestHit(origin, direction, hit)) {
+            const bool isEnvironmentSampled = hasEnvironmentMap() && bsdfPdf > 0.0;
+            const float weight = isEnvironmentSampled ? powerHeuristic(bsdfPdf, environmentPdf(direction)) : 1.0;
+            radiance += throughput * weight * environmentRadiance(direction);
+            break;
+        }
+
+        coneWidth += hit.t * pixelSpreadAngle;
+        const float cosine = max(abs(dot(direction, hit.normal)), 1e-4);
+        const float footprint = coneWidth * sqrt(hit.uvDensity) / cosine;
+        Material material = materials[hit.material];
+        vec3 normal = hit.normal;
+        // Lights are picked by their untextured emission
+        const vec3 lightEmission = material.emission;
+        applyTextures(hit, footprint, material, normal);
+
+        float emissionWeight = 1.0;
+        if (bsdfPdf > 0.0 && lightCount > 0u) {
+            const float lightPdf = emissiveTrianglePdf(lightEmission, hit.t, abs(dot(direction, hit.normal)));
+            emissionWeight = powerHeuristic(bsdfPdf, lightPdf);
+        }
+        radiance += throughput * emissionWeight * material.emission;
+        if (bounce == frame.maxBounces) {
+            break;
+        }
+
+        // Scatter in the shading frame around the normal
+        ShadingPoint shading;
+        shading.position = origin + hit.t * direction;
+        shading.geometricNormal = hit.normal;
+        shading.normal = normal;
+        orthonormalBasis(normal, shading.tangent, shading.bitangent);
+        shading.wo = -vec3(dot(direction, shading.tangent), dot(direction, shading.bitangent), dot(direction, normal));
+        radiance += throughput * sampleDirectLight(shading, material);
+
+        BsdfSample bsdfSample;
+        if (!sampleBsdf(material, shading.wo, vec3(random(), random(), random()), bsdfSample)) {
+            break;
+        }
+        throughput *= bsdfSample.weight;
+        bsdfPdf = bsdfSample.isSpecular ? 0.0 : bsdfSample.pdf;
+
+        // Start the next ray on the side of the surface it leaves to, which
+        // normal maps can make differ from the side of the shading frame
+        direction = normalize(bsdfSample.direction.x * shading.tangent + bsdfSample.direction.y * shading.bitangent + bsdfSample.direction.z * normal);
+        origin = offsetOrigin(shading.position, hit.normal, direction);
+
+        // Continue dim paths with a lower probability, weighted up so the
+        // estimate stays unbiased
+        if (bounce + 1 >= frame.russianRouletteDepth) {
+            const float survival = clamp(max(throughput.r, max(throughput.g, throughput.b)), 0.05, 1.0);
+            if (random() >= survival) {
+                break;
+            }
+            throughput /= survival;
+        }
+    }
+
+    return radiance;
+}
+
+// ACES filmic curve fitted by Krzysztof Narkowicz
+vec3 tonemap(vec3 color) {
+    return clamp((color * (2.51 * color + 0.03)) / (color * (2.43 * color + 0.59) + 0.14), 0.0, 1.0);
+}
+
+// Adds this frame's samples of `pixel` to the running sum in
+// `accumulationImage` and writes the tonemapped average to `image`
+void renderPixel(uvec2 pixel, uvec2 size) {
+    initRandom(pixel, size.x);
+    pixelSpreadAngle = 2.0 * length(frame.cameraUp.xyz) / float(size.y);
+
+    vec3 sum = vec3(0.0);
+    for (uint sampleIndex = 0; sampleIndex < frame.samplesPerFrame; sampleIndex++) {
+        vec3 origin;
+        vec3 direction;
+        cameraRay(pixel, size, origin, direction);
+        sum += tracePath(origin, direction);
+    }
+
+    if (frame.accumulatedSamples > 0) {
+        sum += imageLoad(accumulationImage, ivec2(pixel)).rgb;
+    }
+    const uint sampleCount = frame.accumulatedSamples + frame.samplesPerFrame;
+    imageStore(accumulationImage, ivec2(pixel), vec4(sum, 1.0));
+
+    const vec3 average = sum / float(max(sampleCount, 1u));
+    imageStore(image, ivec2(pixel), vec4(tonemap(average), 1.0));
+}
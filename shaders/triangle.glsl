@@ -0,0 +1,22 @@
+// What raytrace.rchit and trace.comp derive from the corners of the triangle
+// they hit, for texturing. Edges run from the first corner to the other two,
+// in world space and in texture coordinates.
+
+// The direction in which u grows along the triangle, with the handedness of
+// the texture coordinates relative to `normal` in w, and the texture
+// coordinate area per world space area. The tangent is all zero when the
+// texture coordinates don't span the triangle.
+void triangleTextureFrame(vec3 edge1, vec3 edge2, vec2 uvEdge1, vec2 uvEdge2, vec3 normal, out vec4 tangent, out float uvDensity) {
+    const float uvArea = uvEdge1.x * uvEdge2.y - uvEdge2.x * uvEdge1.y;
+    const float area = length(cross(edge1, edge2));
+    uvDensity = area > 0.0 ? abs(uvArea) / area : 0.0;
+
+    if (abs(uvArea) < 1e-12) {
+        tangent = vec4(0.0);
+        return;
+    }
+    const vec3 uDirection = (edge1 * uvEdge2.y - edge2 * uvEdge1.y) / uvArea;
+    const vec3 vDirection = (edge2 * uvEdge1.x - edge1 * uvEdge2.x) / uvArea;
+    const float handedness = dot(cross(normal, uDirection), vDirection) < 0.0 ? -1.0 : 1.0;
+    tangent = vec4(uDirection, handedness);
+}
@@ -0,0 +1,391 @@
+use ash::vk;
+use glam::{Vec2, Vec3};
+use winit::dpi::PhysicalPosition;
+use winit::event::{ElementState, MouseScrollDelta};
+use winit::keyboard::{KeyCode, PhysicalKey};
+use winit::window::{CursorGrabMode, Window};
+
+use crate::engine::path_tracing::{CameraView, Lens};
+use crate::scene;
+
+/// How far the camera can look up or down, just short of straight up so the
+/// view never flips over.
+const MAX_PITCH: f32 = 89.0 * std::f32::consts::PI / 180.0;
+/// Radians turned per pixel of mouse movement
+const MOUSE_SENSITIVITY: f32 = 0.002;
+/// Factor the speed changes by per line scrolled
+const SPEED_STEP: f32 = 1.25;
+const MIN_SPEED: f32 = 1e-3;
+const MAX_SPEED: f32 = 1e4;
+/// Pixels of a touchpad scroll that count as one line
+const PIXELS_PER_LINE: f32 = 40.0;
+const MIN_ORBIT_DISTANCE: f32 = 1e-3;
+/// Longest time step applied at once, so a stalled frame doesn't send the
+/// camera flying
+const MAX_TIME_STEP: f32 = 0.1;
+
+/// A camera that can be flown through the scene or orbited around a point.
+/// Where it looks is given by a yaw about `up` and a pitch towards it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Camera {
+    pub position: Vec3,
+    pub yaw: f32,
+    pub pitch: f32,
+    /// Vertical field of view
+    pub fov_degrees: f32,
+    /// Lens diameter, zero for a pinhole camera
+    pub aperture: f32,
+    pub focus_distance: f32,
+    /// Blades of a polygonal aperture, or zero for a round one
+    pub aperture_blades: u32,
+    pub aperture_rotation_degrees: f32,
+    /// When the shutter opens and closes, between time 0 and 1 of the
+    /// objects' motion
+    pub shutter: [f32; 2],
+    /// Units moved per second
+    pub speed: f32,
+    pub mode: CameraMode,
+    up: Vec3,
+    /// How far in front of the camera orbit mode circles around
+    orbit_distance: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CameraMode {
+    Fly,
+    Orbit { target: Vec3 },
+}
+
+impl Camera {
+    /// Looks from the scene camera's position towards its `look_at`, which is
+    /// also what orbit mode circles around. The speed crosses that distance
+    /// in a second.
+    pub fn from_scene(camera: &scene::Camera) -> Self {
+        let position = Vec3::from_array(camera.position);
+        let up = Vec3::from_array(camera.up).normalize();
+        let to_target = Vec3::from_array(camera.look_at) - position;
+        let orbit_distance = to_target.length().max(MIN_ORBIT_DISTANCE);
+        let (yaw, pitch) = yaw_pitch(up, to_target / orbit_distance);
+
+        Self {
+            position,
+            yaw,
+            pitch,
+            fov_degrees: camera.fov_degrees,
+            aperture: camera.aperture,
+            focus_distance: camera.focus_distance,
+            aperture_blades: camera.aperture_blades,
+            aperture_rotation_degrees: camera.aperture_rotation_degrees,
+            shutter: camera.shutter,
+            speed: orbit_distance.clamp(MIN_SPEED, MAX_SPEED),
+            mode: CameraMode::Fly,
+            up,
+            orbit_distance,
+        }
+    }
+
+    pub fn forward(&self) -> Vec3 {
+        let (x_axis, z_axis) = horizontal_axes(self.up);
+        let horizontal = x_axis * self.yaw.cos() + z_axis * self.yaw.sin();
+        horizontal * self.pitch.cos() + self.up * self.pitch.sin()
+    }
+
+    pub fn view(&self, extent: vk::Extent2D) -> CameraView {
+        CameraView::new(
+            self.position,
+            self.forward(),
+            self.up,
+            self.fov_degrees,
+            Lens {
+                radius: 0.5 * self.aperture,
+                focus_distance: self.focus_distance,
+                blades: self.aperture_blades,
+                rotation: self.aperture_rotation_degrees.to_radians(),
+            },
+            extent,
+        )
+    }
+
+    /// Switches between flying and orbiting the point `orbit_distance` in
+    /// front of the camera.
+    pub fn toggle_orbit(&mut self) {
+        self.mode = match self.mode {
+            CameraMode::Fly => CameraMode::Orbit {
+                target: self.position + self.forward() * self.orbit_distance,
+            },
+            CameraMode::Orbit { .. } => CameraMode::Fly,
+        };
+    }
+
+    /// Turns the camera by a mouse movement of `delta` pixels. In orbit mode
+    /// the camera moves around the target so it keeps looking at it.
+    pub fn rotate(&mut self, delta: Vec2) {
+        self.yaw = (self.yaw + delta.x * MOUSE_SENSITIVITY) % std::f32::consts::TAU;
+        self.pitch = (self.pitch - delta.y * MOUSE_SENSITIVITY).clamp(-MAX_PITCH, MAX_PITCH);
+        self.update_orbit_position();
+    }
+
+    /// Moves the camera for `time_step` seconds along `movement`, given as
+    /// amounts right, up and forward. In orbit mode going forward approaches
+    /// the target and the other directions move the target along.
+    pub fn translate(&mut self, movement: Vec3, time_step: f32) {
+        if movement == Vec3::ZERO {
+            return;
+        }
+        let forward = self.forward();
+        let right = forward.cross(self.up).normalize();
+        let distance = self.speed * time_step.min(MAX_TIME_STEP);
+
+        match &mut self.mode {
+            CameraMode::Fly => {
+                self.position +=
+                    (right * movement.x + self.up * movement.y + forward * movement.z) * distance;
+            }
+            CameraMode::Orbit { target } => {
+                *target += (right * movement.x + self.up * movement.y) * distance;
+                self.orbit_distance =
+                    (self.orbit_distance - movement.z * distance).max(MIN_ORBIT_DISTANCE);
+            }
+        }
+        self.update_orbit_position();
+    }
+
+    /// Scales the speed by `SPEED_STEP` per line scrolled.
+    pub fn change_speed(&mut self, lines: f32) {
+        self.speed = (self.speed * SPEED_STEP.powf(lines)).clamp(MIN_SPEED, MAX_SPEED);
+    }
+
+    fn update_orbit_position(&mut self) {
+        if let CameraMode::Orbit { target } = self.mode {
+            self.position = target - self.forward() * self.orbit_distance;
+        }
+    }
+}
+
+/// Two axes perpendicular to `up` and each other, from which yaw is measured.
+/// For +Y these are +X and +Z.
+fn horizontal_axes(up: Vec3) -> (Vec3, Vec3) {
+    let reference = if up.x.abs() < 0.9 { Vec3::X } else { Vec3::Z };
+    let x_axis = (reference - up * reference.dot(up)).normalize();
+    (x_axis, x_axis.cross(up))
+}
+
+/// The yaw and pitch that `forward`, of unit length, points towards.
+fn yaw_pitch(up: Vec3, forward: Vec3) -> (f32, f32) {
+    let (x_axis, z_axis) = horizontal_axes(up);
+    let yaw = forward.dot(z_axis).atan2(forward.dot(x_axis));
+    let pitch = forward.dot(up).clamp(-1.0, 1.0).asin();
+    (yaw, pitch.clamp(-MAX_PITCH, MAX_PITCH))
+}
+
+/// Lines scrolled by a mouse wheel or touchpad, positive away from the user.
+pub fn scroll_lines(delta: MouseScrollDelta) -> f32 {
+    match delta {
+        MouseScrollDelta::LineDelta(_, lines) => lines,
+        MouseScrollDelta::PixelDelta(PhysicalPosition { y, .. }) => y as f32 / PIXELS_PER_LINE,
+    }
+}
+
+/// Turns keyboard and mouse input into camera movement. Mouse look needs the
+/// cursor grabbed, which a click in the window does.
+#[derive(Debug, Default)]
+pub struct CameraController {
+    /// Movement keys held down: D and A, E and Q, W and S
+    right: (bool, bool),
+    up: (bool, bool),
+    forward: (bool, bool),
+    /// Mouse movement since the last update
+    mouse_delta: Vec2,
+    is_cursor_grabbed: bool,
+}
+
+impl CameraController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_cursor_grabbed(&self) -> bool {
+        self.is_cursor_grabbed
+    }
+
+    /// Records a movement key going up or down. Returns whether the key is
+    /// one of them.
+    pub fn handle_key(&mut self, key: PhysicalKey, state: ElementState) -> bool {
+        let is_pressed = state == ElementState::Pressed;
+        let PhysicalKey::Code(code) = key else {
+            return false;
+        };
+        match code {
+            KeyCode::KeyD => self.right.0 = is_pressed,
+            KeyCode::KeyA => self.right.1 = is_pressed,
+            KeyCode::KeyE => self.up.0 = is_pressed,
+            KeyCode::KeyQ => self.up.1 = is_pressed,
+            KeyCode::KeyW => self.forward.0 = is_pressed,
+            KeyCode::KeyS => self.forward.1 = is_pressed,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Raw mouse movement, which only turns the camera while the cursor is
+    /// grabbed.
+    pub fn handle_mouse_motion(&mut self, delta: (f64, f64)) {
+        if self.is_cursor_grabbed {
+            self.mouse_delta += Vec2::new(delta.0 as f32, delta.1 as f32);
+        }
+    }
+
+    /// Hides the cursor and keeps it in the window so the mouse can look
+    /// around. Locking it in place isn't supported everywhere, in which case
+    /// it is only confined to the window.
+    pub fn grab_cursor(&mut self, window: &Window) {
+        let result = window
+            .set_cursor_grab(CursorGrabMode::Locked)
+            .or_else(|_| window.set_cursor_grab(CursorGrabMode::Confined));
+        match result {
+            Ok(()) => {
+                window.set_cursor_visible(false);
+                self.is_cursor_grabbed = true;
+            }
+            Err(error) => eprintln!("Failed to grab the cursor: {}", error),
+        }
+    }
+
+    pub fn release_cursor(&mut self, window: &Window) {
+        let _ = window.set_cursor_grab(CursorGrabMode::None);
+        window.set_cursor_visible(true);
+        self.is_cursor_grabbed = false;
+        self.mouse_delta = Vec2::ZERO;
+    }
+
+    /// Forgets the held keys, e.g. when the window loses focus and won't see
+    /// them being released.
+    pub fn release_keys(&mut self) {
+        self.right = (false, false);
+        self.up = (false, false);
+        self.forward = (false, false);
+    }
+
+    /// Applies the input since the last update, `time_step` seconds ago.
+    pub fn update(&mut self, camera: &mut Camera, time_step: f32) {
+        if self.mouse_delta != Vec2::ZERO {
+            camera.rotate(self.mouse_delta);
+            self.mouse_delta = Vec2::ZERO;
+        }
+
+        let axis =
+            |(positive, negative): (bool, bool)| positive as i32 as f32 - negative as i32 as f32;
+        let movement = Vec3::new(axis(self.right), axis(self.up), axis(self.forward));
+        camera.translate(movement, time_step);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scene_camera(position: [f32; 3], look_at: [f32; 3], up: [f32; 3]) -> scene::Camera {
+        scene::Camera {
+            position,
+            look_at,
+            up,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn from_scene_looks_at_the_target() {
+        for up in [[0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [1.0, 0.0, 0.0]] {
+            let camera = Camera::from_scene(&scene_camera([1.0, 2.0, 3.0], [4.0, 0.0, -1.0], up));
+            let expected = (Vec3::new(3.0, -2.0, -4.0)).normalize();
+            assert!(
+                camera.forward().distance(expected) < 1e-5,
+                "up {:?}: {} != {}",
+                up,
+                camera.forward(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn default_scene_camera_keeps_its_view() {
+        let scene_camera = scene::Camera::default();
+        let camera = Camera::from_scene(&scene_camera);
+        let view = camera.view(vk::Extent2D {
+            width: 800,
+            height: 600,
+        });
+
+        assert!(view.forward.distance(Vec3::Z) < 1e-6);
+        assert!(view.right.normalize().distance(Vec3::NEG_X) < 1e-6);
+        assert!(view.up.normalize().distance(Vec3::Y) < 1e-6);
+    }
+
+    #[test]
+    fn pitch_is_clamped() {
+        let mut camera = Camera::from_scene(&scene::Camera::default());
+        camera.rotate(Vec2::new(0.0, -1e6));
+        assert_eq!(camera.pitch, MAX_PITCH);
+        camera.rotate(Vec2::new(0.0, 1e6));
+        assert_eq!(camera.pitch, -MAX_PITCH);
+    }
+
+    #[test]
+    fn flying_moves_at_the_speed() {
+        let mut camera = Camera::from_scene(&scene::Camera::default());
+        camera.speed = 2.0;
+        camera.translate(Vec3::Z, 0.05);
+        assert!(camera.position.distance(Vec3::new(0.0, 0.0, -0.9)) < 1e-6);
+
+        // Long frames move no further than the largest time step
+        camera.translate(Vec3::Y, 10.0);
+        assert!(
+            camera
+                .position
+                .distance(Vec3::new(0.0, 2.0 * MAX_TIME_STEP, -0.9))
+                < 1e-6
+        );
+    }
+
+    #[test]
+    fn orbiting_keeps_the_target_in_view() {
+        let mut camera = Camera::from_scene(&scene_camera(
+            [0.0, 0.0, -2.0],
+            [0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ));
+        camera.toggle_orbit();
+        let CameraMode::Orbit { target } = camera.mode else {
+            panic!("Expected orbit mode");
+        };
+        assert!(target.length() < 1e-6);
+
+        camera.rotate(Vec2::new(300.0, -200.0));
+        assert!((camera.position.distance(target) - 2.0).abs() < 1e-5);
+        assert!(
+            (target - camera.position)
+                .normalize()
+                .distance(camera.forward())
+                < 1e-5
+        );
+
+        // Moving forward approaches the target rather than passing it
+        camera.speed = 100.0;
+        camera.translate(Vec3::Z, MAX_TIME_STEP);
+        assert!((camera.position.distance(target) - MIN_ORBIT_DISTANCE).abs() < 1e-5);
+
+        camera.toggle_orbit();
+        assert_eq!(camera.mode, CameraMode::Fly);
+    }
+
+    #[test]
+    fn scrolling_changes_speed() {
+        let mut camera = Camera::from_scene(&scene::Camera::default());
+        let speed = camera.speed;
+        camera.change_speed(2.0);
+        assert!((camera.speed - speed * SPEED_STEP * SPEED_STEP).abs() < 1e-6);
+        camera.change_speed(-1e3);
+        assert_eq!(camera.speed, MIN_SPEED);
+    }
+}
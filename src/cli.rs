@@ -0,0 +1,230 @@
+//! Command-line options of the renderer binary.
+
+use std::path::{Path, PathBuf};
+
+use ash::vk;
+use clap::{Parser, ValueEnum};
+
+use vulkan_ray_tracer::engine::backend::Backend;
+use vulkan_ray_tracer::engine::physical_device::DeviceRequest;
+use vulkan_ray_tracer::scene::RenderSettings;
+use vulkan_ray_tracer::utils::image_export::ImageFileFormat;
+use vulkan_ray_tracer::RendererOptions;
+
+/// Path traces a scene with Vulkan, in a window or headless.
+#[derive(Debug, Clone, Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// Scene file, TOML or glTF. Renders a single triangle without one.
+    pub scene: Option<PathBuf>,
+
+    /// Size of the window or the headless render, as WIDTHxHEIGHT. Overrides
+    /// the scene's render settings.
+    #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = parse_resolution)]
+    pub resolution: Option<(u32, u32)>,
+
+    /// Samples per pixel to accumulate. Overrides the scene's render settings.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub samples: Option<u32>,
+
+    /// Overrides the scene's render settings.
+    #[arg(long)]
+    pub max_bounces: Option<u32>,
+
+    /// How frames are produced. Chosen from the device capabilities when not
+    /// given.
+    #[arg(long, value_enum)]
+    pub backend: Option<BackendArg>,
+
+    /// The device to render with, by its index or UUID in `--list-devices` or
+    /// part of its name. The best scoring suitable device is used otherwise.
+    #[arg(long, value_name = "INDEX|UUID|NAME")]
+    pub gpu: Option<DeviceRequest>,
+
+    /// How frames are presented to the window. Falls back to FIFO, which is
+    /// always supported, when the surface doesn't support the mode. Mailbox
+    /// if available when not given.
+    #[arg(long, value_enum)]
+    pub present_mode: Option<PresentModeArg>,
+
+    /// Disables the Khronos validation layer and the debug messenger.
+    #[arg(long)]
+    pub no_validation: bool,
+
+    /// Renders offscreen without opening a window and saves the result.
+    #[arg(long)]
+    pub headless: bool,
+
+    /// Where a headless render is written, as PNG, PPM or OpenEXR depending
+    /// on the extension.
+    #[arg(
+        long,
+        default_value = "render.png",
+        requires = "headless",
+        value_parser = parse_output
+    )]
+    pub output: PathBuf,
+
+    /// Lists the Vulkan devices and exits.
+    #[arg(long)]
+    pub list_devices: bool,
+
+    /// Prints the devices considered and the one picked.
+    #[arg(short, long)]
+    pub verbose: bool,
+}
+
+impl Args {
+    pub fn is_validation_enabled(&self) -> bool {
+        !self.no_validation
+    }
+
+    pub fn backend(&self) -> Option<Backend> {
+        self.backend.map(|backend| match backend {
+            BackendArg::Raster => Backend::Rasterizer,
+            BackendArg::Compute => Backend::ComputeRayTracing,
+            BackendArg::RayTracing => Backend::HardwareRayTracing,
+        })
+    }
+
+    pub fn present_mode(&self) -> Option<vk::PresentModeKHR> {
+        self.present_mode.map(|present_mode| match present_mode {
+            PresentModeArg::Fifo => vk::PresentModeKHR::FIFO,
+            PresentModeArg::FifoRelaxed => vk::PresentModeKHR::FIFO_RELAXED,
+            PresentModeArg::Mailbox => vk::PresentModeKHR::MAILBOX,
+            PresentModeArg::Immediate => vk::PresentModeKHR::IMMEDIATE,
+        })
+    }
+
+    pub fn renderer_options(&self) -> RendererOptions {
+        RendererOptions {
+            validation: self.is_validation_enabled(),
+            backend: self.backend(),
+            device: self.gpu.clone(),
+            present_mode: self.present_mode(),
+            verbose: self.verbose,
+        }
+    }
+
+    /// Replaces the render settings given on the command line, which take
+    /// precedence over the scene file, also after it is reloaded.
+    pub fn apply_to(&self, render: &mut RenderSettings) {
+        if let Some((width, height)) = self.resolution {
+            render.width = width;
+            render.height = height;
+        }
+        if let Some(samples) = self.samples {
+            render.samples_per_pixel = samples;
+            render.samples_per_frame = render.samples_per_frame.min(samples);
+        }
+        if let Some(max_bounces) = self.max_bounces {
+            render.max_bounces = max_bounces;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BackendArg {
+    /// The graphics pipeline, without path tracing
+    Raster,
+    /// Path tracing through a BVH in a compute shader
+    Compute,
+    /// Path tracing with the ray tracing pipeline extensions
+    RayTracing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PresentModeArg {
+    /// Waits for vertical blank, never tears
+    Fifo,
+    /// Like FIFO, but late frames are shown right away
+    FifoRelaxed,
+    /// Replaces queued frames with newer ones, never tears
+    Mailbox,
+    /// Shows frames right away, may tear
+    Immediate,
+}
+
+fn parse_resolution(value: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("expected WIDTHxHEIGHT, e.g. 1920x1080, got \"{}\"", value);
+    let (width, height) = value.split_once(['x', 'X']).ok_or_else(invalid)?;
+    let width: u32 = width.trim().parse().map_err(|_| invalid())?;
+    let height: u32 = height.trim().parse().map_err(|_| invalid())?;
+    if width == 0 || height == 0 {
+        return Err("the resolution must be at least 1x1".to_string());
+    }
+    Ok((width, height))
+}
+
+fn parse_output(value: &str) -> Result<PathBuf, String> {
+    match ImageFileFormat::from_path(Path::new(value)) {
+        Some(_) => Ok(PathBuf::from(value)),
+        None => Err(format!(
+            "expected a .png, .ppm or .exr file, got \"{}\"",
+            value
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn command_is_consistent() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn parses_resolutions() {
+        assert_eq!(parse_resolution("1920x1080"), Ok((1920, 1080)));
+        assert_eq!(parse_resolution("64X32"), Ok((64, 32)));
+        assert!(parse_resolution("1920").is_err());
+        assert!(parse_resolution("0x10").is_err());
+        assert!(parse_resolution("wide x tall").is_err());
+    }
+
+    #[test]
+    fn output_requires_headless() {
+        assert!(Args::try_parse_from(["renderer", "--output", "a.exr"]).is_err());
+        let args = Args::try_parse_from(["renderer", "--headless", "--output", "a.exr"]).unwrap();
+        assert_eq!(args.output, PathBuf::from("a.exr"));
+    }
+
+    #[test]
+    fn output_needs_an_image_extension() {
+        assert_eq!(parse_output("out/a.PPM"), Ok(PathBuf::from("out/a.PPM")));
+        assert!(parse_output("a.jpg").is_err());
+        assert!(parse_output("render").is_err());
+    }
+
+    #[test]
+    fn overrides_render_settings() {
+        let args = Args::try_parse_from([
+            "renderer",
+            "scene.toml",
+            "--resolution",
+            "320x240",
+            "--samples",
+            "16",
+            "--max-bounces",
+            "8",
+            "--gpu",
+            "1",
+        ])
+        .unwrap();
+        assert_eq!(args.scene, Some(PathBuf::from("scene.toml")));
+        assert_eq!(args.gpu, Some(DeviceRequest::Index(1)));
+
+        let mut render = RenderSettings {
+            samples_per_frame: 32,
+            ..Default::default()
+        };
+        args.apply_to(&mut render);
+        assert_eq!((render.width, render.height), (320, 240));
+        assert_eq!(render.samples_per_pixel, 16);
+        assert_eq!(render.samples_per_frame, 16);
+        assert_eq!(render.max_bounces, 8);
+    }
+}
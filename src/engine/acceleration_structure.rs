@@ -0,0 +1,1063 @@
+use std::ffi::c_void;
+use std::fmt;
+use std::rc::Rc;
+
+use ash;
+use ash::vk;
+
+use crate::engine;
+use crate::engine::allocator::Allocator;
+use crate::engine::buffer::Buffer;
+use crate::engine::error::VkResultExt;
+use crate::engine::logical_device::Device;
+use crate::engine::sync::MAX_FRAMES_IN_FLIGHT;
+
+/// Row-major 3x4 object-to-world transform of an instance.
+pub const IDENTITY_TRANSFORM: [[f32; 4]; 3] = [
+    [1.0, 0.0, 0.0, 0.0],
+    [0.0, 1.0, 0.0, 0.0],
+    [0.0, 0.0, 1.0, 0.0],
+];
+
+/// Converts a column-major matrix into the rows of an instance transform.
+pub fn transform_rows(matrix: &glam::Mat4) -> [[f32; 4]; 3] {
+    let rows = matrix.transpose().to_cols_array_2d();
+    [rows[0], rows[1], rows[2]]
+}
+
+/// Instance custom indices and shader binding table offsets are packed into
+/// the low 24 bits of a `vk::Packed24_8`.
+const MAX_PACKED_24: u32 = (1 << 24) - 1;
+
+/// A triangle mesh inside a bottom-level acceleration structure. Vertices
+/// start with an `R32G32B32_SFLOAT` position and indices are `UINT32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TriangleGeometry {
+    pub vertex_count: u32,
+    /// Distance in bytes between consecutive vertex positions
+    pub vertex_stride: vk::DeviceSize,
+    pub index_count: u32,
+    /// Opaque geometry skips any-hit shaders
+    pub is_opaque: bool,
+}
+
+impl TriangleGeometry {
+    pub fn primitive_count(&self) -> u32 {
+        self.index_count / 3
+    }
+}
+
+/// The geometries making up one bottom-level acceleration structure.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BottomLevelDescription {
+    pub geometries: Vec<TriangleGeometry>,
+    pub flags: vk::BuildAccelerationStructureFlagsKHR,
+}
+
+impl BottomLevelDescription {
+    /// Describes static geometry, which is optimized for tracing and
+    /// compacted after the build.
+    pub fn new(geometries: Vec<TriangleGeometry>) -> Self {
+        Self {
+            geometries,
+            flags: vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
+                | vk::BuildAccelerationStructureFlagsKHR::ALLOW_COMPACTION,
+        }
+    }
+
+    pub fn primitive_counts(&self) -> Vec<u32> {
+        self.geometries
+            .iter()
+            .map(TriangleGeometry::primitive_count)
+            .collect()
+    }
+}
+
+/// A placement of a bottom-level acceleration structure in the scene.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InstanceDescription {
+    /// Index into `SceneDescription::bottom_levels`
+    pub bottom_level: usize,
+    pub transform: [[f32; 4]; 3],
+    pub mask: u8,
+    pub flags: vk::GeometryInstanceFlagsKHR,
+}
+
+impl InstanceDescription {
+    /// A fully visible instance whose triangles are hit from both sides.
+    pub fn new(bottom_level: usize, transform: [[f32; 4]; 3]) -> Self {
+        Self {
+            bottom_level,
+            transform,
+            mask: 0xff,
+            flags: vk::GeometryInstanceFlagsKHR::TRIANGLE_FACING_CULL_DISABLE,
+        }
+    }
+}
+
+/// Host-side layout of the acceleration structures of a scene, independent
+/// of any device.
+///
+/// All geometries of all bottom levels form one scene-wide list, in order.
+/// Each geometry has `ray_type_count` consecutive hit group records in the
+/// shader binding table, so shaders trace with an SBT stride of
+/// `ray_type_count` and find per-geometry data at
+/// `gl_InstanceCustomIndexEXT + gl_GeometryIndexEXT`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SceneDescription {
+    pub bottom_levels: Vec<BottomLevelDescription>,
+    pub instances: Vec<InstanceDescription>,
+    pub ray_type_count: u32,
+}
+
+impl SceneDescription {
+    /// Number of geometries over all bottom levels, which is also the number
+    /// of hit group records per ray type.
+    pub fn geometry_count(&self) -> usize {
+        self.bottom_levels
+            .iter()
+            .map(|bottom_level| bottom_level.geometries.len())
+            .sum()
+    }
+
+    /// Index of the first geometry of each bottom level in the scene-wide
+    /// geometry list.
+    pub fn first_geometry_indices(&self) -> Vec<u32> {
+        let mut first_geometry = 0;
+        self.bottom_levels
+            .iter()
+            .map(|bottom_level| {
+                let index = first_geometry;
+                first_geometry += bottom_level.geometries.len() as u32;
+                index
+            })
+            .collect()
+    }
+
+    pub fn instance_custom_index(&self, instance: usize) -> u32 {
+        self.first_geometry_indices()[self.instances[instance].bottom_level]
+    }
+
+    /// Offset of the first hit group record used by `instance`.
+    pub fn shader_binding_table_offset(&self, instance: usize) -> u32 {
+        self.instance_custom_index(instance) * self.ray_type_count
+    }
+
+    /// Number of hit group records the shader binding table holds.
+    pub fn hit_group_record_count(&self) -> usize {
+        self.geometry_count() * self.ray_type_count as usize
+    }
+
+    /// The last hit group record a ray can select, tracing with an SBT
+    /// stride of `ray_type_count`, or `None` if there are no instances.
+    pub fn last_hit_group_record(&self) -> Option<u32> {
+        (0..self.instances.len())
+            .filter_map(|instance| {
+                let bottom_level = &self.bottom_levels[self.instances[instance].bottom_level];
+                let record_count = bottom_level.geometries.len() as u32 * self.ray_type_count;
+                (self.shader_binding_table_offset(instance) + record_count).checked_sub(1)
+            })
+            .max()
+    }
+
+    pub fn validate(&self) -> Result<(), DescriptionError> {
+        if self.ray_type_count == 0 {
+            return Err(DescriptionError::NoRayTypes);
+        }
+        for (bottom_level_index, bottom_level) in self.bottom_levels.iter().enumerate() {
+            if bottom_level.geometries.is_empty() {
+                return Err(DescriptionError::EmptyBottomLevel(bottom_level_index));
+            }
+            for (geometry_index, geometry) in bottom_level.geometries.iter().enumerate() {
+                if geometry.index_count == 0 || geometry.index_count % 3 != 0 {
+                    return Err(DescriptionError::InvalidIndexCount {
+                        bottom_level: bottom_level_index,
+                        geometry: geometry_index,
+                        index_count: geometry.index_count,
+                    });
+                }
+            }
+        }
+
+        for (instance_index, instance) in self.instances.iter().enumerate() {
+            if instance.bottom_level >= self.bottom_levels.len() {
+                return Err(DescriptionError::MissingBottomLevel {
+                    instance: instance_index,
+                    bottom_level: instance.bottom_level,
+                });
+            }
+            if self.shader_binding_table_offset(instance_index) > MAX_PACKED_24 {
+                return Err(DescriptionError::ShaderBindingTableOffsetOverflow(
+                    instance_index,
+                ));
+            }
+        }
+
+        // The table has `hit_group_record_count` records, which every ray
+        // must land in
+        let record_count = self.hit_group_record_count();
+        if let Some(record) = self.last_hit_group_record() {
+            if record as usize >= record_count {
+                return Err(DescriptionError::HitGroupRecordOutOfRange {
+                    record,
+                    record_count,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The instances as read by the top-level build, given the device
+    /// addresses of the built bottom levels.
+    pub fn instance_data(
+        &self,
+        bottom_level_addresses: &[vk::DeviceAddress],
+    ) -> Vec<vk::AccelerationStructureInstanceKHR> {
+        let first_geometry_indices = self.first_geometry_indices();
+        self.instances
+            .iter()
+            .map(|instance| {
+                let custom_index = first_geometry_indices[instance.bottom_level];
+                vk::AccelerationStructureInstanceKHR {
+                    transform: vk::TransformMatrixKHR {
+                        matrix: instance.transform.concat().try_into().unwrap(),
+                    },
+                    instance_custom_index_and_mask: vk::Packed24_8::new(
+                        custom_index,
+                        instance.mask,
+                    ),
+                    instance_shader_binding_table_record_offset_and_flags: vk::Packed24_8::new(
+                        custom_index * self.ray_type_count,
+                        instance.flags.as_raw() as u8,
+                    ),
+                    acceleration_structure_reference: vk::AccelerationStructureReferenceKHR {
+                        device_handle: bottom_level_addresses[instance.bottom_level],
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+/// A `SceneDescription` that cannot be built.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DescriptionError {
+    EmptyBottomLevel(usize),
+    InvalidIndexCount {
+        bottom_level: usize,
+        geometry: usize,
+        index_count: u32,
+    },
+    MissingBottomLevel {
+        instance: usize,
+        bottom_level: usize,
+    },
+    ShaderBindingTableOffsetOverflow(usize),
+    NoRayTypes,
+    HitGroupRecordOutOfRange {
+        record: u32,
+        record_count: usize,
+    },
+}
+
+impl fmt::Display for DescriptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DescriptionError::EmptyBottomLevel(bottom_level) => {
+                write!(f, "bottom level {} has no geometries", bottom_level)
+            }
+            DescriptionError::InvalidIndexCount {
+                bottom_level,
+                geometry,
+                index_count,
+            } => write!(
+                f,
+                "geometry {} of bottom level {} has {} indices, which is not a whole number of triangles",
+                geometry, bottom_level, index_count
+            ),
+            DescriptionError::MissingBottomLevel {
+                instance,
+                bottom_level,
+            } => write!(
+                f,
+                "instance {} references bottom level {}, which does not exist",
+                instance, bottom_level
+            ),
+            DescriptionError::ShaderBindingTableOffsetOverflow(instance) => write!(
+                f,
+                "the shader binding table offset of instance {} does not fit in 24 bits",
+                instance
+            ),
+            DescriptionError::NoRayTypes => write!(f, "the scene has no ray types"),
+            DescriptionError::HitGroupRecordOutOfRange {
+                record,
+                record_count,
+            } => write!(
+                f,
+                "hit group record {} is past the {} records of the shader binding table",
+                record, record_count
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DescriptionError {}
+
+/// Device addresses of the buffers a `TriangleGeometry` is built from.
+#[derive(Debug, Clone, Copy)]
+pub struct GeometryAddresses {
+    pub vertex_address: vk::DeviceAddress,
+    pub index_address: vk::DeviceAddress,
+}
+
+/// An acceleration structure together with the buffer backing it, both
+/// destroyed when it is dropped. The buffer keeps the device alive.
+pub struct AccelerationStructure {
+    pub handle: vk::AccelerationStructureKHR,
+    pub buffer: Buffer,
+    pub device_address: vk::DeviceAddress,
+    acceleration_structure_device: ash::khr::acceleration_structure::Device,
+}
+
+impl AccelerationStructure {
+    fn new(
+        allocator: &Rc<Allocator>,
+        acceleration_structure_device: &ash::khr::acceleration_structure::Device,
+        ty: vk::AccelerationStructureTypeKHR,
+        size: vk::DeviceSize,
+    ) -> engine::Result<Self> {
+        let buffer = engine::buffer::create_buffer(
+            allocator,
+            size,
+            vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR
+                | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )?;
+        let create_info = vk::AccelerationStructureCreateInfoKHR {
+            buffer: buffer.handle,
+            size,
+            ty,
+            ..Default::default()
+        };
+        let handle = unsafe {
+            acceleration_structure_device.create_acceleration_structure(&create_info, None)
+        }
+        .context("create acceleration structure")?;
+
+        let address_info = vk::AccelerationStructureDeviceAddressInfoKHR {
+            acceleration_structure: handle,
+            ..Default::default()
+        };
+        let device_address = unsafe {
+            acceleration_structure_device.get_acceleration_structure_device_address(&address_info)
+        };
+
+        Ok(Self {
+            handle,
+            buffer,
+            device_address,
+            acceleration_structure_device: acceleration_structure_device.clone(),
+        })
+    }
+}
+
+impl Drop for AccelerationStructure {
+    fn drop(&mut self) {
+        unsafe {
+            self.acceleration_structure_device
+                .destroy_acceleration_structure(self.handle, None);
+        }
+    }
+}
+
+/// Device-local scratch memory for one build, with its address aligned to
+/// `minAccelerationStructureScratchOffsetAlignment`.
+struct ScratchBuffer {
+    _buffer: Buffer,
+    device_address: vk::DeviceAddress,
+}
+
+impl ScratchBuffer {
+    fn new(
+        allocator: &Rc<Allocator>,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+    ) -> engine::Result<Self> {
+        // Over-allocate so the address can be aligned
+        let buffer = engine::buffer::create_buffer(
+            allocator,
+            size + alignment,
+            vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )?;
+        let device_address = buffer.device_address().next_multiple_of(alignment.max(1));
+
+        Ok(Self {
+            _buffer: buffer,
+            device_address,
+        })
+    }
+}
+
+pub fn get_scratch_alignment(
+    instance: &ash::Instance,
+    physical_device: &vk::PhysicalDevice,
+) -> vk::DeviceSize {
+    let mut acceleration_structure_properties =
+        vk::PhysicalDeviceAccelerationStructurePropertiesKHR::default();
+    let mut properties = vk::PhysicalDeviceProperties2 {
+        p_next: &mut acceleration_structure_properties as *mut _ as *mut c_void,
+        ..Default::default()
+    };
+    unsafe { instance.get_physical_device_properties2(*physical_device, &mut properties) };
+
+    acceleration_structure_properties.min_acceleration_structure_scratch_offset_alignment
+        as vk::DeviceSize
+}
+
+fn triangle_geometries(
+    description: &BottomLevelDescription,
+    addresses: &[GeometryAddresses],
+) -> Vec<vk::AccelerationStructureGeometryKHR<'static>> {
+    assert_eq!(
+        description.geometries.len(),
+        addresses.len(),
+        "Every geometry needs its buffer addresses!"
+    );
+
+    description
+        .geometries
+        .iter()
+        .zip(addresses)
+        .map(|(geometry, addresses)| {
+            let triangles = vk::AccelerationStructureGeometryTrianglesDataKHR {
+                vertex_format: vk::Format::R32G32B32_SFLOAT,
+                vertex_data: vk::DeviceOrHostAddressConstKHR {
+                    device_address: addresses.vertex_address,
+                },
+                vertex_stride: geometry.vertex_stride,
+                max_vertex: geometry.vertex_count.saturating_sub(1),
+                index_type: vk::IndexType::UINT32,
+                index_data: vk::DeviceOrHostAddressConstKHR {
+                    device_address: addresses.index_address,
+                },
+                ..Default::default()
+            };
+            vk::AccelerationStructureGeometryKHR {
+                geometry_type: vk::GeometryTypeKHR::TRIANGLES,
+                geometry: vk::AccelerationStructureGeometryDataKHR { triangles },
+                flags: if geometry.is_opaque {
+                    vk::GeometryFlagsKHR::OPAQUE
+                } else {
+                    vk::GeometryFlagsKHR::empty()
+                },
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+fn range_infos(primitive_counts: &[u32]) -> Vec<vk::AccelerationStructureBuildRangeInfoKHR> {
+    primitive_counts
+        .iter()
+        .map(
+            |&primitive_count| vk::AccelerationStructureBuildRangeInfoKHR {
+                primitive_count,
+                ..Default::default()
+            },
+        )
+        .collect()
+}
+
+fn get_build_sizes(
+    acceleration_structure_device: &ash::khr::acceleration_structure::Device,
+    build_info: &vk::AccelerationStructureBuildGeometryInfoKHR,
+    primitive_counts: &[u32],
+) -> vk::AccelerationStructureBuildSizesInfoKHR<'static> {
+    let mut size_info = vk::AccelerationStructureBuildSizesInfoKHR::default();
+    unsafe {
+        acceleration_structure_device.get_acceleration_structure_build_sizes(
+            vk::AccelerationStructureBuildTypeKHR::DEVICE,
+            build_info,
+            primitive_counts,
+            &mut size_info,
+        );
+    }
+
+    size_info
+}
+
+/// A query pool destroyed when it is dropped.
+struct QueryPool {
+    handle: vk::QueryPool,
+    device: Rc<Device>,
+}
+
+impl QueryPool {
+    fn new(
+        logical_device: &Rc<Device>,
+        query_type: vk::QueryType,
+        query_count: u32,
+    ) -> engine::Result<Self> {
+        let create_info = vk::QueryPoolCreateInfo {
+            query_type,
+            query_count,
+            ..Default::default()
+        };
+        let handle = unsafe { logical_device.create_query_pool(&create_info, None) }
+            .context("create query pool")?;
+
+        Ok(Self {
+            handle,
+            device: Rc::clone(logical_device),
+        })
+    }
+}
+
+impl Drop for QueryPool {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_query_pool(self.handle, None) };
+    }
+}
+
+/// Builds a bottom-level acceleration structure on the device and waits for
+/// the build to finish. With `ALLOW_COMPACTION` in the description flags the
+/// result is copied into a compacted structure sized from a query.
+#[allow(clippy::too_many_arguments)]
+pub fn build_bottom_level(
+    logical_device: &Rc<Device>,
+    allocator: &Rc<Allocator>,
+    acceleration_structure_device: &ash::khr::acceleration_structure::Device,
+    command_pool: &vk::CommandPool,
+    queue: &vk::Queue,
+    description: &BottomLevelDescription,
+    addresses: &[GeometryAddresses],
+    scratch_alignment: vk::DeviceSize,
+) -> engine::Result<AccelerationStructure> {
+    let geometries = triangle_geometries(description, addresses);
+    let primitive_counts = description.primitive_counts();
+    let mut build_info = vk::AccelerationStructureBuildGeometryInfoKHR {
+        ty: vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL,
+        flags: description.flags,
+        mode: vk::BuildAccelerationStructureModeKHR::BUILD,
+        geometry_count: geometries.len() as u32,
+        p_geometries: geometries.as_ptr(),
+        ..Default::default()
+    };
+    let size_info = get_build_sizes(
+        acceleration_structure_device,
+        &build_info,
+        &primitive_counts,
+    );
+
+    let structure = AccelerationStructure::new(
+        allocator,
+        acceleration_structure_device,
+        vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL,
+        size_info.acceleration_structure_size,
+    )?;
+    let scratch = ScratchBuffer::new(allocator, size_info.build_scratch_size, scratch_alignment)?;
+    build_info.dst_acceleration_structure = structure.handle;
+    build_info.scratch_data = vk::DeviceOrHostAddressKHR {
+        device_address: scratch.device_address,
+    };
+
+    let is_compacted = description
+        .flags
+        .contains(vk::BuildAccelerationStructureFlagsKHR::ALLOW_COMPACTION);
+    let query_pool = is_compacted
+        .then(|| {
+            QueryPool::new(
+                logical_device,
+                vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR,
+                1,
+            )
+        })
+        .transpose()?;
+
+    let command_buffer =
+        engine::commands::begin_single_time_commands(logical_device, command_pool)?;
+    unsafe {
+        acceleration_structure_device.cmd_build_acceleration_structures(
+            command_buffer,
+            &[build_info],
+            &[&range_infos(&primitive_counts)],
+        );
+
+        if let Some(query_pool) = &query_pool {
+            // The size can only be queried once the build has finished
+            record_build_barrier(logical_device, &command_buffer);
+            logical_device.cmd_reset_query_pool(command_buffer, query_pool.handle, 0, 1);
+            acceleration_structure_device.cmd_write_acceleration_structures_properties(
+                command_buffer,
+                &[structure.handle],
+                vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR,
+                query_pool.handle,
+                0,
+            );
+        }
+    }
+    engine::commands::end_single_time_commands(
+        logical_device,
+        command_pool,
+        queue,
+        command_buffer,
+    )?;
+    drop(scratch);
+
+    let Some(query_pool) = query_pool else {
+        return Ok(structure);
+    };
+
+    let mut compacted_size = [0u64];
+    unsafe {
+        logical_device.get_query_pool_results(
+            query_pool.handle,
+            0,
+            &mut compacted_size,
+            vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WAIT,
+        )
+    }
+    .context("query compacted acceleration structure size")?;
+    drop(query_pool);
+
+    let compacted = AccelerationStructure::new(
+        allocator,
+        acceleration_structure_device,
+        vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL,
+        compacted_size[0],
+    )?;
+    let copy_info = vk::CopyAccelerationStructureInfoKHR {
+        src: structure.handle,
+        dst: compacted.handle,
+        mode: vk::CopyAccelerationStructureModeKHR::COMPACT,
+        ..Default::default()
+    };
+    let command_buffer =
+        engine::commands::begin_single_time_commands(logical_device, command_pool)?;
+    unsafe {
+        acceleration_structure_device.cmd_copy_acceleration_structure(command_buffer, &copy_info);
+    }
+    engine::commands::end_single_time_commands(
+        logical_device,
+        command_pool,
+        queue,
+        command_buffer,
+    )?;
+    drop(structure);
+
+    Ok(compacted)
+}
+
+/// Refits `structure` in place to moved vertices of the same geometries. The
+/// structure must have been built with `ALLOW_UPDATE`.
+#[allow(clippy::too_many_arguments)]
+pub fn refit_bottom_level(
+    logical_device: &ash::Device,
+    allocator: &Rc<Allocator>,
+    acceleration_structure_device: &ash::khr::acceleration_structure::Device,
+    command_pool: &vk::CommandPool,
+    queue: &vk::Queue,
+    structure: &AccelerationStructure,
+    description: &BottomLevelDescription,
+    addresses: &[GeometryAddresses],
+    scratch_alignment: vk::DeviceSize,
+) -> engine::Result<()> {
+    assert!(
+        description
+            .flags
+            .contains(vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE),
+        "Only acceleration structures built with ALLOW_UPDATE can be refitted!"
+    );
+
+    let geometries = triangle_geometries(description, addresses);
+    let primitive_counts = description.primitive_counts();
+    let mut build_info = vk::AccelerationStructureBuildGeometryInfoKHR {
+        ty: vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL,
+        flags: description.flags,
+        mode: vk::BuildAccelerationStructureModeKHR::UPDATE,
+        src_acceleration_structure: structure.handle,
+        dst_acceleration_structure: structure.handle,
+        geometry_count: geometries.len() as u32,
+        p_geometries: geometries.as_ptr(),
+        ..Default::default()
+    };
+    let size_info = get_build_sizes(
+        acceleration_structure_device,
+        &build_info,
+        &primitive_counts,
+    );
+
+    let scratch = ScratchBuffer::new(allocator, size_info.update_scratch_size, scratch_alignment)?;
+    build_info.scratch_data = vk::DeviceOrHostAddressKHR {
+        device_address: scratch.device_address,
+    };
+
+    let command_buffer =
+        engine::commands::begin_single_time_commands(logical_device, command_pool)?;
+    unsafe {
+        acceleration_structure_device.cmd_build_acceleration_structures(
+            command_buffer,
+            &[build_info],
+            &[&range_infos(&primitive_counts)],
+        );
+    }
+    engine::commands::end_single_time_commands(logical_device, command_pool, queue, command_buffer)
+}
+
+fn record_build_barrier(logical_device: &ash::Device, command_buffer: &vk::CommandBuffer) {
+    let barrier = vk::MemoryBarrier {
+        src_access_mask: vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR,
+        dst_access_mask: vk::AccessFlags::ACCELERATION_STRUCTURE_READ_KHR,
+        ..Default::default()
+    };
+
+    unsafe {
+        logical_device.cmd_pipeline_barrier(
+            *command_buffer,
+            vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+            vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+            vk::DependencyFlags::empty(),
+            &[barrier],
+            &[],
+            &[],
+        );
+    }
+}
+
+/// A top-level acceleration structure together with a host visible instance
+/// buffer per frame in flight. Built with `ALLOW_UPDATE`, so animated
+/// instances can be moved with `record_update` instead of a full rebuild.
+pub struct TopLevelAccelerationStructure {
+    pub structure: AccelerationStructure,
+    instance_buffers: Vec<Buffer>,
+    instance_count: u32,
+    update_scratch: ScratchBuffer,
+}
+
+impl TopLevelAccelerationStructure {
+    /// Builds the structure on the device and waits for the build to finish.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        logical_device: &ash::Device,
+        allocator: &Rc<Allocator>,
+        acceleration_structure_device: &ash::khr::acceleration_structure::Device,
+        command_pool: &vk::CommandPool,
+        queue: &vk::Queue,
+        instances: &[vk::AccelerationStructureInstanceKHR],
+        scratch_alignment: vk::DeviceSize,
+    ) -> engine::Result<Self> {
+        let instance_buffers: Vec<Buffer> = (0..MAX_FRAMES_IN_FLIGHT)
+            .map(|_| {
+                engine::buffer::create_buffer_with_data(
+                    allocator,
+                    instances,
+                    vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR
+                        | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                )
+            })
+            .collect::<engine::Result<_>>()?;
+        let instance_count = instances.len() as u32;
+        let geometry = instances_geometry(&instance_buffers[0]);
+        let mut build_info = build_info_for_top_level(&geometry);
+        let size_info = get_build_sizes(
+            acceleration_structure_device,
+            &build_info,
+            &[instance_count],
+        );
+
+        let structure = AccelerationStructure::new(
+            allocator,
+            acceleration_structure_device,
+            vk::AccelerationStructureTypeKHR::TOP_LEVEL,
+            size_info.acceleration_structure_size,
+        )?;
+        let scratch =
+            ScratchBuffer::new(allocator, size_info.build_scratch_size, scratch_alignment)?;
+        build_info.dst_acceleration_structure = structure.handle;
+        build_info.scratch_data = vk::DeviceOrHostAddressKHR {
+            device_address: scratch.device_address,
+        };
+
+        let command_buffer =
+            engine::commands::begin_single_time_commands(logical_device, command_pool)?;
+        unsafe {
+            acceleration_structure_device.cmd_build_acceleration_structures(
+                command_buffer,
+                &[build_info],
+                &[&range_infos(&[instance_count])],
+            );
+        }
+        engine::commands::end_single_time_commands(
+            logical_device,
+            command_pool,
+            queue,
+            command_buffer,
+        )?;
+        drop(scratch);
+
+        // Kept for the lifetime of the structure, as updates happen often
+        let update_scratch =
+            ScratchBuffer::new(allocator, size_info.update_scratch_size, scratch_alignment)?;
+
+        Ok(Self {
+            structure,
+            instance_buffers,
+            instance_count,
+            update_scratch,
+        })
+    }
+
+    /// Writes `instances`, e.g. with new transforms for animation, to the
+    /// instance buffer of `frame` and records refitting the structure in
+    /// place. The instance count must not change, and the previous commands
+    /// of `frame` must have completed.
+    ///
+    /// The refit waits for earlier traces and refits, since they read the
+    /// structure and share the scratch buffer, and the traces after it wait
+    /// for the refit.
+    pub fn record_update(
+        &self,
+        logical_device: &ash::Device,
+        acceleration_structure_device: &ash::khr::acceleration_structure::Device,
+        command_buffer: &vk::CommandBuffer,
+        frame: usize,
+        instances: &[vk::AccelerationStructureInstanceKHR],
+    ) {
+        assert_eq!(
+            instances.len() as u32,
+            self.instance_count,
+            "Updates cannot change the instance count!"
+        );
+
+        let instance_buffer = &self.instance_buffers[frame];
+        instance_buffer.allocation.write(instances);
+
+        let geometry = instances_geometry(instance_buffer);
+        let build_info = vk::AccelerationStructureBuildGeometryInfoKHR {
+            mode: vk::BuildAccelerationStructureModeKHR::UPDATE,
+            src_acceleration_structure: self.structure.handle,
+            dst_acceleration_structure: self.structure.handle,
+            scratch_data: vk::DeviceOrHostAddressKHR {
+                device_address: self.update_scratch.device_address,
+            },
+            ..build_info_for_top_level(&geometry)
+        };
+
+        engine::commands::record_memory_barrier(
+            logical_device,
+            command_buffer,
+            vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR
+                | vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+            vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR,
+            vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+            vk::AccessFlags::ACCELERATION_STRUCTURE_READ_KHR
+                | vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR,
+        );
+        unsafe {
+            acceleration_structure_device.cmd_build_acceleration_structures(
+                *command_buffer,
+                &[build_info],
+                &[&range_infos(&[self.instance_count])],
+            );
+        }
+        engine::commands::record_memory_barrier(
+            logical_device,
+            command_buffer,
+            vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_KHR,
+            vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_KHR,
+            vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+            vk::AccessFlags::ACCELERATION_STRUCTURE_READ_KHR,
+        );
+    }
+}
+
+fn instances_geometry(instance_buffer: &Buffer) -> vk::AccelerationStructureGeometryKHR<'static> {
+    vk::AccelerationStructureGeometryKHR {
+        geometry_type: vk::GeometryTypeKHR::INSTANCES,
+        geometry: vk::AccelerationStructureGeometryDataKHR {
+            instances: vk::AccelerationStructureGeometryInstancesDataKHR {
+                array_of_pointers: vk::FALSE,
+                data: vk::DeviceOrHostAddressConstKHR {
+                    device_address: instance_buffer.device_address(),
+                },
+                ..Default::default()
+            },
+        },
+        ..Default::default()
+    }
+}
+
+fn build_info_for_top_level<'a>(
+    geometry: &'a vk::AccelerationStructureGeometryKHR<'a>,
+) -> vk::AccelerationStructureBuildGeometryInfoKHR<'a> {
+    vk::AccelerationStructureBuildGeometryInfoKHR {
+        ty: vk::AccelerationStructureTypeKHR::TOP_LEVEL,
+        flags: vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE
+            | vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE,
+        mode: vk::BuildAccelerationStructureModeKHR::BUILD,
+        geometry_count: 1,
+        p_geometries: geometry,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mesh(triangle_count: u32) -> TriangleGeometry {
+        TriangleGeometry {
+            vertex_count: triangle_count * 3,
+            vertex_stride: std::mem::size_of::<[f32; 3]>() as vk::DeviceSize,
+            index_count: triangle_count * 3,
+            is_opaque: true,
+        }
+    }
+
+    /// Two bottom levels with two and one geometries, instanced three times
+    fn scene() -> SceneDescription {
+        SceneDescription {
+            bottom_levels: vec![
+                BottomLevelDescription::new(vec![mesh(4), mesh(2)]),
+                BottomLevelDescription::new(vec![mesh(1)]),
+            ],
+            instances: vec![
+                InstanceDescription::new(1, IDENTITY_TRANSFORM),
+                InstanceDescription::new(0, IDENTITY_TRANSFORM),
+                InstanceDescription::new(1, IDENTITY_TRANSFORM),
+            ],
+            ray_type_count: 2,
+        }
+    }
+
+    #[test]
+    fn counts_geometries_and_primitives() {
+        let scene = scene();
+        assert_eq!(scene.geometry_count(), 3);
+        assert_eq!(scene.bottom_levels[0].primitive_counts(), vec![4, 2]);
+        assert_eq!(scene.first_geometry_indices(), vec![0, 2]);
+    }
+
+    #[test]
+    fn custom_indices_point_at_first_geometry() {
+        let scene = scene();
+        let custom_indices: Vec<u32> = (0..scene.instances.len())
+            .map(|instance| scene.instance_custom_index(instance))
+            .collect();
+        assert_eq!(custom_indices, vec![2, 0, 2]);
+    }
+
+    #[test]
+    fn shader_binding_table_offsets_skip_ray_types() {
+        let scene = scene();
+        let offsets: Vec<u32> = (0..scene.instances.len())
+            .map(|instance| scene.shader_binding_table_offset(instance))
+            .collect();
+        assert_eq!(offsets, vec![4, 0, 4]);
+    }
+
+    #[test]
+    fn last_hit_group_record_is_in_the_table() {
+        let mut scene = scene();
+        assert_eq!(scene.hit_group_record_count(), 6);
+        assert_eq!(scene.last_hit_group_record(), Some(5));
+
+        scene.ray_type_count = 1;
+        assert_eq!(scene.hit_group_record_count(), 3);
+        assert_eq!(scene.last_hit_group_record(), Some(2));
+
+        // One bottom level per mesh, as the ray tracing backend builds them
+        let scene = SceneDescription {
+            bottom_levels: vec![
+                BottomLevelDescription::new(vec![mesh(1)]),
+                BottomLevelDescription::new(vec![mesh(2)]),
+                BottomLevelDescription::new(vec![mesh(3)]),
+            ],
+            instances: vec![
+                InstanceDescription::new(2, IDENTITY_TRANSFORM),
+                InstanceDescription::new(0, IDENTITY_TRANSFORM),
+            ],
+            ray_type_count: 1,
+        };
+        assert_eq!(scene.hit_group_record_count(), 3);
+        assert_eq!(scene.last_hit_group_record(), Some(2));
+        assert_eq!(scene.validate(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_scene_without_ray_types() {
+        let mut scene = scene();
+        scene.ray_type_count = 0;
+        assert_eq!(scene.validate(), Err(DescriptionError::NoRayTypes));
+    }
+
+    #[test]
+    fn packs_instance_data() {
+        let scene = scene();
+        let instances = scene.instance_data(&[0x1000, 0x2000]);
+        assert_eq!(instances.len(), 3);
+
+        let instance = &instances[0];
+        assert_eq!(instance.instance_custom_index_and_mask.low_24(), 2);
+        assert_eq!(instance.instance_custom_index_and_mask.high_8(), 0xff);
+        assert_eq!(
+            instance
+                .instance_shader_binding_table_record_offset_and_flags
+                .low_24(),
+            4
+        );
+        assert_eq!(
+            unsafe { instance.acceleration_structure_reference.device_handle },
+            0x2000
+        );
+        assert_eq!(
+            instance.transform.matrix,
+            [1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn accepts_valid_scene() {
+        assert_eq!(scene().validate(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_missing_bottom_level() {
+        let mut scene = scene();
+        scene
+            .instances
+            .push(InstanceDescription::new(2, IDENTITY_TRANSFORM));
+        assert_eq!(
+            scene.validate(),
+            Err(DescriptionError::MissingBottomLevel {
+                instance: 3,
+                bottom_level: 2
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_partial_triangles() {
+        let mut scene = scene();
+        scene.bottom_levels[1].geometries[0].index_count = 4;
+        assert_eq!(
+            scene.validate(),
+            Err(DescriptionError::InvalidIndexCount {
+                bottom_level: 1,
+                geometry: 0,
+                index_count: 4
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_empty_bottom_level() {
+        let mut scene = scene();
+        scene
+            .bottom_levels
+            .push(BottomLevelDescription::new(vec![]));
+        assert_eq!(scene.validate(), Err(DescriptionError::EmptyBottomLevel(2)));
+    }
+}
@@ -0,0 +1,664 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::panic::Location;
+use std::rc::Rc;
+
+use ash::vk;
+
+use crate::engine;
+use crate::engine::error::VkResultExt;
+use crate::engine::logical_device::Device;
+use crate::engine::Error;
+
+/// Size of the blocks of device memory that allocations are carved out of.
+const DEFAULT_BLOCK_SIZE: vk::DeviceSize = 64 * 1024 * 1024;
+/// Small heaps, e.g. the device local and host visible heap of discrete
+/// GPUs without resizable BAR, are split into at least this many blocks.
+const MIN_BLOCKS_PER_HEAP: vk::DeviceSize = 8;
+
+/// Linear and optimal resources must not share a `buffer_image_granularity`
+/// page, so the allocator needs to know which one it places.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResourceKind {
+    /// Buffers and images with linear tiling
+    #[default]
+    Linear,
+    /// Images with optimal tiling
+    Optimal,
+}
+
+/// A resource that is given a `vk::DeviceMemory` of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedicatedResource {
+    Buffer(vk::Buffer),
+    Image(vk::Image),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllocationInfo {
+    pub requirements: vk::MemoryRequirements,
+    /// Properties the memory type must have. Host visible memory must also
+    /// be host coherent, since allocations are never flushed.
+    pub properties: vk::MemoryPropertyFlags,
+    /// Properties that make a memory type a better choice, e.g.
+    /// `HOST_CACHED` for reading back
+    pub preferred_properties: vk::MemoryPropertyFlags,
+    pub kind: ResourceKind,
+    /// Set when the driver prefers or requires a dedicated allocation.
+    /// Allocations larger than half a block get their own memory either way.
+    pub dedicated: Option<DedicatedResource>,
+    /// Allocates with `vk::MemoryAllocateFlags::DEVICE_ADDRESS`, for buffers
+    /// used through their device address
+    pub device_address: bool,
+}
+
+/// Memory allocated by an `Allocator`, which must be given back to it with
+/// `Allocator::free` once.
+#[derive(Debug)]
+pub struct Allocation {
+    memory: vk::DeviceMemory,
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+    /// Where the allocation starts in the mapped memory, or null if it isn't
+    /// host visible
+    mapped: *mut u8,
+    placement: Placement,
+    id: u64,
+}
+
+impl Allocation {
+    /// The memory to bind the resource to, at `offset`.
+    pub fn memory(&self) -> vk::DeviceMemory {
+        self.memory
+    }
+
+    pub fn offset(&self) -> vk::DeviceSize {
+        self.offset
+    }
+
+    pub fn size(&self) -> vk::DeviceSize {
+        self.size
+    }
+
+    /// The allocation in host address space, if its memory is host visible.
+    /// The memory stays mapped for as long as it is allocated.
+    pub fn mapped_ptr(&self) -> Option<*mut u8> {
+        (!self.mapped.is_null()).then_some(self.mapped)
+    }
+
+    /// Copies `data` to the start of the allocation, which must be host
+    /// visible and large enough. Panics otherwise, since that is a bug in the
+    /// caller rather than something the driver can cause.
+    pub fn write<T: Copy>(&self, data: &[T]) {
+        let size = std::mem::size_of_val(data);
+        assert!(
+            size as vk::DeviceSize <= self.size,
+            "Writing past the allocation!"
+        );
+        let mapped = self
+            .mapped_ptr()
+            .expect("Writing to memory that isn't host visible!");
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr() as *const u8, mapped, size) };
+    }
+
+    /// Copies the first `size` bytes of the allocation, which must be host
+    /// visible. Panics otherwise, like `write`.
+    pub fn read(&self, size: vk::DeviceSize) -> Vec<u8> {
+        assert!(size <= self.size, "Reading past the allocation!");
+        let mapped = self
+            .mapped_ptr()
+            .expect("Reading from memory that isn't host visible!");
+        unsafe { std::slice::from_raw_parts(mapped, size as usize).to_vec() }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Placement {
+    Block { pool: PoolKey, index: usize },
+    Dedicated,
+}
+
+/// Memory allocated with and without the device address flag can't be
+/// shared, so each gets its own pool of blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct PoolKey {
+    memory_type: u32,
+    device_address: bool,
+}
+
+struct MemoryBlock {
+    memory: vk::DeviceMemory,
+    /// Start of the block in host address space, or null
+    mapped: *mut u8,
+    block: Block,
+}
+
+/// What the leak report tells about an allocation that was never freed.
+struct LiveAllocation {
+    size: vk::DeviceSize,
+    /// Memory to free at cleanup, for dedicated allocations
+    dedicated_memory: Option<vk::DeviceMemory>,
+    caller: &'static Location<'static>,
+}
+
+#[derive(Default)]
+struct State {
+    pools: HashMap<PoolKey, Vec<Option<MemoryBlock>>>,
+    live: HashMap<u64, LiveAllocation>,
+    next_id: u64,
+}
+
+/// Sub-allocates device memory out of large blocks, since drivers only allow
+/// a few thousand `vk::DeviceMemory` objects and allocating them is slow.
+/// Host visible blocks are mapped once when they are created. Resources that
+/// free their memory on drop hold an `Rc` of the allocator, so its memory is
+/// freed after theirs and before the device's. Failing Vulkan calls are
+/// returned as errors, while the bookkeeping panics when it finds itself
+/// inconsistent, e.g. on a double free, as only a bug can cause that.
+pub struct Allocator {
+    device: Rc<Device>,
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    buffer_image_granularity: vk::DeviceSize,
+    state: RefCell<State>,
+}
+
+impl Allocator {
+    pub fn new(logical_device: &Rc<Device>, physical_device: &vk::PhysicalDevice) -> Self {
+        let instance = logical_device.instance();
+        let memory_properties =
+            unsafe { instance.get_physical_device_memory_properties(*physical_device) };
+        let properties = unsafe { instance.get_physical_device_properties(*physical_device) };
+
+        Self {
+            device: Rc::clone(logical_device),
+            memory_properties,
+            buffer_image_granularity: properties.limits.buffer_image_granularity.max(1),
+            state: RefCell::default(),
+        }
+    }
+
+    /// The device the memory is allocated from.
+    pub fn device(&self) -> &Rc<Device> {
+        &self.device
+    }
+
+    /// Allocates memory for a resource. Where it was called from is kept for
+    /// the leak report.
+    #[track_caller]
+    pub fn allocate(&self, info: &AllocationInfo) -> engine::Result<Allocation> {
+        let requirements = info.requirements;
+        let memory_type = engine::memory::select_memory_type(
+            &self.memory_properties,
+            requirements.memory_type_bits,
+            info.properties,
+            info.preferred_properties,
+        )
+        .ok_or(Error::NoSuitableMemoryType {
+            type_filter: requirements.memory_type_bits,
+            properties: info.properties,
+        })?;
+        let pool = PoolKey {
+            memory_type,
+            device_address: info.device_address,
+        };
+
+        let block_size = self.block_size(memory_type);
+        let mut allocation = if info.dedicated.is_some() || requirements.size > block_size / 2 {
+            self.allocate_dedicated(pool, info)?
+        } else {
+            self.allocate_from_block(pool, block_size, info)?
+        };
+
+        let mut state = self.state.borrow_mut();
+        allocation.id = state.next_id;
+        state.next_id += 1;
+        state.live.insert(
+            allocation.id,
+            LiveAllocation {
+                size: allocation.size,
+                dedicated_memory: match allocation.placement {
+                    Placement::Dedicated => Some(allocation.memory),
+                    Placement::Block { .. } => None,
+                },
+                caller: Location::caller(),
+            },
+        );
+
+        Ok(allocation)
+    }
+
+    pub fn free(&self, allocation: &Allocation) {
+        let mut state = self.state.borrow_mut();
+        assert!(
+            state.live.remove(&allocation.id).is_some(),
+            "Freed an allocation twice!"
+        );
+
+        match allocation.placement {
+            Placement::Dedicated => unsafe { self.device.free_memory(allocation.memory, None) },
+            Placement::Block { pool, index } => {
+                let blocks = state
+                    .pools
+                    .get_mut(&pool)
+                    .expect("Freed from an unknown pool!");
+                let memory_block = blocks[index]
+                    .as_mut()
+                    .expect("Freed from a block that was released!");
+                memory_block.block.free(allocation.offset);
+
+                // One empty block is kept, so that resources recreated every
+                // frame don't allocate a new block every time
+                let block_count = blocks.iter().flatten().count();
+                let memory_block = blocks[index].as_ref().expect("Freed block is gone!");
+                if memory_block.block.is_empty() && block_count > 1 {
+                    unsafe { self.device.free_memory(memory_block.memory, None) };
+                    blocks[index] = None;
+                }
+            }
+        }
+    }
+
+    /// Blocks of the default size, or smaller on heaps that would only fit a
+    /// few of them.
+    fn block_size(&self, memory_type: u32) -> vk::DeviceSize {
+        let heap_index = self.memory_properties.memory_types[memory_type as usize].heap_index;
+        let heap_size = self.memory_properties.memory_heaps[heap_index as usize].size;
+        DEFAULT_BLOCK_SIZE.min(heap_size / MIN_BLOCKS_PER_HEAP)
+    }
+
+    fn is_host_visible(&self, memory_type: u32) -> bool {
+        self.memory_properties.memory_types[memory_type as usize]
+            .property_flags
+            .contains(vk::MemoryPropertyFlags::HOST_VISIBLE)
+    }
+
+    fn allocate_from_block(
+        &self,
+        pool: PoolKey,
+        block_size: vk::DeviceSize,
+        info: &AllocationInfo,
+    ) -> engine::Result<Allocation> {
+        let requirements = info.requirements;
+        let mut state = self.state.borrow_mut();
+        let blocks = state.pools.entry(pool).or_default();
+
+        let existing = blocks.iter_mut().enumerate().find_map(|(index, slot)| {
+            let memory_block = slot.as_mut()?;
+            let offset = memory_block.block.allocate(
+                requirements.size,
+                requirements.alignment,
+                info.kind,
+            )?;
+            Some((index, offset))
+        });
+        let (index, offset) = match existing {
+            Some(placement) => placement,
+            None => {
+                let (memory, mapped) = self.allocate_memory(pool, block_size, None)?;
+                let mut block = Block::new(block_size, self.buffer_image_granularity);
+                let offset = block
+                    .allocate(requirements.size, requirements.alignment, info.kind)
+                    .expect("Allocation doesn't fit in an empty block!");
+                let memory_block = Some(MemoryBlock {
+                    memory,
+                    mapped,
+                    block,
+                });
+                let index = match blocks.iter().position(Option::is_none) {
+                    Some(index) => {
+                        blocks[index] = memory_block;
+                        index
+                    }
+                    None => {
+                        blocks.push(memory_block);
+                        blocks.len() - 1
+                    }
+                };
+                (index, offset)
+            }
+        };
+
+        let memory_block = blocks[index]
+            .as_ref()
+            .expect("Allocated from a block that was released!");
+        Ok(Allocation {
+            memory: memory_block.memory,
+            offset,
+            size: requirements.size,
+            mapped: if memory_block.mapped.is_null() {
+                std::ptr::null_mut()
+            } else {
+                unsafe { memory_block.mapped.add(offset as usize) }
+            },
+            placement: Placement::Block { pool, index },
+            id: 0,
+        })
+    }
+
+    fn allocate_dedicated(
+        &self,
+        pool: PoolKey,
+        info: &AllocationInfo,
+    ) -> engine::Result<Allocation> {
+        let (memory, mapped) =
+            self.allocate_memory(pool, info.requirements.size, info.dedicated)?;
+
+        Ok(Allocation {
+            memory,
+            offset: 0,
+            size: info.requirements.size,
+            mapped,
+            placement: Placement::Dedicated,
+            id: 0,
+        })
+    }
+
+    /// Allocates a `vk::DeviceMemory` and maps it if it is host visible.
+    fn allocate_memory(
+        &self,
+        pool: PoolKey,
+        size: vk::DeviceSize,
+        dedicated: Option<DedicatedResource>,
+    ) -> engine::Result<(vk::DeviceMemory, *mut u8)> {
+        let dedicated_info = match dedicated {
+            Some(DedicatedResource::Buffer(buffer)) => vk::MemoryDedicatedAllocateInfo {
+                buffer,
+                ..Default::default()
+            },
+            Some(DedicatedResource::Image(image)) => vk::MemoryDedicatedAllocateInfo {
+                image,
+                ..Default::default()
+            },
+            None => vk::MemoryDedicatedAllocateInfo::default(),
+        };
+        let flags_info = vk::MemoryAllocateFlagsInfo {
+            p_next: if dedicated.is_some() {
+                &dedicated_info as *const _ as *const c_void
+            } else {
+                std::ptr::null()
+            },
+            flags: vk::MemoryAllocateFlags::DEVICE_ADDRESS,
+            ..Default::default()
+        };
+        let allocate_info = vk::MemoryAllocateInfo {
+            p_next: if pool.device_address {
+                &flags_info as *const _ as *const c_void
+            } else {
+                flags_info.p_next
+            },
+            allocation_size: size,
+            memory_type_index: pool.memory_type,
+            ..Default::default()
+        };
+
+        let memory = unsafe { self.device.allocate_memory(&allocate_info, None) }
+            .context("allocate device memory")?;
+        if !self.is_host_visible(pool.memory_type) {
+            return Ok((memory, std::ptr::null_mut()));
+        }
+
+        let mapped = unsafe {
+            self.device
+                .map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())
+        }
+        .context("map device memory")
+        .inspect_err(|_| unsafe { self.device.free_memory(memory, None) })?;
+        Ok((memory, mapped as *mut u8))
+    }
+}
+
+impl Drop for Allocator {
+    /// Frees all the memory, after reporting the allocations that were never
+    /// freed and where they were made.
+    fn drop(&mut self) {
+        let state = self.state.get_mut();
+
+        if !state.live.is_empty() {
+            let mut leaks: Vec<_> = state.live.iter().collect();
+            leaks.sort_by_key(|(id, _)| **id);
+            let leaked_size: vk::DeviceSize = leaks.iter().map(|(_, leak)| leak.size).sum();
+            eprintln!(
+                "Leaked {} device memory allocations, {} bytes in total:",
+                leaks.len(),
+                leaked_size
+            );
+            for (_, leak) in leaks {
+                eprintln!("  {} bytes allocated at {}", leak.size, leak.caller);
+            }
+        }
+
+        unsafe {
+            for leak in state.live.values() {
+                if let Some(memory) = leak.dedicated_memory {
+                    self.device.free_memory(memory, None);
+                }
+            }
+            for memory_block in state.pools.values().flatten().flatten() {
+                self.device.free_memory(memory_block.memory, None);
+            }
+        }
+    }
+}
+
+/// A range of a block, which is free when `kind` is `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Range {
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+    kind: Option<ResourceKind>,
+}
+
+impl Range {
+    fn end(&self) -> vk::DeviceSize {
+        self.offset + self.size
+    }
+}
+
+/// The free list of one block of device memory: its ranges in order, with
+/// no two free ranges next to each other. It knows nothing of the memory
+/// itself, so that it can be tested on the host.
+#[derive(Debug)]
+pub struct Block {
+    size: vk::DeviceSize,
+    /// `buffer_image_granularity`, a power of two
+    granularity: vk::DeviceSize,
+    ranges: Vec<Range>,
+}
+
+impl Block {
+    pub fn new(size: vk::DeviceSize, granularity: vk::DeviceSize) -> Self {
+        Self {
+            size,
+            granularity,
+            ranges: vec![Range {
+                offset: 0,
+                size,
+                kind: None,
+            }],
+        }
+    }
+
+    pub fn size(&self) -> vk::DeviceSize {
+        self.size
+    }
+
+    /// Bytes in allocated ranges, including the padding the allocations
+    /// were aligned with.
+    pub fn used(&self) -> vk::DeviceSize {
+        self.ranges
+            .iter()
+            .filter(|range| range.kind.is_some())
+            .map(|range| range.size)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.iter().all(|range| range.kind.is_none())
+    }
+
+    /// Places `size` bytes at a multiple of `alignment` in the smallest free
+    /// range they fit in, and returns their offset. `None` if the block is
+    /// too full.
+    pub fn allocate(
+        &mut self,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+        kind: ResourceKind,
+    ) -> Option<vk::DeviceSize> {
+        let (index, offset) = (0..self.ranges.len())
+            .filter_map(|index| Some((index, self.fit(index, size, alignment.max(1), kind)?)))
+            .min_by_key(|&(index, _)| self.ranges[index].size)?;
+
+        let range = self.ranges[index];
+        let end = offset + size;
+        let mut replacement = Vec::with_capacity(3);
+        if offset > range.offset {
+            replacement.push(Range {
+                offset: range.offset,
+                size: offset - range.offset,
+                kind: None,
+            });
+        }
+        replacement.push(Range {
+            offset,
+            size,
+            kind: Some(kind),
+        });
+        if end < range.end() {
+            replacement.push(Range {
+                offset: end,
+                size: range.end() - end,
+                kind: None,
+            });
+        }
+        self.ranges.splice(index..=index, replacement);
+
+        Some(offset)
+    }
+
+    /// Frees the allocation at `offset`, merging it with free neighbours.
+    pub fn free(&mut self, offset: vk::DeviceSize) {
+        let index = self
+            .ranges
+            .binary_search_by_key(&offset, |range| range.offset)
+            .ok()
+            .filter(|&index| self.ranges[index].kind.is_some())
+            .expect("Freed an offset that isn't allocated in the block!");
+        self.ranges[index].kind = None;
+
+        if self
+            .ranges
+            .get(index + 1)
+            .is_some_and(|next| next.kind.is_none())
+        {
+            self.ranges[index].size += self.ranges[index + 1].size;
+            self.ranges.remove(index + 1);
+        }
+        if index > 0 && self.ranges[index - 1].kind.is_none() {
+            self.ranges[index - 1].size += self.ranges[index].size;
+            self.ranges.remove(index);
+        }
+    }
+
+    /// Where an allocation would start in the free range at `index`, moved
+    /// to the next page if it would share one with a resource of the other
+    /// kind.
+    fn fit(
+        &self,
+        index: usize,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+        kind: ResourceKind,
+    ) -> Option<vk::DeviceSize> {
+        let range = self.ranges[index];
+        if range.kind.is_some() {
+            return None;
+        }
+
+        let mut offset = align_up(range.offset, alignment);
+        if let Some(previous) = index.checked_sub(1).map(|index| self.ranges[index]) {
+            if self.shares_page(previous.kind, previous.end(), Some(kind), offset) {
+                offset = align_up(offset, self.granularity.max(alignment));
+            }
+        }
+        let end = offset.checked_add(size)?;
+        if end > range.end() {
+            return None;
+        }
+        if let Some(next) = self.ranges.get(index + 1) {
+            if self.shares_page(Some(kind), end, next.kind, next.offset) {
+                return None;
+            }
+        }
+
+        Some(offset)
+    }
+
+    /// Whether a resource ending at `end` and one starting at `start` are of
+    /// different kinds and on the same page.
+    fn shares_page(
+        &self,
+        kind: Option<ResourceKind>,
+        end: vk::DeviceSize,
+        next_kind: Option<ResourceKind>,
+        start: vk::DeviceSize,
+    ) -> bool {
+        let is_conflict = matches!((kind, next_kind), (Some(a), Some(b)) if a != b);
+        is_conflict && (end - 1) / self.granularity == start / self.granularity
+    }
+}
+
+fn align_up(value: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    value.div_ceil(alignment) * alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aligns_and_reuses_freed_ranges() {
+        let mut block = Block::new(1024, 1);
+        assert_eq!(block.allocate(100, 1, ResourceKind::Linear), Some(0));
+        assert_eq!(block.allocate(100, 64, ResourceKind::Linear), Some(128));
+        assert_eq!(block.allocate(100, 64, ResourceKind::Linear), Some(256));
+        assert_eq!(block.used(), 300);
+
+        // The freed range, merged with the padding around it, is the smallest
+        // that fits
+        block.free(128);
+        assert_eq!(block.allocate(50, 16, ResourceKind::Linear), Some(112));
+        assert_eq!(block.allocate(1024, 1, ResourceKind::Linear), None);
+
+        block.free(0);
+        block.free(112);
+        block.free(256);
+        assert!(block.is_empty());
+        assert_eq!(block.ranges.len(), 1);
+        assert_eq!(block.allocate(1024, 1, ResourceKind::Linear), Some(0));
+    }
+
+    #[test]
+    fn keeps_linear_and_optimal_resources_on_separate_pages() {
+        let mut block = Block::new(8192, 1024);
+        assert_eq!(block.allocate(100, 16, ResourceKind::Linear), Some(0));
+        // Next to the buffer, the image moves on to the next page
+        assert_eq!(block.allocate(100, 16, ResourceKind::Optimal), Some(1024));
+        assert_eq!(block.allocate(100, 16, ResourceKind::Optimal), Some(1136));
+        block.free(1024);
+
+        // A buffer that would end on the page of the remaining image doesn't
+        // fit in front of it
+        assert_eq!(block.allocate(1000, 16, ResourceKind::Linear), Some(2048));
+        assert_eq!(block.allocate(100, 16, ResourceKind::Linear), Some(112));
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_freeing_unallocated_offsets() {
+        let mut block = Block::new(1024, 1);
+        block.allocate(100, 1, ResourceKind::Linear);
+        block.free(100);
+    }
+}
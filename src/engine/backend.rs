@@ -0,0 +1,186 @@
+use std::rc::Rc;
+
+use ash;
+use ash::vk;
+
+use crate::engine;
+use crate::engine::allocator::Allocator;
+use crate::engine::error::Error;
+use crate::engine::image::StorageImage;
+use crate::engine::logical_device::Device;
+use crate::engine::path_tracing::FrameConstants;
+use crate::scene::mesh::SceneGeometry;
+
+/// How frames are produced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Draws `shaders/shader.vert` and `shaders/shader.frag` with the
+    /// graphics pipeline
+    Rasterizer,
+    /// Traces rays with `VK_KHR_ray_tracing_pipeline`
+    HardwareRayTracing,
+    /// Traces rays through a BVH in a compute shader, for devices without
+    /// ray tracing extensions
+    ComputeRayTracing,
+}
+
+impl Backend {
+    /// Uses `requested` if given, and otherwise the hardware ray tracer when
+    /// the device supports it and the compute fallback when it doesn't.
+    ///
+    /// The ray tracers blit into the frames, so unless `frame_usage`, the
+    /// usage the frames can be created with, has `TRANSFER_DST` they are only
+    /// used when requested, which is an error then.
+    pub fn choose(
+        device: &vk::PhysicalDevice,
+        instance: &ash::Instance,
+        requested: Option<Backend>,
+        frame_usage: vk::ImageUsageFlags,
+    ) -> engine::Result<Backend> {
+        let can_blit = frame_usage.contains(vk::ImageUsageFlags::TRANSFER_DST);
+        match requested {
+            Some(backend) if backend != Backend::Rasterizer && !can_blit => {
+                Err(Error::UnsupportedBackend(backend))
+            }
+            Some(backend) => Ok(backend),
+            None if !can_blit => Ok(Backend::Rasterizer),
+            None if engine::physical_device::supports_hardware_ray_tracing(device, instance) => {
+                Ok(Backend::HardwareRayTracing)
+            }
+            None => Ok(Backend::ComputeRayTracing),
+        }
+    }
+
+    pub fn is_ray_tracing_enabled(&self) -> bool {
+        *self == Backend::HardwareRayTracing
+    }
+}
+
+/// The resources of whichever ray tracing backend is in use.
+pub enum Tracer {
+    Hardware(Box<engine::ray_tracing::RayTracer>),
+    Compute(Box<engine::compute_tracing::ComputeTracer>),
+}
+
+impl Tracer {
+    /// Creates the tracer for `backend` tracing `geometry`, or `None` for the
+    /// rasterizer.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        backend: Backend,
+        instance: &ash::Instance,
+        physical_device: &vk::PhysicalDevice,
+        logical_device: &Rc<Device>,
+        allocator: &Rc<Allocator>,
+        command_pool: &vk::CommandPool,
+        queue: &vk::Queue,
+        extent: vk::Extent2D,
+        geometry: &SceneGeometry,
+    ) -> engine::Result<Option<Self>> {
+        Ok(match backend {
+            Backend::Rasterizer => None,
+            Backend::HardwareRayTracing => Some(Tracer::Hardware(Box::new(
+                engine::ray_tracing::RayTracer::new(
+                    instance,
+                    physical_device,
+                    logical_device,
+                    allocator,
+                    command_pool,
+                    queue,
+                    extent,
+                    geometry,
+                )?,
+            ))),
+            Backend::ComputeRayTracing => Some(Tracer::Compute(Box::new(
+                engine::compute_tracing::ComputeTracer::new(
+                    instance,
+                    physical_device,
+                    logical_device,
+                    allocator,
+                    command_pool,
+                    queue,
+                    extent,
+                    geometry,
+                )?,
+            ))),
+        })
+    }
+
+    pub fn resize(
+        &mut self,
+        logical_device: &Rc<Device>,
+        allocator: &Rc<Allocator>,
+        command_pool: &vk::CommandPool,
+        queue: &vk::Queue,
+        extent: vk::Extent2D,
+    ) -> engine::Result<()> {
+        match self {
+            Tracer::Hardware(ray_tracer) => {
+                ray_tracer.resize(logical_device, allocator, command_pool, queue, extent)
+            }
+            Tracer::Compute(compute_tracer) => {
+                compute_tracer.resize(logical_device, allocator, command_pool, queue, extent)
+            }
+        }
+    }
+
+    /// Records moving the instances of `geometry`, which the tracer was
+    /// created with, to where they are at `time`, between 0 and 1, ahead of
+    /// the frame's tracing. The previous commands of frame in flight `frame`
+    /// must have completed.
+    pub fn record_set_time(
+        &mut self,
+        logical_device: &ash::Device,
+        command_buffer: &vk::CommandBuffer,
+        frame: usize,
+        geometry: &SceneGeometry,
+        time: f32,
+    ) {
+        match self {
+            Tracer::Hardware(ray_tracer) => {
+                ray_tracer.record_set_time(logical_device, command_buffer, frame, geometry, time)
+            }
+            Tracer::Compute(compute_tracer) => compute_tracer.record_set_time(
+                logical_device,
+                command_buffer,
+                frame,
+                geometry,
+                time,
+            ),
+        }
+    }
+
+    pub fn record_command_buffer(
+        &self,
+        logical_device: &ash::Device,
+        command_buffer: &vk::CommandBuffer,
+        target_image: &vk::Image,
+        target_layout: vk::ImageLayout,
+        constants: &FrameConstants,
+    ) {
+        match self {
+            Tracer::Hardware(ray_tracer) => ray_tracer.record_command_buffer(
+                logical_device,
+                command_buffer,
+                target_image,
+                target_layout,
+                constants,
+            ),
+            Tracer::Compute(compute_tracer) => compute_tracer.record_command_buffer(
+                logical_device,
+                command_buffer,
+                target_image,
+                target_layout,
+                constants,
+            ),
+        }
+    }
+
+    /// The running sum of the samples, in `ACCUMULATION_IMAGE_FORMAT`.
+    pub fn accumulation_image(&self) -> &StorageImage {
+        match self {
+            Tracer::Hardware(ray_tracer) => ray_tracer.accumulation_image(),
+            Tracer::Compute(compute_tracer) => compute_tracer.accumulation_image(),
+        }
+    }
+}
@@ -0,0 +1,598 @@
+use std::f32::consts::PI;
+
+use glam::Vec3;
+
+use crate::engine::material::{
+    GpuMaterial, KIND_CONDUCTOR, KIND_DIELECTRIC, KIND_LAMBERT, KIND_METALLIC_ROUGHNESS,
+};
+
+/// Smallest GGX alpha of the microfacet lobes. Dielectrics smoother than this
+/// are perfectly smooth, with specular reflection and refraction.
+pub const MIN_ALPHA: f32 = 1e-3;
+
+/// A direction sampled from a BSDF.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BsdfSample {
+    pub direction: Vec3,
+    /// `eval * |cos| / pdf`, what a path's throughput is multiplied by
+    pub weight: Vec3,
+    /// Density of `direction` per solid angle, or for specular samples the
+    /// probability of picking that reflection or refraction
+    pub pdf: f32,
+    /// Perfect reflection or refraction, which `eval` and `pdf` never return
+    pub is_specular: bool,
+}
+
+// The CPU reference of `shaders/bsdf.glsl`. Directions are in the shading
+// frame, with the geometric normal along +Z, and point away from the surface.
+// Opaque materials scatter the same on both sides, dielectrics refract into
+// the side `wo` is not on. Internally `wo` is flipped above the surface.
+
+/// The BSDF of `material` for light arriving from `wi` and leaving towards
+/// `wo`, without the cosine term.
+pub fn eval(material: &GpuMaterial, wo: Vec3, wi: Vec3) -> Vec3 {
+    let is_flipped = wo.z < 0.0;
+    let (wo, wi) = (flip(wo, is_flipped), flip(wi, is_flipped));
+    if material.kind == KIND_DIELECTRIC {
+        dielectric_eval(material, relative_ior(material, is_flipped), wo, wi)
+    } else {
+        opaque_eval(material, wo, wi)
+    }
+}
+
+/// The density per solid angle with which `sample` picks `wi` for `wo`.
+pub fn pdf(material: &GpuMaterial, wo: Vec3, wi: Vec3) -> f32 {
+    let is_flipped = wo.z < 0.0;
+    let (wo, wi) = (flip(wo, is_flipped), flip(wi, is_flipped));
+    if material.kind == KIND_DIELECTRIC {
+        dielectric_pdf(material, relative_ior(material, is_flipped), wo, wi)
+    } else {
+        opaque_pdf(material, wo, wi)
+    }
+}
+
+/// Picks a direction light arrives from for light leaving towards `wo`,
+/// using the three random numbers in `u`. The first one chooses between
+/// lobes, the others the direction within it.
+pub fn sample(material: &GpuMaterial, wo: Vec3, u: Vec3) -> Option<BsdfSample> {
+    let is_flipped = wo.z < 0.0;
+    let wo = flip(wo, is_flipped);
+    let sample = if material.kind == KIND_DIELECTRIC {
+        dielectric_sample(material, relative_ior(material, is_flipped), wo, u)
+    } else {
+        opaque_sample(material, wo, u)
+    }?;
+
+    Some(BsdfSample {
+        direction: flip(sample.direction, is_flipped),
+        ..sample
+    })
+}
+
+/// `w` mirrored to the other side of the surface if `is_flipped`.
+fn flip(w: Vec3, is_flipped: bool) -> Vec3 {
+    if is_flipped {
+        Vec3::new(w.x, w.y, -w.z)
+    } else {
+        w
+    }
+}
+
+/// The index of refraction below the surface relative to the one above,
+/// after flipping.
+fn relative_ior(material: &GpuMaterial, is_flipped: bool) -> f32 {
+    if is_flipped {
+        1.0 / material.ior
+    } else {
+        material.ior
+    }
+}
+
+fn alpha(roughness: f32) -> f32 {
+    (roughness * roughness).max(MIN_ALPHA)
+}
+
+// Opaque materials, with `wo` above the surface
+
+fn opaque_eval(material: &GpuMaterial, wo: Vec3, wi: Vec3) -> Vec3 {
+    if wo.z <= 0.0 || wi.z <= 0.0 {
+        return Vec3::ZERO;
+    }
+
+    let color = Vec3::from_array(material.color);
+    let alpha = alpha(material.roughness);
+    match material.kind {
+        KIND_LAMBERT => color / PI,
+        KIND_CONDUCTOR => conductor_eval(color, alpha, wo, wi),
+        KIND_METALLIC_ROUGHNESS => {
+            (1.0 - material.metallic) * color / PI
+                + material.metallic * conductor_eval(color, alpha, wo, wi)
+        }
+        _ => Vec3::ZERO,
+    }
+}
+
+fn opaque_pdf(material: &GpuMaterial, wo: Vec3, wi: Vec3) -> f32 {
+    if wo.z <= 0.0 || wi.z <= 0.0 {
+        return 0.0;
+    }
+
+    let alpha = alpha(material.roughness);
+    match material.kind {
+        KIND_LAMBERT => wi.z / PI,
+        KIND_CONDUCTOR => conductor_pdf(alpha, wo, wi),
+        KIND_METALLIC_ROUGHNESS => {
+            (1.0 - material.metallic) * wi.z / PI + material.metallic * conductor_pdf(alpha, wo, wi)
+        }
+        _ => 0.0,
+    }
+}
+
+fn opaque_sample(material: &GpuMaterial, wo: Vec3, u: Vec3) -> Option<BsdfSample> {
+    if wo.z <= 0.0 {
+        return None;
+    }
+
+    let alpha = alpha(material.roughness);
+    let direction = match material.kind {
+        KIND_LAMBERT => sample_cosine_hemisphere(u.y, u.z),
+        KIND_CONDUCTOR => reflect(wo, sample_ggx_visible_normal(alpha, wo, u.y, u.z)),
+        KIND_METALLIC_ROUGHNESS if u.x < material.metallic => {
+            reflect(wo, sample_ggx_visible_normal(alpha, wo, u.y, u.z))
+        }
+        KIND_METALLIC_ROUGHNESS => sample_cosine_hemisphere(u.y, u.z),
+        _ => return None,
+    };
+
+    let pdf = opaque_pdf(material, wo, direction);
+    if direction.z <= 0.0 || pdf <= 0.0 {
+        return None;
+    }
+    Some(BsdfSample {
+        direction,
+        weight: opaque_eval(material, wo, direction) * direction.z / pdf,
+        pdf,
+        is_specular: false,
+    })
+}
+
+fn conductor_eval(color: Vec3, alpha: f32, wo: Vec3, wi: Vec3) -> Vec3 {
+    let half = (wo + wi).normalize();
+    fresnel_schlick(color, wi.dot(half))
+        * ggx_distribution(alpha, half)
+        * ggx_masking(alpha, wo, wi)
+        / (4.0 * wo.z * wi.z)
+}
+
+fn conductor_pdf(alpha: f32, wo: Vec3, wi: Vec3) -> f32 {
+    let half = (wo + wi).normalize();
+    ggx_visible_normal_pdf(alpha, wo, half) / (4.0 * wo.dot(half))
+}
+
+// Dielectrics, with `eta` the relative index of refraction
+
+/// The microfacet normal that scatters `wo` into `wi`, facing up, or `None`
+/// when no front-facing microfacet does.
+fn dielectric_half_vector(eta: f32, wo: Vec3, wi: Vec3) -> Option<Vec3> {
+    let is_reflection = wi.z > 0.0;
+    let half = if is_reflection {
+        wo + wi
+    } else {
+        wo + wi * eta
+    };
+    if wo.z == 0.0 || wi.z == 0.0 || half.length_squared() == 0.0 {
+        return None;
+    }
+
+    let half = half.normalize();
+    let half = if half.z < 0.0 { -half } else { half };
+    if half.dot(wi) * wi.z < 0.0 || half.dot(wo) * wo.z < 0.0 {
+        return None;
+    }
+    Some(half)
+}
+
+fn dielectric_eval(material: &GpuMaterial, eta: f32, wo: Vec3, wi: Vec3) -> Vec3 {
+    let alpha = material.roughness * material.roughness;
+    if alpha < MIN_ALPHA {
+        return Vec3::ZERO;
+    }
+    let Some(half) = dielectric_half_vector(eta, wo, wi) else {
+        return Vec3::ZERO;
+    };
+
+    let fresnel = fresnel_dielectric(wo.dot(half), eta);
+    let microfacets = ggx_distribution(alpha, half) * ggx_masking(alpha, wo, wi);
+    if wi.z > 0.0 {
+        Vec3::splat(fresnel * microfacets / (4.0 * wo.z * wi.z))
+    } else {
+        // Radiance is not scaled by the squared ratio of the indices of
+        // refraction, which cancels out for closed objects
+        let denominator = (wi.dot(half) + wo.dot(half) / eta).powi(2) * wi.z * wo.z;
+        Vec3::from_array(material.color)
+            * ((1.0 - fresnel) * microfacets * (wi.dot(half) * wo.dot(half) / denominator).abs())
+    }
+}
+
+fn dielectric_pdf(material: &GpuMaterial, eta: f32, wo: Vec3, wi: Vec3) -> f32 {
+    let alpha = material.roughness * material.roughness;
+    if alpha < MIN_ALPHA {
+        return 0.0;
+    }
+    let Some(half) = dielectric_half_vector(eta, wo, wi) else {
+        return 0.0;
+    };
+
+    let fresnel = fresnel_dielectric(wo.dot(half), eta);
+    let visible_pdf = ggx_visible_normal_pdf(alpha, wo, half);
+    if wi.z > 0.0 {
+        fresnel * visible_pdf / (4.0 * wo.dot(half))
+    } else {
+        let jacobian = wi.dot(half).abs() / (wi.dot(half) + wo.dot(half) / eta).powi(2);
+        (1.0 - fresnel) * visible_pdf * jacobian
+    }
+}
+
+fn dielectric_sample(material: &GpuMaterial, eta: f32, wo: Vec3, u: Vec3) -> Option<BsdfSample> {
+    let alpha = material.roughness * material.roughness;
+    if alpha < MIN_ALPHA {
+        let fresnel = fresnel_dielectric(wo.z, eta);
+        return Some(if u.x < fresnel {
+            BsdfSample {
+                direction: Vec3::new(-wo.x, -wo.y, wo.z),
+                weight: Vec3::ONE,
+                pdf: fresnel,
+                is_specular: true,
+            }
+        } else {
+            BsdfSample {
+                direction: refract(wo, Vec3::Z, eta)?,
+                weight: Vec3::from_array(material.color),
+                pdf: 1.0 - fresnel,
+                is_specular: true,
+            }
+        });
+    }
+
+    // Reflect or refract through a visible microfacet, by its reflectance
+    let half = sample_ggx_visible_normal(alpha, wo, u.y, u.z);
+    let direction = if u.x < fresnel_dielectric(wo.dot(half), eta) {
+        Some(reflect(wo, half)).filter(|direction| direction.z > 0.0)
+    } else {
+        refract(wo, half, eta).filter(|direction| direction.z < 0.0)
+    }?;
+
+    let pdf = dielectric_pdf(material, eta, wo, direction);
+    if pdf <= 0.0 {
+        return None;
+    }
+    Some(BsdfSample {
+        direction,
+        weight: dielectric_eval(material, eta, wo, direction) * direction.z.abs() / pdf,
+        pdf,
+        is_specular: false,
+    })
+}
+
+// Scattering helpers
+
+fn reflect(w: Vec3, normal: Vec3) -> Vec3 {
+    2.0 * w.dot(normal) * normal - w
+}
+
+/// `w` refracted through a surface with `normal` on its side, or `None` on
+/// total internal reflection.
+fn refract(w: Vec3, normal: Vec3, eta: f32) -> Option<Vec3> {
+    let cos_incident = w.dot(normal);
+    let sin2_transmitted = (1.0 - cos_incident * cos_incident).max(0.0) / (eta * eta);
+    if sin2_transmitted >= 1.0 {
+        return None;
+    }
+
+    let cos_transmitted = (1.0 - sin2_transmitted).sqrt();
+    Some(-w / eta + (cos_incident / eta - cos_transmitted) * normal)
+}
+
+/// Unpolarized reflectance of a smooth dielectric boundary, for light coming
+/// from the side with `cos_incident` into one with `eta` times its index.
+fn fresnel_dielectric(cos_incident: f32, eta: f32) -> f32 {
+    let sin2_transmitted = (1.0 - cos_incident * cos_incident).max(0.0) / (eta * eta);
+    if sin2_transmitted >= 1.0 {
+        return 1.0;
+    }
+
+    let cos_transmitted = (1.0 - sin2_transmitted).sqrt();
+    let parallel = (eta * cos_incident - cos_transmitted) / (eta * cos_incident + cos_transmitted);
+    let perpendicular =
+        (cos_incident - eta * cos_transmitted) / (cos_incident + eta * cos_transmitted);
+    (parallel * parallel + perpendicular * perpendicular) / 2.0
+}
+
+fn fresnel_schlick(reflectance: Vec3, cos_theta: f32) -> Vec3 {
+    reflectance + (Vec3::ONE - reflectance) * (1.0 - cos_theta).clamp(0.0, 1.0).powi(5)
+}
+
+fn ggx_distribution(alpha: f32, half: Vec3) -> f32 {
+    let alpha2 = alpha * alpha;
+    let denominator = half.z * half.z * (alpha2 - 1.0) + 1.0;
+    alpha2 / (PI * denominator * denominator)
+}
+
+/// Smith's lambda, the shadowed fraction of microfacets relative to the lit
+/// ones when seen from `w`.
+fn ggx_lambda(alpha: f32, w: Vec3) -> f32 {
+    let cos2 = w.z * w.z;
+    if cos2 == 0.0 {
+        return f32::INFINITY;
+    }
+    ((1.0 + alpha * alpha * (1.0 - cos2) / cos2).sqrt() - 1.0) / 2.0
+}
+
+/// Height-correlated masking and shadowing.
+fn ggx_masking(alpha: f32, wo: Vec3, wi: Vec3) -> f32 {
+    1.0 / (1.0 + ggx_lambda(alpha, wo) + ggx_lambda(alpha, wi))
+}
+
+fn ggx_visible_normal_pdf(alpha: f32, wo: Vec3, half: Vec3) -> f32 {
+    let masking = 1.0 / (1.0 + ggx_lambda(alpha, wo));
+    masking * wo.dot(half).max(0.0) * ggx_distribution(alpha, half) / wo.z
+}
+
+/// A microfacet normal seen from `wo`, with the method of "Sampling the GGX
+/// Distribution of Visible Normals" by Heitz.
+fn sample_ggx_visible_normal(alpha: f32, wo: Vec3, u1: f32, u2: f32) -> Vec3 {
+    // Stretch the view so the microfacets form a hemisphere
+    let view = Vec3::new(alpha * wo.x, alpha * wo.y, wo.z).normalize();
+    let length_squared = view.x * view.x + view.y * view.y;
+    let tangent = if length_squared > 0.0 {
+        Vec3::new(-view.y, view.x, 0.0) / length_squared.sqrt()
+    } else {
+        Vec3::X
+    };
+    let bitangent = view.cross(tangent);
+
+    // A point on the projected hemisphere
+    let radius = u1.sqrt();
+    let phi = 2.0 * PI * u2;
+    let t1 = radius * phi.cos();
+    let s = 0.5 * (1.0 + view.z);
+    let t2 = (1.0 - s) * (1.0 - t1 * t1).sqrt() + s * radius * phi.sin();
+    let normal = t1 * tangent + t2 * bitangent + (1.0 - t1 * t1 - t2 * t2).max(0.0).sqrt() * view;
+
+    Vec3::new(alpha * normal.x, alpha * normal.y, normal.z.max(0.0)).normalize()
+}
+
+fn sample_cosine_hemisphere(u1: f32, u2: f32) -> Vec3 {
+    let radius = u1.sqrt();
+    let phi = 2.0 * PI * u2;
+    Vec3::new(
+        radius * phi.cos(),
+        radius * phi.sin(),
+        (1.0 - u1).max(0.0).sqrt(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::material::KIND_EMISSIVE;
+    use crate::engine::test_util::Lcg;
+
+    fn material(kind: u32, roughness: f32) -> GpuMaterial {
+        GpuMaterial {
+            color: [1.0; 3],
+            kind,
+            roughness,
+            metallic: 0.5,
+            ..GpuMaterial::DEFAULT
+        }
+    }
+
+    /// White materials of every kind that scatters light
+    fn white_materials(roughness: f32) -> Vec<GpuMaterial> {
+        [
+            KIND_LAMBERT,
+            KIND_CONDUCTOR,
+            KIND_DIELECTRIC,
+            KIND_METALLIC_ROUGHNESS,
+        ]
+        .into_iter()
+        .map(|kind| material(kind, roughness))
+        .collect()
+    }
+
+    /// Directions at increasingly grazing angles, above and below the
+    /// surface
+    fn outgoing_directions() -> Vec<Vec3> {
+        [0.1f32, 0.7, 1.2, 1.45]
+            .into_iter()
+            .flat_map(|theta| {
+                let direction = Vec3::new(theta.sin() * 0.6, theta.sin() * 0.8, theta.cos());
+                [direction, Vec3::new(direction.x, direction.y, -direction.z)]
+            })
+            .collect()
+    }
+
+    /// The fraction of light leaving towards `wo`, estimated by sampling
+    fn sampled_albedo(material: &GpuMaterial, wo: Vec3, random: &mut Lcg) -> Vec3 {
+        let count = 20000;
+        let sum: Vec3 = (0..count)
+            .filter_map(|_| sample(material, wo, random.next_vec3()))
+            .map(|sample| sample.weight)
+            .sum();
+        sum / count as f32
+    }
+
+    /// Midpoint quadrature of `f` over the sphere, in steps of equal area
+    fn integrate_sphere(f: impl Fn(Vec3) -> f32) -> f32 {
+        let (z_steps, phi_steps) = (400, 800);
+        let area = 4.0 * PI / (z_steps * phi_steps) as f32;
+        let mut sum = 0.0;
+        for i in 0..z_steps {
+            let z = -1.0 + 2.0 * (i as f32 + 0.5) / z_steps as f32;
+            let radius = (1.0 - z * z).sqrt();
+            for j in 0..phi_steps {
+                let phi = 2.0 * PI * (j as f32 + 0.5) / phi_steps as f32;
+                sum += f(Vec3::new(radius * phi.cos(), radius * phi.sin(), z));
+            }
+        }
+        sum * area
+    }
+
+    #[test]
+    fn white_furnace_conserves_energy() {
+        let mut random = Lcg(1);
+        for roughness in [0.0, 0.2, 0.6, 1.0] {
+            for material in white_materials(roughness) {
+                for wo in outgoing_directions() {
+                    let albedo = sampled_albedo(&material, wo, &mut random);
+                    assert!(
+                        albedo.max_element() <= 1.01,
+                        "{:?} reflects {} of the light towards {}",
+                        material,
+                        albedo,
+                        wo
+                    );
+                    // Neither absorbs any light
+                    let is_lossless = material.kind == KIND_LAMBERT
+                        || (material.kind == KIND_DIELECTRIC && roughness == 0.0);
+                    if is_lossless {
+                        assert!(
+                            albedo.min_element() >= 0.99,
+                            "{:?} only reflects {} of the light towards {}",
+                            material,
+                            albedo,
+                            wo
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sampling_matches_pdf_and_eval() {
+        let mut random = Lcg(2);
+        for material in white_materials(0.7) {
+            for wo in outgoing_directions().into_iter().take(6) {
+                // Sampling and the pdf agree if the density integrates to one
+                // where the BSDF is non-zero and estimates the same albedo
+                let pdf_integral = integrate_sphere(|wi| pdf(&material, wo, wi));
+                assert!(
+                    pdf_integral <= 1.01,
+                    "{:?} has a pdf integrating to {} for {}",
+                    material,
+                    pdf_integral,
+                    wo
+                );
+                if material.kind == KIND_LAMBERT {
+                    assert!((pdf_integral - 1.0).abs() < 0.01);
+                }
+
+                let integrated = integrate_sphere(|wi| eval(&material, wo, wi).x * wi.z.abs());
+                let sampled = sampled_albedo(&material, wo, &mut random).x;
+                assert!(
+                    (integrated - sampled).abs() < 0.02,
+                    "{:?} integrates to {} but samples to {} for {}",
+                    material,
+                    integrated,
+                    sampled,
+                    wo
+                );
+
+                for _ in 0..100 {
+                    if let Some(sample) = sample(&material, wo, random.next_vec3()) {
+                        let expected = pdf(&material, wo, sample.direction);
+                        assert!((sample.pdf - expected).abs() <= 1e-3 * expected.max(1.0));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reflection_is_reciprocal() {
+        let mut random = Lcg(3);
+        for roughness in [0.1, 0.5, 1.0] {
+            for material in white_materials(roughness) {
+                for _ in 0..1000 {
+                    let wo = random.next_direction();
+                    // Dielectric transmission is only reciprocal with the
+                    // indices of refraction accounted for
+                    let mut wi = random.next_direction();
+                    if wi.z * wo.z < 0.0 {
+                        wi.z = -wi.z;
+                    }
+                    let forward = eval(&material, wo, wi);
+                    let backward = eval(&material, wi, wo);
+                    assert!(
+                        (forward - backward).abs().max_element()
+                            <= 1e-3 * forward.max_element().max(1.0),
+                        "{:?} is not reciprocal for {} and {}: {} and {}",
+                        material,
+                        wo,
+                        wi,
+                        forward,
+                        backward
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn opaque_materials_are_two_sided() {
+        let wo = Vec3::new(0.3, 0.4, 0.866).normalize();
+        let wi = Vec3::new(-0.5, 0.1, 0.8).normalize();
+        let below = |w: Vec3| Vec3::new(w.x, w.y, -w.z);
+        for material in white_materials(0.4) {
+            if material.kind != KIND_DIELECTRIC {
+                assert_eq!(
+                    eval(&material, wo, wi),
+                    eval(&material, below(wo), below(wi))
+                );
+                assert_eq!(eval(&material, wo, below(wi)), Vec3::ZERO);
+            }
+        }
+    }
+
+    #[test]
+    fn smooth_dielectric_reflects_or_refracts() {
+        let glass = material(KIND_DIELECTRIC, 0.0);
+        let mut random = Lcg(4);
+
+        // About 4% of the light is reflected at normal incidence
+        let reflected = (0..10000)
+            .filter_map(|_| sample(&glass, Vec3::Z, random.next_vec3()))
+            .filter(|sample| sample.direction.z > 0.0)
+            .count();
+        assert!((300..500).contains(&reflected), "{} reflected", reflected);
+
+        // Refraction bends towards the normal when entering
+        let wo = Vec3::new(0.5f32.sqrt(), 0.0, 0.5f32.sqrt());
+        let refracted = sample(&glass, wo, Vec3::new(0.99, 0.5, 0.5)).unwrap();
+        assert!(refracted.is_specular && refracted.direction.z < 0.0);
+        let sin_transmitted = refracted.direction.x.abs();
+        assert!((sin_transmitted * 1.5 - wo.x).abs() < 1e-5);
+
+        // Past the critical angle light stays inside
+        let grazing_inside = Vec3::new(0.9, 0.0, -(1.0f32 - 0.81).sqrt());
+        for _ in 0..100 {
+            let sample = sample(&glass, grazing_inside, random.next_vec3()).unwrap();
+            assert!(sample.direction.z < 0.0);
+            assert_eq!(sample.weight, Vec3::ONE);
+        }
+    }
+
+    #[test]
+    fn emissive_materials_absorb() {
+        let emissive = GpuMaterial {
+            kind: KIND_EMISSIVE,
+            emission: [5.0; 3],
+            ..GpuMaterial::DEFAULT
+        };
+        assert_eq!(eval(&emissive, Vec3::Z, Vec3::Z), Vec3::ZERO);
+        assert_eq!(pdf(&emissive, Vec3::Z, Vec3::Z), 0.0);
+        assert!(sample(&emissive, Vec3::Z, Vec3::splat(0.5)).is_none());
+    }
+}
@@ -0,0 +1,217 @@
+use std::ops::Deref;
+use std::rc::Rc;
+
+use ash;
+use ash::vk;
+
+use crate::engine;
+use crate::engine::allocator::{Allocation, AllocationInfo, Allocator, ResourceKind};
+use crate::engine::error::VkResultExt;
+use crate::engine::sync::MAX_FRAMES_IN_FLIGHT;
+
+/// A buffer together with its memory, both given back when it is dropped.
+pub struct Buffer {
+    pub handle: vk::Buffer,
+    pub allocation: Allocation,
+    allocator: Rc<Allocator>,
+}
+
+impl Buffer {
+    pub fn device_address(&self) -> vk::DeviceAddress {
+        get_buffer_device_address(self.allocator.device(), &self.handle)
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        unsafe { self.allocator.device().destroy_buffer(self.handle, None) };
+        self.allocator.free(&self.allocation);
+    }
+}
+
+/// A buffer the shaders read which the host rewrites while frames are in
+/// flight. Each frame in flight writes a staging buffer of its own, which
+/// its command buffer copies into the buffer, so a frame still tracing never
+/// sees the contents of a later one.
+pub struct StagedBuffer {
+    buffer: Buffer,
+    capacity: vk::DeviceSize,
+    staging_buffers: Vec<Buffer>,
+}
+
+impl StagedBuffer {
+    /// Creates a buffer with room for `capacity` bytes, starting out with
+    /// `data`.
+    pub fn new<T: Copy>(
+        allocator: &Rc<Allocator>,
+        data: &[T],
+        capacity: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+    ) -> engine::Result<Self> {
+        let host_visible =
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+        let buffer = create_buffer(
+            allocator,
+            capacity,
+            usage | vk::BufferUsageFlags::TRANSFER_DST,
+            host_visible,
+        )?;
+        buffer.allocation.write(data);
+        let staging_buffers = (0..MAX_FRAMES_IN_FLIGHT)
+            .map(|_| {
+                create_buffer(
+                    allocator,
+                    capacity,
+                    vk::BufferUsageFlags::TRANSFER_SRC,
+                    host_visible,
+                )
+            })
+            .collect::<engine::Result<_>>()?;
+
+        Ok(Self {
+            buffer,
+            capacity,
+            staging_buffers,
+        })
+    }
+
+    /// Writes `data` to the staging buffer of `frame`, whose previous
+    /// commands must have completed, and records copying it to the start of
+    /// the buffer. Shaders reading the buffer must be ordered around the copy
+    /// with barriers.
+    pub fn record_write<T: Copy>(
+        &self,
+        logical_device: &ash::Device,
+        command_buffer: &vk::CommandBuffer,
+        frame: usize,
+        data: &[T],
+    ) {
+        let size = std::mem::size_of_val(data) as vk::DeviceSize;
+        assert!(size <= self.capacity, "Staging more than the buffer holds!");
+        if size == 0 {
+            return;
+        }
+
+        let staging_buffer = &self.staging_buffers[frame];
+        staging_buffer.allocation.write(data);
+        let region = vk::BufferCopy {
+            src_offset: 0,
+            dst_offset: 0,
+            size,
+        };
+        unsafe {
+            logical_device.cmd_copy_buffer(
+                *command_buffer,
+                staging_buffer.handle,
+                self.buffer.handle,
+                &[region],
+            );
+        }
+    }
+}
+
+impl Deref for StagedBuffer {
+    type Target = Buffer;
+
+    fn deref(&self) -> &Buffer {
+        &self.buffer
+    }
+}
+
+#[track_caller]
+pub fn create_buffer(
+    allocator: &Rc<Allocator>,
+    size: vk::DeviceSize,
+    usage: vk::BufferUsageFlags,
+    properties: vk::MemoryPropertyFlags,
+) -> engine::Result<Buffer> {
+    create_buffer_preferring(
+        allocator,
+        size,
+        usage,
+        properties,
+        vk::MemoryPropertyFlags::empty(),
+    )
+}
+
+/// Like `create_buffer`, choosing memory with `preferred_properties` where
+/// there is a choice, e.g. `HOST_CACHED` for reading back.
+#[track_caller]
+pub fn create_buffer_preferring(
+    allocator: &Rc<Allocator>,
+    size: vk::DeviceSize,
+    usage: vk::BufferUsageFlags,
+    properties: vk::MemoryPropertyFlags,
+    preferred_properties: vk::MemoryPropertyFlags,
+) -> engine::Result<Buffer> {
+    let logical_device = allocator.device();
+    let create_info = vk::BufferCreateInfo {
+        size,
+        usage,
+        sharing_mode: vk::SharingMode::EXCLUSIVE,
+        ..Default::default()
+    };
+
+    let buffer =
+        unsafe { logical_device.create_buffer(&create_info, None) }.context("create buffer")?;
+
+    let memory_requirements = unsafe { logical_device.get_buffer_memory_requirements(buffer) };
+    let allocation_info = AllocationInfo {
+        requirements: memory_requirements,
+        properties,
+        preferred_properties,
+        kind: ResourceKind::Linear,
+        // Buffers used through their device address, e.g. by acceleration
+        // structures, need memory allocated with the device address flag
+        device_address: usage.contains(vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS),
+        ..Default::default()
+    };
+    let allocation = allocator
+        .allocate(&allocation_info)
+        .and_then(|allocation| {
+            unsafe {
+                logical_device.bind_buffer_memory(buffer, allocation.memory(), allocation.offset())
+            }
+            .context("bind buffer memory")
+            .inspect_err(|_| allocator.free(&allocation))
+            .map(|()| allocation)
+        })
+        .inspect_err(|_| unsafe { logical_device.destroy_buffer(buffer, None) })?;
+
+    Ok(Buffer {
+        handle: buffer,
+        allocation,
+        allocator: Rc::clone(allocator),
+    })
+}
+
+/// Creates a host visible buffer and fills it with `data`.
+#[track_caller]
+pub fn create_buffer_with_data<T: Copy>(
+    allocator: &Rc<Allocator>,
+    data: &[T],
+    usage: vk::BufferUsageFlags,
+) -> engine::Result<Buffer> {
+    let size = std::mem::size_of_val(data) as vk::DeviceSize;
+    let buffer = create_buffer(
+        allocator,
+        size,
+        usage,
+        vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+    )?;
+    buffer.allocation.write(data);
+
+    Ok(buffer)
+}
+
+pub fn get_buffer_device_address(
+    logical_device: &ash::Device,
+    buffer: &vk::Buffer,
+) -> vk::DeviceAddress {
+    let info = vk::BufferDeviceAddressInfo {
+        buffer: *buffer,
+        ..Default::default()
+    };
+
+    unsafe { logical_device.get_buffer_device_address(&info) }
+}
@@ -0,0 +1,733 @@
+use glam::Vec3;
+
+/// Matches `STACK_SIZE` in `shaders/trace.comp`. Traversal pushes both
+/// children of a node, so trees deeper than this could overflow the stack.
+pub const MAX_DEPTH: usize = 32;
+
+/// Estimated cost of visiting an interior node, relative to intersecting one
+/// triangle.
+const TRAVERSAL_COST: f32 = 1.0;
+
+/// A BVH node as laid out in the node storage buffer of `shaders/trace.comp`.
+/// Interior nodes have `count == 0`, their first child directly follows them
+/// and `offset` is the index of their second child. Leaves reference `count`
+/// triangles starting at `offset`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GpuBvhNode {
+    pub bounds_min: [f32; 3],
+    pub offset: u32,
+    pub bounds_max: [f32; 3],
+    pub count: u32,
+}
+
+/// A triangle as laid out in the triangle storage buffer of
+/// `shaders/trace.comp`. The `w` components are padding.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GpuTriangle {
+    pub v0: [f32; 4],
+    pub v1: [f32; 4],
+    pub v2: [f32; 4],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ray {
+    pub origin: Vec3,
+    pub direction: Vec3,
+}
+
+impl Ray {
+    pub fn new(origin: Vec3, direction: Vec3) -> Self {
+        Self { origin, direction }
+    }
+
+    pub fn at(&self, t: f32) -> Vec3 {
+        self.origin + t * self.direction
+    }
+}
+
+/// An axis-aligned bounding box. The empty box has its minimum above its
+/// maximum, so growing it by any point gives that point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub const EMPTY: Aabb = Aabb {
+        min: Vec3::splat(f32::MAX),
+        max: Vec3::splat(f32::MIN),
+    };
+
+    pub fn grow(&mut self, point: Vec3) {
+        self.min = self.min.min(point);
+        self.max = self.max.max(point);
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.cmpgt(self.max).any()
+    }
+
+    pub fn contains(&self, other: &Aabb) -> bool {
+        self.min.cmple(other.min).all() && self.max.cmpge(other.max).all()
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let size = self.max - self.min;
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Triangle {
+    pub v0: Vec3,
+    pub v1: Vec3,
+    pub v2: Vec3,
+}
+
+impl Triangle {
+    pub fn new(v0: Vec3, v1: Vec3, v2: Vec3) -> Self {
+        Self { v0, v1, v2 }
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        let mut bounds = Aabb::EMPTY;
+        bounds.grow(self.v0);
+        bounds.grow(self.v1);
+        bounds.grow(self.v2);
+        bounds
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.v0 + self.v1 + self.v2) / 3.0
+    }
+}
+
+/// The closest intersection along a ray.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hit {
+    pub t: f32,
+    /// Weights of `v1` and `v2`, like the hit attributes of a triangle hit
+    /// group
+    pub barycentrics: [f32; 2],
+    /// Index of the triangle in the slice the BVH was built from
+    pub triangle: usize,
+}
+
+/// Möller–Trumbore without backface culling, the same test as
+/// `shaders/trace.comp`. Returns the distance and barycentrics of a hit
+/// within `t_min..t_max`.
+pub fn intersect_triangle(
+    ray: &Ray,
+    triangle: &Triangle,
+    t_min: f32,
+    t_max: f32,
+) -> Option<(f32, [f32; 2])> {
+    let edge1 = triangle.v1 - triangle.v0;
+    let edge2 = triangle.v2 - triangle.v0;
+    let p = ray.direction.cross(edge2);
+    let determinant = edge1.dot(p);
+    if determinant.abs() < 1e-8 {
+        return None;
+    }
+
+    let inverse_determinant = 1.0 / determinant;
+    let s = ray.origin - triangle.v0;
+    let u = s.dot(p) * inverse_determinant;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let q = s.cross(edge1);
+    let v = ray.direction.dot(q) * inverse_determinant;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = edge2.dot(q) * inverse_determinant;
+    (t > t_min && t < t_max).then_some((t, [u, v]))
+}
+
+/// Slab test of a ray against a box. `inverse_direction` is the reciprocal
+/// of the ray direction, computed once per ray.
+pub fn intersect_aabb(
+    ray: &Ray,
+    inverse_direction: Vec3,
+    bounds: &Aabb,
+    t_min: f32,
+    t_max: f32,
+) -> bool {
+    let t0 = (bounds.min - ray.origin) * inverse_direction;
+    let t1 = (bounds.max - ray.origin) * inverse_direction;
+    let enter = t0.min(t1).max_element().max(t_min);
+    let exit = t0.max(t1).min_element().min(t_max);
+    enter <= exit
+}
+
+/// Tests every triangle, as a reference for the BVH.
+pub fn intersect_brute_force(
+    triangles: &[Triangle],
+    ray: &Ray,
+    t_min: f32,
+    t_max: f32,
+) -> Option<Hit> {
+    let mut closest = None;
+    let mut closest_t = t_max;
+    for (index, triangle) in triangles.iter().enumerate() {
+        if let Some((t, barycentrics)) = intersect_triangle(ray, triangle, t_min, closest_t) {
+            closest_t = t;
+            closest = Some(Hit {
+                t,
+                barycentrics,
+                triangle: index,
+            });
+        }
+    }
+
+    closest
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BvhBuildOptions {
+    /// Nodes with more triangles are split unless they are `MAX_DEPTH - 1`
+    /// deep. Smaller nodes are split only when the surface area heuristic
+    /// expects it to pay off.
+    pub max_leaf_size: usize,
+    /// Number of centroid bins evaluated per axis for each split
+    pub bin_count: usize,
+}
+
+impl Default for BvhBuildOptions {
+    fn default() -> Self {
+        Self {
+            max_leaf_size: 4,
+            bin_count: 16,
+        }
+    }
+}
+
+/// A node of the linearized tree, in the same depth-first order and with the
+/// same `offset` and `count` meaning as `GpuBvhNode`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BvhNode {
+    pub bounds: Aabb,
+    pub offset: u32,
+    pub count: u32,
+}
+
+impl BvhNode {
+    pub fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+}
+
+/// A bounding volume hierarchy over triangles, built top-down with a binned
+/// surface area heuristic.
+#[derive(Debug, Clone)]
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+    /// The triangles in leaf order
+    pub triangles: Vec<Triangle>,
+    /// Index in the original slice of each triangle in `triangles`
+    pub triangle_indices: Vec<u32>,
+}
+
+/// Per-triangle data the builder sorts into leaves.
+struct BuildPrimitive {
+    bounds: Aabb,
+    centroid: Vec3,
+}
+
+#[derive(Clone, Copy)]
+struct Bin {
+    bounds: Aabb,
+    count: usize,
+}
+
+struct Split {
+    axis: usize,
+    bin: usize,
+    cost: f32,
+}
+
+impl Bvh {
+    pub fn build(triangles: &[Triangle], options: &BvhBuildOptions) -> Self {
+        assert!(options.max_leaf_size > 0, "Leaves must hold triangles!");
+        assert!(options.bin_count > 1, "Splits need at least two bins!");
+
+        let primitives: Vec<BuildPrimitive> = triangles
+            .iter()
+            .map(|triangle| BuildPrimitive {
+                bounds: triangle.bounds(),
+                centroid: triangle.centroid(),
+            })
+            .collect();
+        let mut indices: Vec<u32> = (0..triangles.len() as u32).collect();
+        let mut nodes = Vec::new();
+        if triangles.is_empty() {
+            // A root without triangles, which traversal never enters
+            nodes.push(BvhNode {
+                bounds: Aabb::EMPTY,
+                offset: 0,
+                count: 0,
+            });
+        } else {
+            build_node(&mut nodes, &primitives, &mut indices, 0, 1, options);
+        }
+
+        Self {
+            nodes,
+            triangles: indices
+                .iter()
+                .map(|&index| triangles[index as usize])
+                .collect(),
+            triangle_indices: indices,
+        }
+    }
+
+    /// Number of nodes on the longest path from the root to a leaf.
+    pub fn depth(&self) -> usize {
+        fn node_depth(nodes: &[BvhNode], index: usize) -> usize {
+            let node = &nodes[index];
+            if node.is_leaf() || nodes.len() == 1 {
+                1
+            } else {
+                1 + node_depth(nodes, index + 1).max(node_depth(nodes, node.offset as usize))
+            }
+        }
+
+        node_depth(&self.nodes, 0)
+    }
+
+    /// Finds the closest hit by traversing the tree the way
+    /// `shaders/trace.comp` does.
+    pub fn intersect(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<Hit> {
+        if self.triangles.is_empty() {
+            return None;
+        }
+
+        let inverse_direction = ray.direction.recip();
+        let mut closest = None;
+        let mut closest_t = t_max;
+
+        let mut stack = vec![0];
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if !intersect_aabb(ray, inverse_direction, &node.bounds, t_min, closest_t) {
+                continue;
+            }
+
+            if node.is_leaf() {
+                let first = node.offset as usize;
+                for index in first..first + node.count as usize {
+                    if let Some((t, barycentrics)) =
+                        intersect_triangle(ray, &self.triangles[index], t_min, closest_t)
+                    {
+                        closest_t = t;
+                        closest = Some(Hit {
+                            t,
+                            barycentrics,
+                            triangle: self.triangle_indices[index] as usize,
+                        });
+                    }
+                }
+            } else {
+                stack.push(node.offset as usize);
+                stack.push(node_index + 1);
+            }
+        }
+
+        closest
+    }
+
+    /// The node and triangle buffers read by `shaders/trace.comp`.
+    pub fn to_gpu(&self) -> (Vec<GpuBvhNode>, Vec<GpuTriangle>) {
+        // Storage buffers cannot be empty
+        assert!(!self.triangles.is_empty(), "BVH has no triangles!");
+        assert!(
+            self.depth() < MAX_DEPTH,
+            "BVH is too deep for the compute shader traversal stack!"
+        );
+
+        let nodes = self
+            .nodes
+            .iter()
+            .map(|node| GpuBvhNode {
+                bounds_min: node.bounds.min.to_array(),
+                offset: node.offset,
+                bounds_max: node.bounds.max.to_array(),
+                count: node.count,
+            })
+            .collect();
+        let triangles = self
+            .triangles
+            .iter()
+            .map(|triangle| GpuTriangle {
+                v0: triangle.v0.extend(0.0).to_array(),
+                v1: triangle.v1.extend(0.0).to_array(),
+                v2: triangle.v2.extend(0.0).to_array(),
+            })
+            .collect();
+
+        (nodes, triangles)
+    }
+}
+
+/// Appends the subtree over `indices`, whose first triangle will end up at
+/// `first` in leaf order, in depth-first order. `depth` counts the nodes from
+/// the root to this one, and nodes at `MAX_DEPTH - 1` become leaves so the
+/// tree fits the traversal stack however the triangles are spread.
+fn build_node(
+    nodes: &mut Vec<BvhNode>,
+    primitives: &[BuildPrimitive],
+    indices: &mut [u32],
+    first: usize,
+    depth: usize,
+    options: &BvhBuildOptions,
+) {
+    let mut bounds = Aabb::EMPTY;
+    let mut centroid_bounds = Aabb::EMPTY;
+    for &index in indices.iter() {
+        let primitive = &primitives[index as usize];
+        bounds = bounds.union(&primitive.bounds);
+        centroid_bounds.grow(primitive.centroid);
+    }
+
+    let node_index = nodes.len();
+    nodes.push(BvhNode {
+        bounds,
+        offset: first as u32,
+        count: indices.len() as u32,
+    });
+    if indices.len() == 1 || depth >= MAX_DEPTH - 1 {
+        return;
+    }
+
+    let split = find_best_split(primitives, indices, &bounds, &centroid_bounds, options);
+    let leaf_cost = indices.len() as f32;
+    let left_count = match split {
+        Some(split) if split.cost < leaf_cost || indices.len() > options.max_leaf_size => {
+            partition(indices, |index| {
+                let centroid = primitives[index as usize].centroid;
+                bin_index(centroid, &centroid_bounds, split.axis, options.bin_count) < split.bin
+            })
+        }
+        // All centroids coincide, so no plane separates them
+        None if indices.len() > options.max_leaf_size => indices.len() / 2,
+        _ => return,
+    };
+
+    let (left, right) = indices.split_at_mut(left_count);
+    build_node(nodes, primitives, left, first, depth + 1, options);
+    let right_index = nodes.len();
+    build_node(
+        nodes,
+        primitives,
+        right,
+        first + left_count,
+        depth + 1,
+        options,
+    );
+    nodes[node_index].offset = right_index as u32;
+    nodes[node_index].count = 0;
+}
+
+fn bin_index(centroid: Vec3, centroid_bounds: &Aabb, axis: usize, bin_count: usize) -> usize {
+    let extent = centroid_bounds.max[axis] - centroid_bounds.min[axis];
+    let relative = (centroid[axis] - centroid_bounds.min[axis]) / extent;
+    ((relative * bin_count as f32) as usize).min(bin_count - 1)
+}
+
+/// Evaluates the planes between centroid bins on every axis. The cost is
+/// relative to intersecting one triangle, so it compares directly with the
+/// triangle count of a leaf.
+fn find_best_split(
+    primitives: &[BuildPrimitive],
+    indices: &[u32],
+    bounds: &Aabb,
+    centroid_bounds: &Aabb,
+    options: &BvhBuildOptions,
+) -> Option<Split> {
+    let bin_count = options.bin_count;
+    let mut best: Option<Split> = None;
+
+    for axis in 0..3 {
+        if centroid_bounds.max[axis] <= centroid_bounds.min[axis] {
+            continue;
+        }
+
+        let mut bins = vec![
+            Bin {
+                bounds: Aabb::EMPTY,
+                count: 0,
+            };
+            bin_count
+        ];
+        for &index in indices {
+            let primitive = &primitives[index as usize];
+            let bin = &mut bins[bin_index(primitive.centroid, centroid_bounds, axis, bin_count)];
+            bin.bounds = bin.bounds.union(&primitive.bounds);
+            bin.count += 1;
+        }
+
+        // Sweep from the right to get the area and count right of each plane
+        let mut right_areas = vec![0.0; bin_count];
+        let mut right_counts = vec![0; bin_count];
+        let mut right = Bin {
+            bounds: Aabb::EMPTY,
+            count: 0,
+        };
+        for bin in (1..bin_count).rev() {
+            right.bounds = right.bounds.union(&bins[bin].bounds);
+            right.count += bins[bin].count;
+            right_areas[bin] = right.bounds.surface_area();
+            right_counts[bin] = right.count;
+        }
+
+        let mut left = Bin {
+            bounds: Aabb::EMPTY,
+            count: 0,
+        };
+        for plane in 1..bin_count {
+            left.bounds = left.bounds.union(&bins[plane - 1].bounds);
+            left.count += bins[plane - 1].count;
+            if left.count == 0 || right_counts[plane] == 0 {
+                continue;
+            }
+
+            let cost = TRAVERSAL_COST
+                + (left.bounds.surface_area() * left.count as f32
+                    + right_areas[plane] * right_counts[plane] as f32)
+                    / bounds.surface_area().max(f32::MIN_POSITIVE);
+            if best.as_ref().is_none_or(|best| cost < best.cost) {
+                best = Some(Split {
+                    axis,
+                    bin: plane,
+                    cost,
+                });
+            }
+        }
+    }
+
+    best
+}
+
+/// Moves the indices matching `is_left` to the front and returns how many
+/// there are.
+fn partition(indices: &mut [u32], is_left: impl Fn(u32) -> bool) -> usize {
+    let mut left_count = 0;
+    for i in 0..indices.len() {
+        if is_left(indices[i]) {
+            indices.swap(i, left_count);
+            left_count += 1;
+        }
+    }
+
+    left_count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::test_util::Lcg;
+
+    /// Small triangles scattered through the unit cube
+    fn random_triangles(count: usize, random: &mut Lcg) -> Vec<Triangle> {
+        (0..count)
+            .map(|_| {
+                let center = random.next_vec3();
+                Triangle::new(
+                    center + (random.next_vec3() - 0.5) * 0.2,
+                    center + (random.next_vec3() - 0.5) * 0.2,
+                    center + (random.next_vec3() - 0.5) * 0.2,
+                )
+            })
+            .collect()
+    }
+
+    fn random_ray(random: &mut Lcg) -> Ray {
+        let origin = random.next_vec3() * 3.0 - 1.0;
+        let target = random.next_vec3();
+        Ray::new(origin, (target - origin).normalize())
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let mut random = Lcg(1);
+        let triangles = random_triangles(500, &mut random);
+
+        for max_leaf_size in [1, 4, 16] {
+            let options = BvhBuildOptions {
+                max_leaf_size,
+                ..Default::default()
+            };
+            let bvh = Bvh::build(&triangles, &options);
+
+            for _ in 0..1000 {
+                let ray = random_ray(&mut random);
+                let expected = intersect_brute_force(&triangles, &ray, 0.001, f32::MAX);
+                let actual = bvh.intersect(&ray, 0.001, f32::MAX);
+                assert_eq!(
+                    actual.map(|hit| hit.triangle),
+                    expected.map(|hit| hit.triangle)
+                );
+                if let (Some(actual), Some(expected)) = (actual, expected) {
+                    assert!((actual.t - expected.t).abs() < 1e-5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn leaves_reference_every_triangle_once() {
+        let mut random = Lcg(2);
+        let triangles = random_triangles(200, &mut random);
+        let options = BvhBuildOptions::default();
+        let bvh = Bvh::build(&triangles, &options);
+
+        let mut seen = vec![false; triangles.len()];
+        for node in bvh.nodes.iter().filter(|node| node.is_leaf()) {
+            assert!(node.count as usize <= options.max_leaf_size);
+            for index in node.offset..node.offset + node.count {
+                let original = bvh.triangle_indices[index as usize] as usize;
+                assert!(!seen[original]);
+                seen[original] = true;
+            }
+        }
+        assert!(seen.iter().all(|&seen| seen));
+    }
+
+    #[test]
+    fn children_are_inside_their_parent() {
+        let mut random = Lcg(3);
+        let triangles = random_triangles(200, &mut random);
+        let bvh = Bvh::build(&triangles, &BvhBuildOptions::default());
+
+        for (index, node) in bvh.nodes.iter().enumerate() {
+            if node.is_leaf() {
+                for triangle in &bvh.triangles[node.offset as usize..][..node.count as usize] {
+                    assert!(node.bounds.contains(&triangle.bounds()));
+                }
+            } else {
+                assert!(node.bounds.contains(&bvh.nodes[index + 1].bounds));
+                assert!(node
+                    .bounds
+                    .contains(&bvh.nodes[node.offset as usize].bounds));
+            }
+        }
+    }
+
+    #[test]
+    fn splits_coincident_centroids() {
+        let triangle = Triangle::new(Vec3::ZERO, Vec3::X, Vec3::Y);
+        let bvh = Bvh::build(&[triangle; 9], &BvhBuildOptions::default());
+        assert!(bvh
+            .nodes
+            .iter()
+            .all(|node| !node.is_leaf() || node.count <= 4));
+        assert_eq!(bvh.triangles.len(), 9);
+    }
+
+    #[test]
+    fn depth_fits_the_traversal_stack() {
+        // Geometric spacing makes every SAH split peel off only the farthest
+        // few triangles, which without a depth limit overflows the stack
+        let triangles: Vec<Triangle> = (0..80)
+            .map(|i| {
+                let x = 3.0f32.powi(i);
+                Triangle::new(
+                    Vec3::new(x, 0.0, 0.0),
+                    Vec3::new(x, 1.0, 0.0),
+                    Vec3::new(x, 0.0, 1.0),
+                )
+            })
+            .collect();
+        let bvh = Bvh::build(&triangles, &BvhBuildOptions::default());
+        assert!(bvh.depth() < MAX_DEPTH);
+        assert_eq!(bvh.to_gpu().1.len(), triangles.len());
+
+        let ray = Ray::new(Vec3::new(3.0f32.powi(40) * 0.9, 0.25, 0.25), Vec3::X);
+        assert_eq!(
+            bvh.intersect(&ray, 0.0, f32::MAX).map(|hit| hit.triangle),
+            Some(40)
+        );
+    }
+
+    #[test]
+    fn empty_bvh_is_never_hit() {
+        let bvh = Bvh::build(&[], &BvhBuildOptions::default());
+        let ray = Ray::new(Vec3::ZERO, Vec3::Z);
+        assert_eq!(bvh.intersect(&ray, 0.0, f32::MAX), None);
+    }
+
+    #[test]
+    fn ray_box_intersection() {
+        let bounds = Aabb {
+            min: Vec3::splat(-1.0),
+            max: Vec3::splat(1.0),
+        };
+        let towards = Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::Z);
+        let away = Ray::new(Vec3::new(0.0, 0.0, -5.0), -Vec3::Z);
+        let beside = Ray::new(Vec3::new(2.0, 0.0, -5.0), Vec3::Z);
+        for (ray, expected) in [(towards, true), (away, false), (beside, false)] {
+            let inverse_direction = ray.direction.recip();
+            assert_eq!(
+                intersect_aabb(&ray, inverse_direction, &bounds, 0.0, f32::MAX),
+                expected
+            );
+        }
+        // The box is beyond the maximum distance
+        let inverse_direction = towards.direction.recip();
+        assert!(!intersect_aabb(
+            &towards,
+            inverse_direction,
+            &bounds,
+            0.0,
+            3.0
+        ));
+    }
+
+    #[test]
+    fn ray_triangle_intersection() {
+        let triangle = Triangle::new(Vec3::ZERO, Vec3::X, Vec3::Y);
+        let ray = Ray::new(Vec3::new(0.25, 0.5, -1.0), Vec3::Z);
+        let (t, [u, v]) = intersect_triangle(&ray, &triangle, 0.0, f32::MAX).unwrap();
+        assert!((t - 1.0).abs() < 1e-6);
+        assert!((u - 0.25).abs() < 1e-6 && (v - 0.5).abs() < 1e-6);
+        assert_eq!(ray.at(t), Vec3::new(0.25, 0.5, 0.0));
+
+        // Back faces are hit too
+        let reversed = Ray::new(Vec3::new(0.25, 0.5, 1.0), -Vec3::Z);
+        assert!(intersect_triangle(&reversed, &triangle, 0.0, f32::MAX).is_some());
+
+        let outside = Ray::new(Vec3::new(0.75, 0.75, -1.0), Vec3::Z);
+        assert!(intersect_triangle(&outside, &triangle, 0.0, f32::MAX).is_none());
+    }
+
+    #[test]
+    fn gpu_layout_matches_shader() {
+        assert_eq!(std::mem::size_of::<GpuBvhNode>(), 32);
+        assert_eq!(std::mem::size_of::<GpuTriangle>(), 48);
+
+        let mut random = Lcg(4);
+        let triangles = random_triangles(50, &mut random);
+        let bvh = Bvh::build(&triangles, &BvhBuildOptions::default());
+        let (nodes, gpu_triangles) = bvh.to_gpu();
+        assert_eq!(nodes.len(), bvh.nodes.len());
+        assert_eq!(gpu_triangles.len(), triangles.len());
+        assert_eq!(nodes[0].bounds_min, bvh.nodes[0].bounds.min.to_array());
+        assert_eq!(gpu_triangles[0].v0[..3], bvh.triangles[0].v0.to_array());
+    }
+}
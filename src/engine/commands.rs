@@ -0,0 +1,148 @@
+use std::rc::Rc;
+
+use ash;
+use ash::vk;
+
+use crate::engine;
+use crate::engine::error::VkResultExt;
+use crate::engine::logical_device::Device;
+
+/// A command pool, destroyed with the command buffers allocated from it
+/// when it is dropped.
+pub struct CommandPool {
+    pub handle: vk::CommandPool,
+    device: Rc<Device>,
+}
+
+impl CommandPool {
+    /// Creates a pool whose command buffers can be reset one by one.
+    pub fn new(logical_device: &Rc<Device>, queue_family_index: u32) -> engine::Result<Self> {
+        let create_info = vk::CommandPoolCreateInfo {
+            flags: vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
+            queue_family_index,
+            ..Default::default()
+        };
+        let handle = unsafe { logical_device.create_command_pool(&create_info, None) }
+            .context("create command pool")?;
+
+        Ok(Self {
+            handle,
+            device: Rc::clone(logical_device),
+        })
+    }
+}
+
+impl Drop for CommandPool {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_command_pool(self.handle, None) };
+    }
+}
+
+/// Allocates a primary command buffer and begins recording it for a single
+/// submission. Finish it with [`end_single_time_commands`].
+pub fn begin_single_time_commands(
+    logical_device: &ash::Device,
+    command_pool: &vk::CommandPool,
+) -> engine::Result<vk::CommandBuffer> {
+    let allocate_info = vk::CommandBufferAllocateInfo {
+        level: vk::CommandBufferLevel::PRIMARY,
+        command_pool: *command_pool,
+        command_buffer_count: 1,
+        ..Default::default()
+    };
+
+    let command_buffer = unsafe { logical_device.allocate_command_buffers(&allocate_info) }
+        .context("allocate command buffer")?[0];
+
+    let begin_info = vk::CommandBufferBeginInfo {
+        flags: vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT,
+        ..Default::default()
+    };
+    unsafe { logical_device.begin_command_buffer(command_buffer, &begin_info) }
+        .context("begin command buffer")
+        .inspect_err(|_| unsafe {
+            logical_device.free_command_buffers(*command_pool, &[command_buffer])
+        })?;
+
+    Ok(command_buffer)
+}
+
+/// Submits `command_buffer`, waits for the queue to go idle and frees it,
+/// also when submitting fails.
+pub fn end_single_time_commands(
+    logical_device: &ash::Device,
+    command_pool: &vk::CommandPool,
+    queue: &vk::Queue,
+    command_buffer: vk::CommandBuffer,
+) -> engine::Result<()> {
+    let command_buffers = [command_buffer];
+    let result = unsafe {
+        logical_device
+            .end_command_buffer(command_buffer)
+            .context("end command buffer")
+            .and_then(|()| {
+                let submit_info = vk::SubmitInfo {
+                    command_buffer_count: 1,
+                    p_command_buffers: command_buffers.as_ptr(),
+                    ..Default::default()
+                };
+                logical_device
+                    .queue_submit(*queue, &[submit_info], vk::Fence::null())
+                    .context("submit single time commands")
+            })
+            .and_then(|()| {
+                logical_device
+                    .queue_wait_idle(*queue)
+                    .context("wait for single time commands")
+            })
+    };
+
+    unsafe { logical_device.free_command_buffers(*command_pool, &command_buffers) };
+    result
+}
+
+pub fn create_command_buffers(
+    logical_device: &ash::Device,
+    command_pool: &vk::CommandPool,
+    count: u32,
+) -> engine::Result<Vec<vk::CommandBuffer>> {
+    let allocate_info = vk::CommandBufferAllocateInfo {
+        level: vk::CommandBufferLevel::PRIMARY,
+        command_pool: *command_pool,
+        command_buffer_count: count,
+        ..Default::default()
+    };
+
+    unsafe { logical_device.allocate_command_buffers(&allocate_info) }
+        .context("allocate command buffers")
+}
+
+/// Records a global memory barrier, which makes the `src_access` writes of
+/// `src_stage` visible to the `dst_access` of `dst_stage`. With empty access
+/// masks it only orders the stages, e.g. to overwrite what was read.
+pub fn record_memory_barrier(
+    logical_device: &ash::Device,
+    command_buffer: &vk::CommandBuffer,
+    src_stage: vk::PipelineStageFlags,
+    src_access: vk::AccessFlags,
+    dst_stage: vk::PipelineStageFlags,
+    dst_access: vk::AccessFlags,
+) {
+    let barrier = vk::MemoryBarrier {
+        src_access_mask: src_access,
+        dst_access_mask: dst_access,
+        ..Default::default()
+    };
+
+    unsafe {
+        logical_device.cmd_pipeline_barrier(
+            *command_buffer,
+            src_stage,
+            dst_stage,
+            vk::DependencyFlags::empty(),
+            &[barrier],
+            &[],
+            &[],
+        );
+    }
+}
@@ -0,0 +1,700 @@
+use std::rc::Rc;
+
+use ash;
+use ash::vk;
+
+use crate::engine;
+use crate::engine::allocator::Allocator;
+use crate::engine::buffer::{Buffer, StagedBuffer};
+use crate::engine::bvh::{Bvh, BvhBuildOptions, GpuBvhNode, GpuTriangle, Triangle};
+use crate::engine::descriptor::{DescriptorPool, DescriptorSetLayout};
+use crate::engine::environment::EnvironmentBuffer;
+use crate::engine::error::VkResultExt;
+use crate::engine::light::LightBuffer;
+use crate::engine::logical_device::Device;
+use crate::engine::material::MaterialTable;
+use crate::engine::path_tracing::{FrameConstants, ACCUMULATION_IMAGE_FORMAT};
+use crate::engine::pipeline::Pipeline;
+use crate::engine::texture::TextureArray;
+use crate::scene::mesh::SceneGeometry;
+
+const COMPUTE_SHADER_CODE: &[u8] = include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/shaders/spv/trace.comp.spv"
+));
+
+/// Matches `local_size_x` and `local_size_y` in `shaders/trace.comp`.
+const WORKGROUP_SIZE: u32 = 8;
+
+/// Same as the hardware backend, see `engine::ray_tracing`.
+const STORAGE_IMAGE_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+/// The compute fallback for devices without hardware ray tracing. It traces
+/// the same paths as `engine::ray_tracing::RayTracer` through a BVH in
+/// storage buffers, accumulating the same way, and blits the result to the
+/// frame being presented.
+pub struct ComputeTracer {
+    _descriptor_set_layout: DescriptorSetLayout,
+    /// Frees `descriptor_set` when it is dropped
+    _descriptor_pool: DescriptorPool,
+    descriptor_set: vk::DescriptorSet,
+    pipeline: Pipeline,
+    storage_image: engine::image::StorageImage,
+    accumulation_image: engine::image::StorageImage,
+    bvh_buffers: BvhBuffers,
+    /// `MaterialTable::materials`
+    material_buffer: Buffer,
+    texture_array: TextureArray,
+    environment_buffer: EnvironmentBuffer,
+    light_buffer: LightBuffer,
+    /// What the BVH and the lights are built from when the instances move
+    material_table: MaterialTable,
+}
+
+impl ComputeTracer {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        instance: &ash::Instance,
+        physical_device: &vk::PhysicalDevice,
+        logical_device: &Rc<Device>,
+        allocator: &Rc<Allocator>,
+        command_pool: &vk::CommandPool,
+        queue: &vk::Queue,
+        extent: vk::Extent2D,
+        geometry: &SceneGeometry,
+    ) -> engine::Result<Self> {
+        let material_table = MaterialTable::new(geometry);
+        let bvh = build_scene_bvh(geometry, &material_table, 0.0);
+        let bvh_buffers = BvhBuffers::new(allocator, &bvh)?;
+        let material_buffer = engine::buffer::create_buffer_with_data(
+            allocator,
+            &material_table.materials,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+        )?;
+        let texture_array = TextureArray::new(
+            instance,
+            physical_device,
+            logical_device,
+            allocator,
+            command_pool,
+            queue,
+            geometry,
+        )?;
+        let environment_buffer =
+            EnvironmentBuffer::new(allocator, geometry, texture_array.environment)?;
+        let light_buffer = LightBuffer::new(allocator, geometry, &material_table, 0.0)?;
+
+        let descriptor_set_layout =
+            create_descriptor_set_layout(logical_device, texture_array.descriptor_count())?;
+        let pipeline = create_compute_pipeline(logical_device, &descriptor_set_layout.handle)?;
+
+        let descriptor_pool =
+            create_descriptor_pool(logical_device, texture_array.descriptor_count())?;
+        let descriptor_set = descriptor_pool.allocate_set(&descriptor_set_layout)?;
+
+        let storage_image = engine::image::StorageImage::new(
+            logical_device,
+            allocator,
+            command_pool,
+            queue,
+            STORAGE_IMAGE_FORMAT,
+            extent,
+        )?;
+        let accumulation_image = engine::image::StorageImage::new(
+            logical_device,
+            allocator,
+            command_pool,
+            queue,
+            ACCUMULATION_IMAGE_FORMAT,
+            extent,
+        )?;
+
+        let compute_tracer = Self {
+            _descriptor_set_layout: descriptor_set_layout,
+            _descriptor_pool: descriptor_pool,
+            descriptor_set,
+            pipeline,
+            storage_image,
+            accumulation_image,
+            bvh_buffers,
+            material_buffer,
+            texture_array,
+            environment_buffer,
+            light_buffer,
+            material_table,
+        };
+        compute_tracer.write_descriptor_set(logical_device);
+
+        Ok(compute_tracer)
+    }
+
+    fn write_descriptor_set(&self, logical_device: &ash::Device) {
+        let image_info = vk::DescriptorImageInfo {
+            image_view: self.storage_image.image_view.handle,
+            image_layout: vk::ImageLayout::GENERAL,
+            ..Default::default()
+        };
+        let accumulation_image_info = vk::DescriptorImageInfo {
+            image_view: self.accumulation_image.image_view.handle,
+            image_layout: vk::ImageLayout::GENERAL,
+            ..Default::default()
+        };
+        let node_buffer_info = vk::DescriptorBufferInfo {
+            buffer: self.bvh_buffers.node_buffer.handle,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        };
+        let triangle_buffer_info = vk::DescriptorBufferInfo {
+            buffer: self.bvh_buffers.triangle_buffer.handle,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        };
+        let material_buffer_info = vk::DescriptorBufferInfo {
+            buffer: self.material_buffer.handle,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        };
+        let triangle_material_buffer_info = vk::DescriptorBufferInfo {
+            buffer: self.bvh_buffers.triangle_material_buffer.handle,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        };
+        let texture_infos = self.texture_array.image_infos();
+        let triangle_uv_buffer_info = vk::DescriptorBufferInfo {
+            buffer: self.bvh_buffers.triangle_uv_buffer.handle,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        };
+        let environment_buffer_info = vk::DescriptorBufferInfo {
+            buffer: self.environment_buffer.buffer.handle,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        };
+        let light_buffer_info = vk::DescriptorBufferInfo {
+            buffer: self.light_buffer.buffer.handle,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        };
+
+        let writes = [
+            vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: 0,
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+                p_image_info: &image_info,
+                ..Default::default()
+            },
+            vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: 1,
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+                p_buffer_info: &node_buffer_info,
+                ..Default::default()
+            },
+            vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: 2,
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+                p_buffer_info: &triangle_buffer_info,
+                ..Default::default()
+            },
+            vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: 3,
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+                p_image_info: &accumulation_image_info,
+                ..Default::default()
+            },
+            vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: 4,
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+                p_buffer_info: &material_buffer_info,
+                ..Default::default()
+            },
+            vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: 5,
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+                p_buffer_info: &triangle_material_buffer_info,
+                ..Default::default()
+            },
+            vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: 6,
+                descriptor_count: texture_infos.len() as u32,
+                descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                p_image_info: texture_infos.as_ptr(),
+                ..Default::default()
+            },
+            vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: 7,
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+                p_buffer_info: &triangle_uv_buffer_info,
+                ..Default::default()
+            },
+            vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: 8,
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+                p_buffer_info: &environment_buffer_info,
+                ..Default::default()
+            },
+            vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: 9,
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+                p_buffer_info: &light_buffer_info,
+                ..Default::default()
+            },
+        ];
+
+        unsafe { logical_device.update_descriptor_sets(&writes, &[]) };
+    }
+
+    /// Recreates the storage and accumulation images for a new target
+    /// extent, e.g. after the swapchain was recreated. The device must be
+    /// idle, and the accumulation starts over.
+    pub fn resize(
+        &mut self,
+        logical_device: &Rc<Device>,
+        allocator: &Rc<Allocator>,
+        command_pool: &vk::CommandPool,
+        queue: &vk::Queue,
+        extent: vk::Extent2D,
+    ) -> engine::Result<()> {
+        if extent == self.storage_image.extent {
+            return Ok(());
+        }
+
+        let storage_image = engine::image::StorageImage::new(
+            logical_device,
+            allocator,
+            command_pool,
+            queue,
+            STORAGE_IMAGE_FORMAT,
+            extent,
+        )?;
+        self.storage_image = storage_image;
+        let accumulation_image = engine::image::StorageImage::new(
+            logical_device,
+            allocator,
+            command_pool,
+            queue,
+            ACCUMULATION_IMAGE_FORMAT,
+            extent,
+        )?;
+        self.accumulation_image = accumulation_image;
+        self.write_descriptor_set(logical_device);
+
+        Ok(())
+    }
+
+    /// Records moving the instances of `geometry`, which the tracer was
+    /// created with, to where they are at `time`, ahead of the frame's
+    /// tracing. The BVH and the lights are built anew on the host, so this is
+    /// slow for large scenes. The buffers of frame in flight `frame` are
+    /// rewritten, so its previous commands must have completed.
+    pub fn record_set_time(
+        &self,
+        logical_device: &ash::Device,
+        command_buffer: &vk::CommandBuffer,
+        frame: usize,
+        geometry: &SceneGeometry,
+        time: f32,
+    ) {
+        let bvh = build_scene_bvh(geometry, &self.material_table, time);
+
+        // Earlier frames may still be tracing the previous BVH and lights
+        engine::commands::record_memory_barrier(
+            logical_device,
+            command_buffer,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::empty(),
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::empty(),
+        );
+        self.bvh_buffers
+            .record_write(logical_device, command_buffer, frame, &bvh);
+        self.light_buffer.record_set_time(
+            logical_device,
+            command_buffer,
+            frame,
+            geometry,
+            &self.material_table,
+            time,
+        );
+        engine::commands::record_memory_barrier(
+            logical_device,
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::SHADER_READ,
+        );
+    }
+
+    /// Records tracing the samples of a frame and blitting the accumulated
+    /// result into `target_image`, which is left in `target_layout`.
+    pub fn record_command_buffer(
+        &self,
+        logical_device: &ash::Device,
+        command_buffer: &vk::CommandBuffer,
+        target_image: &vk::Image,
+        target_layout: vk::ImageLayout,
+        constants: &FrameConstants,
+    ) {
+        // Wait for the previous frame's samples before adding to them
+        engine::image::transition_image_layout(
+            logical_device,
+            command_buffer,
+            &self.accumulation_image.image.handle,
+            vk::ImageLayout::GENERAL,
+            vk::ImageLayout::GENERAL,
+        );
+
+        let extent = self.storage_image.extent;
+        unsafe {
+            logical_device.cmd_bind_pipeline(
+                *command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline.handle,
+            );
+            logical_device.cmd_bind_descriptor_sets(
+                *command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline.layout,
+                0,
+                &[self.descriptor_set],
+                &[],
+            );
+            logical_device.cmd_push_constants(
+                *command_buffer,
+                self.pipeline.layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                constants.as_bytes(),
+            );
+            logical_device.cmd_dispatch(
+                *command_buffer,
+                extent.width.div_ceil(WORKGROUP_SIZE),
+                extent.height.div_ceil(WORKGROUP_SIZE),
+                1,
+            );
+        }
+
+        self.storage_image
+            .record_blit(logical_device, command_buffer, target_image, target_layout);
+    }
+
+    /// The running sum of the samples, which the storage image holds the
+    /// tonemapped average of.
+    pub fn accumulation_image(&self) -> &engine::image::StorageImage {
+        &self.accumulation_image
+    }
+}
+
+/// A BVH over the triangles of every instance in the layout of the storage
+/// buffers, with what the shader needs of each triangle in the same order.
+struct SceneBvh {
+    nodes: Vec<GpuBvhNode>,
+    triangles: Vec<GpuTriangle>,
+    triangle_materials: Vec<u32>,
+    /// The texture coordinates of the corners
+    triangle_uvs: Vec<[[f32; 2]; 3]>,
+}
+
+/// Builds a BVH over the triangles of every instance, moved into world
+/// space where they are at `time`.
+fn build_scene_bvh(
+    geometry: &SceneGeometry,
+    material_table: &MaterialTable,
+    time: f32,
+) -> SceneBvh {
+    let (triangles, uvs): (Vec<Triangle>, Vec<[[f32; 2]; 3]>) = geometry
+        .instances
+        .iter()
+        .flat_map(|instance| {
+            let transform = instance.transform_at(time);
+            // Keep the front faces on the outside of mirrored instances
+            let is_mirrored = transform.determinant() < 0.0;
+            let mesh = &geometry.meshes[instance.mesh];
+            mesh.transformed_triangles(&transform)
+                .zip(mesh.triangle_uvs())
+                .map(move |(triangle, [uv0, uv1, uv2])| {
+                    if is_mirrored {
+                        (
+                            Triangle::new(triangle.v0, triangle.v2, triangle.v1),
+                            [uv0, uv2, uv1],
+                        )
+                    } else {
+                        (triangle, [uv0, uv1, uv2])
+                    }
+                })
+        })
+        .unzip();
+    let materials: Vec<u32> = (0..geometry.instances.len())
+        .flat_map(|instance| material_table.instance_material_ids(geometry, instance))
+        .collect();
+
+    let bvh = Bvh::build(&triangles, &BvhBuildOptions::default());
+    let triangle_materials = bvh
+        .triangle_indices
+        .iter()
+        .map(|&index| materials[index as usize])
+        .collect();
+    let triangle_uvs = bvh
+        .triangle_indices
+        .iter()
+        .map(|&index| uvs[index as usize])
+        .collect();
+    let (nodes, triangles) = bvh.to_gpu();
+    SceneBvh {
+        nodes,
+        triangles,
+        triangle_materials,
+        triangle_uvs,
+    }
+}
+
+/// The storage buffers a `SceneBvh` is uploaded to, with room for any BVH
+/// over the same triangles.
+struct BvhBuffers {
+    node_buffer: StagedBuffer,
+    triangle_buffer: StagedBuffer,
+    /// The material of each triangle, in the order of the triangle buffer
+    triangle_material_buffer: StagedBuffer,
+    /// The texture coordinates of each triangle, in the same order
+    triangle_uv_buffer: StagedBuffer,
+}
+
+impl BvhBuffers {
+    fn new(allocator: &Rc<Allocator>, bvh: &SceneBvh) -> engine::Result<Self> {
+        fn staged_buffer<T: Copy>(
+            allocator: &Rc<Allocator>,
+            data: &[T],
+            capacity: usize,
+        ) -> engine::Result<StagedBuffer> {
+            StagedBuffer::new(
+                allocator,
+                data,
+                (capacity * std::mem::size_of::<T>()) as vk::DeviceSize,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+            )
+        }
+
+        // Every leaf holds a triangle, so a binary tree over them has fewer
+        // than twice as many nodes
+        let triangle_count = bvh.triangles.len();
+        Ok(Self {
+            node_buffer: staged_buffer(allocator, &bvh.nodes, 2 * triangle_count)?,
+            triangle_buffer: staged_buffer(allocator, &bvh.triangles, triangle_count)?,
+            triangle_material_buffer: staged_buffer(
+                allocator,
+                &bvh.triangle_materials,
+                triangle_count,
+            )?,
+            triangle_uv_buffer: staged_buffer(allocator, &bvh.triangle_uvs, triangle_count)?,
+        })
+    }
+
+    /// Records replacing the BVH with `bvh`, over the same triangles, like
+    /// `StagedBuffer::record_write`.
+    fn record_write(
+        &self,
+        logical_device: &ash::Device,
+        command_buffer: &vk::CommandBuffer,
+        frame: usize,
+        bvh: &SceneBvh,
+    ) {
+        self.node_buffer
+            .record_write(logical_device, command_buffer, frame, &bvh.nodes);
+        self.triangle_buffer
+            .record_write(logical_device, command_buffer, frame, &bvh.triangles);
+        self.triangle_material_buffer.record_write(
+            logical_device,
+            command_buffer,
+            frame,
+            &bvh.triangle_materials,
+        );
+        self.triangle_uv_buffer.record_write(
+            logical_device,
+            command_buffer,
+            frame,
+            &bvh.triangle_uvs,
+        );
+    }
+}
+
+fn create_descriptor_set_layout(
+    logical_device: &Rc<Device>,
+    texture_count: u32,
+) -> engine::Result<DescriptorSetLayout> {
+    let bindings = [
+        vk::DescriptorSetLayoutBinding {
+            binding: 0,
+            descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            ..Default::default()
+        },
+        vk::DescriptorSetLayoutBinding {
+            binding: 1,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            ..Default::default()
+        },
+        vk::DescriptorSetLayoutBinding {
+            binding: 2,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            ..Default::default()
+        },
+        vk::DescriptorSetLayoutBinding {
+            binding: 3,
+            descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            ..Default::default()
+        },
+        vk::DescriptorSetLayoutBinding {
+            binding: 4,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            ..Default::default()
+        },
+        vk::DescriptorSetLayoutBinding {
+            binding: 5,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            ..Default::default()
+        },
+        vk::DescriptorSetLayoutBinding {
+            binding: 6,
+            descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            descriptor_count: texture_count,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            ..Default::default()
+        },
+        vk::DescriptorSetLayoutBinding {
+            binding: 7,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            ..Default::default()
+        },
+        vk::DescriptorSetLayoutBinding {
+            binding: 8,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            ..Default::default()
+        },
+        vk::DescriptorSetLayoutBinding {
+            binding: 9,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            ..Default::default()
+        },
+    ];
+
+    let create_info = vk::DescriptorSetLayoutCreateInfo {
+        binding_count: bindings.len() as u32,
+        p_bindings: bindings.as_ptr(),
+        ..Default::default()
+    };
+
+    DescriptorSetLayout::new(logical_device, &create_info)
+}
+
+fn create_descriptor_pool(
+    logical_device: &Rc<Device>,
+    texture_count: u32,
+) -> engine::Result<DescriptorPool> {
+    let pool_sizes = [
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_IMAGE,
+            descriptor_count: 2,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 7,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            descriptor_count: texture_count,
+        },
+    ];
+
+    let create_info = vk::DescriptorPoolCreateInfo {
+        max_sets: 1,
+        pool_size_count: pool_sizes.len() as u32,
+        p_pool_sizes: pool_sizes.as_ptr(),
+        ..Default::default()
+    };
+
+    DescriptorPool::new(logical_device, &create_info)
+}
+
+fn create_compute_pipeline(
+    logical_device: &Rc<Device>,
+    descriptor_set_layout: &vk::DescriptorSetLayout,
+) -> engine::Result<Pipeline> {
+    let set_layouts = [*descriptor_set_layout];
+    let push_constant_ranges = [vk::PushConstantRange {
+        stage_flags: vk::ShaderStageFlags::COMPUTE,
+        offset: 0,
+        size: std::mem::size_of::<FrameConstants>() as u32,
+    }];
+    let pipeline_layout_info = vk::PipelineLayoutCreateInfo {
+        set_layout_count: set_layouts.len() as u32,
+        p_set_layouts: set_layouts.as_ptr(),
+        push_constant_range_count: push_constant_ranges.len() as u32,
+        p_push_constant_ranges: push_constant_ranges.as_ptr(),
+        ..Default::default()
+    };
+    let pipeline_layout =
+        unsafe { logical_device.create_pipeline_layout(&pipeline_layout_info, None) }
+            .context("create pipeline layout")?;
+
+    let shader_module =
+        engine::pipeline::create_shader_module(logical_device, COMPUTE_SHADER_CODE)?;
+
+    let pipeline_info = vk::ComputePipelineCreateInfo {
+        stage: vk::PipelineShaderStageCreateInfo {
+            stage: vk::ShaderStageFlags::COMPUTE,
+            module: shader_module,
+            p_name: engine::pipeline::SHADER_ENTRY_POINT.as_ptr(),
+            ..Default::default()
+        },
+        layout: pipeline_layout,
+        ..Default::default()
+    };
+
+    let pipeline = unsafe {
+        logical_device.create_compute_pipelines(vk::PipelineCache::null(), &[pipeline_info], None)
+    }
+    .context("create compute pipeline");
+
+    unsafe { logical_device.destroy_shader_module(shader_module, None) };
+
+    let pipeline = pipeline.inspect_err(|_| unsafe {
+        logical_device.destroy_pipeline_layout(pipeline_layout, None)
+    })?[0];
+    Ok(Pipeline::new(logical_device, pipeline_layout, pipeline))
+}
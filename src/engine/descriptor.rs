@@ -0,0 +1,78 @@
+use std::rc::Rc;
+
+use ash;
+use ash::vk;
+
+use crate::engine;
+use crate::engine::error::VkResultExt;
+use crate::engine::logical_device::Device;
+
+/// A descriptor set layout, destroyed when it is dropped.
+pub struct DescriptorSetLayout {
+    pub handle: vk::DescriptorSetLayout,
+    device: Rc<Device>,
+}
+
+impl DescriptorSetLayout {
+    pub fn new(
+        logical_device: &Rc<Device>,
+        create_info: &vk::DescriptorSetLayoutCreateInfo,
+    ) -> engine::Result<Self> {
+        let handle = unsafe { logical_device.create_descriptor_set_layout(create_info, None) }
+            .context("create descriptor set layout")?;
+
+        Ok(Self {
+            handle,
+            device: Rc::clone(logical_device),
+        })
+    }
+}
+
+impl Drop for DescriptorSetLayout {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_descriptor_set_layout(self.handle, None) };
+    }
+}
+
+/// A descriptor pool, destroyed with the sets allocated from it when it is
+/// dropped.
+pub struct DescriptorPool {
+    pub handle: vk::DescriptorPool,
+    device: Rc<Device>,
+}
+
+impl DescriptorPool {
+    pub fn new(
+        logical_device: &Rc<Device>,
+        create_info: &vk::DescriptorPoolCreateInfo,
+    ) -> engine::Result<Self> {
+        let handle = unsafe { logical_device.create_descriptor_pool(create_info, None) }
+            .context("create descriptor pool")?;
+
+        Ok(Self {
+            handle,
+            device: Rc::clone(logical_device),
+        })
+    }
+
+    /// Allocates a set with `layout`, which lives as long as the pool.
+    pub fn allocate_set(&self, layout: &DescriptorSetLayout) -> engine::Result<vk::DescriptorSet> {
+        let set_layouts = [layout.handle];
+        let allocate_info = vk::DescriptorSetAllocateInfo {
+            descriptor_pool: self.handle,
+            descriptor_set_count: set_layouts.len() as u32,
+            p_set_layouts: set_layouts.as_ptr(),
+            ..Default::default()
+        };
+
+        let sets = unsafe { self.device.allocate_descriptor_sets(&allocate_info) }
+            .context("allocate descriptor set")?;
+        Ok(sets[0])
+    }
+}
+
+impl Drop for DescriptorPool {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_descriptor_pool(self.handle, None) };
+    }
+}
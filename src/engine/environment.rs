@@ -0,0 +1,412 @@
+use std::f32::consts::{PI, TAU};
+use std::rc::Rc;
+
+use ash;
+use ash::vk;
+use glam::{Vec2, Vec3};
+
+use crate::engine;
+use crate::engine::allocator::Allocator;
+use crate::engine::buffer::Buffer;
+use crate::engine::material::NO_TEXTURE;
+use crate::scene::mesh::SceneGeometry;
+use crate::scene::texture::{Texture, TextureFormat};
+use crate::scene::EnvironmentMap;
+
+/// The texture coordinates of an equirectangular environment map turned by
+/// `rotation` radians about +Y, in `direction`. Rows go from +Y down to -Y,
+/// columns from +X towards +Z.
+pub fn direction_to_uv(direction: Vec3, rotation: f32) -> Vec2 {
+    let phi = direction.z.atan2(direction.x) - rotation;
+    Vec2::new(
+        (phi / TAU).rem_euclid(1.0),
+        direction.y.clamp(-1.0, 1.0).acos() / PI,
+    )
+}
+
+/// The unit direction `direction_to_uv` maps to `uv`.
+pub fn uv_to_direction(uv: Vec2, rotation: f32) -> Vec3 {
+    let phi = uv.x * TAU + rotation;
+    let theta = uv.y * PI;
+    Vec3::new(
+        theta.sin() * phi.cos(),
+        theta.cos(),
+        theta.sin() * phi.sin(),
+    )
+}
+
+/// Converts a density over texture coordinates into one over the solid angle
+/// around `direction`. Rows near the poles cover less solid angle.
+pub fn solid_angle_pdf(uv_pdf: f32, direction: Vec3) -> f32 {
+    let sin_theta = (1.0 - direction.y * direction.y).max(0.0).sqrt();
+    if sin_theta > 0.0 {
+        uv_pdf / (2.0 * PI * PI * sin_theta)
+    } else {
+        0.0
+    }
+}
+
+/// What each texel of an environment map is worth sampling: its luminance,
+/// times the solid angle its row covers. 8-bit texels are sRGB encoded.
+pub fn texel_weights(texture: &Texture) -> Vec<f32> {
+    let colors: Vec<[f32; 3]> = match texture.format {
+        TextureFormat::Rgba8 => texture
+            .pixels
+            .chunks_exact(4)
+            .map(|texel| [0, 1, 2].map(|channel| srgb_to_linear(texel[channel])))
+            .collect(),
+        TextureFormat::Rgba32Float => texture
+            .pixels
+            .chunks_exact(16)
+            .map(|texel| {
+                [0, 1, 2].map(|channel| {
+                    let bytes = &texel[4 * channel..4 * channel + 4];
+                    f32::from_ne_bytes(bytes.try_into().unwrap())
+                })
+            })
+            .collect(),
+    };
+
+    let width = texture.width as usize;
+    colors
+        .iter()
+        .enumerate()
+        .map(|(index, [r, g, b])| {
+            let theta = PI * ((index / width) as f32 + 0.5) / texture.height as f32;
+            let luminance = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+            if luminance.is_finite() {
+                luminance.max(0.0) * theta.sin()
+            } else {
+                0.0
+            }
+        })
+        .collect()
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// A piecewise constant distribution over the unit square, sampled by
+/// picking a row from the marginal CDF and then a column from that row's
+/// conditional CDF. The shaders sample it the same way, from the same CDFs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Distribution2D {
+    pub width: u32,
+    pub height: u32,
+    /// `height + 1` values, rising from 0 to 1
+    pub marginal_cdf: Vec<f32>,
+    /// `width + 1` values rising from 0 to 1 for each row, top row first
+    pub conditional_cdfs: Vec<f32>,
+}
+
+impl Distribution2D {
+    /// Builds the CDFs from non-negative `weights`, in rows of `width`, top
+    /// row first. Rows, or the whole distribution, without any weight are
+    /// sampled uniformly.
+    pub fn new(weights: &[f32], width: u32, height: u32) -> Self {
+        assert!(width > 0 && height > 0, "distribution has no texels");
+        assert_eq!(weights.len(), (width * height) as usize);
+
+        let mut conditional_cdfs = Vec::with_capacity(((width + 1) * height) as usize);
+        let mut row_sums = Vec::with_capacity(height as usize);
+        for row in weights.chunks_exact(width as usize) {
+            let (cdf, sum) = cumulative_distribution(row);
+            conditional_cdfs.extend(cdf);
+            row_sums.push(sum as f32);
+        }
+        let (marginal_cdf, _) = cumulative_distribution(&row_sums);
+
+        Self {
+            width,
+            height,
+            marginal_cdf,
+            conditional_cdfs,
+        }
+    }
+
+    fn conditional_cdf(&self, row: usize) -> &[f32] {
+        let stride = self.width as usize + 1;
+        &self.conditional_cdfs[row * stride..(row + 1) * stride]
+    }
+
+    /// Density over the unit square within texel (`column`, `row`).
+    fn texel_pdf(&self, column: usize, row: usize) -> f32 {
+        let conditional = self.conditional_cdf(row);
+        let row_probability = self.marginal_cdf[row + 1] - self.marginal_cdf[row];
+        let column_probability = conditional[column + 1] - conditional[column];
+        row_probability * column_probability * (self.width * self.height) as f32
+    }
+
+    /// Maps two uniform random numbers to a point in the unit square and its
+    /// density.
+    pub fn sample(&self, u: Vec2) -> (Vec2, f32) {
+        let (row, v_offset) = sample_cdf(&self.marginal_cdf, u.y);
+        let (column, u_offset) = sample_cdf(self.conditional_cdf(row), u.x);
+        let point = Vec2::new(
+            (column as f32 + u_offset) / self.width as f32,
+            (row as f32 + v_offset) / self.height as f32,
+        );
+        (point, self.texel_pdf(column, row))
+    }
+
+    /// The density with which `sample` picks `point`.
+    pub fn pdf(&self, point: Vec2) -> f32 {
+        let column = ((point.x * self.width as f32) as usize).min(self.width as usize - 1);
+        let row = ((point.y * self.height as f32) as usize).min(self.height as usize - 1);
+        self.texel_pdf(column, row)
+    }
+}
+
+/// The normalized running sum of `weights`, starting at 0, and their total.
+/// Zero weights give a uniform CDF.
+fn cumulative_distribution(weights: &[f32]) -> (Vec<f32>, f64) {
+    let mut sums = Vec::with_capacity(weights.len() + 1);
+    let mut sum = 0.0f64;
+    sums.push(sum);
+    for &weight in weights {
+        sum += weight.max(0.0) as f64;
+        sums.push(sum);
+    }
+
+    let count = weights.len() as f64;
+    let cdf = sums
+        .iter()
+        .enumerate()
+        .map(|(index, &partial)| {
+            if sum > 0.0 {
+                (partial / sum) as f32
+            } else {
+                (index as f64 / count) as f32
+            }
+        })
+        .collect();
+
+    (cdf, sum)
+}
+
+/// The interval of `cdf` that `u` falls into, skipping empty ones, and how
+/// far into it `u` lies.
+fn sample_cdf(cdf: &[f32], u: f32) -> (usize, f32) {
+    let index = cdf
+        .partition_point(|&value| value <= u)
+        .saturating_sub(1)
+        .min(cdf.len() - 2);
+    let width = cdf[index + 1] - cdf[index];
+    let offset = if width > 0.0 {
+        ((u - cdf[index]) / width).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    (index, offset)
+}
+
+/// The contents of the environment buffer, matching `Environment` in
+/// `shaders/environment.glsl`: the index of the map in the texture array, its
+/// intensity, rotation and size, followed by the CDFs of its
+/// `Distribution2D`. Without a map the texture is `NO_TEXTURE` and there are
+/// no CDFs.
+pub fn environment_buffer_data(environment: Option<(&EnvironmentMap, u32)>) -> Vec<u32> {
+    let Some((environment, texture)) = environment else {
+        return vec![NO_TEXTURE, 0, 0, 0, 0];
+    };
+
+    let map = &environment.texture;
+    let distribution = Distribution2D::new(&texel_weights(map), map.width, map.height);
+    let mut data = vec![
+        texture,
+        environment.intensity.to_bits(),
+        environment.rotation_degrees.to_radians().to_bits(),
+        distribution.width,
+        distribution.height,
+    ];
+    data.extend(
+        distribution
+            .marginal_cdf
+            .iter()
+            .map(|value| value.to_bits()),
+    );
+    data.extend(
+        distribution
+            .conditional_cdfs
+            .iter()
+            .map(|value| value.to_bits()),
+    );
+
+    data
+}
+
+/// The storage buffer with the environment map's parameters and sampling
+/// distribution, bound at the same binding in both backends.
+pub struct EnvironmentBuffer {
+    pub buffer: Buffer,
+}
+
+impl EnvironmentBuffer {
+    /// `texture` is where the texture array keeps the environment map.
+    pub fn new(
+        allocator: &Rc<Allocator>,
+        geometry: &SceneGeometry,
+        texture: Option<u32>,
+    ) -> engine::Result<Self> {
+        let data = environment_buffer_data(geometry.environment.as_ref().zip(texture));
+        let buffer = engine::buffer::create_buffer_with_data(
+            allocator,
+            &data,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+        )?;
+
+        Ok(Self { buffer })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::test_util::Lcg;
+    use crate::scene::texture::Sampler;
+
+    fn assert_is_cdf(cdf: &[f32]) {
+        assert_eq!(cdf[0], 0.0);
+        assert_eq!(*cdf.last().unwrap(), 1.0);
+        assert!(cdf.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", cdf);
+    }
+
+    #[test]
+    fn cdfs_rise_from_zero_to_one() {
+        let mut random = Lcg(7);
+        let mut weights: Vec<f32> = (0..6 * 5).map(|_| random.next_f32() * 10.0).collect();
+        // An all black row is sampled uniformly, but never picked
+        weights[12..18].fill(0.0);
+        let distribution = Distribution2D::new(&weights, 6, 5);
+
+        assert_eq!(distribution.marginal_cdf.len(), 6);
+        assert_eq!(distribution.conditional_cdfs.len(), 5 * 7);
+        assert_is_cdf(&distribution.marginal_cdf);
+        for row in 0..5 {
+            assert_is_cdf(distribution.conditional_cdf(row));
+        }
+        assert_eq!(distribution.marginal_cdf[2], distribution.marginal_cdf[3]);
+        let black_row = distribution.conditional_cdf(2);
+        for (index, &value) in black_row.iter().enumerate() {
+            assert!((value - index as f32 / 6.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn samples_follow_the_weights() {
+        let weights = [1.0, 2.0, 0.0, 5.0, 0.5, 0.0, 3.0, 1.5, 4.0, 2.0, 0.25, 0.75];
+        let total: f32 = weights.iter().sum();
+        let distribution = Distribution2D::new(&weights, 4, 3);
+
+        let sample_count = 200_000;
+        let mut counts = [0u32; 12];
+        let mut random = Lcg(42);
+        for _ in 0..sample_count {
+            let (point, pdf) = distribution.sample(random.next_vec2());
+            assert!((0.0..=1.0).contains(&point.x) && (0.0..=1.0).contains(&point.y));
+            assert!((pdf - distribution.pdf(point)).abs() < 1e-4 * pdf);
+            let column = ((point.x * 4.0) as usize).min(3);
+            let row = ((point.y * 3.0) as usize).min(2);
+            counts[row * 4 + column] += 1;
+        }
+
+        for (index, (&count, &weight)) in counts.iter().zip(&weights).enumerate() {
+            let frequency = count as f32 / sample_count as f32;
+            let probability = weight / total;
+            assert!(
+                (frequency - probability).abs() < 0.005,
+                "texel {} was picked {} of the time instead of {}",
+                index,
+                frequency,
+                probability
+            );
+            if weight == 0.0 {
+                assert_eq!(count, 0, "texel {} has no weight", index);
+            }
+        }
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        let mut random = Lcg(3);
+        let weights: Vec<f32> = (0..8 * 4).map(|_| random.next_f32()).collect();
+        let distribution = Distribution2D::new(&weights, 8, 4);
+
+        let mut integral = 0.0;
+        for row in 0..4 {
+            for column in 0..8 {
+                let center = Vec2::new((column as f32 + 0.5) / 8.0, (row as f32 + 0.5) / 4.0);
+                integral += distribution.pdf(center) / 32.0;
+            }
+        }
+        assert!((integral - 1.0).abs() < 1e-5, "{}", integral);
+    }
+
+    #[test]
+    fn finds_the_sun() {
+        let mut weights = vec![1.0; 16 * 8];
+        weights[2 * 16 + 11] = 10_000.0;
+        let distribution = Distribution2D::new(&weights, 16, 8);
+
+        let mut random = Lcg(1);
+        let hits = (0..10_000)
+            .filter(|_| {
+                let (point, _) = distribution.sample(random.next_vec2());
+                (point.x * 16.0) as usize == 11 && (point.y * 8.0) as usize == 2
+            })
+            .count();
+        assert!(hits > 9_000, "{}", hits);
+    }
+
+    #[test]
+    fn directions_round_trip() {
+        let mut random = Lcg(5);
+        for rotation in [0.0, 1.0, -2.5] {
+            for _ in 0..100 {
+                let uv = random.next_vec2();
+                let direction = uv_to_direction(uv, rotation);
+                assert!((direction.length() - 1.0).abs() < 1e-5);
+                let round_trip = direction_to_uv(direction, rotation);
+                let du = (round_trip.x - uv.x).abs();
+                assert!(du.min(1.0 - du) < 1e-4 && (round_trip.y - uv.y).abs() < 1e-4);
+            }
+        }
+        assert!(uv_to_direction(Vec2::new(0.0, 0.5), 0.0).abs_diff_eq(Vec3::X, 1e-6));
+        assert!(uv_to_direction(Vec2::new(0.25, 0.5), 0.0).abs_diff_eq(Vec3::Z, 1e-6));
+        assert!(uv_to_direction(Vec2::new(0.3, 0.0), 0.0).abs_diff_eq(Vec3::Y, 1e-6));
+    }
+
+    #[test]
+    fn uniform_maps_light_every_direction_alike() {
+        let (width, height) = (32, 16);
+        let texture = Texture {
+            name: None,
+            width,
+            height,
+            format: TextureFormat::Rgba32Float,
+            pixels: [0.5f32, 0.5, 0.5, 1.0]
+                .repeat((width * height) as usize)
+                .iter()
+                .flat_map(|value| value.to_ne_bytes())
+                .collect(),
+            sampler: Sampler::default(),
+        };
+        let distribution = Distribution2D::new(&texel_weights(&texture), width, height);
+
+        // The sine weighting cancels the distortion of the rows
+        let mut random = Lcg(9);
+        for _ in 0..100 {
+            let (point, pdf) = distribution.sample(random.next_vec2());
+            let row_center = Vec2::new(point.x, ((point.y * 16.0).floor() + 0.5) / 16.0);
+            let direction = uv_to_direction(row_center, 0.0);
+            let pdf = solid_angle_pdf(pdf, direction);
+            assert!((pdf * 4.0 * PI - 1.0).abs() < 5e-3, "{}", pdf * 4.0 * PI);
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use std::fmt;
+
+use ash::vk;
+
+use crate::engine::acceleration_structure::DescriptionError;
+use crate::engine::backend::Backend;
+use crate::engine::physical_device::DeviceRequest;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Why the engine failed, so that applications can report it instead of the
+/// process dying on the first driver error.
+#[derive(Debug)]
+pub enum Error {
+    /// A Vulkan call returned an error
+    Vulkan {
+        /// What was attempted, e.g. "create buffer"
+        operation: &'static str,
+        result: vk::Result,
+    },
+    /// An instance layer that was asked for isn't installed
+    MissingLayer(String),
+    /// The window is on a display server without a Vulkan surface extension
+    UnsupportedWindow(String),
+    /// The windowing system failed, e.g. to create the window
+    Window {
+        /// What was attempted, e.g. "create window"
+        operation: &'static str,
+        message: String,
+    },
+    /// `report` lists every device and why it was rejected
+    NoSuitableDevice {
+        report: String,
+    },
+    RequestedDeviceNotFound {
+        request: DeviceRequest,
+        report: String,
+    },
+    RequestedDeviceUnsuitable {
+        request: DeviceRequest,
+        report: String,
+    },
+    NoSuitableMemoryType {
+        type_filter: u32,
+        properties: vk::MemoryPropertyFlags,
+    },
+    /// The backend blits into the frames, which the surface doesn't allow
+    UnsupportedBackend(Backend),
+    InvalidShader(std::io::Error),
+    InvalidSceneDescription(DescriptionError),
+}
+
+impl Error {
+    /// What the user can do about the error, if there is a likely fix.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            Error::MissingLayer(_)
+            | Error::Vulkan {
+                result: vk::Result::ERROR_LAYER_NOT_PRESENT,
+                ..
+            } => Some(
+                "The Khronos validation layer is not installed. Install the Vulkan SDK or your \
+                 distribution's validation layers package, e.g. vulkan-validationlayers, or \
+                 disable validation.",
+            ),
+            Error::Vulkan {
+                result: vk::Result::ERROR_INCOMPATIBLE_DRIVER,
+                ..
+            } => Some(
+                "No Vulkan 1.2 driver was found. Update the graphics driver, or install a \
+                 software implementation such as Mesa's lavapipe.",
+            ),
+            Error::Vulkan {
+                operation: "create instance",
+                result: vk::Result::ERROR_INITIALIZATION_FAILED,
+            } => Some(
+                "The Vulkan loader found no working driver. Check that `vulkaninfo` lists a \
+                 device.",
+            ),
+            Error::Vulkan {
+                operation: "create instance",
+                result: vk::Result::ERROR_EXTENSION_NOT_PRESENT,
+            } => Some(
+                "The Vulkan driver can't present to this display. Headless rendering needs no \
+                 surface extensions.",
+            ),
+            Error::Vulkan {
+                result:
+                    vk::Result::ERROR_OUT_OF_DEVICE_MEMORY | vk::Result::ERROR_OUT_OF_HOST_MEMORY,
+                ..
+            } => Some("Lower the resolution or render a smaller scene."),
+            Error::Vulkan {
+                result: vk::Result::ERROR_DEVICE_LOST,
+                ..
+            } => Some(
+                "The driver reset the device, which often happens when a frame takes too long. \
+                 Take fewer samples per frame or fewer bounces.",
+            ),
+            Error::UnsupportedWindow(_) => {
+                Some("Run on Wayland, X11, Windows or macOS, or render headless.")
+            }
+            Error::NoSuitableDevice { .. } | Error::RequestedDeviceUnsuitable { .. } => Some(
+                "The compute backend works without ray tracing support. Listing the devices \
+                 shows why each one can't be used.",
+            ),
+            Error::RequestedDeviceNotFound { .. } => {
+                Some("Listing the devices shows their indices, names and UUIDs.")
+            }
+            Error::UnsupportedBackend(_) => Some("Use the rasterizer, or render headless."),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Vulkan { operation, result } => {
+                write!(f, "Failed to {}: {} ({:?})", operation, result, result)
+            }
+            Error::MissingLayer(layer) => write!(f, "The {} layer is not available", layer),
+            Error::UnsupportedWindow(window) => write!(f, "Can't create a surface for {}", window),
+            Error::Window { operation, message } => {
+                write!(f, "Failed to {}: {}", operation, message)
+            }
+            Error::NoSuitableDevice { report } => {
+                write!(f, "Could not find any suitable devices:\n{}", report)
+            }
+            Error::RequestedDeviceNotFound { request, report } => {
+                write!(
+                    f,
+                    "Could not find the requested {}. Devices:\n{}",
+                    request, report
+                )
+            }
+            Error::RequestedDeviceUnsuitable { request, report } => {
+                write!(f, "The requested {} is not suitable:\n{}", request, report)
+            }
+            Error::NoSuitableMemoryType {
+                type_filter,
+                properties,
+            } => write!(
+                f,
+                "Failed to find a memory type in {:#b} with {:?}",
+                type_filter, properties
+            ),
+            Error::UnsupportedBackend(backend) => write!(
+                f,
+                "The {:?} backend can't draw to this window, whose surface doesn't allow \
+                 transfers into its images",
+                backend
+            ),
+            Error::InvalidShader(error) => write!(f, "Failed to read SPIR-V: {}", error),
+            Error::InvalidSceneDescription(error) => {
+                write!(f, "Invalid acceleration structure description: {}", error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::InvalidShader(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// Wraps the error of a windowing system call, e.g.
+    /// `window.window_handle().map_err(Error::window("get window handle"))?`.
+    pub fn window<E: fmt::Display>(operation: &'static str) -> impl FnOnce(E) -> Self {
+        move |error| Error::Window {
+            operation,
+            message: error.to_string(),
+        }
+    }
+}
+
+impl From<DescriptionError> for Error {
+    fn from(error: DescriptionError) -> Self {
+        Error::InvalidSceneDescription(error)
+    }
+}
+
+/// Attaches the operation to the error of a Vulkan call, e.g.
+/// `create_buffer(..).context("create buffer")?`.
+pub trait VkResultExt<T> {
+    fn context(self, operation: &'static str) -> Result<T>;
+}
+
+impl<T> VkResultExt<T> for ash::prelude::VkResult<T> {
+    fn context(self, operation: &'static str) -> Result<T> {
+        self.map_err(|result| Error::Vulkan { operation, result })
+    }
+}
+
+/// Pipeline creation also returns the pipelines that were created.
+impl<T> VkResultExt<T> for std::result::Result<T, (T, vk::Result)> {
+    fn context(self, operation: &'static str) -> Result<T> {
+        self.map_err(|(_, result)| Error::Vulkan { operation, result })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_the_failed_operation() {
+        let result: ash::prelude::VkResult<()> = Err(vk::Result::ERROR_OUT_OF_DEVICE_MEMORY);
+        let error = result.context("allocate buffer memory").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to allocate buffer memory: A device memory allocation has failed \
+             (ERROR_OUT_OF_DEVICE_MEMORY)"
+        );
+        assert!(error.hint().is_some());
+    }
+
+    #[test]
+    fn names_the_failed_window_operation() {
+        let result: std::result::Result<(), &str> = Err("no display");
+        let error = result.map_err(Error::window("create window")).unwrap_err();
+        assert_eq!(error.to_string(), "Failed to create window: no display");
+    }
+
+    #[test]
+    fn hints_at_missing_validation_layers() {
+        let error = Error::Vulkan {
+            operation: "create instance",
+            result: vk::Result::ERROR_LAYER_NOT_PRESENT,
+        };
+        assert!(error.hint().unwrap().contains("validation layer"));
+        assert!(
+            Error::MissingLayer("VK_LAYER_KHRONOS_validation".to_string())
+                .hint()
+                .unwrap()
+                .contains("validation layer")
+        );
+    }
+}
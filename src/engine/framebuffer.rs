@@ -0,0 +1,51 @@
+use std::rc::Rc;
+
+use ash;
+use ash::vk;
+
+use crate::engine;
+use crate::engine::error::VkResultExt;
+use crate::engine::logical_device::Device;
+
+/// A framebuffer, destroyed when it is dropped.
+pub struct Framebuffer {
+    pub handle: vk::Framebuffer,
+    device: Rc<Device>,
+}
+
+impl Drop for Framebuffer {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_framebuffer(self.handle, None) };
+    }
+}
+
+/// Creates one framebuffer per image view, all sharing `render_pass`.
+pub fn create_framebuffers(
+    logical_device: &Rc<Device>,
+    render_pass: &vk::RenderPass,
+    image_views: &[vk::ImageView],
+    extent: vk::Extent2D,
+) -> engine::Result<Vec<Framebuffer>> {
+    image_views
+        .iter()
+        .map(|image_view| {
+            let attachments = [*image_view];
+            let create_info = vk::FramebufferCreateInfo {
+                render_pass: *render_pass,
+                attachment_count: attachments.len() as u32,
+                p_attachments: attachments.as_ptr(),
+                width: extent.width,
+                height: extent.height,
+                layers: 1,
+                ..Default::default()
+            };
+
+            let handle = unsafe { logical_device.create_framebuffer(&create_info, None) }
+                .context("create framebuffer")?;
+            Ok(Framebuffer {
+                handle,
+                device: Rc::clone(logical_device),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,4 @@
+/// The triangle `shaders/shader.vert` rasterizes, in the same coordinates, for
+/// the ray tracing backends to trace against.
+pub const TRIANGLE_VERTICES: [[f32; 3]; 3] = [[0.0, -0.5, 0.0], [0.5, 0.5, 0.0], [-0.5, 0.5, 0.0]];
+pub const TRIANGLE_INDICES: [u32; 3] = [0, 1, 2];
@@ -0,0 +1,515 @@
+use std::rc::Rc;
+
+use ash;
+use ash::vk;
+
+use crate::engine;
+use crate::engine::allocator::{
+    Allocation, AllocationInfo, Allocator, DedicatedResource, ResourceKind,
+};
+use crate::engine::buffer::Buffer;
+use crate::engine::error::VkResultExt;
+use crate::engine::logical_device::Device;
+
+/// An image together with its memory, both given back when it is dropped.
+pub struct Image {
+    pub handle: vk::Image,
+    pub allocation: Allocation,
+    allocator: Rc<Allocator>,
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        unsafe { self.allocator.device().destroy_image(self.handle, None) };
+        self.allocator.free(&self.allocation);
+    }
+}
+
+/// Creates an optimally tiled 2D color image. It gets memory of its own
+/// when the driver prefers that, as some do for large render targets.
+#[track_caller]
+pub fn create_image(
+    allocator: &Rc<Allocator>,
+    extent: vk::Extent2D,
+    mip_levels: u32,
+    format: vk::Format,
+    usage: vk::ImageUsageFlags,
+    properties: vk::MemoryPropertyFlags,
+) -> engine::Result<Image> {
+    let logical_device = allocator.device();
+    let create_info = vk::ImageCreateInfo {
+        image_type: vk::ImageType::TYPE_2D,
+        extent: vk::Extent3D {
+            width: extent.width,
+            height: extent.height,
+            depth: 1,
+        },
+        mip_levels,
+        array_layers: 1,
+        format,
+        tiling: vk::ImageTiling::OPTIMAL,
+        initial_layout: vk::ImageLayout::UNDEFINED,
+        usage,
+        sharing_mode: vk::SharingMode::EXCLUSIVE,
+        samples: vk::SampleCountFlags::TYPE_1,
+        ..Default::default()
+    };
+
+    let image =
+        unsafe { logical_device.create_image(&create_info, None) }.context("create image")?;
+
+    let mut dedicated_requirements = vk::MemoryDedicatedRequirements::default();
+    let mut memory_requirements = vk::MemoryRequirements2 {
+        p_next: &mut dedicated_requirements as *mut _ as *mut std::ffi::c_void,
+        ..Default::default()
+    };
+    let requirements_info = vk::ImageMemoryRequirementsInfo2 {
+        image,
+        ..Default::default()
+    };
+    unsafe {
+        logical_device.get_image_memory_requirements2(&requirements_info, &mut memory_requirements)
+    };
+    let is_dedicated = dedicated_requirements.prefers_dedicated_allocation == vk::TRUE
+        || dedicated_requirements.requires_dedicated_allocation == vk::TRUE;
+
+    let allocation_info = AllocationInfo {
+        requirements: memory_requirements.memory_requirements,
+        properties,
+        kind: ResourceKind::Optimal,
+        dedicated: is_dedicated.then_some(DedicatedResource::Image(image)),
+        ..Default::default()
+    };
+    let allocation = allocator
+        .allocate(&allocation_info)
+        .and_then(|allocation| {
+            unsafe {
+                logical_device.bind_image_memory(image, allocation.memory(), allocation.offset())
+            }
+            .context("bind image memory")
+            .inspect_err(|_| allocator.free(&allocation))
+            .map(|()| allocation)
+        })
+        .inspect_err(|_| unsafe { logical_device.destroy_image(image, None) })?;
+
+    Ok(Image {
+        handle: image,
+        allocation,
+        allocator: Rc::clone(allocator),
+    })
+}
+
+/// An image view, destroyed when dropped.
+pub struct ImageView {
+    pub handle: vk::ImageView,
+    device: Rc<Device>,
+}
+
+impl ImageView {
+    /// Creates a view of the first `mip_levels` mip levels of a color image.
+    pub fn new(
+        logical_device: &Rc<Device>,
+        image: &vk::Image,
+        format: vk::Format,
+        mip_levels: u32,
+    ) -> engine::Result<Self> {
+        Ok(Self {
+            handle: create_image_view(logical_device, image, format, mip_levels)?,
+            device: Rc::clone(logical_device),
+        })
+    }
+}
+
+impl Drop for ImageView {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_image_view(self.handle, None) };
+    }
+}
+
+/// Creates a view of the first `mip_levels` mip levels of a color image.
+pub fn create_image_view(
+    logical_device: &ash::Device,
+    image: &vk::Image,
+    format: vk::Format,
+    mip_levels: u32,
+) -> engine::Result<vk::ImageView> {
+    let create_info = vk::ImageViewCreateInfo {
+        image: *image,
+        view_type: vk::ImageViewType::TYPE_2D,
+        format,
+        components: vk::ComponentMapping::default(),
+        subresource_range: vk::ImageSubresourceRange {
+            level_count: mip_levels,
+            ..color_subresource_range()
+        },
+        ..Default::default()
+    };
+
+    unsafe { logical_device.create_image_view(&create_info, None) }.context("create image view")
+}
+
+pub fn color_subresource_range() -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 1,
+    }
+}
+
+/// Records a pipeline barrier moving a color image between layouts. The
+/// access masks and stages are derived from the layouts, which covers the
+/// transfer and attachment cases the engine uses.
+pub fn transition_image_layout(
+    logical_device: &ash::Device,
+    command_buffer: &vk::CommandBuffer,
+    image: &vk::Image,
+    old_layout: vk::ImageLayout,
+    new_layout: vk::ImageLayout,
+) {
+    transition_mip_levels(
+        logical_device,
+        command_buffer,
+        image,
+        0,
+        1,
+        old_layout,
+        new_layout,
+    );
+}
+
+/// Like `transition_image_layout`, for `level_count` mip levels starting at
+/// `base_mip_level`.
+pub fn transition_mip_levels(
+    logical_device: &ash::Device,
+    command_buffer: &vk::CommandBuffer,
+    image: &vk::Image,
+    base_mip_level: u32,
+    level_count: u32,
+    old_layout: vk::ImageLayout,
+    new_layout: vk::ImageLayout,
+) {
+    let (src_access_mask, src_stage) = layout_access(old_layout);
+    let (dst_access_mask, dst_stage) = layout_access(new_layout);
+
+    let barrier = vk::ImageMemoryBarrier {
+        old_layout,
+        new_layout,
+        src_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+        dst_queue_family_index: vk::QUEUE_FAMILY_IGNORED,
+        image: *image,
+        subresource_range: vk::ImageSubresourceRange {
+            base_mip_level,
+            level_count,
+            ..color_subresource_range()
+        },
+        src_access_mask,
+        dst_access_mask,
+        ..Default::default()
+    };
+
+    unsafe {
+        logical_device.cmd_pipeline_barrier(
+            *command_buffer,
+            src_stage,
+            dst_stage,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[barrier],
+        );
+    }
+}
+
+fn layout_access(layout: vk::ImageLayout) -> (vk::AccessFlags, vk::PipelineStageFlags) {
+    match layout {
+        vk::ImageLayout::UNDEFINED => (
+            vk::AccessFlags::empty(),
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+        ),
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL => (
+            vk::AccessFlags::TRANSFER_READ,
+            vk::PipelineStageFlags::TRANSFER,
+        ),
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL => (
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::PipelineStageFlags::TRANSFER,
+        ),
+        vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL => (
+            vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        ),
+        // Textures sampled by the ray tracing or compute shaders
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL => (
+            vk::AccessFlags::SHADER_READ,
+            vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR | vk::PipelineStageFlags::COMPUTE_SHADER,
+        ),
+        // Storage images written by the ray tracing or compute shaders
+        vk::ImageLayout::GENERAL => (
+            vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+            vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR | vk::PipelineStageFlags::COMPUTE_SHADER,
+        ),
+        vk::ImageLayout::PRESENT_SRC_KHR => (
+            vk::AccessFlags::empty(),
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+        ),
+        _ => (
+            vk::AccessFlags::MEMORY_READ | vk::AccessFlags::MEMORY_WRITE,
+            vk::PipelineStageFlags::ALL_COMMANDS,
+        ),
+    }
+}
+
+/// Size in bytes of one texel of the color formats the engine reads back.
+pub fn texel_size(format: vk::Format) -> u32 {
+    match format {
+        vk::Format::R32G32B32A32_SFLOAT => 16,
+        vk::Format::R16G16B16A16_SFLOAT => 8,
+        _ => 4,
+    }
+}
+
+/// Size in bytes of a color image of `format` and `extent` as tightly packed
+/// rows of texels.
+pub fn packed_size(format: vk::Format, extent: vk::Extent2D) -> vk::DeviceSize {
+    extent.width as vk::DeviceSize
+        * extent.height as vk::DeviceSize
+        * texel_size(format) as vk::DeviceSize
+}
+
+/// A host visible buffer that a color image of `format` and `extent` can be
+/// copied into, as by `record_copy_image_to_buffer`.
+pub fn create_read_back_buffer(
+    allocator: &Rc<Allocator>,
+    format: vk::Format,
+    extent: vk::Extent2D,
+) -> engine::Result<Buffer> {
+    engine::buffer::create_buffer_preferring(
+        allocator,
+        packed_size(format, extent),
+        vk::BufferUsageFlags::TRANSFER_DST,
+        vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        vk::MemoryPropertyFlags::HOST_CACHED,
+    )
+}
+
+/// Copies a color image into host memory as tightly packed rows of texels in
+/// `format`. The image is moved out of `layout` for the copy and returned to
+/// it afterwards.
+#[allow(clippy::too_many_arguments)]
+pub fn read_back_image(
+    logical_device: &ash::Device,
+    allocator: &Rc<Allocator>,
+    command_pool: &vk::CommandPool,
+    queue: &vk::Queue,
+    image: &vk::Image,
+    format: vk::Format,
+    extent: vk::Extent2D,
+    layout: vk::ImageLayout,
+) -> engine::Result<Vec<u8>> {
+    let staging_buffer = create_read_back_buffer(allocator, format, extent)?;
+
+    let command_buffer =
+        engine::commands::begin_single_time_commands(logical_device, command_pool)?;
+    record_copy_image_to_buffer(
+        logical_device,
+        &command_buffer,
+        image,
+        extent,
+        layout,
+        &staging_buffer.handle,
+    );
+    engine::commands::end_single_time_commands(
+        logical_device,
+        command_pool,
+        queue,
+        command_buffer,
+    )?;
+
+    Ok(staging_buffer.allocation.read(packed_size(format, extent)))
+}
+
+/// Records copying a color image into `buffer` as tightly packed rows of
+/// texels. The image is moved out of `layout` for the copy and returned to
+/// it afterwards.
+pub fn record_copy_image_to_buffer(
+    logical_device: &ash::Device,
+    command_buffer: &vk::CommandBuffer,
+    image: &vk::Image,
+    extent: vk::Extent2D,
+    layout: vk::ImageLayout,
+    buffer: &vk::Buffer,
+) {
+    let needs_transition = layout != vk::ImageLayout::TRANSFER_SRC_OPTIMAL;
+    if needs_transition {
+        transition_image_layout(
+            logical_device,
+            command_buffer,
+            image,
+            layout,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        );
+    }
+
+    let region = vk::BufferImageCopy {
+        buffer_offset: 0,
+        buffer_row_length: 0,
+        buffer_image_height: 0,
+        image_subresource: vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        },
+        image_offset: vk::Offset3D::default(),
+        image_extent: vk::Extent3D {
+            width: extent.width,
+            height: extent.height,
+            depth: 1,
+        },
+    };
+    unsafe {
+        logical_device.cmd_copy_image_to_buffer(
+            *command_buffer,
+            *image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            *buffer,
+            &[region],
+        );
+    }
+
+    if needs_transition {
+        transition_image_layout(
+            logical_device,
+            command_buffer,
+            image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            layout,
+        );
+    }
+}
+
+/// A device-local image written by the ray tracing or compute shaders and
+/// blitted to the frame being presented. It stays in `GENERAL` between
+/// frames.
+pub struct StorageImage {
+    pub image: Image,
+    pub image_view: ImageView,
+    pub format: vk::Format,
+    pub extent: vk::Extent2D,
+}
+
+impl StorageImage {
+    pub fn new(
+        logical_device: &Rc<Device>,
+        allocator: &Rc<Allocator>,
+        command_pool: &vk::CommandPool,
+        queue: &vk::Queue,
+        format: vk::Format,
+        extent: vk::Extent2D,
+    ) -> engine::Result<Self> {
+        let image = create_image(
+            allocator,
+            extent,
+            1,
+            format,
+            vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::TRANSFER_SRC,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )?;
+        let image_view = ImageView::new(logical_device, &image.handle, format, 1)?;
+
+        let command_buffer =
+            engine::commands::begin_single_time_commands(logical_device, command_pool)?;
+        transition_image_layout(
+            logical_device,
+            &command_buffer,
+            &image.handle,
+            vk::ImageLayout::UNDEFINED,
+            vk::ImageLayout::GENERAL,
+        );
+        engine::commands::end_single_time_commands(
+            logical_device,
+            command_pool,
+            queue,
+            command_buffer,
+        )?;
+
+        Ok(Self {
+            image,
+            image_view,
+            format,
+            extent,
+        })
+    }
+
+    /// Records a blit of the whole image into `target_image`, which must have
+    /// the same extent and is left in `target_layout`. The previous contents
+    /// of the target are discarded.
+    pub fn record_blit(
+        &self,
+        logical_device: &ash::Device,
+        command_buffer: &vk::CommandBuffer,
+        target_image: &vk::Image,
+        target_layout: vk::ImageLayout,
+    ) {
+        transition_image_layout(
+            logical_device,
+            command_buffer,
+            &self.image.handle,
+            vk::ImageLayout::GENERAL,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        );
+        transition_image_layout(
+            logical_device,
+            command_buffer,
+            target_image,
+            vk::ImageLayout::UNDEFINED,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        );
+
+        let subresource = vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let corner = vk::Offset3D {
+            x: self.extent.width as i32,
+            y: self.extent.height as i32,
+            z: 1,
+        };
+        let region = vk::ImageBlit {
+            src_subresource: subresource,
+            src_offsets: [vk::Offset3D::default(), corner],
+            dst_subresource: subresource,
+            dst_offsets: [vk::Offset3D::default(), corner],
+        };
+        unsafe {
+            logical_device.cmd_blit_image(
+                *command_buffer,
+                self.image.handle,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                *target_image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[region],
+                vk::Filter::NEAREST,
+            );
+        }
+
+        transition_image_layout(
+            logical_device,
+            command_buffer,
+            target_image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            target_layout,
+        );
+        transition_image_layout(
+            logical_device,
+            command_buffer,
+            &self.image.handle,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            vk::ImageLayout::GENERAL,
+        );
+    }
+}
@@ -1,20 +1,92 @@
+use winit::raw_window_handle::RawDisplayHandle;
+
 use ash;
 use ash::vk;
 
-use std::ffi::c_void;
+use std::ffi::{c_void, CStr};
+use std::ops::Deref;
 
+use crate::engine;
+use crate::engine::error::VkResultExt;
 use crate::utils;
 
-pub fn create_instance(entry: &ash::Entry, is_debug_enabled: bool) -> ash::Instance {
+/// The Vulkan instance and its debug messenger. Surfaces and devices hold an
+/// `Rc` of it, so it is destroyed after everything created from it.
+pub struct Instance {
+    instance: ash::Instance,
+    debug_utils_loader: ash::ext::debug_utils::Instance,
+    /// Null unless validation is enabled
+    debug_messenger: vk::DebugUtilsMessengerEXT,
+    /// Keeps the Vulkan library loaded
+    entry: ash::Entry,
+}
+
+impl Instance {
+    /// Pass the display the window will be opened on, or `None` when
+    /// rendering headless. With `is_debug_enabled` the validation layer is
+    /// enabled and its messages are printed.
+    pub fn new(
+        entry: ash::Entry,
+        is_debug_enabled: bool,
+        display_handle: Option<&RawDisplayHandle>,
+    ) -> engine::Result<Self> {
+        let instance = create_instance(&entry, is_debug_enabled, display_handle)?;
+        let (debug_utils_loader, debug_messenger) =
+            utils::debug::setup_debug_utils(is_debug_enabled, &entry, &instance)
+                .inspect_err(|_| unsafe { instance.destroy_instance(None) })?;
+
+        Ok(Self {
+            instance,
+            debug_utils_loader,
+            debug_messenger,
+            entry,
+        })
+    }
+
+    pub fn entry(&self) -> &ash::Entry {
+        &self.entry
+    }
+}
+
+impl Deref for Instance {
+    type Target = ash::Instance;
+
+    fn deref(&self) -> &ash::Instance {
+        &self.instance
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        unsafe {
+            if self.debug_messenger != vk::DebugUtilsMessengerEXT::null() {
+                self.debug_utils_loader
+                    .destroy_debug_utils_messenger(self.debug_messenger, None);
+            }
+            self.instance.destroy_instance(None);
+        }
+    }
+}
+
+/// Pass the display the window will be opened on, or `None` when rendering
+/// headless.
+pub fn create_instance(
+    entry: &ash::Entry,
+    is_debug_enabled: bool,
+    display_handle: Option<&RawDisplayHandle>,
+) -> engine::Result<ash::Instance> {
+    // Vulkan 1.2 for buffer device addresses and SPIR-V 1.4, which the ray
+    // tracing backend relies on
     let app_info = vk::ApplicationInfo {
-        api_version: vk::make_api_version(0, 1, 0, 0),
+        api_version: vk::make_api_version(0, 1, 2, 0),
         ..Default::default()
     };
 
-    let extensions = utils::platforms::get_required_extensions();
+    let extensions = utils::platforms::get_required_extensions(display_handle)?;
 
     let debug_create_info = utils::debug::populate_debug_messenger_create_info();
     let enabled_layer_names = utils::debug::get_required_layers(is_debug_enabled);
+    check_layer_support(entry, &enabled_layer_names)?;
 
     let create_info = vk::InstanceCreateInfo {
         p_application_info: &app_info,
@@ -26,9 +98,26 @@ pub fn create_instance(entry: &ash::Entry, is_debug_enabled: bool) -> ash::Insta
         ..Default::default()
     };
 
-    unsafe {
-        entry
-            .create_instance(&create_info, None)
-            .expect("Failed to create instance!")
+    unsafe { entry.create_instance(&create_info, None) }.context("create instance")
+}
+
+/// Reports the first missing layer by name, which the driver's
+/// `ERROR_LAYER_NOT_PRESENT` doesn't.
+fn check_layer_support(entry: &ash::Entry, layer_names: &[*const i8]) -> engine::Result<()> {
+    let available_layers = unsafe { entry.enumerate_instance_layer_properties() }
+        .context("enumerate instance layers")?;
+
+    for layer_name in layer_names.iter() {
+        let layer_name = unsafe { CStr::from_ptr(*layer_name) };
+        let is_available = available_layers
+            .iter()
+            .any(|layer| layer.layer_name_as_c_str() == Ok(layer_name));
+        if !is_available {
+            return Err(engine::Error::MissingLayer(
+                layer_name.to_string_lossy().into_owned(),
+            ));
+        }
     }
+
+    Ok(())
 }
@@ -0,0 +1,498 @@
+use std::f32::consts::PI;
+use std::rc::Rc;
+
+use ash;
+use ash::vk;
+use glam::Vec3;
+
+use crate::engine;
+use crate::engine::allocator::Allocator;
+use crate::engine::buffer::StagedBuffer;
+use crate::engine::bvh::Aabb;
+use crate::engine::material::MaterialTable;
+use crate::scene::mesh::SceneGeometry;
+use crate::scene::Light;
+
+// Values of `GpuLight::kind`, matching the constants in `shaders/lights.glsl`
+pub const LIGHT_POINT: u32 = 0;
+pub const LIGHT_SPOT: u32 = 1;
+pub const LIGHT_DIRECTIONAL: u32 = 2;
+pub const LIGHT_TRIANGLE: u32 = 3;
+
+/// A light as laid out in the light storage buffer, matching `Light` in
+/// `shaders/lights.glsl`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GpuLight {
+    /// Where point and spot lights are, or the first corner of a triangle
+    pub position: [f32; 3],
+    pub kind: u32,
+    /// Where spot lights point and directional light travels, or the edge
+    /// from the first corner of a triangle to the second
+    pub direction: [f32; 3],
+    /// Cosine of the angle inside which spot lights are at full intensity
+    pub cos_inner: f32,
+    /// The edge from the first corner of a triangle to the third
+    pub edge: [f32; 3],
+    /// Cosine of the angle outside which spot lights are dark
+    pub cos_outer: f32,
+    /// Intensity of point and spot lights, illuminance of directional lights
+    /// or radiance of triangles, times their color
+    pub color: [f32; 3],
+    /// Of picking the light, in proportion to its power
+    pub probability: f32,
+    /// Where `AliasTable::probabilities` and `AliasTable::aliases` go
+    pub alias_probability: f32,
+    pub alias: u32,
+    pub _padding: [u32; 2],
+}
+
+impl GpuLight {
+    const DEFAULT: Self = Self {
+        position: [0.0; 3],
+        kind: LIGHT_POINT,
+        direction: [0.0; 3],
+        cos_inner: 0.0,
+        edge: [0.0; 3],
+        cos_outer: 0.0,
+        color: [0.0; 3],
+        probability: 0.0,
+        alias_probability: 1.0,
+        alias: 0,
+        _padding: [0; 2],
+    };
+}
+
+pub fn luminance(color: Vec3) -> f32 {
+    color.dot(Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+/// Walker's alias method, which picks index `i` with probability
+/// `weights[i] / sum` in constant time: scale a uniform number by the length
+/// of the table, keep the entry it falls into with that entry's probability
+/// and take its alias otherwise.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AliasTable {
+    pub probabilities: Vec<f32>,
+    pub aliases: Vec<u32>,
+}
+
+impl AliasTable {
+    /// Vose's construction. Negative weights count as zero, and if no weight
+    /// is left every index is equally likely.
+    pub fn new(weights: &[f32]) -> Self {
+        let count = weights.len();
+        let sum: f64 = weights.iter().map(|&weight| weight.max(0.0) as f64).sum();
+        let mut scaled: Vec<f64> = weights
+            .iter()
+            .map(|&weight| {
+                if sum > 0.0 {
+                    weight.max(0.0) as f64 * count as f64 / sum
+                } else {
+                    1.0
+                }
+            })
+            .collect();
+
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) =
+            (0..count).partition(|&index| scaled[index] < 1.0);
+        let mut probabilities = vec![1.0; count];
+        let mut aliases: Vec<u32> = (0..count as u32).collect();
+        while let (Some(&less), Some(&more)) = (small.last(), large.last()) {
+            small.pop();
+            probabilities[less] = scaled[less] as f32;
+            aliases[less] = more as u32;
+            // The entry gives what `less` lacks
+            scaled[more] -= 1.0 - scaled[less];
+            if scaled[more] < 1.0 {
+                large.pop();
+                small.push(more);
+            }
+        }
+        // Whatever is left is within rounding of 1 and keeps itself
+
+        Self {
+            probabilities,
+            aliases,
+        }
+    }
+
+    /// Picks an index with one uniform random number in [0, 1).
+    pub fn sample(&self, u: f32) -> usize {
+        let scaled = u * self.probabilities.len() as f32;
+        let index = (scaled as usize).min(self.probabilities.len() - 1);
+        if scaled - (index as f32) < self.probabilities[index] {
+            index
+        } else {
+            self.aliases[index] as usize
+        }
+    }
+}
+
+/// The lights of a scene as uploaded to the GPU: the point, spot and
+/// directional lights followed by every triangle with an emissive material,
+/// each picked in proportion to its power. Moving instances are placed where
+/// they are at the time the list is made for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LightList {
+    pub lights: Vec<GpuLight>,
+    /// The power of every light together, which `probability` divides
+    pub total_power: f32,
+}
+
+impl LightList {
+    pub fn new(geometry: &SceneGeometry, material_table: &MaterialTable, time: f32) -> Self {
+        let mut lights = Vec::new();
+        let mut powers = Vec::new();
+
+        let scene_radius = scene_bounds(geometry, time)
+            .map_or(1.0, |bounds| 0.5 * (bounds.max - bounds.min).length());
+        for light in &geometry.lights {
+            let (light, power) = match *light {
+                Light::Point {
+                    position,
+                    color,
+                    intensity,
+                } => {
+                    let color = Vec3::from_array(color) * intensity;
+                    let light = GpuLight {
+                        position,
+                        kind: LIGHT_POINT,
+                        color: color.to_array(),
+                        ..GpuLight::DEFAULT
+                    };
+                    (light, 4.0 * PI * luminance(color))
+                }
+                Light::Spot {
+                    position,
+                    direction,
+                    color,
+                    intensity,
+                    inner_angle_degrees,
+                    outer_angle_degrees,
+                } => {
+                    let color = Vec3::from_array(color) * intensity;
+                    let cos_inner = inner_angle_degrees.to_radians().cos();
+                    let cos_outer = outer_angle_degrees.to_radians().cos();
+                    let light = GpuLight {
+                        position,
+                        kind: LIGHT_SPOT,
+                        direction: Vec3::from_array(direction).normalize_or_zero().to_array(),
+                        cos_inner,
+                        cos_outer,
+                        color: color.to_array(),
+                        ..GpuLight::DEFAULT
+                    };
+                    // The solid angle of a cone halfway between both angles
+                    let solid_angle = 2.0 * PI * (1.0 - 0.5 * (cos_inner + cos_outer));
+                    (light, solid_angle * luminance(color))
+                }
+                Light::Directional {
+                    direction,
+                    color,
+                    intensity,
+                } => {
+                    let color = Vec3::from_array(color) * intensity;
+                    let light = GpuLight {
+                        kind: LIGHT_DIRECTIONAL,
+                        direction: Vec3::from_array(direction).normalize_or_zero().to_array(),
+                        color: color.to_array(),
+                        ..GpuLight::DEFAULT
+                    };
+                    // What falls on a disk covering the scene
+                    let power = PI * scene_radius * scene_radius * luminance(color);
+                    (light, power)
+                }
+                // Loaded as emissive meshes, gathered below
+                Light::Area { .. } => continue,
+            };
+            lights.push(light);
+            powers.push(power);
+        }
+
+        for (index, instance) in geometry.instances.iter().enumerate() {
+            let mesh = &geometry.meshes[instance.mesh];
+            let triangles = mesh
+                .transformed_triangles(&instance.transform_at(time))
+                .zip(material_table.instance_material_ids(geometry, index));
+            for (triangle, material_id) in triangles {
+                let emission =
+                    Vec3::from_array(material_table.materials[material_id as usize].emission);
+                let edge1 = triangle.v1 - triangle.v0;
+                let edge2 = triangle.v2 - triangle.v0;
+                let area = 0.5 * edge1.cross(edge2).length();
+                // Emissive surfaces glow on both sides
+                let power = 2.0 * PI * area * luminance(emission);
+                if power > 0.0 {
+                    lights.push(GpuLight {
+                        position: triangle.v0.to_array(),
+                        kind: LIGHT_TRIANGLE,
+                        direction: edge1.to_array(),
+                        edge: edge2.to_array(),
+                        color: emission.to_array(),
+                        ..GpuLight::DEFAULT
+                    });
+                    powers.push(power);
+                }
+            }
+        }
+
+        let total_power: f32 = powers.iter().sum();
+        let alias_table = AliasTable::new(&powers);
+        for (index, light) in lights.iter_mut().enumerate() {
+            light.probability = if total_power > 0.0 {
+                powers[index] / total_power
+            } else {
+                1.0 / powers.len() as f32
+            };
+            light.alias_probability = alias_table.probabilities[index];
+            light.alias = alias_table.aliases[index];
+        }
+
+        Self {
+            lights,
+            total_power,
+        }
+    }
+
+    /// The most lights a list of `geometry` can have at any time: moving
+    /// instances only change the triangles, not which are emissive.
+    pub fn max_light_count(geometry: &SceneGeometry, material_table: &MaterialTable) -> usize {
+        let analytic_lights = geometry
+            .lights
+            .iter()
+            .filter(|light| !matches!(light, Light::Area { .. }))
+            .count();
+        let emissive_triangles: usize = (0..geometry.instances.len())
+            .map(|index| {
+                material_table
+                    .instance_material_ids(geometry, index)
+                    .filter(|&material_id| {
+                        let emission = material_table.materials[material_id as usize].emission;
+                        luminance(Vec3::from_array(emission)) > 0.0
+                    })
+                    .count()
+            })
+            .sum();
+
+        analytic_lights + emissive_triangles
+    }
+
+    /// Size of the light buffer holding `light_count` lights.
+    pub fn buffer_size(light_count: usize) -> vk::DeviceSize {
+        (16 + light_count * std::mem::size_of::<GpuLight>()) as vk::DeviceSize
+    }
+
+    /// The contents of the light buffer, matching `Lights` in
+    /// `shaders/lights.glsl`: the number of lights and their total power,
+    /// padded to 16 bytes, followed by the lights.
+    pub fn buffer_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(16 + std::mem::size_of_val(&self.lights[..]));
+        data.extend((self.lights.len() as u32).to_ne_bytes());
+        data.extend(self.total_power.to_ne_bytes());
+        data.extend([0; 8]);
+        data.extend(unsafe {
+            std::slice::from_raw_parts(
+                self.lights.as_ptr() as *const u8,
+                std::mem::size_of_val(&self.lights[..]),
+            )
+        });
+
+        data
+    }
+}
+
+/// The bounds of every instance's triangles at `time`, `None` without any.
+fn scene_bounds(geometry: &SceneGeometry, time: f32) -> Option<Aabb> {
+    geometry
+        .instances
+        .iter()
+        .flat_map(|instance| {
+            geometry.meshes[instance.mesh].transformed_triangles(&instance.transform_at(time))
+        })
+        .map(|triangle| triangle.bounds())
+        .reduce(|bounds, other| bounds.union(&other))
+}
+
+/// The storage buffer with the `LightList`, bound at the same binding in both
+/// backends. It has room for the lights at any time, so that they can follow
+/// moving instances.
+pub struct LightBuffer {
+    pub buffer: StagedBuffer,
+}
+
+impl LightBuffer {
+    pub fn new(
+        allocator: &Rc<Allocator>,
+        geometry: &SceneGeometry,
+        material_table: &MaterialTable,
+        time: f32,
+    ) -> engine::Result<Self> {
+        let light_list = LightList::new(geometry, material_table, time);
+        let capacity = LightList::buffer_size(LightList::max_light_count(geometry, material_table));
+        let buffer = StagedBuffer::new(
+            allocator,
+            &light_list.buffer_data(),
+            capacity,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+        )?;
+
+        Ok(Self { buffer })
+    }
+
+    /// Records replacing the lights with those at `time`, through the
+    /// staging buffer of `frame`, like `StagedBuffer::record_write`.
+    pub fn record_set_time(
+        &self,
+        logical_device: &ash::Device,
+        command_buffer: &vk::CommandBuffer,
+        frame: usize,
+        geometry: &SceneGeometry,
+        material_table: &MaterialTable,
+        time: f32,
+    ) {
+        let light_list = LightList::new(geometry, material_table, time);
+        self.buffer.record_write(
+            logical_device,
+            command_buffer,
+            frame,
+            &light_list.buffer_data(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Mat4;
+
+    use super::*;
+    use crate::engine::test_util::Lcg;
+    use crate::scene::mesh::{MeshInstance, TriangleMesh};
+    use crate::scene::{Material, MaterialModel};
+
+    /// The probability of the table picking each index, read off its entries.
+    fn table_distribution(table: &AliasTable) -> Vec<f32> {
+        let count = table.probabilities.len() as f32;
+        let mut distribution: Vec<f32> = table.probabilities.iter().map(|&p| p / count).collect();
+        for (&probability, &alias) in table.probabilities.iter().zip(&table.aliases) {
+            distribution[alias as usize] += (1.0 - probability) / count;
+        }
+        distribution
+    }
+
+    #[test]
+    fn gpu_layout_matches_shader() {
+        assert_eq!(std::mem::size_of::<GpuLight>(), 80);
+    }
+
+    #[test]
+    fn alias_table_reproduces_the_weights() {
+        let mut random = Lcg(11);
+        let mut weights: Vec<f32> = (0..37).map(|_| random.next_f32() * 100.0).collect();
+        weights[3] = 0.0;
+        weights[20] = 5000.0;
+        let sum: f32 = weights.iter().sum();
+
+        let table = AliasTable::new(&weights);
+        for (index, probability) in table_distribution(&table).into_iter().enumerate() {
+            assert!(
+                (probability - weights[index] / sum).abs() < 1e-5,
+                "entry {} has probability {} instead of {}",
+                index,
+                probability,
+                weights[index] / sum
+            );
+        }
+        assert!(table
+            .probabilities
+            .iter()
+            .all(|probability| (0.0..=1.0).contains(probability)));
+    }
+
+    #[test]
+    fn alias_table_samples_follow_the_weights() {
+        let weights = [1.0, 0.0, 3.0, 6.0];
+        let table = AliasTable::new(&weights);
+
+        let sample_count = 100_000;
+        let mut counts = [0u32; 4];
+        let mut random = Lcg(5);
+        for _ in 0..sample_count {
+            counts[table.sample(random.next_f32())] += 1;
+        }
+        for (count, weight) in counts.into_iter().zip(weights) {
+            let frequency = count as f32 / sample_count as f32;
+            assert!((frequency - weight / 10.0).abs() < 0.005, "{:?}", counts);
+        }
+        assert_eq!(counts[1], 0);
+    }
+
+    #[test]
+    fn alias_table_without_weight_is_uniform() {
+        let table = AliasTable::new(&[0.0, 0.0, 0.0]);
+        for probability in table_distribution(&table) {
+            assert!((probability - 1.0 / 3.0).abs() < 1e-6);
+        }
+        assert_eq!(AliasTable::new(&[2.0]).sample(0.99), 0);
+    }
+
+    #[test]
+    fn gathers_analytic_lights_and_emissive_triangles() {
+        // A unit square glowing with radiance 2, and a dark triangle
+        let mut mesh = TriangleMesh::parallelogram(Vec3::ZERO, Vec3::X, Vec3::Y);
+        mesh.material_ids = vec![0; 2];
+        let geometry = SceneGeometry {
+            meshes: vec![
+                mesh,
+                TriangleMesh::from_primitive(crate::scene::Primitive::Triangle),
+            ],
+            instances: vec![
+                MeshInstance {
+                    mesh: 0,
+                    transform: Mat4::from_translation(Vec3::new(0.0, 0.0, 5.0)),
+                    end_transform: None,
+                    material: None,
+                },
+                MeshInstance {
+                    mesh: 1,
+                    transform: Mat4::IDENTITY,
+                    end_transform: None,
+                    material: None,
+                },
+            ],
+            materials: vec![Material {
+                name: "glow".to_string(),
+                model: MaterialModel::Emissive {
+                    color: [1.0; 3],
+                    strength: 2.0,
+                },
+            }],
+            textures: Vec::new(),
+            environment: None,
+            lights: vec![Light::Point {
+                position: [0.0, 1.0, 0.0],
+                color: [1.0; 3],
+                intensity: 3.0,
+            }],
+        };
+
+        let material_table = MaterialTable::new(&geometry);
+        let light_list = LightList::new(&geometry, &material_table, 0.0);
+        let kinds: Vec<u32> = light_list.lights.iter().map(|light| light.kind).collect();
+        assert_eq!(kinds, vec![LIGHT_POINT, LIGHT_TRIANGLE, LIGHT_TRIANGLE]);
+
+        // The point light shines 4π * 3, each half of the square 2π * 0.5 * 2
+        let expected_total = 12.0 * PI + 4.0 * PI;
+        assert!((light_list.total_power - expected_total).abs() < 1e-3);
+        assert!((light_list.lights[0].probability - 0.75).abs() < 1e-6);
+        assert!((light_list.lights[1].probability - 0.125).abs() < 1e-6);
+        assert_eq!(light_list.lights[1].position, [0.0, 0.0, 5.0]);
+        assert_eq!(light_list.lights[1].color, [2.0; 3]);
+
+        let data = light_list.buffer_data();
+        assert_eq!(data.len(), 16 + 3 * 80);
+        assert_eq!(data[..4], 3u32.to_ne_bytes());
+        assert_eq!(LightList::max_light_count(&geometry, &material_table), 3);
+        assert_eq!(LightList::buffer_size(3), data.len() as vk::DeviceSize);
+    }
+}
@@ -1,25 +1,61 @@
+use std::ffi::c_void;
+use std::ops::Deref;
+use std::rc::Rc;
+
 use ash;
 use ash::vk;
 
 use crate::engine;
+use crate::engine::error::VkResultExt;
+use crate::engine::instance::Instance;
 use crate::utils::required;
 
+/// The logical device. Everything created from it holds an `Rc` of it, so it
+/// is destroyed last, and it holds the instance it was created from.
+pub struct Device {
+    device: ash::Device,
+    instance: Rc<Instance>,
+}
+
+impl Device {
+    pub fn instance(&self) -> &Rc<Instance> {
+        &self.instance
+    }
+}
+
+impl Deref for Device {
+    type Target = ash::Device;
+
+    fn deref(&self) -> &ash::Device {
+        &self.device
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_device(None) };
+    }
+}
+
+/// Creates the logical device. Pass `None` for `surface` when rendering
+/// headless, in which case only a graphics queue is created. The descriptor
+/// indexing features the texture array needs are always enabled, see
+/// `physical_device::supports_texture_arrays`. With `is_ray_tracing_enabled`
+/// the ray tracing extensions and features are enabled as well.
 pub fn create_logical_device(
     device: &vk::PhysicalDevice,
-    instance: &ash::Instance,
-    surface: &vk::SurfaceKHR,
-    surface_loader: &ash::khr::surface::Instance,
-) -> ash::Device {
-    let indices =
-        engine::queue_families::find_queue_families(device, instance, surface, surface_loader);
+    instance: &Rc<Instance>,
+    surface: Option<(&vk::SurfaceKHR, &ash::khr::surface::Instance)>,
+    is_ray_tracing_enabled: bool,
+) -> engine::Result<Device> {
+    let is_headless = surface.is_none();
+    let indices = engine::queue_families::find_queue_families(device, instance, surface);
     let queue_priority = 1.0_f32;
-    let queues = [
-        indices.graphics_family.unwrap(),
-        indices.present_family.unwrap(),
-    ];
 
+    // The graphics and present families are often the same, and a family may
+    // only appear once in the create infos
     let mut queue_create_infos = vec![];
-    for queue in queues.iter() {
+    for queue in indices.unique_families().iter() {
         queue_create_infos.push(vk::DeviceQueueCreateInfo {
             queue_family_index: *queue,
             queue_count: 1,
@@ -32,7 +68,35 @@ pub fn create_logical_device(
         ..Default::default()
     };
 
-    let required_extensions = required::get_required_extensions();
+    let required_extensions =
+        required::get_required_extensions(is_headless, is_ray_tracing_enabled);
+
+    let mut ray_tracing_pipeline_features = vk::PhysicalDeviceRayTracingPipelineFeaturesKHR {
+        ray_tracing_pipeline: vk::TRUE,
+        ..Default::default()
+    };
+    let mut acceleration_structure_features = vk::PhysicalDeviceAccelerationStructureFeaturesKHR {
+        acceleration_structure: vk::TRUE,
+        p_next: &mut ray_tracing_pipeline_features as *mut _ as *mut c_void,
+        ..Default::default()
+    };
+    let mut buffer_device_address_features = vk::PhysicalDeviceBufferDeviceAddressFeatures {
+        buffer_device_address: vk::TRUE,
+        p_next: &mut acceleration_structure_features as *mut _ as *mut c_void,
+        ..Default::default()
+    };
+    // Shaders index the texture array with material data, which differs
+    // between invocations
+    let descriptor_indexing_features = vk::PhysicalDeviceDescriptorIndexingFeatures {
+        shader_sampled_image_array_non_uniform_indexing: vk::TRUE,
+        runtime_descriptor_array: vk::TRUE,
+        p_next: if is_ray_tracing_enabled {
+            &mut buffer_device_address_features as *mut _ as *mut c_void
+        } else {
+            std::ptr::null_mut()
+        },
+        ..Default::default()
+    };
 
     // todo! update with is_debug_enabled
     // let layers = debug::get_required_layers(true);
@@ -44,8 +108,15 @@ pub fn create_logical_device(
         p_enabled_features: &device_features,
         enabled_extension_count: required_extensions.len() as u32,
         pp_enabled_extension_names: required_extensions.as_ptr(),
+        p_next: &descriptor_indexing_features as *const _ as *const c_void,
         ..Default::default()
     };
 
-    unsafe { instance.create_device(*device, &create_info, None).unwrap() }
+    let device = unsafe { instance.create_device(*device, &create_info, None) }
+        .context("create logical device")?;
+
+    Ok(Device {
+        device,
+        instance: Rc::clone(instance),
+    })
 }
@@ -0,0 +1,251 @@
+use crate::scene::mesh::{SceneGeometry, NO_MATERIAL};
+use crate::scene::MaterialModel;
+
+// Values of `GpuMaterial::kind`, matching the constants in `shaders/bsdf.glsl`
+pub const KIND_LAMBERT: u32 = 0;
+pub const KIND_CONDUCTOR: u32 = 1;
+pub const KIND_DIELECTRIC: u32 = 2;
+pub const KIND_EMISSIVE: u32 = 3;
+pub const KIND_METALLIC_ROUGHNESS: u32 = 4;
+
+/// Texture slots of a material that has no texture there.
+pub const NO_TEXTURE: u32 = u32::MAX;
+
+/// A material as laid out in the material storage buffer, matching
+/// `Material` in `shaders/bsdf.glsl`. `engine::bsdf` evaluates it on the CPU.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GpuMaterial {
+    /// Lambertian albedo, conductor reflectance at normal incidence,
+    /// dielectric transmittance or metallic-roughness base color
+    pub color: [f32; 3],
+    pub kind: u32,
+    /// Radiance leaving the surface in every direction
+    pub emission: [f32; 3],
+    pub roughness: f32,
+    pub ior: f32,
+    pub metallic: f32,
+    /// Indices into `SceneGeometry::textures`, which the texture array
+    /// keeps, or `NO_TEXTURE`
+    pub base_color_texture: u32,
+    pub metallic_roughness_texture: u32,
+    pub normal_texture: u32,
+    pub emissive_texture: u32,
+    pub _padding: [f32; 2],
+}
+
+impl GpuMaterial {
+    /// The white Lambertian surface of objects without a material.
+    pub const DEFAULT: Self = Self {
+        color: [0.8; 3],
+        kind: KIND_LAMBERT,
+        emission: [0.0; 3],
+        roughness: 1.0,
+        ior: 1.5,
+        metallic: 0.0,
+        base_color_texture: NO_TEXTURE,
+        metallic_roughness_texture: NO_TEXTURE,
+        normal_texture: NO_TEXTURE,
+        emissive_texture: NO_TEXTURE,
+        _padding: [0.0; 2],
+    };
+
+    pub fn new(model: &MaterialModel) -> Self {
+        match *model {
+            MaterialModel::Lambert { albedo } => Self {
+                color: albedo,
+                ..Self::DEFAULT
+            },
+            MaterialModel::Conductor { color, roughness } => Self {
+                color,
+                kind: KIND_CONDUCTOR,
+                roughness,
+                ..Self::DEFAULT
+            },
+            MaterialModel::Dielectric { ior, roughness } => Self {
+                color: [1.0; 3],
+                kind: KIND_DIELECTRIC,
+                roughness,
+                ior,
+                ..Self::DEFAULT
+            },
+            MaterialModel::Emissive { color, strength } => Self {
+                color: [0.0; 3],
+                kind: KIND_EMISSIVE,
+                emission: color.map(|channel| channel * strength),
+                ..Self::DEFAULT
+            },
+            MaterialModel::MetallicRoughness {
+                base_color,
+                metallic,
+                roughness,
+                emissive,
+                base_color_texture,
+                metallic_roughness_texture,
+                normal_texture,
+                emissive_texture,
+            } => {
+                let texture_index =
+                    |texture: Option<usize>| texture.map_or(NO_TEXTURE, |index| index as u32);
+                Self {
+                    color: base_color,
+                    kind: KIND_METALLIC_ROUGHNESS,
+                    emission: emissive,
+                    roughness,
+                    metallic,
+                    base_color_texture: texture_index(base_color_texture),
+                    metallic_roughness_texture: texture_index(metallic_roughness_texture),
+                    normal_texture: texture_index(normal_texture),
+                    emissive_texture: texture_index(emissive_texture),
+                    ..Self::DEFAULT
+                }
+            }
+        }
+    }
+}
+
+/// The materials of a scene as uploaded to the GPU, and which of them every
+/// triangle uses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaterialTable {
+    /// `SceneGeometry::materials` followed by `GpuMaterial::DEFAULT`
+    pub materials: Vec<GpuMaterial>,
+    /// Per mesh, the material of each triangle, with triangles without one
+    /// using the default material
+    pub mesh_material_ids: Vec<Vec<u32>>,
+    /// Per instance, the material replacing those of its mesh, or
+    /// `NO_MATERIAL`
+    pub instance_materials: Vec<u32>,
+}
+
+impl MaterialTable {
+    pub fn new(geometry: &SceneGeometry) -> Self {
+        let mut materials: Vec<GpuMaterial> = geometry
+            .materials
+            .iter()
+            .map(|material| GpuMaterial::new(&material.model))
+            .collect();
+        let default_material = materials.len() as u32;
+        materials.push(GpuMaterial::DEFAULT);
+
+        let mesh_material_ids = geometry
+            .meshes
+            .iter()
+            .map(|mesh| {
+                mesh.material_ids
+                    .iter()
+                    .map(|&material_id| material_id.min(default_material))
+                    .collect()
+            })
+            .collect();
+        let instance_materials = geometry
+            .instances
+            .iter()
+            .map(|instance| instance.material.map_or(NO_MATERIAL, |index| index as u32))
+            .collect();
+
+        Self {
+            materials,
+            mesh_material_ids,
+            instance_materials,
+        }
+    }
+
+    /// The material of each triangle of instance `instance` of `geometry`,
+    /// in index order.
+    pub fn instance_material_ids<'a>(
+        &'a self,
+        geometry: &SceneGeometry,
+        instance: usize,
+    ) -> impl Iterator<Item = u32> + 'a {
+        let instance_material = self.instance_materials[instance];
+        self.mesh_material_ids[geometry.instances[instance].mesh]
+            .iter()
+            .map(move |&material_id| {
+                if instance_material == NO_MATERIAL {
+                    material_id
+                } else {
+                    instance_material
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Mat4;
+
+    use super::*;
+    use crate::scene::mesh::{MeshInstance, TriangleMesh};
+    use crate::scene::{Material, Primitive};
+
+    fn lambert(name: &str, albedo: f32) -> Material {
+        Material {
+            name: name.to_string(),
+            model: MaterialModel::Lambert {
+                albedo: [albedo; 3],
+            },
+        }
+    }
+
+    #[test]
+    fn gpu_layout_matches_shader() {
+        assert_eq!(std::mem::size_of::<GpuMaterial>(), 64);
+    }
+
+    #[test]
+    fn resolves_triangle_and_instance_materials() {
+        let mut mesh = TriangleMesh::from_primitive(Primitive::Triangle);
+        mesh.append(TriangleMesh::from_primitive(Primitive::Triangle));
+        mesh.material_ids = vec![1, NO_MATERIAL];
+        let geometry = SceneGeometry {
+            meshes: vec![mesh],
+            instances: vec![
+                MeshInstance {
+                    mesh: 0,
+                    transform: Mat4::IDENTITY,
+                    end_transform: None,
+                    material: None,
+                },
+                MeshInstance {
+                    mesh: 0,
+                    transform: Mat4::IDENTITY,
+                    end_transform: None,
+                    material: Some(0),
+                },
+            ],
+            materials: vec![lambert("dark", 0.1), lambert("light", 0.9)],
+            textures: Vec::new(),
+            environment: None,
+            lights: Vec::new(),
+        };
+
+        let table = MaterialTable::new(&geometry);
+        assert_eq!(table.materials.len(), 3);
+        assert_eq!(table.materials[2], GpuMaterial::DEFAULT);
+        assert_eq!(table.materials[1].color, [0.9; 3]);
+        assert_eq!(
+            table
+                .instance_material_ids(&geometry, 0)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(
+            table
+                .instance_material_ids(&geometry, 1)
+                .collect::<Vec<_>>(),
+            vec![0, 0]
+        );
+    }
+
+    #[test]
+    fn emissive_materials_only_emit() {
+        let material = GpuMaterial::new(&MaterialModel::Emissive {
+            color: [1.0, 0.5, 0.0],
+            strength: 4.0,
+        });
+        assert_eq!(material.kind, KIND_EMISSIVE);
+        assert_eq!(material.color, [0.0; 3]);
+        assert_eq!(material.emission, [4.0, 2.0, 0.0]);
+    }
+}
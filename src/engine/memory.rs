@@ -0,0 +1,74 @@
+use ash::vk;
+
+/// Picks the memory type allowed by `type_filter` that has all of `required`
+/// and the most of `preferred`, the lowest index among equals.
+pub fn select_memory_type(
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    type_filter: u32,
+    required: vk::MemoryPropertyFlags,
+    preferred: vk::MemoryPropertyFlags,
+) -> Option<u32> {
+    (0..memory_properties.memory_type_count)
+        .filter(|i| type_filter & (1 << i) != 0)
+        .filter(|&i| {
+            memory_properties.memory_types[i as usize]
+                .property_flags
+                .contains(required)
+        })
+        .max_by_key(|&i| {
+            let flags = memory_properties.memory_types[i as usize].property_flags;
+            // max_by_key returns the last of equal elements
+            (
+                (flags & preferred).as_raw().count_ones(),
+                std::cmp::Reverse(i),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory_properties(types: &[vk::MemoryPropertyFlags]) -> vk::PhysicalDeviceMemoryProperties {
+        let mut properties = vk::PhysicalDeviceMemoryProperties {
+            memory_type_count: types.len() as u32,
+            ..Default::default()
+        };
+        for (memory_type, &property_flags) in properties.memory_types.iter_mut().zip(types) {
+            memory_type.property_flags = property_flags;
+        }
+        properties
+    }
+
+    #[test]
+    fn selects_required_and_preferred_properties() {
+        let host = vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+        let properties = memory_properties(&[
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            host,
+            host | vk::MemoryPropertyFlags::HOST_CACHED,
+        ]);
+
+        let select = |filter, required, preferred| {
+            select_memory_type(&properties, filter, required, preferred)
+        };
+        let none = vk::MemoryPropertyFlags::empty();
+        assert_eq!(
+            select(0b111, vk::MemoryPropertyFlags::DEVICE_LOCAL, none),
+            Some(0)
+        );
+        assert_eq!(select(0b111, host, none), Some(1));
+        assert_eq!(
+            select(0b111, host, vk::MemoryPropertyFlags::HOST_CACHED),
+            Some(2)
+        );
+        assert_eq!(
+            select(0b011, host, vk::MemoryPropertyFlags::HOST_CACHED),
+            Some(1)
+        );
+        assert_eq!(
+            select(0b110, vk::MemoryPropertyFlags::DEVICE_LOCAL, none),
+            None
+        );
+    }
+}
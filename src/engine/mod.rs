@@ -1,6 +1,34 @@
+pub mod acceleration_structure;
+pub mod allocator;
+pub mod backend;
+pub mod bsdf;
+pub mod buffer;
+pub mod bvh;
+pub mod commands;
+pub mod compute_tracing;
+pub mod descriptor;
+pub mod environment;
+pub mod error;
+pub mod framebuffer;
+pub mod geometry;
+pub mod image;
 pub mod instance;
+pub mod light;
 pub mod logical_device;
+pub mod material;
+pub mod memory;
+pub mod offscreen;
+pub mod path_tracing;
 pub mod physical_device;
+pub mod pipeline;
 pub mod queue_families;
+pub mod ray_tracing;
+pub mod render_pass;
 pub mod surface;
 pub mod swap_chain;
+pub mod sync;
+#[cfg(test)]
+pub mod test_util;
+pub mod texture;
+
+pub use error::{Error, Result};
@@ -0,0 +1,68 @@
+use std::rc::Rc;
+
+use ash;
+use ash::vk;
+
+use crate::engine;
+use crate::engine::allocator::Allocator;
+use crate::engine::image::{Image, ImageView};
+use crate::engine::logical_device::Device;
+
+/// A device-local color image that stands in for the swapchain when rendering
+/// without a window. The render pass leaves it in `TRANSFER_SRC_OPTIMAL` so it
+/// can be read back with [`OffscreenTarget::read_back`].
+pub struct OffscreenTarget {
+    pub image: Image,
+    pub image_view: ImageView,
+    pub image_format: vk::Format,
+    pub extent: vk::Extent2D,
+}
+
+impl OffscreenTarget {
+    pub fn new(
+        logical_device: &Rc<Device>,
+        allocator: &Rc<Allocator>,
+        extent: vk::Extent2D,
+    ) -> engine::Result<Self> {
+        let image_format = vk::Format::R8G8B8A8_SRGB;
+        let image = engine::image::create_image(
+            allocator,
+            extent,
+            1,
+            image_format,
+            vk::ImageUsageFlags::COLOR_ATTACHMENT
+                | vk::ImageUsageFlags::TRANSFER_SRC
+                | vk::ImageUsageFlags::TRANSFER_DST,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )?;
+        let image_view = ImageView::new(logical_device, &image.handle, image_format, 1)?;
+
+        Ok(Self {
+            image,
+            image_view,
+            image_format,
+            extent,
+        })
+    }
+
+    /// Copies the image into host memory as tightly packed rows of texels in
+    /// `image_format`. The image must be in `TRANSFER_SRC_OPTIMAL`.
+    pub fn read_back(
+        &self,
+        logical_device: &ash::Device,
+        allocator: &Rc<Allocator>,
+        command_pool: &vk::CommandPool,
+        queue: &vk::Queue,
+    ) -> engine::Result<Vec<u8>> {
+        engine::image::read_back_image(
+            logical_device,
+            allocator,
+            command_pool,
+            queue,
+            &self.image.handle,
+            self.image_format,
+            self.extent,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        )
+    }
+}
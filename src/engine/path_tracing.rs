@@ -0,0 +1,394 @@
+use ash::vk;
+use glam::{UVec2, Vec2, Vec3};
+use std::f32::consts::{FRAC_PI_2, FRAC_PI_4, TAU};
+
+use crate::engine::bvh::Ray;
+use crate::scene::RenderSettings;
+
+/// Format of the image both backends sum their samples in. The average is
+/// tonemapped into the storage image that is blitted to the frame.
+pub const ACCUMULATION_IMAGE_FORMAT: vk::Format = vk::Format::R32G32B32A32_SFLOAT;
+
+/// How paths are traced and how many samples are gathered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathTracingSettings {
+    pub samples_per_frame: u32,
+    /// Accumulation stops once every pixel has this many samples
+    pub max_samples: u32,
+    pub max_bounces: u32,
+    pub russian_roulette: bool,
+    /// Bounce from which paths may be terminated at random
+    pub russian_roulette_depth: u32,
+}
+
+impl PathTracingSettings {
+    pub fn new(render: &RenderSettings) -> Self {
+        Self {
+            samples_per_frame: render.samples_per_frame,
+            max_samples: render.samples_per_pixel,
+            max_bounces: render.max_bounces,
+            russian_roulette: render.russian_roulette,
+            russian_roulette_depth: render.russian_roulette_depth,
+        }
+    }
+
+    /// Whether samples traced with `self` and with `other` can be averaged.
+    fn is_compatible(&self, other: &PathTracingSettings) -> bool {
+        self.max_bounces == other.max_bounces
+            && self.russian_roulette == other.russian_roulette
+            && self.russian_roulette_depth == other.russian_roulette_depth
+    }
+}
+
+/// A thin lens, which keeps only what is at `focus_distance` sharp. A zero
+/// `radius` makes a pinhole camera with everything in focus.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lens {
+    pub radius: f32,
+    pub focus_distance: f32,
+    /// Blades of a polygonal aperture, or zero for a round one
+    pub blades: u32,
+    /// Of a polygonal aperture, in radians
+    pub rotation: f32,
+}
+
+impl Lens {
+    pub const PINHOLE: Lens = Lens {
+        radius: 0.0,
+        focus_distance: 1.0,
+        blades: 0,
+        rotation: 0.0,
+    };
+}
+
+/// A camera as the shaders see it: its position, the directions through
+/// the center and the edges of the image and its lens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraView {
+    pub position: Vec3,
+    pub forward: Vec3,
+    /// From the image center to its right edge
+    pub right: Vec3,
+    /// From the image center to its top edge
+    pub up: Vec3,
+    pub lens: Lens,
+}
+
+impl CameraView {
+    pub fn new(
+        position: Vec3,
+        forward: Vec3,
+        up: Vec3,
+        fov_degrees: f32,
+        lens: Lens,
+        extent: vk::Extent2D,
+    ) -> Self {
+        let forward = forward.normalize();
+        let right = forward.cross(up).normalize();
+        let up = right.cross(forward);
+
+        let half_height = (fov_degrees.to_radians() / 2.0).tan();
+        let aspect_ratio = extent.width as f32 / extent.height.max(1) as f32;
+
+        Self {
+            position,
+            forward,
+            right: right * half_height * aspect_ratio,
+            up: up * half_height,
+            lens,
+        }
+    }
+
+    /// The ray through `pixel` of an image of `size`, the same as
+    /// `cameraRay` in `shaders/path_tracing.glsl`. `jitter` places it within
+    /// the pixel and `lens_sample` on the lens, both from uniform numbers in
+    /// [0, 1).
+    pub fn ray(&self, pixel: UVec2, size: UVec2, jitter: Vec2, lens_sample: Vec2) -> Ray {
+        let uv = (pixel.as_vec2() + jitter) / size.as_vec2();
+        // Image rows go down, the camera's up vector goes up
+        let ndc = Vec2::new(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0);
+        let direction = (self.forward + ndc.x * self.right + ndc.y * self.up).normalize();
+        if self.lens.radius <= 0.0 {
+            return Ray::new(self.position, direction);
+        }
+
+        // Every ray through the pixel meets on the plane in focus
+        let focus_point =
+            self.position + direction * (self.lens.focus_distance / direction.dot(self.forward));
+        let offset =
+            self.lens.radius * sample_aperture(lens_sample, self.lens.blades, self.lens.rotation);
+        let origin =
+            self.position + offset.x * self.right.normalize() + offset.y * self.up.normalize();
+        Ray::new(origin, (focus_point - origin).normalize())
+    }
+}
+
+/// A uniformly distributed point on an aperture of radius 1, the same as
+/// `sampleAperture` in `shaders/path_tracing.glsl`. With `blades` the
+/// aperture is the regular polygon with a corner at `rotation`, and
+/// otherwise a disk.
+pub fn sample_aperture(u: Vec2, blades: u32, rotation: f32) -> Vec2 {
+    if blades == 0 {
+        // The concentric mapping from "A Low Distortion Map Between Disk and
+        // Square" by Shirley and Chiu, which keeps strata compact
+        let offset = 2.0 * u - Vec2::ONE;
+        if offset == Vec2::ZERO {
+            return Vec2::ZERO;
+        }
+        let (radius, angle) = if offset.x.abs() > offset.y.abs() {
+            (offset.x, FRAC_PI_4 * (offset.y / offset.x))
+        } else {
+            (offset.y, FRAC_PI_2 - FRAC_PI_4 * (offset.x / offset.y))
+        };
+        return radius * Vec2::new(angle.cos(), angle.sin());
+    }
+
+    // One of the triangles between the center and two neighbouring corners,
+    // all of the same area, then a point within it
+    let scaled = u.x * blades as f32;
+    let blade = (scaled as u32).min(blades - 1);
+    let remainder = scaled - blade as f32;
+    let corner_angle = |index: u32| rotation + TAU * index as f32 / blades as f32;
+    let first = Vec2::from_angle(corner_angle(blade));
+    let second = Vec2::from_angle(corner_angle(blade + 1));
+    remainder.sqrt() * first.lerp(second, u.y)
+}
+
+/// When during the shutter interval the frame `frame_index` is traced. The
+/// times of consecutive frames follow the van der Corput sequence, so any run
+/// of frames covers the interval evenly.
+pub fn shutter_time(shutter: [f32; 2], frame_index: u32) -> f32 {
+    let [open, close] = shutter;
+    let fraction = frame_index.reverse_bits() as f32 / 4294967296.0;
+    open + (close - open) * fraction
+}
+
+/// Matches the push constants in `shaders/path_tracing.glsl`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameConstants {
+    pub camera_position: [f32; 4],
+    pub camera_forward: [f32; 4],
+    pub camera_right: [f32; 4],
+    pub camera_up: [f32; 4],
+    pub frame_index: u32,
+    pub accumulated_samples: u32,
+    pub samples_per_frame: u32,
+    pub max_bounces: u32,
+    pub russian_roulette_depth: u32,
+    pub lens_radius: f32,
+    pub focus_distance: f32,
+    pub aperture_blades: u32,
+    pub aperture_rotation: f32,
+}
+
+impl FrameConstants {
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const Self as *const u8,
+                std::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
+
+/// Counts the samples in the accumulation image and starts over whenever the
+/// camera or the settings change what a sample would show.
+#[derive(Debug, Default)]
+pub struct Accumulation {
+    frame_index: u32,
+    accumulated_samples: u32,
+    last_frame: Option<(CameraView, PathTracingSettings)>,
+}
+
+impl Accumulation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Discards the accumulated samples, e.g. after the images were resized
+    /// or the scene changed.
+    pub fn reset(&mut self) {
+        self.accumulated_samples = 0;
+    }
+
+    pub fn accumulated_samples(&self) -> u32 {
+        self.accumulated_samples
+    }
+
+    pub fn is_complete(&self, settings: &PathTracingSettings) -> bool {
+        self.accumulated_samples >= settings.max_samples
+    }
+
+    /// The push constants of the next frame, counting its samples as
+    /// accumulated. Once `settings.max_samples` is reached frames only
+    /// display the result.
+    pub fn next_frame(
+        &mut self,
+        view: CameraView,
+        settings: &PathTracingSettings,
+    ) -> FrameConstants {
+        let is_same_image = self.last_frame.is_some_and(|(last_view, last_settings)| {
+            last_view == view && last_settings.is_compatible(settings)
+        });
+        if !is_same_image {
+            self.reset();
+        }
+        self.last_frame = Some((view, *settings));
+
+        let samples = settings.samples_per_frame.min(
+            settings
+                .max_samples
+                .saturating_sub(self.accumulated_samples),
+        );
+        let constants = FrameConstants {
+            camera_position: view.position.extend(1.0).to_array(),
+            camera_forward: view.forward.extend(0.0).to_array(),
+            camera_right: view.right.extend(0.0).to_array(),
+            camera_up: view.up.extend(0.0).to_array(),
+            frame_index: self.frame_index,
+            accumulated_samples: self.accumulated_samples,
+            samples_per_frame: samples,
+            max_bounces: settings.max_bounces,
+            russian_roulette_depth: if settings.russian_roulette {
+                settings.russian_roulette_depth
+            } else {
+                u32::MAX
+            },
+            lens_radius: view.lens.radius,
+            focus_distance: view.lens.focus_distance,
+            aperture_blades: view.lens.blades,
+            aperture_rotation: view.lens.rotation,
+        };
+
+        self.frame_index = self.frame_index.wrapping_add(1);
+        self.accumulated_samples += samples;
+        constants
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::test_util::Lcg;
+
+    const SIZE: UVec2 = UVec2::new(64, 48);
+
+    fn view(lens: Lens) -> CameraView {
+        CameraView::new(
+            Vec3::new(1.0, 2.0, 3.0),
+            Vec3::new(0.0, -0.5, 1.0),
+            Vec3::Y,
+            50.0,
+            lens,
+            vk::Extent2D {
+                width: SIZE.x,
+                height: SIZE.y,
+            },
+        )
+    }
+
+    #[test]
+    fn pinhole_rays_start_at_the_camera() {
+        let view = view(Lens::PINHOLE);
+        let center = view.ray(SIZE / 2, SIZE, Vec2::ZERO, Vec2::new(0.3, 0.9));
+        assert_eq!(center.origin, view.position);
+        assert!(center.direction.distance(view.forward) < 1e-6);
+
+        // The top left corner of the image
+        let corner = view.ray(UVec2::ZERO, SIZE, Vec2::ZERO, Vec2::ZERO);
+        let expected = (view.forward - view.right + view.up).normalize();
+        assert!(corner.direction.distance(expected) < 1e-6);
+    }
+
+    #[test]
+    fn thin_lens_rays_meet_on_the_focus_plane() {
+        let lens = Lens {
+            radius: 0.25,
+            focus_distance: 4.0,
+            blades: 0,
+            rotation: 0.0,
+        };
+        let view = view(lens);
+        let pinhole = self::view(Lens::PINHOLE);
+        let mut random = Lcg(3);
+        for _ in 0..100 {
+            let pixel = (random.next_vec2() * SIZE.as_vec2()).as_uvec2();
+            let jitter = random.next_vec2();
+            let ray = view.ray(pixel, SIZE, jitter, random.next_vec2());
+            let center = pinhole.ray(pixel, SIZE, jitter, Vec2::ZERO);
+
+            let offset = ray.origin - view.position;
+            assert!(offset.length() <= lens.radius + 1e-6);
+            assert!(offset.dot(view.forward).abs() < 1e-6);
+
+            let focus_t = lens.focus_distance / center.direction.dot(view.forward);
+            let t = (focus_t * center.direction - offset).length();
+            assert!(ray.at(t).distance(center.at(focus_t)) < 1e-4);
+        }
+    }
+
+    #[test]
+    fn round_aperture_is_uniform() {
+        let mut random = Lcg(5);
+        let count = 100_000;
+        let mut inner = 0;
+        let mut sum = Vec2::ZERO;
+        for _ in 0..count {
+            let point = sample_aperture(random.next_vec2(), 0, 0.0);
+            assert!(point.length() <= 1.0 + 1e-6);
+            if point.length() < 0.5 {
+                inner += 1;
+            }
+            sum += point;
+        }
+
+        // A quarter of the area lies within half the radius
+        assert!((inner as f32 / count as f32 - 0.25).abs() < 0.01);
+        assert!((sum / count as f32).length() < 0.01);
+    }
+
+    #[test]
+    fn polygonal_aperture_is_uniform() {
+        let blades = 6;
+        let rotation = 0.3;
+        let corners: Vec<Vec2> = (0..blades)
+            .map(|index| Vec2::from_angle(rotation + TAU * index as f32 / blades as f32))
+            .collect();
+
+        let mut random = Lcg(7);
+        let count = 100_000;
+        let mut inner = 0;
+        for _ in 0..count {
+            let point = sample_aperture(random.next_vec2(), blades, rotation);
+            for index in 0..corners.len() {
+                let edge = corners[(index + 1) % corners.len()] - corners[index];
+                assert!(edge.perp_dot(point - corners[index]) >= -1e-5);
+            }
+            // The polygon scaled by half about its center
+            let is_inner = (0..corners.len()).all(|index| {
+                let edge = corners[(index + 1) % corners.len()] - corners[index];
+                edge.perp_dot(point - 0.5 * corners[index]) >= 0.0
+            });
+            if is_inner {
+                inner += 1;
+            }
+        }
+
+        assert!((inner as f32 / count as f32 - 0.25).abs() < 0.01);
+    }
+
+    #[test]
+    fn shutter_times_cover_the_interval() {
+        let shutter = [0.25, 0.75];
+        let times: Vec<f32> = (0..4).map(|frame| shutter_time(shutter, frame)).collect();
+        assert_eq!(times, [0.25, 0.5, 0.375, 0.625]);
+
+        for frame in 0..1000 {
+            let time = shutter_time(shutter, frame);
+            assert!((0.25..=0.75).contains(&time));
+        }
+        assert_eq!(shutter_time([0.5, 0.5], 17), 0.5);
+    }
+}
@@ -1,72 +1,564 @@
-use std::ffi::CStr;
+use std::ffi::{c_void, CStr};
+use std::fmt;
+use std::str::FromStr;
 
 use ash;
 use ash::vk;
 
 use crate::engine;
+use crate::engine::error::VkResultExt;
 use crate::utils::required;
 
+/// A device chosen on the command line instead of the best scoring one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceRequest {
+    /// Position in the order Vulkan enumerates the devices
+    Index(usize),
+    /// `VkPhysicalDeviceIDProperties::deviceUUID`, which stays the same
+    /// when devices are enumerated in a different order
+    Uuid([u8; vk::UUID_SIZE]),
+    /// Case-insensitive part of the device name
+    Name(String),
+}
+
+impl DeviceRequest {
+    pub fn matches(&self, index: usize, name: &str, uuid: &[u8; vk::UUID_SIZE]) -> bool {
+        match self {
+            DeviceRequest::Index(requested) => *requested == index,
+            DeviceRequest::Uuid(requested) => requested == uuid,
+            DeviceRequest::Name(requested) => {
+                name.to_lowercase().contains(&requested.to_lowercase())
+            }
+        }
+    }
+}
+
+impl FromStr for DeviceRequest {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Err("expected a device index, UUID or name".to_string());
+        }
+        if let Some(uuid) = parse_uuid(value) {
+            return Ok(DeviceRequest::Uuid(uuid));
+        }
+        Ok(match value.parse() {
+            Ok(index) => DeviceRequest::Index(index),
+            Err(_) => DeviceRequest::Name(value.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for DeviceRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceRequest::Index(index) => write!(f, "device {}", index),
+            DeviceRequest::Uuid(uuid) => write!(f, "device with UUID {}", format_uuid(uuid)),
+            DeviceRequest::Name(name) => write!(f, "device named \"{}\"", name),
+        }
+    }
+}
+
+/// Reads the 32 hex digits of a UUID, with or without the dashes.
+fn parse_uuid(value: &str) -> Option<[u8; vk::UUID_SIZE]> {
+    let digits: Vec<u8> = value.bytes().filter(|byte| *byte != b'-').collect();
+    if digits.len() != 2 * vk::UUID_SIZE || !digits.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+
+    let mut uuid = [0; vk::UUID_SIZE];
+    for (byte, pair) in uuid.iter_mut().zip(digits.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(uuid)
+}
+
+/// Formats a UUID the usual 8-4-4-4-12 way.
+pub fn format_uuid(uuid: &[u8; vk::UUID_SIZE]) -> String {
+    let mut formatted = String::new();
+    for (i, byte) in uuid.iter().enumerate() {
+        if matches!(i, 4 | 6 | 8 | 10) {
+            formatted.push('-');
+        }
+        formatted.push_str(&format!("{:02x}", byte));
+    }
+    formatted
+}
+
+/// Why a device can't be used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    ApiVersion { major: u32, minor: u32 },
+    MissingQueueFamilies,
+    MissingExtensions(Vec<String>),
+    NoSwapChainSupport,
+    NoRayTracing,
+    NoTextureArrays,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::ApiVersion { major, minor } => {
+                write!(f, "supports Vulkan {}.{}, 1.2 is required", major, minor)
+            }
+            Rejection::MissingQueueFamilies => {
+                write!(
+                    f,
+                    "has no queue family for graphics and compute or presentation"
+                )
+            }
+            Rejection::MissingExtensions(extensions) => {
+                write!(f, "lacks the extensions {}", extensions.join(", "))
+            }
+            Rejection::NoSwapChainSupport => {
+                write!(f, "has no surface formats or present modes for the window")
+            }
+            Rejection::NoRayTracing => write!(f, "doesn't support the ray tracing features"),
+            Rejection::NoTextureArrays => {
+                write!(f, "doesn't support non-uniformly indexed texture arrays")
+            }
+        }
+    }
+}
+
+/// What device selection knows about a device.
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    pub device: vk::PhysicalDevice,
+    /// Position in the order Vulkan enumerates the devices
+    pub index: usize,
+    pub name: String,
+    pub uuid: [u8; vk::UUID_SIZE],
+    pub device_type: vk::PhysicalDeviceType,
+    pub api_version: u32,
+    /// The size of all device local memory heaps, in bytes
+    pub device_local_memory: vk::DeviceSize,
+    pub supports_ray_tracing: bool,
+    /// Empty if the device is suitable
+    pub rejections: Vec<Rejection>,
+}
+
+impl DeviceInfo {
+    /// Queries `device` and checks it against the requirements. Pass `None`
+    /// for `surface` when rendering headless, in which case presentation
+    /// support is not checked. With `is_ray_tracing_enabled` the device must
+    /// support the hardware ray tracing backend.
+    pub fn new(
+        device: vk::PhysicalDevice,
+        index: usize,
+        instance: &ash::Instance,
+        surface: Option<(&vk::SurfaceKHR, &ash::khr::surface::Instance)>,
+        is_ray_tracing_enabled: bool,
+    ) -> Self {
+        let mut id_properties = vk::PhysicalDeviceIDProperties::default();
+        let mut properties = vk::PhysicalDeviceProperties2 {
+            p_next: &mut id_properties as *mut _ as *mut c_void,
+            ..Default::default()
+        };
+        unsafe { instance.get_physical_device_properties2(device, &mut properties) };
+        let properties = properties.properties;
+
+        let memory_properties = unsafe { instance.get_physical_device_memory_properties(device) };
+        let device_local_memory = memory_properties.memory_heaps
+            [..memory_properties.memory_heap_count as usize]
+            .iter()
+            .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+            .map(|heap| heap.size)
+            .sum();
+
+        let rejections = find_rejections(&device, instance, surface, is_ray_tracing_enabled);
+
+        Self {
+            device,
+            index,
+            name: properties
+                .device_name_as_c_str()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            uuid: id_properties.device_uuid,
+            device_type: properties.device_type,
+            api_version: properties.api_version,
+            device_local_memory,
+            supports_ray_tracing: supports_hardware_ray_tracing(&device, instance),
+            rejections,
+        }
+    }
+
+    pub fn is_suitable(&self) -> bool {
+        self.rejections.is_empty()
+    }
+
+    pub fn score(&self) -> u64 {
+        score(
+            self.device_type,
+            self.device_local_memory,
+            self.supports_ray_tracing,
+        )
+    }
+
+    pub fn matches(&self, requested: &DeviceRequest) -> bool {
+        requested.matches(self.index, &self.name, &self.uuid)
+    }
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} ({:?}, Vulkan {}.{}, {} MiB, ray tracing {}, UUID {})",
+            self.index,
+            self.name,
+            self.device_type,
+            vk::api_version_major(self.api_version),
+            vk::api_version_minor(self.api_version),
+            self.device_local_memory / (1024 * 1024),
+            if self.supports_ray_tracing {
+                "supported"
+            } else {
+                "not supported"
+            },
+            format_uuid(&self.uuid),
+        )
+    }
+}
+
+/// Ranks a suitable device. The type counts most, so a discrete GPU beats an
+/// integrated one, which beats a CPU implementation. Ray tracing support
+/// comes next, and device local memory breaks ties.
+pub fn score(
+    device_type: vk::PhysicalDeviceType,
+    device_local_memory: vk::DeviceSize,
+    supports_ray_tracing: bool,
+) -> u64 {
+    let type_score = match device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+        vk::PhysicalDeviceType::CPU => 1,
+        _ => 0,
+    };
+    // Whole GiB, so small differences don't outweigh ray tracing
+    let memory_score = (device_local_memory >> 30).min(999);
+
+    type_score * 10_000 + if supports_ray_tracing { 1_000 } else { 0 } + memory_score
+}
+
+/// Picks the `requested` device, or else the suitable device with the
+/// highest `score`. The error lists the reasons each device was rejected if
+/// there is none. See `DeviceInfo::new` for `surface` and
+/// `is_ray_tracing_enabled`. With `verbose` the devices and the one picked
+/// are printed.
 pub fn pick_physical_device(
     instance: &ash::Instance,
-    surface: &vk::SurfaceKHR,
-    surface_loader: &ash::khr::surface::Instance,
-) -> vk::PhysicalDevice {
-    let devices = unsafe { instance.enumerate_physical_devices().unwrap() };
-    println!("Devices [{}]: ", devices.len());
-    devices.iter().for_each(|device| {
-        let device_properties = unsafe { instance.get_physical_device_properties(*device) };
-        println!("{:?}", device_properties.device_name_as_c_str().unwrap());
-    });
+    surface: Option<(&vk::SurfaceKHR, &ash::khr::surface::Instance)>,
+    is_ray_tracing_enabled: bool,
+    requested: Option<&DeviceRequest>,
+    verbose: bool,
+) -> engine::Result<vk::PhysicalDevice> {
+    let devices = enumerate_devices(instance, surface, is_ray_tracing_enabled)?;
+    if verbose {
+        println!("Devices [{}]: ", devices.len());
+        devices.iter().for_each(|device| println!("{}", device));
+    }
 
+    let picked = match requested {
+        Some(requested) => {
+            let Some(device) = devices.iter().find(|device| device.matches(requested)) else {
+                return Err(engine::Error::RequestedDeviceNotFound {
+                    request: requested.clone(),
+                    report: rejection_report(&devices),
+                });
+            };
+            if !device.is_suitable() {
+                return Err(engine::Error::RequestedDeviceUnsuitable {
+                    request: requested.clone(),
+                    report: rejection_report(std::slice::from_ref(device)),
+                });
+            }
+            device
+        }
+        None => devices
+            .iter()
+            .filter(|device| device.is_suitable())
+            .max_by_key(|device| (device.score(), std::cmp::Reverse(device.index)))
+            .ok_or_else(|| engine::Error::NoSuitableDevice {
+                report: rejection_report(&devices),
+            })?,
+    };
+    if verbose {
+        println!("Using device {}", picked.index);
+    }
+
+    Ok(picked.device)
+}
+
+/// Queries every device, in enumeration order.
+pub fn enumerate_devices(
+    instance: &ash::Instance,
+    surface: Option<(&vk::SurfaceKHR, &ash::khr::surface::Instance)>,
+    is_ray_tracing_enabled: bool,
+) -> engine::Result<Vec<DeviceInfo>> {
+    let devices =
+        unsafe { instance.enumerate_physical_devices() }.context("enumerate physical devices")?;
+    Ok(devices
+        .into_iter()
+        .enumerate()
+        .map(|(index, device)| {
+            DeviceInfo::new(device, index, instance, surface, is_ray_tracing_enabled)
+        })
+        .collect())
+}
+
+/// One line per device, with the reasons it can't be used.
+pub fn rejection_report(devices: &[DeviceInfo]) -> String {
+    if devices.is_empty() {
+        return "  No Vulkan devices found".to_string();
+    }
+
+    let lines: Vec<String> = devices
+        .iter()
+        .map(|device| {
+            let reasons: Vec<String> = device
+                .rejections
+                .iter()
+                .map(|rejection| rejection.to_string())
+                .collect();
+            if reasons.is_empty() {
+                format!("  {}: {}: suitable", device.index, device.name)
+            } else {
+                format!(
+                    "  {}: {}: {}",
+                    device.index,
+                    device.name,
+                    reasons.join("; ")
+                )
+            }
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// Every device with its score, or why it can't render headless, one line
+/// each.
+pub fn device_list(instance: &ash::Instance) -> engine::Result<String> {
+    let devices = enumerate_devices(instance, None, false)?;
+    if devices.is_empty() {
+        return Ok("No Vulkan devices found.".to_string());
+    }
+
+    let mut lines = Vec::new();
     for device in devices.iter() {
-        if is_device_suitable(&device, instance, surface, surface_loader) {
-            return *device;
+        lines.push(device.to_string());
+        if device.is_suitable() {
+            lines.push(format!("    score {}", device.score()));
+        }
+        for rejection in device.rejections.iter() {
+            lines.push(format!("    {}", rejection));
         }
     }
 
-    panic!("Could not find any suitable devices!");
+    Ok(lines.join("\n"))
 }
 
-fn is_device_suitable(
+fn find_rejections(
     device: &vk::PhysicalDevice,
     instance: &ash::Instance,
-    surface: &vk::SurfaceKHR,
-    surface_loader: &ash::khr::surface::Instance,
-) -> bool {
+    surface: Option<(&vk::SurfaceKHR, &ash::khr::surface::Instance)>,
+    is_ray_tracing_enabled: bool,
+) -> Vec<Rejection> {
+    let is_headless = surface.is_none();
     let device_properties = unsafe { instance.get_physical_device_properties(*device) };
-    let indices =
-        engine::queue_families::find_queue_families(device, instance, surface, surface_loader);
+    let mut rejections = vec![];
+
+    // The feature checks below fail too before Vulkan 1.2, so only the
+    // version is reported
+    let is_version_adequate = device_properties.api_version >= vk::make_api_version(0, 1, 2, 0);
+    if !is_version_adequate {
+        rejections.push(Rejection::ApiVersion {
+            major: vk::api_version_major(device_properties.api_version),
+            minor: vk::api_version_minor(device_properties.api_version),
+        });
+    }
+
+    let indices = engine::queue_families::find_queue_families(device, instance, surface);
+    if !indices.is_complete(is_headless) {
+        rejections.push(Rejection::MissingQueueFamilies);
+    }
+
+    let missing_extensions =
+        missing_device_extensions(device, instance, is_headless, is_ray_tracing_enabled);
+    if !missing_extensions.is_empty() {
+        rejections.push(Rejection::MissingExtensions(
+            missing_extensions
+                .iter()
+                .map(|extension| extension.to_string_lossy().into_owned())
+                .collect(),
+        ));
+    }
+
+    if let Some((surface, surface_loader)) = surface {
+        let swap_chain_support_details =
+            engine::swap_chain::query_swap_chain_support(device, surface, surface_loader);
+        if swap_chain_support_details.map_or(true, |details| {
+            details.formats.is_empty() || details.present_modes.is_empty()
+        }) {
+            rejections.push(Rejection::NoSwapChainSupport);
+        }
+    }
+
+    if is_version_adequate {
+        if is_ray_tracing_enabled && !supports_ray_tracing(device, instance) {
+            rejections.push(Rejection::NoRayTracing);
+        }
+        if !supports_texture_arrays(device, instance) {
+            rejections.push(Rejection::NoTextureArrays);
+        }
+    }
+
+    rejections
+}
 
-    let extensions_supported = check_device_extension_support(device, instance);
+/// Checks both the extensions and the features the hardware ray tracing
+/// backend needs.
+pub fn supports_hardware_ray_tracing(
+    device: &vk::PhysicalDevice,
+    instance: &ash::Instance,
+) -> bool {
+    missing_device_extensions(device, instance, true, true).is_empty()
+        && supports_ray_tracing(device, instance)
+}
 
-    let swap_chain_support_details =
-        engine::swap_chain::query_swap_chain_support(device, surface, surface_loader);
-    let swap_chain_adequate = !swap_chain_support_details.formats.is_empty()
-        && !swap_chain_support_details.present_modes.is_empty();
+/// Checks the descriptor indexing features the texture array of both
+/// backends needs, which are core in Vulkan 1.2.
+pub fn supports_texture_arrays(device: &vk::PhysicalDevice, instance: &ash::Instance) -> bool {
+    let device_properties = unsafe { instance.get_physical_device_properties(*device) };
+    if device_properties.api_version < vk::make_api_version(0, 1, 2, 0) {
+        return false;
+    }
+
+    let mut descriptor_indexing_features = vk::PhysicalDeviceDescriptorIndexingFeatures::default();
+    let mut features = vk::PhysicalDeviceFeatures2 {
+        p_next: &mut descriptor_indexing_features as *mut _ as *mut c_void,
+        ..Default::default()
+    };
+    unsafe { instance.get_physical_device_features2(*device, &mut features) };
 
-    device_properties.device_type == vk::PhysicalDeviceType::DISCRETE_GPU
-        && indices.is_complete()
-        && extensions_supported
-        && swap_chain_adequate
+    descriptor_indexing_features.shader_sampled_image_array_non_uniform_indexing == vk::TRUE
+        && descriptor_indexing_features.runtime_descriptor_array == vk::TRUE
 }
 
-fn check_device_extension_support(device: &vk::PhysicalDevice, instance: &ash::Instance) -> bool {
-    let available_extensions = unsafe {
-        instance
-            .enumerate_device_extension_properties(*device)
-            .unwrap()
+/// Checks the features the hardware ray tracing backend needs. The device
+/// must also support Vulkan 1.2 and the extensions from
+/// `required::get_ray_tracing_extensions_cstr`.
+pub fn supports_ray_tracing(device: &vk::PhysicalDevice, instance: &ash::Instance) -> bool {
+    let device_properties = unsafe { instance.get_physical_device_properties(*device) };
+    if device_properties.api_version < vk::make_api_version(0, 1, 2, 0) {
+        return false;
+    }
+
+    let mut ray_tracing_pipeline_features =
+        vk::PhysicalDeviceRayTracingPipelineFeaturesKHR::default();
+    let mut acceleration_structure_features = vk::PhysicalDeviceAccelerationStructureFeaturesKHR {
+        p_next: &mut ray_tracing_pipeline_features as *mut _ as *mut c_void,
+        ..Default::default()
+    };
+    let mut buffer_device_address_features = vk::PhysicalDeviceBufferDeviceAddressFeatures {
+        p_next: &mut acceleration_structure_features as *mut _ as *mut c_void,
+        ..Default::default()
     };
+    let mut features = vk::PhysicalDeviceFeatures2 {
+        p_next: &mut buffer_device_address_features as *mut _ as *mut c_void,
+        ..Default::default()
+    };
+    unsafe { instance.get_physical_device_features2(*device, &mut features) };
+
+    buffer_device_address_features.buffer_device_address == vk::TRUE
+        && acceleration_structure_features.acceleration_structure == vk::TRUE
+        && ray_tracing_pipeline_features.ray_tracing_pipeline == vk::TRUE
+}
+
+fn missing_device_extensions(
+    device: &vk::PhysicalDevice,
+    instance: &ash::Instance,
+    is_headless: bool,
+    is_ray_tracing_enabled: bool,
+) -> Vec<&'static CStr> {
+    // Extensions that can't be queried count as missing
+    let available_extensions =
+        unsafe { instance.enumerate_device_extension_properties(*device) }.unwrap_or_default();
     let available_extensions: Vec<&CStr> = available_extensions
         .iter()
-        .map(|extension| extension.extension_name_as_c_str().unwrap())
+        .filter_map(|extension| extension.extension_name_as_c_str().ok())
         .collect();
-    let required_extensions = required::get_required_extensions_cstr();
 
-    for required in required_extensions.iter() {
-        if !available_extensions.contains(&required) {
-            return false;
-        }
+    required::get_required_extensions_cstr(is_headless, is_ray_tracing_enabled)
+        .into_iter()
+        .filter(|required| !available_extensions.contains(required))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: [u8; vk::UUID_SIZE] = [
+        0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0, 0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd,
+        0xef,
+    ];
+
+    #[test]
+    fn parses_device_requests() {
+        assert_eq!("1".parse(), Ok(DeviceRequest::Index(1)));
+        assert_eq!(
+            " GeForce ".parse(),
+            Ok(DeviceRequest::Name("GeForce".to_string()))
+        );
+        assert_eq!(
+            "12345678-9abc-def0-0123-456789abcdef".parse(),
+            Ok(DeviceRequest::Uuid(UUID))
+        );
+        assert_eq!(
+            "123456789ABCDEF00123456789ABCDEF".parse(),
+            Ok(DeviceRequest::Uuid(UUID))
+        );
+        assert!("".parse::<DeviceRequest>().is_err());
+    }
+
+    #[test]
+    fn formats_uuids() {
+        assert_eq!(format_uuid(&UUID), "12345678-9abc-def0-0123-456789abcdef");
+        assert_eq!(parse_uuid(&format_uuid(&UUID)), Some(UUID));
     }
 
-    true
+    #[test]
+    fn matches_devices() {
+        let request = DeviceRequest::Name("rtx".to_string());
+        assert!(request.matches(0, "NVIDIA GeForce RTX 3080", &UUID));
+        assert!(!request.matches(0, "AMD Radeon RX 6800", &UUID));
+        assert!(DeviceRequest::Index(2).matches(2, "anything", &UUID));
+        assert!(!DeviceRequest::Index(2).matches(1, "anything", &UUID));
+        assert!(DeviceRequest::Uuid(UUID).matches(5, "anything", &UUID));
+        assert!(!DeviceRequest::Uuid([0; vk::UUID_SIZE]).matches(5, "anything", &UUID));
+    }
+
+    #[test]
+    fn ranks_type_then_ray_tracing_then_memory() {
+        const GIB: vk::DeviceSize = 1 << 30;
+        let discrete = score(vk::PhysicalDeviceType::DISCRETE_GPU, 4 * GIB, false);
+        let integrated = score(vk::PhysicalDeviceType::INTEGRATED_GPU, 64 * GIB, true);
+        let cpu = score(vk::PhysicalDeviceType::CPU, 512 * GIB, false);
+        assert!(discrete > integrated);
+        assert!(integrated > cpu);
+        assert!(cpu > score(vk::PhysicalDeviceType::OTHER, 0, true));
+
+        let ray_tracing = score(vk::PhysicalDeviceType::DISCRETE_GPU, 2 * GIB, true);
+        let large = score(vk::PhysicalDeviceType::DISCRETE_GPU, 48 * GIB, false);
+        assert!(ray_tracing > large);
+        assert!(large > discrete);
+        // Huge heaps don't spill over into the ray tracing bonus
+        assert!(ray_tracing > score(vk::PhysicalDeviceType::DISCRETE_GPU, u64::MAX, false));
+    }
 }
@@ -0,0 +1,247 @@
+use std::ffi::CStr;
+use std::io::Cursor;
+use std::rc::Rc;
+
+use ash;
+use ash::vk;
+
+use crate::engine;
+use crate::engine::error::VkResultExt;
+use crate::engine::logical_device::Device;
+
+const VERTEX_SHADER_CODE: &[u8] = include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/shaders/spv/shader.vert.spv"
+));
+const FRAGMENT_SHADER_CODE: &[u8] = include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/shaders/spv/shader.frag.spv"
+));
+
+pub const SHADER_ENTRY_POINT: &CStr = c"main";
+
+/// A pipeline together with its layout, both destroyed when it is dropped.
+pub struct Pipeline {
+    pub handle: vk::Pipeline,
+    pub layout: vk::PipelineLayout,
+    device: Rc<Device>,
+}
+
+impl Pipeline {
+    /// Takes ownership of `handle` and the `layout` it was created with.
+    pub fn new(
+        logical_device: &Rc<Device>,
+        layout: vk::PipelineLayout,
+        handle: vk::Pipeline,
+    ) -> Self {
+        Self {
+            handle,
+            layout,
+            device: Rc::clone(logical_device),
+        }
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_pipeline(self.handle, None);
+            self.device.destroy_pipeline_layout(self.layout, None);
+        }
+    }
+}
+
+pub fn create_shader_module(
+    logical_device: &ash::Device,
+    code: &[u8],
+) -> engine::Result<vk::ShaderModule> {
+    // SPIR-V is a stream of u32 words, and include_bytes! gives no alignment
+    // guarantee, so copy it into a properly aligned buffer first
+    let code = ash::util::read_spv(&mut Cursor::new(code)).map_err(engine::Error::InvalidShader)?;
+    let create_info = vk::ShaderModuleCreateInfo {
+        code_size: code.len() * std::mem::size_of::<u32>(),
+        p_code: code.as_ptr(),
+        ..Default::default()
+    };
+
+    unsafe { logical_device.create_shader_module(&create_info, None) }
+        .context("create shader module")
+}
+
+/// Builds the pipeline drawing `shaders/shader.vert` and `shaders/shader.frag`.
+/// The viewport and scissor are dynamic state, so the pipeline does not
+/// depend on the swapchain extent.
+pub fn create_graphics_pipeline(
+    logical_device: &Rc<Device>,
+    render_pass: &vk::RenderPass,
+) -> engine::Result<Pipeline> {
+    let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default();
+    let pipeline_layout =
+        unsafe { logical_device.create_pipeline_layout(&pipeline_layout_info, None) }
+            .context("create pipeline layout")?;
+
+    let vertex_shader_module = create_shader_module(logical_device, VERTEX_SHADER_CODE)?;
+    let fragment_shader_module = create_shader_module(logical_device, FRAGMENT_SHADER_CODE)?;
+
+    let shader_stages = [
+        vk::PipelineShaderStageCreateInfo {
+            stage: vk::ShaderStageFlags::VERTEX,
+            module: vertex_shader_module,
+            p_name: SHADER_ENTRY_POINT.as_ptr(),
+            ..Default::default()
+        },
+        vk::PipelineShaderStageCreateInfo {
+            stage: vk::ShaderStageFlags::FRAGMENT,
+            module: fragment_shader_module,
+            p_name: SHADER_ENTRY_POINT.as_ptr(),
+            ..Default::default()
+        },
+    ];
+
+    let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+    let dynamic_state = vk::PipelineDynamicStateCreateInfo {
+        dynamic_state_count: dynamic_states.len() as u32,
+        p_dynamic_states: dynamic_states.as_ptr(),
+        ..Default::default()
+    };
+
+    // The vertices are hard-coded in the vertex shader
+    let vertex_input_info = vk::PipelineVertexInputStateCreateInfo::default();
+
+    let input_assembly = vk::PipelineInputAssemblyStateCreateInfo {
+        topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+        primitive_restart_enable: vk::FALSE,
+        ..Default::default()
+    };
+
+    let viewport_state = vk::PipelineViewportStateCreateInfo {
+        viewport_count: 1,
+        scissor_count: 1,
+        ..Default::default()
+    };
+
+    let rasterizer = vk::PipelineRasterizationStateCreateInfo {
+        depth_clamp_enable: vk::FALSE,
+        rasterizer_discard_enable: vk::FALSE,
+        polygon_mode: vk::PolygonMode::FILL,
+        line_width: 1.0,
+        cull_mode: vk::CullModeFlags::BACK,
+        front_face: vk::FrontFace::CLOCKWISE,
+        depth_bias_enable: vk::FALSE,
+        ..Default::default()
+    };
+
+    let multisampling = vk::PipelineMultisampleStateCreateInfo {
+        sample_shading_enable: vk::FALSE,
+        rasterization_samples: vk::SampleCountFlags::TYPE_1,
+        ..Default::default()
+    };
+
+    let color_blend_attachment = vk::PipelineColorBlendAttachmentState {
+        color_write_mask: vk::ColorComponentFlags::RGBA,
+        blend_enable: vk::FALSE,
+        ..Default::default()
+    };
+
+    let color_blending = vk::PipelineColorBlendStateCreateInfo {
+        logic_op_enable: vk::FALSE,
+        attachment_count: 1,
+        p_attachments: &color_blend_attachment,
+        ..Default::default()
+    };
+
+    let pipeline_info = vk::GraphicsPipelineCreateInfo {
+        stage_count: shader_stages.len() as u32,
+        p_stages: shader_stages.as_ptr(),
+        p_vertex_input_state: &vertex_input_info,
+        p_input_assembly_state: &input_assembly,
+        p_viewport_state: &viewport_state,
+        p_rasterization_state: &rasterizer,
+        p_multisample_state: &multisampling,
+        p_color_blend_state: &color_blending,
+        p_dynamic_state: &dynamic_state,
+        layout: pipeline_layout,
+        render_pass: *render_pass,
+        subpass: 0,
+        ..Default::default()
+    };
+
+    let graphics_pipeline = unsafe {
+        logical_device.create_graphics_pipelines(vk::PipelineCache::null(), &[pipeline_info], None)
+    }
+    .context("create graphics pipeline");
+
+    // The modules are only needed while the pipeline is created
+    unsafe {
+        logical_device.destroy_shader_module(vertex_shader_module, None);
+        logical_device.destroy_shader_module(fragment_shader_module, None);
+    }
+
+    let graphics_pipeline = graphics_pipeline.inspect_err(|_| unsafe {
+        logical_device.destroy_pipeline_layout(pipeline_layout, None)
+    })?[0];
+    Ok(Pipeline::new(
+        logical_device,
+        pipeline_layout,
+        graphics_pipeline,
+    ))
+}
+
+/// Records a render pass that draws the hard-coded triangle into
+/// `framebuffer`.
+pub fn record_command_buffer(
+    logical_device: &ash::Device,
+    command_buffer: &vk::CommandBuffer,
+    render_pass: &vk::RenderPass,
+    framebuffer: &vk::Framebuffer,
+    extent: vk::Extent2D,
+    graphics_pipeline: &vk::Pipeline,
+) {
+    let clear_values = [vk::ClearValue {
+        color: vk::ClearColorValue {
+            float32: [0.0, 0.0, 0.0, 1.0],
+        },
+    }];
+
+    let render_pass_info = vk::RenderPassBeginInfo {
+        render_pass: *render_pass,
+        framebuffer: *framebuffer,
+        render_area: vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent,
+        },
+        clear_value_count: clear_values.len() as u32,
+        p_clear_values: clear_values.as_ptr(),
+        ..Default::default()
+    };
+
+    let viewport = vk::Viewport {
+        x: 0.0,
+        y: 0.0,
+        width: extent.width as f32,
+        height: extent.height as f32,
+        min_depth: 0.0,
+        max_depth: 1.0,
+    };
+    let scissor = vk::Rect2D {
+        offset: vk::Offset2D { x: 0, y: 0 },
+        extent,
+    };
+
+    unsafe {
+        logical_device.cmd_begin_render_pass(
+            *command_buffer,
+            &render_pass_info,
+            vk::SubpassContents::INLINE,
+        );
+        logical_device.cmd_bind_pipeline(
+            *command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            *graphics_pipeline,
+        );
+        logical_device.cmd_set_viewport(*command_buffer, 0, &[viewport]);
+        logical_device.cmd_set_scissor(*command_buffer, 0, &[scissor]);
+        logical_device.cmd_draw(*command_buffer, 3, 1, 0, 0);
+        logical_device.cmd_end_render_pass(*command_buffer);
+    }
+}
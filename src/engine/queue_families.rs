@@ -8,33 +8,56 @@ pub struct QueueFamilyIndices {
 }
 
 impl QueueFamilyIndices {
-    pub fn is_complete(&self) -> bool {
-        self.graphics_family.is_some() && self.present_family.is_some()
+    /// A headless device has no surface to present to, so only the graphics
+    /// family is required.
+    pub fn is_complete(&self, is_headless: bool) -> bool {
+        self.graphics_family.is_some() && (is_headless || self.present_family.is_some())
+    }
+
+    /// The distinct queue families that need a queue created for them.
+    pub fn unique_families(&self) -> Vec<u32> {
+        let mut families = vec![];
+        for family in [self.graphics_family, self.present_family]
+            .into_iter()
+            .flatten()
+        {
+            if !families.contains(&family) {
+                families.push(family);
+            }
+        }
+
+        families
     }
 }
 
 pub fn find_queue_families(
     device: &vk::PhysicalDevice,
     instance: &ash::Instance,
-    surface: &vk::SurfaceKHR,
-    surface_loader: &ash::khr::surface::Instance,
+    surface: Option<(&vk::SurfaceKHR, &ash::khr::surface::Instance)>,
 ) -> QueueFamilyIndices {
     let mut indices = QueueFamilyIndices::default();
 
     let queue_families = unsafe { instance.get_physical_device_queue_family_properties(*device) };
 
     for (i, family) in queue_families.iter().enumerate() {
-        if family.queue_flags.contains(vk::QueueFlags::GRAPHICS) {
+        // The compute ray tracing backend submits to the graphics queue too
+        if family
+            .queue_flags
+            .contains(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+        {
             indices.graphics_family = Some(i as u32);
         }
 
-        let present_support = unsafe {
-            surface_loader
-                .get_physical_device_surface_support(*device, i as u32, *surface)
-                .unwrap()
-        };
-        if present_support {
-            indices.present_family = Some(i as u32);
+        if let Some((surface, surface_loader)) = surface {
+            // A family whose support can't be queried can't be used either
+            let present_support = unsafe {
+                surface_loader
+                    .get_physical_device_surface_support(*device, i as u32, *surface)
+                    .unwrap_or(false)
+            };
+            if present_support {
+                indices.present_family = Some(i as u32);
+            }
         }
     }
 
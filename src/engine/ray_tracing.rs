@@ -0,0 +1,999 @@
+use std::ffi::c_void;
+use std::rc::Rc;
+
+use ash;
+use ash::vk;
+
+use crate::engine;
+use crate::engine::acceleration_structure::{
+    AccelerationStructure, BottomLevelDescription, GeometryAddresses, InstanceDescription,
+    SceneDescription, TopLevelAccelerationStructure,
+};
+use crate::engine::allocator::Allocator;
+use crate::engine::buffer::Buffer;
+use crate::engine::descriptor::{DescriptorPool, DescriptorSetLayout};
+use crate::engine::environment::EnvironmentBuffer;
+use crate::engine::error::VkResultExt;
+use crate::engine::light::LightBuffer;
+use crate::engine::logical_device::Device;
+use crate::engine::material::MaterialTable;
+use crate::engine::path_tracing::{FrameConstants, ACCUMULATION_IMAGE_FORMAT};
+use crate::engine::pipeline::Pipeline;
+use crate::engine::texture::TextureArray;
+use crate::scene::mesh::SceneGeometry;
+
+const RAYGEN_SHADER_CODE: &[u8] = include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/shaders/spv/raytrace.rgen.spv"
+));
+const MISS_SHADER_CODE: &[u8] = include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/shaders/spv/raytrace.rmiss.spv"
+));
+const CLOSEST_HIT_SHADER_CODE: &[u8] = include_bytes!(concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/shaders/spv/raytrace.rchit.spv"
+));
+
+/// Format of the image the ray generation shader writes to, matching the
+/// `rgba16f` qualifier in `shaders/raytrace.rgen`. 8-bit sRGB formats rarely
+/// support storage, so the shaders write linear values and the blit to the
+/// swapchain does the encoding. Half floats keep enough precision in the
+/// shadows for that, where 8-bit linear values would band.
+const STORAGE_IMAGE_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+fn align_up(value: u32, alignment: u32) -> u32 {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+/// Where the records are in the shader binding table: the raygen region,
+/// the miss region and the hit region, each starting on a
+/// `shader_group_base_alignment` boundary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ShaderBindingTableLayout {
+    handle_size: u32,
+    /// The handle size rounded up to `shader_group_handle_alignment`
+    record_stride: u32,
+    /// Size of the raygen and miss regions, which hold one record each
+    single_region_size: u32,
+    hit_record_count: u32,
+    hit_region_size: u32,
+}
+
+impl ShaderBindingTableLayout {
+    fn new(
+        properties: &vk::PhysicalDeviceRayTracingPipelinePropertiesKHR,
+        hit_record_count: u32,
+    ) -> Self {
+        let record_stride = align_up(
+            properties.shader_group_handle_size,
+            properties.shader_group_handle_alignment,
+        );
+        let base_alignment = properties.shader_group_base_alignment;
+        Self {
+            handle_size: properties.shader_group_handle_size,
+            record_stride,
+            single_region_size: align_up(record_stride, base_alignment),
+            hit_record_count,
+            hit_region_size: align_up(hit_record_count * record_stride, base_alignment),
+        }
+    }
+
+    fn miss_offset(&self) -> u32 {
+        self.single_region_size
+    }
+
+    fn hit_offset(&self) -> u32 {
+        2 * self.single_region_size
+    }
+
+    fn hit_record_offset(&self, record: u32) -> u32 {
+        self.hit_offset() + record * self.record_stride
+    }
+
+    fn size(&self) -> u32 {
+        self.hit_offset() + self.hit_region_size
+    }
+}
+
+/// The hardware ray tracing backend. It path traces the scene with a raygen,
+/// miss and closest-hit pipeline, adds the samples to an accumulation image,
+/// writes their tonemapped average to a storage image and blits that to the
+/// frame being presented.
+pub struct RayTracer {
+    acceleration_structure_device: ash::khr::acceleration_structure::Device,
+    ray_tracing_pipeline_device: ash::khr::ray_tracing_pipeline::Device,
+    pipeline_properties: vk::PhysicalDeviceRayTracingPipelinePropertiesKHR<'static>,
+    /// What the acceleration structures were built from, with the instances
+    /// where they are at the time last set
+    scene: SceneDescription,
+    bottom_levels: Vec<AccelerationStructure>,
+    top_level: TopLevelAccelerationStructure,
+    _descriptor_set_layout: DescriptorSetLayout,
+    /// Frees `descriptor_set` when it is dropped
+    _descriptor_pool: DescriptorPool,
+    descriptor_set: vk::DescriptorSet,
+    pipeline: Pipeline,
+    /// `None` until the pipeline's shader group handles were written
+    shader_binding_table: Option<Buffer>,
+    raygen_region: vk::StridedDeviceAddressRegionKHR,
+    miss_region: vk::StridedDeviceAddressRegionKHR,
+    hit_region: vk::StridedDeviceAddressRegionKHR,
+    callable_region: vk::StridedDeviceAddressRegionKHR,
+    storage_image: engine::image::StorageImage,
+    accumulation_image: engine::image::StorageImage,
+    /// Vertex and index buffers of each mesh, which the acceleration
+    /// structures were built from
+    _mesh_buffers: Vec<MeshBuffers>,
+    /// A `GeometryRecord` per bottom level geometry
+    geometry_buffer: Buffer,
+    /// `MaterialTable::materials`, read by the ray generation shader
+    material_buffer: Buffer,
+    /// `MaterialTable::instance_materials`, read by the closest-hit shader
+    instance_material_buffer: Buffer,
+    /// Sampled by the ray generation shader
+    texture_array: TextureArray,
+    environment_buffer: EnvironmentBuffer,
+    light_buffer: LightBuffer,
+    /// What the light buffer is gathered from when the instances move
+    material_table: MaterialTable,
+}
+
+/// Where the closest-hit shader finds the vertices, indices and triangle
+/// materials of a geometry, indexed with the instance custom index plus the
+/// geometry index.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct GeometryRecord {
+    vertex_address: vk::DeviceAddress,
+    index_address: vk::DeviceAddress,
+    material_id_address: vk::DeviceAddress,
+}
+
+struct MeshBuffers {
+    vertex_buffer: Buffer,
+    index_buffer: Buffer,
+    material_id_buffer: Buffer,
+}
+
+impl RayTracer {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        instance: &ash::Instance,
+        physical_device: &vk::PhysicalDevice,
+        logical_device: &Rc<Device>,
+        allocator: &Rc<Allocator>,
+        command_pool: &vk::CommandPool,
+        queue: &vk::Queue,
+        extent: vk::Extent2D,
+        geometry: &SceneGeometry,
+    ) -> engine::Result<Self> {
+        let acceleration_structure_device =
+            ash::khr::acceleration_structure::Device::new(instance, logical_device);
+        let ray_tracing_pipeline_device =
+            ash::khr::ray_tracing_pipeline::Device::new(instance, logical_device);
+
+        let mut pipeline_properties = vk::PhysicalDeviceRayTracingPipelinePropertiesKHR::default();
+        let mut properties = vk::PhysicalDeviceProperties2 {
+            p_next: &mut pipeline_properties as *mut _ as *mut c_void,
+            ..Default::default()
+        };
+        unsafe { instance.get_physical_device_properties2(*physical_device, &mut properties) };
+        let scratch_alignment =
+            engine::acceleration_structure::get_scratch_alignment(instance, physical_device);
+
+        // Build the scene, with one bottom level per mesh
+        let scene = SceneDescription {
+            bottom_levels: geometry
+                .meshes
+                .iter()
+                .map(|mesh| BottomLevelDescription::new(vec![mesh.geometry()]))
+                .collect(),
+            instances: instance_descriptions(geometry, 0.0),
+            ray_type_count: 1,
+        };
+        scene.validate()?;
+
+        // The closest-hit shader reads the build inputs too
+        let build_input_usage =
+            vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR
+                | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+                | vk::BufferUsageFlags::STORAGE_BUFFER;
+        let material_table = MaterialTable::new(geometry);
+        let mesh_buffers: Vec<MeshBuffers> = geometry
+            .meshes
+            .iter()
+            .zip(&material_table.mesh_material_ids)
+            .map(|(mesh, material_ids)| {
+                let vertex_buffer = engine::buffer::create_buffer_with_data(
+                    allocator,
+                    &mesh.vertices,
+                    build_input_usage,
+                )?;
+                let index_buffer = engine::buffer::create_buffer_with_data(
+                    allocator,
+                    &mesh.indices,
+                    build_input_usage,
+                )?;
+                let material_id_buffer = engine::buffer::create_buffer_with_data(
+                    allocator,
+                    material_ids,
+                    vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+                        | vk::BufferUsageFlags::STORAGE_BUFFER,
+                )?;
+                Ok(MeshBuffers {
+                    vertex_buffer,
+                    index_buffer,
+                    material_id_buffer,
+                })
+            })
+            .collect::<engine::Result<_>>()?;
+
+        let geometry_records: Vec<GeometryRecord> = mesh_buffers
+            .iter()
+            .map(|buffers| GeometryRecord {
+                vertex_address: buffers.vertex_buffer.device_address(),
+                index_address: buffers.index_buffer.device_address(),
+                material_id_address: buffers.material_id_buffer.device_address(),
+            })
+            .collect();
+        let geometry_buffer = engine::buffer::create_buffer_with_data(
+            allocator,
+            &geometry_records,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+        )?;
+        let material_buffer = engine::buffer::create_buffer_with_data(
+            allocator,
+            &material_table.materials,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+        )?;
+        let instance_material_buffer = engine::buffer::create_buffer_with_data(
+            allocator,
+            &material_table.instance_materials,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+        )?;
+
+        let bottom_levels: Vec<AccelerationStructure> = scene
+            .bottom_levels
+            .iter()
+            .zip(&geometry_records)
+            .map(|(description, record)| {
+                let addresses = GeometryAddresses {
+                    vertex_address: record.vertex_address,
+                    index_address: record.index_address,
+                };
+                engine::acceleration_structure::build_bottom_level(
+                    logical_device,
+                    allocator,
+                    &acceleration_structure_device,
+                    command_pool,
+                    queue,
+                    description,
+                    &[addresses],
+                    scratch_alignment,
+                )
+            })
+            .collect::<engine::Result<_>>()?;
+        let bottom_level_addresses: Vec<vk::DeviceAddress> = bottom_levels
+            .iter()
+            .map(|bottom_level| bottom_level.device_address)
+            .collect();
+        let top_level = TopLevelAccelerationStructure::new(
+            logical_device,
+            allocator,
+            &acceleration_structure_device,
+            command_pool,
+            queue,
+            &scene.instance_data(&bottom_level_addresses),
+            scratch_alignment,
+        )?;
+
+        let texture_array = TextureArray::new(
+            instance,
+            physical_device,
+            logical_device,
+            allocator,
+            command_pool,
+            queue,
+            geometry,
+        )?;
+        let environment_buffer =
+            EnvironmentBuffer::new(allocator, geometry, texture_array.environment)?;
+        let light_buffer = LightBuffer::new(allocator, geometry, &material_table, 0.0)?;
+
+        // Create the pipeline
+        let descriptor_set_layout =
+            create_descriptor_set_layout(logical_device, texture_array.descriptor_count())?;
+        let pipeline = create_ray_tracing_pipeline(
+            logical_device,
+            &ray_tracing_pipeline_device,
+            &descriptor_set_layout.handle,
+        )?;
+
+        let descriptor_pool =
+            create_descriptor_pool(logical_device, texture_array.descriptor_count())?;
+        let descriptor_set = descriptor_pool.allocate_set(&descriptor_set_layout)?;
+
+        let mut ray_tracer = Self {
+            acceleration_structure_device,
+            ray_tracing_pipeline_device,
+            pipeline_properties,
+            scene,
+            bottom_levels,
+            top_level,
+            _descriptor_set_layout: descriptor_set_layout,
+            _descriptor_pool: descriptor_pool,
+            descriptor_set,
+            pipeline,
+            shader_binding_table: None,
+            raygen_region: vk::StridedDeviceAddressRegionKHR::default(),
+            miss_region: vk::StridedDeviceAddressRegionKHR::default(),
+            hit_region: vk::StridedDeviceAddressRegionKHR::default(),
+            callable_region: vk::StridedDeviceAddressRegionKHR::default(),
+            storage_image: engine::image::StorageImage::new(
+                logical_device,
+                allocator,
+                command_pool,
+                queue,
+                STORAGE_IMAGE_FORMAT,
+                extent,
+            )?,
+            accumulation_image: engine::image::StorageImage::new(
+                logical_device,
+                allocator,
+                command_pool,
+                queue,
+                ACCUMULATION_IMAGE_FORMAT,
+                extent,
+            )?,
+            _mesh_buffers: mesh_buffers,
+            geometry_buffer,
+            material_buffer,
+            instance_material_buffer,
+            texture_array,
+            environment_buffer,
+            light_buffer,
+            material_table,
+        };
+        ray_tracer.create_shader_binding_table(allocator)?;
+        ray_tracer.write_descriptor_set(logical_device);
+
+        Ok(ray_tracer)
+    }
+
+    /// Writes the raygen and miss records and one hit record per geometry
+    /// and ray type of the scene, all with the one hit group, so that every
+    /// instance's records are inside the hit region.
+    fn create_shader_binding_table(&mut self, allocator: &Rc<Allocator>) -> engine::Result<()> {
+        let layout = ShaderBindingTableLayout::new(
+            &self.pipeline_properties,
+            self.scene.hit_group_record_count() as u32,
+        );
+        let handle_size = layout.handle_size as usize;
+
+        let group_count = 3;
+        let handles = unsafe {
+            self.ray_tracing_pipeline_device
+                .get_ray_tracing_shader_group_handles(
+                    self.pipeline.handle,
+                    0,
+                    group_count,
+                    group_count as usize * handle_size,
+                )
+        }
+        .context("get shader group handles")?;
+        let handle = |group: usize| &handles[group * handle_size..][..handle_size];
+
+        // Over-allocate so the table can start at an aligned address
+        let base_alignment = self.pipeline_properties.shader_group_base_alignment as vk::DeviceSize;
+        let buffer = engine::buffer::create_buffer(
+            allocator,
+            layout.size() as vk::DeviceSize + base_alignment,
+            vk::BufferUsageFlags::SHADER_BINDING_TABLE_KHR
+                | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        )?;
+        let address = buffer
+            .device_address()
+            .next_multiple_of(base_alignment.max(1));
+        let start = (address - buffer.device_address()) as usize;
+
+        let mut table = vec![0u8; start + layout.size() as usize];
+        let mut write_record = |offset: u32, handle: &[u8]| {
+            table[start + offset as usize..][..handle_size].copy_from_slice(handle);
+        };
+        write_record(0, handle(0));
+        write_record(layout.miss_offset(), handle(1));
+        for record in 0..layout.hit_record_count {
+            write_record(layout.hit_record_offset(record), handle(2));
+        }
+        buffer.allocation.write(&table);
+
+        self.shader_binding_table = Some(buffer);
+        // The raygen region must have its size equal to its stride
+        self.raygen_region = vk::StridedDeviceAddressRegionKHR {
+            device_address: address,
+            stride: layout.single_region_size as vk::DeviceSize,
+            size: layout.single_region_size as vk::DeviceSize,
+        };
+        self.miss_region = vk::StridedDeviceAddressRegionKHR {
+            device_address: address + layout.miss_offset() as vk::DeviceSize,
+            stride: layout.record_stride as vk::DeviceSize,
+            size: layout.single_region_size as vk::DeviceSize,
+        };
+        self.hit_region = vk::StridedDeviceAddressRegionKHR {
+            device_address: address + layout.hit_offset() as vk::DeviceSize,
+            stride: layout.record_stride as vk::DeviceSize,
+            size: layout.hit_region_size as vk::DeviceSize,
+        };
+
+        Ok(())
+    }
+
+    fn write_descriptor_set(&self, logical_device: &ash::Device) {
+        let acceleration_structures = [self.top_level.structure.handle];
+        let acceleration_structure_info = vk::WriteDescriptorSetAccelerationStructureKHR {
+            acceleration_structure_count: acceleration_structures.len() as u32,
+            p_acceleration_structures: acceleration_structures.as_ptr(),
+            ..Default::default()
+        };
+        let image_info = vk::DescriptorImageInfo {
+            image_view: self.storage_image.image_view.handle,
+            image_layout: vk::ImageLayout::GENERAL,
+            ..Default::default()
+        };
+        let accumulation_image_info = vk::DescriptorImageInfo {
+            image_view: self.accumulation_image.image_view.handle,
+            image_layout: vk::ImageLayout::GENERAL,
+            ..Default::default()
+        };
+        let geometry_buffer_info = vk::DescriptorBufferInfo {
+            buffer: self.geometry_buffer.handle,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        };
+        let material_buffer_info = vk::DescriptorBufferInfo {
+            buffer: self.material_buffer.handle,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        };
+        let instance_material_buffer_info = vk::DescriptorBufferInfo {
+            buffer: self.instance_material_buffer.handle,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        };
+        let texture_infos = self.texture_array.image_infos();
+        let environment_buffer_info = vk::DescriptorBufferInfo {
+            buffer: self.environment_buffer.buffer.handle,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        };
+        let light_buffer_info = vk::DescriptorBufferInfo {
+            buffer: self.light_buffer.buffer.handle,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        };
+
+        let writes = [
+            vk::WriteDescriptorSet {
+                p_next: &acceleration_structure_info as *const _ as *const c_void,
+                dst_set: self.descriptor_set,
+                dst_binding: 0,
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+                ..Default::default()
+            },
+            vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: 1,
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+                p_image_info: &image_info,
+                ..Default::default()
+            },
+            vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: 2,
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+                p_image_info: &accumulation_image_info,
+                ..Default::default()
+            },
+            vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: 3,
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+                p_buffer_info: &geometry_buffer_info,
+                ..Default::default()
+            },
+            vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: 4,
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+                p_buffer_info: &material_buffer_info,
+                ..Default::default()
+            },
+            vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: 5,
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+                p_buffer_info: &instance_material_buffer_info,
+                ..Default::default()
+            },
+            vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: 6,
+                descriptor_count: texture_infos.len() as u32,
+                descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                p_image_info: texture_infos.as_ptr(),
+                ..Default::default()
+            },
+            vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: 8,
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+                p_buffer_info: &environment_buffer_info,
+                ..Default::default()
+            },
+            vk::WriteDescriptorSet {
+                dst_set: self.descriptor_set,
+                dst_binding: 9,
+                descriptor_count: 1,
+                descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+                p_buffer_info: &light_buffer_info,
+                ..Default::default()
+            },
+        ];
+
+        unsafe { logical_device.update_descriptor_sets(&writes, &[]) };
+    }
+
+    /// Recreates the storage and accumulation images for a new target
+    /// extent, e.g. after the swapchain was recreated. The device must be
+    /// idle, and the accumulation starts over.
+    pub fn resize(
+        &mut self,
+        logical_device: &Rc<Device>,
+        allocator: &Rc<Allocator>,
+        command_pool: &vk::CommandPool,
+        queue: &vk::Queue,
+        extent: vk::Extent2D,
+    ) -> engine::Result<()> {
+        if extent == self.storage_image.extent {
+            return Ok(());
+        }
+
+        let storage_image = engine::image::StorageImage::new(
+            logical_device,
+            allocator,
+            command_pool,
+            queue,
+            STORAGE_IMAGE_FORMAT,
+            extent,
+        )?;
+        self.storage_image = storage_image;
+        let accumulation_image = engine::image::StorageImage::new(
+            logical_device,
+            allocator,
+            command_pool,
+            queue,
+            ACCUMULATION_IMAGE_FORMAT,
+            extent,
+        )?;
+        self.accumulation_image = accumulation_image;
+        self.write_descriptor_set(logical_device);
+
+        Ok(())
+    }
+
+    /// Records moving the instances of `geometry`, which the tracer was
+    /// created with, to where they are at `time`: refitting the top level
+    /// and gathering the lights anew, ahead of the frame's tracing. The
+    /// buffers of frame in flight `frame` are rewritten, so its previous
+    /// commands must have completed.
+    pub fn record_set_time(
+        &mut self,
+        logical_device: &ash::Device,
+        command_buffer: &vk::CommandBuffer,
+        frame: usize,
+        geometry: &SceneGeometry,
+        time: f32,
+    ) {
+        self.scene.instances = instance_descriptions(geometry, time);
+        let bottom_level_addresses: Vec<vk::DeviceAddress> = self
+            .bottom_levels
+            .iter()
+            .map(|bottom_level| bottom_level.device_address)
+            .collect();
+        self.top_level.record_update(
+            logical_device,
+            &self.acceleration_structure_device,
+            command_buffer,
+            frame,
+            &self.scene.instance_data(&bottom_level_addresses),
+        );
+
+        // Earlier frames may still be reading the lights
+        engine::commands::record_memory_barrier(
+            logical_device,
+            command_buffer,
+            vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+            vk::AccessFlags::empty(),
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::empty(),
+        );
+        self.light_buffer.record_set_time(
+            logical_device,
+            command_buffer,
+            frame,
+            geometry,
+            &self.material_table,
+            time,
+        );
+        engine::commands::record_memory_barrier(
+            logical_device,
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::PipelineStageFlags::RAY_TRACING_SHADER_KHR,
+            vk::AccessFlags::SHADER_READ,
+        );
+    }
+
+    /// Records tracing the samples of a frame and blitting the accumulated
+    /// result into `target_image`, which is left in `target_layout`. The
+    /// previous contents of the target are discarded.
+    pub fn record_command_buffer(
+        &self,
+        logical_device: &ash::Device,
+        command_buffer: &vk::CommandBuffer,
+        target_image: &vk::Image,
+        target_layout: vk::ImageLayout,
+        constants: &FrameConstants,
+    ) {
+        // Wait for the previous frame's samples before adding to them
+        engine::image::transition_image_layout(
+            logical_device,
+            command_buffer,
+            &self.accumulation_image.image.handle,
+            vk::ImageLayout::GENERAL,
+            vk::ImageLayout::GENERAL,
+        );
+
+        unsafe {
+            logical_device.cmd_bind_pipeline(
+                *command_buffer,
+                vk::PipelineBindPoint::RAY_TRACING_KHR,
+                self.pipeline.handle,
+            );
+            logical_device.cmd_bind_descriptor_sets(
+                *command_buffer,
+                vk::PipelineBindPoint::RAY_TRACING_KHR,
+                self.pipeline.layout,
+                0,
+                &[self.descriptor_set],
+                &[],
+            );
+            logical_device.cmd_push_constants(
+                *command_buffer,
+                self.pipeline.layout,
+                vk::ShaderStageFlags::RAYGEN_KHR,
+                0,
+                constants.as_bytes(),
+            );
+            self.ray_tracing_pipeline_device.cmd_trace_rays(
+                *command_buffer,
+                &self.raygen_region,
+                &self.miss_region,
+                &self.hit_region,
+                &self.callable_region,
+                self.storage_image.extent.width,
+                self.storage_image.extent.height,
+                1,
+            );
+        }
+
+        self.storage_image
+            .record_blit(logical_device, command_buffer, target_image, target_layout);
+    }
+
+    /// The running sum of the samples, which the storage image holds the
+    /// tonemapped average of.
+    pub fn accumulation_image(&self) -> &engine::image::StorageImage {
+        &self.accumulation_image
+    }
+}
+
+/// An instance of a bottom level per mesh instance, placed where it is at
+/// `time`.
+fn instance_descriptions(geometry: &SceneGeometry, time: f32) -> Vec<InstanceDescription> {
+    geometry
+        .instances
+        .iter()
+        .map(|instance| {
+            InstanceDescription::new(
+                instance.mesh,
+                engine::acceleration_structure::transform_rows(&instance.transform_at(time)),
+            )
+        })
+        .collect()
+}
+
+fn create_descriptor_set_layout(
+    logical_device: &Rc<Device>,
+    texture_count: u32,
+) -> engine::Result<DescriptorSetLayout> {
+    let bindings = [
+        vk::DescriptorSetLayoutBinding {
+            binding: 0,
+            descriptor_type: vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::RAYGEN_KHR,
+            ..Default::default()
+        },
+        vk::DescriptorSetLayoutBinding {
+            binding: 1,
+            descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::RAYGEN_KHR,
+            ..Default::default()
+        },
+        vk::DescriptorSetLayoutBinding {
+            binding: 2,
+            descriptor_type: vk::DescriptorType::STORAGE_IMAGE,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::RAYGEN_KHR,
+            ..Default::default()
+        },
+        vk::DescriptorSetLayoutBinding {
+            binding: 3,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+            ..Default::default()
+        },
+        vk::DescriptorSetLayoutBinding {
+            binding: 4,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::RAYGEN_KHR,
+            ..Default::default()
+        },
+        vk::DescriptorSetLayoutBinding {
+            binding: 5,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+            ..Default::default()
+        },
+        vk::DescriptorSetLayoutBinding {
+            binding: 6,
+            descriptor_type: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            descriptor_count: texture_count,
+            stage_flags: vk::ShaderStageFlags::RAYGEN_KHR,
+            ..Default::default()
+        },
+        vk::DescriptorSetLayoutBinding {
+            binding: 8,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::RAYGEN_KHR,
+            ..Default::default()
+        },
+        vk::DescriptorSetLayoutBinding {
+            binding: 9,
+            descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 1,
+            stage_flags: vk::ShaderStageFlags::RAYGEN_KHR,
+            ..Default::default()
+        },
+    ];
+
+    let create_info = vk::DescriptorSetLayoutCreateInfo {
+        binding_count: bindings.len() as u32,
+        p_bindings: bindings.as_ptr(),
+        ..Default::default()
+    };
+
+    DescriptorSetLayout::new(logical_device, &create_info)
+}
+
+fn create_descriptor_pool(
+    logical_device: &Rc<Device>,
+    texture_count: u32,
+) -> engine::Result<DescriptorPool> {
+    let pool_sizes = [
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::ACCELERATION_STRUCTURE_KHR,
+            descriptor_count: 1,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_IMAGE,
+            descriptor_count: 2,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 5,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            descriptor_count: texture_count,
+        },
+    ];
+
+    let create_info = vk::DescriptorPoolCreateInfo {
+        max_sets: 1,
+        pool_size_count: pool_sizes.len() as u32,
+        p_pool_sizes: pool_sizes.as_ptr(),
+        ..Default::default()
+    };
+
+    DescriptorPool::new(logical_device, &create_info)
+}
+
+fn create_ray_tracing_pipeline(
+    logical_device: &Rc<Device>,
+    ray_tracing_pipeline_device: &ash::khr::ray_tracing_pipeline::Device,
+    descriptor_set_layout: &vk::DescriptorSetLayout,
+) -> engine::Result<Pipeline> {
+    let set_layouts = [*descriptor_set_layout];
+    let push_constant_ranges = [vk::PushConstantRange {
+        stage_flags: vk::ShaderStageFlags::RAYGEN_KHR,
+        offset: 0,
+        size: std::mem::size_of::<FrameConstants>() as u32,
+    }];
+    let pipeline_layout_info = vk::PipelineLayoutCreateInfo {
+        set_layout_count: set_layouts.len() as u32,
+        p_set_layouts: set_layouts.as_ptr(),
+        push_constant_range_count: push_constant_ranges.len() as u32,
+        p_push_constant_ranges: push_constant_ranges.as_ptr(),
+        ..Default::default()
+    };
+    let pipeline_layout =
+        unsafe { logical_device.create_pipeline_layout(&pipeline_layout_info, None) }
+            .context("create pipeline layout")?;
+
+    let raygen_module = engine::pipeline::create_shader_module(logical_device, RAYGEN_SHADER_CODE)?;
+    let miss_module = engine::pipeline::create_shader_module(logical_device, MISS_SHADER_CODE)?;
+    let closest_hit_module =
+        engine::pipeline::create_shader_module(logical_device, CLOSEST_HIT_SHADER_CODE)?;
+
+    let shader_stages = [
+        vk::PipelineShaderStageCreateInfo {
+            stage: vk::ShaderStageFlags::RAYGEN_KHR,
+            module: raygen_module,
+            p_name: engine::pipeline::SHADER_ENTRY_POINT.as_ptr(),
+            ..Default::default()
+        },
+        vk::PipelineShaderStageCreateInfo {
+            stage: vk::ShaderStageFlags::MISS_KHR,
+            module: miss_module,
+            p_name: engine::pipeline::SHADER_ENTRY_POINT.as_ptr(),
+            ..Default::default()
+        },
+        vk::PipelineShaderStageCreateInfo {
+            stage: vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+            module: closest_hit_module,
+            p_name: engine::pipeline::SHADER_ENTRY_POINT.as_ptr(),
+            ..Default::default()
+        },
+    ];
+
+    // One group per stage, in the order of the shader binding table
+    let shader_groups = [
+        vk::RayTracingShaderGroupCreateInfoKHR {
+            ty: vk::RayTracingShaderGroupTypeKHR::GENERAL,
+            general_shader: 0,
+            closest_hit_shader: vk::SHADER_UNUSED_KHR,
+            any_hit_shader: vk::SHADER_UNUSED_KHR,
+            intersection_shader: vk::SHADER_UNUSED_KHR,
+            ..Default::default()
+        },
+        vk::RayTracingShaderGroupCreateInfoKHR {
+            ty: vk::RayTracingShaderGroupTypeKHR::GENERAL,
+            general_shader: 1,
+            closest_hit_shader: vk::SHADER_UNUSED_KHR,
+            any_hit_shader: vk::SHADER_UNUSED_KHR,
+            intersection_shader: vk::SHADER_UNUSED_KHR,
+            ..Default::default()
+        },
+        vk::RayTracingShaderGroupCreateInfoKHR {
+            ty: vk::RayTracingShaderGroupTypeKHR::TRIANGLES_HIT_GROUP,
+            general_shader: vk::SHADER_UNUSED_KHR,
+            closest_hit_shader: 2,
+            any_hit_shader: vk::SHADER_UNUSED_KHR,
+            intersection_shader: vk::SHADER_UNUSED_KHR,
+            ..Default::default()
+        },
+    ];
+
+    let pipeline_info = vk::RayTracingPipelineCreateInfoKHR {
+        stage_count: shader_stages.len() as u32,
+        p_stages: shader_stages.as_ptr(),
+        group_count: shader_groups.len() as u32,
+        p_groups: shader_groups.as_ptr(),
+        max_pipeline_ray_recursion_depth: 1,
+        layout: pipeline_layout,
+        ..Default::default()
+    };
+
+    let pipeline = unsafe {
+        ray_tracing_pipeline_device.create_ray_tracing_pipelines(
+            vk::DeferredOperationKHR::null(),
+            vk::PipelineCache::null(),
+            &[pipeline_info],
+            None,
+        )
+    }
+    .context("create ray tracing pipeline");
+
+    unsafe {
+        logical_device.destroy_shader_module(raygen_module, None);
+        logical_device.destroy_shader_module(miss_module, None);
+        logical_device.destroy_shader_module(closest_hit_module, None);
+    }
+
+    let pipeline = pipeline.inspect_err(|_| unsafe {
+        logical_device.destroy_pipeline_layout(pipeline_layout, None)
+    })?[0];
+    Ok(Pipeline::new(logical_device, pipeline_layout, pipeline))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::acceleration_structure::{TriangleGeometry, IDENTITY_TRANSFORM};
+
+    fn properties() -> vk::PhysicalDeviceRayTracingPipelinePropertiesKHR<'static> {
+        vk::PhysicalDeviceRayTracingPipelinePropertiesKHR {
+            shader_group_handle_size: 32,
+            shader_group_handle_alignment: 32,
+            shader_group_base_alignment: 64,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn lays_out_aligned_regions() {
+        let layout = ShaderBindingTableLayout::new(&properties(), 3);
+        assert_eq!(layout.record_stride, 32);
+        assert_eq!(layout.miss_offset(), 64);
+        assert_eq!(layout.hit_offset(), 128);
+        assert_eq!(layout.hit_region_size, 128);
+        assert_eq!(layout.hit_record_offset(2), 192);
+        assert_eq!(layout.size(), 256);
+    }
+
+    #[test]
+    fn hit_region_holds_every_instance_record() {
+        let mesh = TriangleGeometry {
+            vertex_count: 3,
+            vertex_stride: 12,
+            index_count: 3,
+            is_opaque: true,
+        };
+        let scene = SceneDescription {
+            bottom_levels: (0..4)
+                .map(|_| BottomLevelDescription::new(vec![mesh]))
+                .collect(),
+            instances: (0..4)
+                .rev()
+                .map(|bottom_level| InstanceDescription::new(bottom_level, IDENTITY_TRANSFORM))
+                .collect(),
+            ray_type_count: 1,
+        };
+
+        let layout =
+            ShaderBindingTableLayout::new(&properties(), scene.hit_group_record_count() as u32);
+        let last_record = scene.last_hit_group_record().unwrap();
+        assert!(last_record < layout.hit_record_count);
+        assert!(
+            layout.hit_record_offset(last_record) + layout.handle_size
+                <= layout.hit_offset() + layout.hit_region_size
+        );
+    }
+}
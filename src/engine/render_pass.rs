@@ -0,0 +1,84 @@
+use std::rc::Rc;
+
+use ash;
+use ash::vk;
+
+use crate::engine;
+use crate::engine::error::VkResultExt;
+use crate::engine::logical_device::Device;
+
+/// A render pass, destroyed when it is dropped.
+pub struct RenderPass {
+    pub handle: vk::RenderPass,
+    device: Rc<Device>,
+}
+
+impl Drop for RenderPass {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_render_pass(self.handle, None) };
+    }
+}
+
+/// Creates a single-subpass render pass with one color attachment. The
+/// attachment is cleared on load and left in `final_layout`, which is
+/// `PRESENT_SRC_KHR` for the swapchain and `TRANSFER_SRC_OPTIMAL` for the
+/// offscreen image so it can be read back.
+pub fn create_render_pass(
+    logical_device: &Rc<Device>,
+    format: vk::Format,
+    final_layout: vk::ImageLayout,
+) -> engine::Result<RenderPass> {
+    let color_attachment = vk::AttachmentDescription {
+        format,
+        samples: vk::SampleCountFlags::TYPE_1,
+        load_op: vk::AttachmentLoadOp::CLEAR,
+        store_op: vk::AttachmentStoreOp::STORE,
+        stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+        stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+        initial_layout: vk::ImageLayout::UNDEFINED,
+        final_layout,
+        ..Default::default()
+    };
+
+    let color_attachment_ref = vk::AttachmentReference {
+        attachment: 0,
+        layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+    };
+
+    let subpass = vk::SubpassDescription {
+        pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
+        color_attachment_count: 1,
+        p_color_attachments: &color_attachment_ref,
+        ..Default::default()
+    };
+
+    // Wait for the image to be released by the presentation engine before
+    // writing to it
+    let dependency = vk::SubpassDependency {
+        src_subpass: vk::SUBPASS_EXTERNAL,
+        dst_subpass: 0,
+        src_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        src_access_mask: vk::AccessFlags::empty(),
+        dst_stage_mask: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        dst_access_mask: vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+        ..Default::default()
+    };
+
+    let create_info = vk::RenderPassCreateInfo {
+        attachment_count: 1,
+        p_attachments: &color_attachment,
+        subpass_count: 1,
+        p_subpasses: &subpass,
+        dependency_count: 1,
+        p_dependencies: &dependency,
+        ..Default::default()
+    };
+
+    let handle = unsafe { logical_device.create_render_pass(&create_info, None) }
+        .context("create render pass")?;
+
+    Ok(RenderPass {
+        handle,
+        device: Rc::clone(logical_device),
+    })
+}
@@ -1,34 +1,129 @@
-use winit::raw_window_handle::RawWindowHandle;
+use std::rc::Rc;
+
+use winit::raw_window_handle::{RawDisplayHandle, RawWindowHandle};
 
 use ash;
 use ash::vk;
 
+use crate::engine;
+use crate::engine::error::VkResultExt;
+use crate::engine::instance::Instance;
+
+/// The surface of a window, destroyed before the instance it was created
+/// from.
+pub struct Surface {
+    pub handle: vk::SurfaceKHR,
+    pub loader: ash::khr::surface::Instance,
+    _instance: Rc<Instance>,
+}
+
+impl Surface {
+    pub fn new(
+        instance: &Rc<Instance>,
+        raw_display_handle: &RawDisplayHandle,
+        raw_window_handle: &RawWindowHandle,
+    ) -> engine::Result<Self> {
+        let handle = create_surface(
+            instance.entry(),
+            instance,
+            raw_display_handle,
+            raw_window_handle,
+        )?;
+
+        Ok(Self {
+            handle,
+            loader: ash::khr::surface::Instance::new(instance.entry(), instance),
+            _instance: Rc::clone(instance),
+        })
+    }
+
+    /// The surface as the functions that also work headless take it.
+    pub fn info(&self) -> (&vk::SurfaceKHR, &ash::khr::surface::Instance) {
+        (&self.handle, &self.loader)
+    }
+}
+
+impl Drop for Surface {
+    fn drop(&mut self) {
+        unsafe { self.loader.destroy_surface(self.handle, None) };
+    }
+}
+
+/// Creates a surface for the window with the platform's surface extension,
+/// which `utils::platforms::get_required_extensions` enabled for the same
+/// `raw_display_handle`.
 pub fn create_surface(
     entry: &ash::Entry,
     instance: &ash::Instance,
+    raw_display_handle: &RawDisplayHandle,
     raw_window_handle: &RawWindowHandle,
-) -> vk::SurfaceKHR {
-    let mut create_info = vk::Win32SurfaceCreateInfoKHR {
-        ..Default::default()
-    };
-
-    match raw_window_handle {
-        RawWindowHandle::Win32(handle) => {
-            create_info = vk::Win32SurfaceCreateInfoKHR {
+) -> engine::Result<vk::SurfaceKHR> {
+    match (raw_display_handle, raw_window_handle) {
+        (RawDisplayHandle::Windows(_), RawWindowHandle::Win32(handle)) => {
+            let create_info = vk::Win32SurfaceCreateInfoKHR {
                 hwnd: isize::from(handle.hwnd),
-                hinstance: isize::from(handle.hinstance.unwrap()),
+                hinstance: handle.hinstance.map_or(0, isize::from),
+                ..Default::default()
+            };
+            let surface_instance = ash::khr::win32_surface::Instance::new(entry, instance);
+            unsafe { surface_instance.create_win32_surface(&create_info, None) }
+                .context("create Win32 surface")
+        }
+        (RawDisplayHandle::Wayland(display), RawWindowHandle::Wayland(handle)) => {
+            let create_info = vk::WaylandSurfaceCreateInfoKHR {
+                display: display.display.as_ptr(),
+                surface: handle.surface.as_ptr(),
+                ..Default::default()
+            };
+            let surface_instance = ash::khr::wayland_surface::Instance::new(entry, instance);
+            unsafe { surface_instance.create_wayland_surface(&create_info, None) }
+                .context("create Wayland surface")
+        }
+        (RawDisplayHandle::Xcb(display), RawWindowHandle::Xcb(handle)) => {
+            let create_info = vk::XcbSurfaceCreateInfoKHR {
+                connection: display
+                    .connection
+                    .ok_or_else(|| unsupported_window(raw_display_handle, raw_window_handle))?
+                    .as_ptr(),
+                window: handle.window.get(),
+                ..Default::default()
+            };
+            let surface_instance = ash::khr::xcb_surface::Instance::new(entry, instance);
+            unsafe { surface_instance.create_xcb_surface(&create_info, None) }
+                .context("create XCB surface")
+        }
+        (RawDisplayHandle::Xlib(display), RawWindowHandle::Xlib(handle)) => {
+            let create_info = vk::XlibSurfaceCreateInfoKHR {
+                dpy: display
+                    .display
+                    .ok_or_else(|| unsupported_window(raw_display_handle, raw_window_handle))?
+                    .as_ptr(),
+                window: handle.window,
+                ..Default::default()
+            };
+            let surface_instance = ash::khr::xlib_surface::Instance::new(entry, instance);
+            unsafe { surface_instance.create_xlib_surface(&create_info, None) }
+                .context("create Xlib surface")
+        }
+        (RawDisplayHandle::AppKit(_), RawWindowHandle::AppKit(handle)) => {
+            let create_info = vk::MacOSSurfaceCreateInfoMVK {
+                p_view: handle.ns_view.as_ptr(),
                 ..Default::default()
             };
+            let surface_instance = ash::mvk::macos_surface::Instance::new(entry, instance);
+            unsafe { surface_instance.create_mac_os_surface(&create_info, None) }
+                .context("create macOS surface")
         }
-        RawWindowHandle::Xlib(handle) => println!("{}", handle.window),
-        RawWindowHandle::AppKit(_) => println!("macos window handle"),
-        _ => {}
+        _ => Err(unsupported_window(raw_display_handle, raw_window_handle)),
     }
+}
 
-    let surface_instance = ash::khr::win32_surface::Instance::new(entry, instance);
-    unsafe {
-        surface_instance
-            .create_win32_surface(&create_info, None)
-            .unwrap()
-    }
+fn unsupported_window(
+    raw_display_handle: &RawDisplayHandle,
+    raw_window_handle: &RawWindowHandle,
+) -> engine::Error {
+    engine::Error::UnsupportedWindow(format!(
+        "{:?} on {:?}",
+        raw_window_handle, raw_display_handle
+    ))
 }
@@ -1,28 +1,103 @@
+use std::rc::Rc;
+
 use ash;
 use ash::vk;
 
+use crate::engine;
+use crate::engine::error::VkResultExt;
+use crate::engine::image::ImageView;
+use crate::engine::logical_device::Device;
+use crate::engine::surface::Surface;
+
+/// The swapchain of a surface, which it owns so that the surface outlives
+/// it. Dropping it destroys the image views, the swapchain and then the
+/// surface.
 pub struct SwapChain {
     pub swap_chain: vk::SwapchainKHR,
+    pub image_usage: vk::ImageUsageFlags,
     pub swap_chain_device: ash::khr::swapchain::Device,
     pub swap_chain_images: Vec<vk::Image>,
-    pub swap_chain_image_views: Vec<vk::ImageView>,
+    pub swap_chain_image_views: Vec<ImageView>,
     pub image_format: vk::Format,
     pub extent: vk::Extent2D,
+    /// Used when the surface supports it, kept when the swapchain is
+    /// recreated. Mailbox if available when `None`.
+    pub preferred_present_mode: Option<vk::PresentModeKHR>,
+    surface: Surface,
+    device: Rc<Device>,
 }
 
 impl SwapChain {
     pub fn new(
-        device: &vk::PhysicalDevice,
-        instance: &ash::Instance,
-        logical_device: &ash::Device,
-        surface: &vk::SurfaceKHR,
-        surface_loader: &ash::khr::surface::Instance,
+        physical_device: &vk::PhysicalDevice,
+        logical_device: &Rc<Device>,
+        surface: Surface,
         window: &winit::window::Window,
-    ) -> Self {
-        let swap_chain_support = query_swap_chain_support(device, surface, surface_loader);
+        preferred_present_mode: Option<vk::PresentModeKHR>,
+    ) -> engine::Result<Self> {
+        let mut swap_chain = Self {
+            swap_chain: vk::SwapchainKHR::null(),
+            image_usage: vk::ImageUsageFlags::empty(),
+            swap_chain_device: ash::khr::swapchain::Device::new(
+                logical_device.instance(),
+                logical_device,
+            ),
+            swap_chain_images: vec![],
+            swap_chain_image_views: vec![],
+            image_format: vk::Format::UNDEFINED,
+            extent: vk::Extent2D::default(),
+            preferred_present_mode,
+            surface,
+            device: Rc::clone(logical_device),
+        };
+        swap_chain.create(physical_device, window)?;
+
+        Ok(swap_chain)
+    }
+
+    /// Rebuilds the swapchain for the current surface, e.g. after a resize or
+    /// an out of date error. The old swapchain is handed to the driver so it
+    /// can reuse its resources and is destroyed afterwards, along with its
+    /// image views. The caller must make sure the device is idle and rebuild
+    /// anything that depends on the images or the extent.
+    pub fn recreate(
+        &mut self,
+        physical_device: &vk::PhysicalDevice,
+        window: &winit::window::Window,
+    ) -> engine::Result<()> {
+        let old_swap_chain = self.swap_chain;
+        let old_image_views = std::mem::take(&mut self.swap_chain_image_views);
+
+        let result = self.create(physical_device, window);
 
+        // The old swapchain is retired even if creating the new one failed
+        drop(old_image_views);
+        unsafe {
+            self.swap_chain_device
+                .destroy_swapchain(old_swap_chain, None);
+        }
+        if result.is_err() {
+            self.swap_chain = vk::SwapchainKHR::null();
+        }
+
+        result
+    }
+
+    /// Creates the swapchain and its image views, retiring `self.swap_chain`
+    /// if there is one.
+    fn create(
+        &mut self,
+        physical_device: &vk::PhysicalDevice,
+        window: &winit::window::Window,
+    ) -> engine::Result<()> {
+        let (surface, surface_loader) = self.surface.info();
+        let swap_chain_support =
+            query_swap_chain_support(physical_device, surface, surface_loader)?;
         let surface_format = choose_swap_surface_format(&swap_chain_support.formats);
-        let present_mode = choose_swap_present_mode(&swap_chain_support.present_modes);
+        let present_mode = choose_swap_present_mode(
+            &swap_chain_support.present_modes,
+            self.preferred_present_mode,
+        );
         let extent = choose_swap_extent(&swap_chain_support.capabilities, window);
 
         let mut image_count = swap_chain_support.capabilities.min_image_count + 1;
@@ -32,6 +107,14 @@ impl SwapChain {
             image_count = swap_chain_support.capabilities.max_image_count;
         }
 
+        // Screenshots copy out of the swapchain images and the ray tracing
+        // backends blit into them, when the surface allows it. Without
+        // TRANSFER_DST `Backend::choose` picks the rasterizer.
+        let supported_usage = swap_chain_support.capabilities.supported_usage_flags;
+        let image_usage = vk::ImageUsageFlags::COLOR_ATTACHMENT
+            | (supported_usage
+                & (vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST));
+
         let mut create_info = vk::SwapchainCreateInfoKHR {
             surface: *surface,
             min_image_count: image_count,
@@ -39,19 +122,23 @@ impl SwapChain {
             image_color_space: surface_format.color_space,
             image_extent: extent,
             image_array_layers: 1,
-            image_usage: vk::ImageUsageFlags::COLOR_ATTACHMENT,
+            image_usage,
             ..Default::default()
         };
 
         let indices = crate::engine::queue_families::find_queue_families(
-            device,
-            instance,
-            surface,
-            surface_loader,
+            physical_device,
+            self.device.instance(),
+            Some((surface, surface_loader)),
         );
+        // The device was picked for presenting to this surface
         let queue_family_indices = [
-            indices.graphics_family.unwrap(),
-            indices.present_family.unwrap(),
+            indices
+                .graphics_family
+                .expect("The device has no graphics queue family!"),
+            indices
+                .present_family
+                .expect("The device can't present to the surface!"),
         ];
 
         if indices.graphics_family != indices.present_family {
@@ -66,76 +153,39 @@ impl SwapChain {
         create_info.composite_alpha = vk::CompositeAlphaFlagsKHR::OPAQUE;
         create_info.present_mode = present_mode;
         create_info.clipped = vk::TRUE;
-        create_info.old_swapchain = vk::SwapchainKHR::null();
+        create_info.old_swapchain = self.swap_chain;
 
-        let swap_chain_device = ash::khr::swapchain::Device::new(instance, logical_device);
-        let swap_chain = unsafe {
-            swap_chain_device
-                .create_swapchain(&create_info, None)
-                .expect("Failed to create swapchain!")
-        };
+        let swap_chain = unsafe { self.swap_chain_device.create_swapchain(&create_info, None) }
+            .context("create swapchain")?;
 
-        let swap_chain_images =
-            unsafe { swap_chain_device.get_swapchain_images(swap_chain).unwrap() };
+        let swap_chain_images = unsafe { self.swap_chain_device.get_swapchain_images(swap_chain) }
+            .context("get swapchain images")
+            .inspect_err(|_| unsafe {
+                self.swap_chain_device.destroy_swapchain(swap_chain, None)
+            })?;
         let swap_chain_image_views = swap_chain_images
             .iter()
-            .map(|image| {
-                let components = vk::ComponentMapping {
-                    r: vk::ComponentSwizzle::IDENTITY,
-                    g: vk::ComponentSwizzle::IDENTITY,
-                    b: vk::ComponentSwizzle::IDENTITY,
-                    a: vk::ComponentSwizzle::IDENTITY,
-                };
-                let image_subresource_range = vk::ImageSubresourceRange {
-                    aspect_mask: vk::ImageAspectFlags::COLOR,
-                    base_mip_level: 0,
-                    level_count: 1,
-                    base_array_layer: 0,
-                    layer_count: 1,
-                };
-                let create_info = vk::ImageViewCreateInfo {
-                    image: *image,
-                    view_type: vk::ImageViewType::TYPE_2D,
-                    format: surface_format.format,
-                    components: components,
-                    subresource_range: image_subresource_range,
-                    ..Default::default()
-                };
-
-                unsafe {
-                    logical_device
-                        .create_image_view(&create_info, None)
-                        .unwrap()
-                }
-            })
-            .collect();
-
-        let image_format = surface_format.format;
-        let extent = choose_swap_extent(&swap_chain_support.capabilities, window);
+            .map(|image| ImageView::new(&self.device, image, surface_format.format, 1))
+            .collect::<engine::Result<_>>()
+            .inspect_err(|_| unsafe {
+                self.swap_chain_device.destroy_swapchain(swap_chain, None)
+            })?;
 
-        Self {
-            swap_chain,
-            swap_chain_device,
-            swap_chain_images,
-            swap_chain_image_views,
-            image_format,
-            extent,
-        }
-    }
+        self.swap_chain = swap_chain;
+        self.image_usage = image_usage;
+        self.swap_chain_images = swap_chain_images;
+        self.swap_chain_image_views = swap_chain_image_views;
+        self.image_format = surface_format.format;
+        self.extent = extent;
 
-    pub fn cleanup(&mut self, logical_device: &ash::Device) {
-        unsafe {
-            for image_view in self.swap_chain_image_views.iter() {
-                logical_device.destroy_image_view(*image_view, None);
-            }
-            self.swap_chain_device
-                .destroy_swapchain(self.swap_chain, None);
-        }
+        Ok(())
     }
 }
 
 impl Drop for SwapChain {
     fn drop(&mut self) {
+        // The views go first, while the images they view still exist
+        self.swap_chain_image_views.clear();
         unsafe {
             self.swap_chain_device
                 .destroy_swapchain(self.swap_chain, None);
@@ -153,30 +203,23 @@ pub fn query_swap_chain_support(
     device: &vk::PhysicalDevice,
     surface: &vk::SurfaceKHR,
     surface_loader: &ash::khr::surface::Instance,
-) -> SwapChainSupportDetails {
-    let capabilities = unsafe {
-        surface_loader
-            .get_physical_device_surface_capabilities(*device, *surface)
-            .unwrap()
-    };
-
-    let formats = unsafe {
-        surface_loader
-            .get_physical_device_surface_formats(*device, *surface)
-            .unwrap()
-    };
-
-    let present_modes = unsafe {
-        surface_loader
-            .get_physical_device_surface_present_modes(*device, *surface)
-            .unwrap()
-    };
-
-    SwapChainSupportDetails {
+) -> engine::Result<SwapChainSupportDetails> {
+    let capabilities =
+        unsafe { surface_loader.get_physical_device_surface_capabilities(*device, *surface) }
+            .context("get surface capabilities")?;
+
+    let formats = unsafe { surface_loader.get_physical_device_surface_formats(*device, *surface) }
+        .context("get surface formats")?;
+
+    let present_modes =
+        unsafe { surface_loader.get_physical_device_surface_present_modes(*device, *surface) }
+            .context("get surface present modes")?;
+
+    Ok(SwapChainSupportDetails {
         capabilities,
         formats,
         present_modes,
-    }
+    })
 }
 
 fn choose_swap_surface_format(
@@ -193,15 +236,21 @@ fn choose_swap_surface_format(
     available_formats[0]
 }
 
+/// Uses `preferred` if available, or mailbox if none was given, and
+/// otherwise FIFO, which every surface supports. Only an unavailable
+/// `preferred` mode is worth a warning.
 fn choose_swap_present_mode(
     available_present_modes: &Vec<vk::PresentModeKHR>,
+    preferred: Option<vk::PresentModeKHR>,
 ) -> vk::PresentModeKHR {
-    for present_mode in available_present_modes.iter() {
-        if *present_mode == vk::PresentModeKHR::MAILBOX {
-            return *present_mode;
-        }
+    let mode = preferred.unwrap_or(vk::PresentModeKHR::MAILBOX);
+    if available_present_modes.contains(&mode) {
+        return mode;
     }
 
+    if let Some(preferred) = preferred {
+        eprintln!("{:?} presentation is not supported, using FIFO", preferred);
+    }
     vk::PresentModeKHR::FIFO
 }
 
@@ -0,0 +1,111 @@
+use std::rc::Rc;
+
+use ash;
+use ash::vk;
+
+use crate::engine;
+use crate::engine::error::VkResultExt;
+use crate::engine::logical_device::Device;
+
+/// The number of frames the CPU may record ahead of the GPU.
+pub const MAX_FRAMES_IN_FLIGHT: usize = 2;
+
+/// A semaphore destroyed when it is dropped.
+pub struct Semaphore {
+    pub handle: vk::Semaphore,
+    device: Rc<Device>,
+}
+
+impl Semaphore {
+    pub fn new(logical_device: &Rc<Device>) -> engine::Result<Self> {
+        let semaphore_info = vk::SemaphoreCreateInfo::default();
+        let handle = unsafe { logical_device.create_semaphore(&semaphore_info, None) }
+            .context("create semaphore")?;
+
+        Ok(Self {
+            handle,
+            device: Rc::clone(logical_device),
+        })
+    }
+}
+
+impl Drop for Semaphore {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_semaphore(self.handle, None) };
+    }
+}
+
+/// A fence destroyed when it is dropped.
+pub struct Fence {
+    pub handle: vk::Fence,
+    device: Rc<Device>,
+}
+
+impl Fence {
+    pub fn new(logical_device: &Rc<Device>, flags: vk::FenceCreateFlags) -> engine::Result<Self> {
+        let fence_info = vk::FenceCreateInfo {
+            flags,
+            ..Default::default()
+        };
+        let handle =
+            unsafe { logical_device.create_fence(&fence_info, None) }.context("create fence")?;
+
+        Ok(Self {
+            handle,
+            device: Rc::clone(logical_device),
+        })
+    }
+}
+
+impl Drop for Fence {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_fence(self.handle, None) };
+    }
+}
+
+/// Synchronization for the acquire-submit-present loop. The image available
+/// semaphores and in flight fences are per frame in flight, while the render
+/// finished semaphores are per swapchain image because the presentation
+/// engine holds on to them until that image is reacquired.
+pub struct SyncObjects {
+    pub image_available_semaphores: Vec<Semaphore>,
+    pub render_finished_semaphores: Vec<Semaphore>,
+    pub in_flight_fences: Vec<Fence>,
+}
+
+impl SyncObjects {
+    pub fn new(logical_device: &Rc<Device>, image_count: usize) -> engine::Result<Self> {
+        let create_semaphores = |count: usize| -> engine::Result<Vec<Semaphore>> {
+            (0..count).map(|_| Semaphore::new(logical_device)).collect()
+        };
+
+        let image_available_semaphores = create_semaphores(MAX_FRAMES_IN_FLIGHT)?;
+        let render_finished_semaphores = create_semaphores(image_count)?;
+        // Start signaled so the first wait on each frame doesn't block forever
+        let in_flight_fences = (0..MAX_FRAMES_IN_FLIGHT)
+            .map(|_| Fence::new(logical_device, vk::FenceCreateFlags::SIGNALED))
+            .collect::<engine::Result<_>>()?;
+
+        Ok(Self {
+            image_available_semaphores,
+            render_finished_semaphores,
+            in_flight_fences,
+        })
+    }
+
+    /// Matches the render finished semaphores to a recreated swapchain,
+    /// which may have a different number of images. The device must be idle.
+    pub fn resize_render_finished_semaphores(
+        &mut self,
+        logical_device: &Rc<Device>,
+        image_count: usize,
+    ) -> engine::Result<()> {
+        self.render_finished_semaphores.truncate(image_count);
+        while self.render_finished_semaphores.len() < image_count {
+            self.render_finished_semaphores
+                .push(Semaphore::new(logical_device)?);
+        }
+
+        Ok(())
+    }
+}
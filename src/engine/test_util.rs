@@ -0,0 +1,33 @@
+use std::f32::consts::PI;
+
+use glam::{Vec2, Vec3};
+
+/// Deterministic pseudo-random numbers in `0.0..1.0`, so the tests don't
+/// need a random number crate.
+pub struct Lcg(pub u64);
+
+impl Lcg {
+    pub fn next_f32(&mut self) -> f32 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    pub fn next_vec2(&mut self) -> Vec2 {
+        Vec2::new(self.next_f32(), self.next_f32())
+    }
+
+    pub fn next_vec3(&mut self) -> Vec3 {
+        Vec3::new(self.next_f32(), self.next_f32(), self.next_f32())
+    }
+
+    /// Uniform on the sphere
+    pub fn next_direction(&mut self) -> Vec3 {
+        let z = 1.0 - 2.0 * self.next_f32();
+        let radius = (1.0 - z * z).max(0.0).sqrt();
+        let phi = 2.0 * PI * self.next_f32();
+        Vec3::new(radius * phi.cos(), radius * phi.sin(), z)
+    }
+}
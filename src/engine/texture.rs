@@ -0,0 +1,391 @@
+use ash;
+use ash::vk;
+
+use std::rc::Rc;
+
+use crate::engine;
+use crate::engine::allocator::Allocator;
+use crate::engine::error::VkResultExt;
+use crate::engine::image::{Image, ImageView};
+use crate::engine::logical_device::Device;
+use crate::scene::mesh::SceneGeometry;
+use crate::scene::texture::{Sampler, Texture, TextureFormat};
+use crate::scene::MaterialModel;
+
+/// Number of mip levels in a full chain down to a single texel.
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+    u32::BITS - width.max(height).max(1).leading_zeros()
+}
+
+/// A texture in a device-local image with its mip chain, in
+/// `SHADER_READ_ONLY_OPTIMAL`, and the sampler it's read with.
+pub struct GpuTexture {
+    pub image: Image,
+    pub image_view: ImageView,
+    pub sampler: GpuSampler,
+    pub format: vk::Format,
+    pub mip_levels: u32,
+}
+
+impl GpuTexture {
+    /// Uploads `texture` through a staging buffer and generates its mip
+    /// levels by blitting each from the one above. `is_color` textures hold
+    /// sRGB encoded colors, which the sampler decodes. Formats that can't be
+    /// filtered linearly keep only their base level and are sampled with
+    /// nearest filtering.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        instance: &ash::Instance,
+        physical_device: &vk::PhysicalDevice,
+        logical_device: &Rc<Device>,
+        allocator: &Rc<Allocator>,
+        command_pool: &vk::CommandPool,
+        queue: &vk::Queue,
+        texture: &Texture,
+        is_color: bool,
+    ) -> engine::Result<Self> {
+        let format = match texture.format {
+            TextureFormat::Rgba8 if is_color => vk::Format::R8G8B8A8_SRGB,
+            TextureFormat::Rgba8 => vk::Format::R8G8B8A8_UNORM,
+            TextureFormat::Rgba32Float => vk::Format::R32G32B32A32_SFLOAT,
+        };
+        let features = unsafe {
+            instance
+                .get_physical_device_format_properties(*physical_device, format)
+                .optimal_tiling_features
+        };
+        let is_filterable = features.contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR);
+        let can_blit =
+            features.contains(vk::FormatFeatureFlags::BLIT_SRC | vk::FormatFeatureFlags::BLIT_DST);
+        let mip_levels = if texture.sampler.use_mipmaps && is_filterable && can_blit {
+            mip_level_count(texture.width, texture.height)
+        } else {
+            1
+        };
+
+        let staging_buffer = engine::buffer::create_buffer_with_data(
+            allocator,
+            &texture.pixels,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+        )?;
+        let extent = vk::Extent2D {
+            width: texture.width,
+            height: texture.height,
+        };
+        let image = engine::image::create_image(
+            allocator,
+            extent,
+            mip_levels,
+            format,
+            vk::ImageUsageFlags::TRANSFER_SRC
+                | vk::ImageUsageFlags::TRANSFER_DST
+                | vk::ImageUsageFlags::SAMPLED,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )?;
+
+        let command_buffer =
+            engine::commands::begin_single_time_commands(logical_device, command_pool)?;
+        engine::image::transition_mip_levels(
+            logical_device,
+            &command_buffer,
+            &image.handle,
+            0,
+            mip_levels,
+            vk::ImageLayout::UNDEFINED,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        );
+        let region = vk::BufferImageCopy {
+            buffer_offset: 0,
+            buffer_row_length: 0,
+            buffer_image_height: 0,
+            image_subresource: mip_subresource(0),
+            image_offset: vk::Offset3D::default(),
+            image_extent: vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            },
+        };
+        unsafe {
+            logical_device.cmd_copy_buffer_to_image(
+                command_buffer,
+                staging_buffer.handle,
+                image.handle,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[region],
+            );
+        }
+        record_mip_chain(
+            logical_device,
+            &command_buffer,
+            &image.handle,
+            extent,
+            mip_levels,
+        );
+        engine::commands::end_single_time_commands(
+            logical_device,
+            command_pool,
+            queue,
+            command_buffer,
+        )?;
+
+        let image_view = ImageView::new(logical_device, &image.handle, format, mip_levels)?;
+        let sampler = create_sampler(logical_device, &texture.sampler, mip_levels, is_filterable)?;
+
+        Ok(Self {
+            image,
+            image_view,
+            sampler,
+            format,
+            mip_levels,
+        })
+    }
+}
+
+fn mip_subresource(mip_level: u32) -> vk::ImageSubresourceLayers {
+    vk::ImageSubresourceLayers {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        mip_level,
+        base_array_layer: 0,
+        layer_count: 1,
+    }
+}
+
+/// Records filling mip levels 1 and up from level 0, which must hold the
+/// texture, with all levels in `TRANSFER_DST_OPTIMAL`. Every level ends up in
+/// `SHADER_READ_ONLY_OPTIMAL`.
+fn record_mip_chain(
+    logical_device: &ash::Device,
+    command_buffer: &vk::CommandBuffer,
+    image: &vk::Image,
+    extent: vk::Extent2D,
+    mip_levels: u32,
+) {
+    let corner = |level: u32| vk::Offset3D {
+        x: (extent.width >> level).max(1) as i32,
+        y: (extent.height >> level).max(1) as i32,
+        z: 1,
+    };
+
+    for level in 1..mip_levels {
+        engine::image::transition_mip_levels(
+            logical_device,
+            command_buffer,
+            image,
+            level - 1,
+            1,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        );
+        let region = vk::ImageBlit {
+            src_subresource: mip_subresource(level - 1),
+            src_offsets: [vk::Offset3D::default(), corner(level - 1)],
+            dst_subresource: mip_subresource(level),
+            dst_offsets: [vk::Offset3D::default(), corner(level)],
+        };
+        unsafe {
+            logical_device.cmd_blit_image(
+                *command_buffer,
+                *image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                *image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[region],
+                vk::Filter::LINEAR,
+            );
+        }
+        engine::image::transition_mip_levels(
+            logical_device,
+            command_buffer,
+            image,
+            level - 1,
+            1,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        );
+    }
+
+    // The last level was only ever written to
+    engine::image::transition_mip_levels(
+        logical_device,
+        command_buffer,
+        image,
+        mip_levels - 1,
+        1,
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+    );
+}
+
+/// A sampler, destroyed when it is dropped.
+pub struct GpuSampler {
+    pub handle: vk::Sampler,
+    device: Rc<Device>,
+}
+
+impl Drop for GpuSampler {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_sampler(self.handle, None) };
+    }
+}
+
+fn create_sampler(
+    logical_device: &Rc<Device>,
+    sampler: &Sampler,
+    mip_levels: u32,
+    is_filterable: bool,
+) -> engine::Result<GpuSampler> {
+    let filter = |filter: vk::Filter| {
+        if is_filterable {
+            filter
+        } else {
+            vk::Filter::NEAREST
+        }
+    };
+    let create_info = vk::SamplerCreateInfo {
+        mag_filter: filter(sampler.mag_filter),
+        min_filter: filter(sampler.min_filter),
+        mipmap_mode: sampler.mipmap_mode,
+        address_mode_u: sampler.address_mode_u,
+        address_mode_v: sampler.address_mode_v,
+        address_mode_w: vk::SamplerAddressMode::REPEAT,
+        min_lod: 0.0,
+        max_lod: (mip_levels - 1) as f32,
+        border_color: vk::BorderColor::FLOAT_OPAQUE_BLACK,
+        ..Default::default()
+    };
+
+    let handle =
+        unsafe { logical_device.create_sampler(&create_info, None) }.context("create sampler")?;
+
+    Ok(GpuSampler {
+        handle,
+        device: Rc::clone(logical_device),
+    })
+}
+
+/// The textures of a scene in the order of `SceneGeometry::textures`, then
+/// the environment map if there is one, bound as one array of combined image
+/// samplers that materials index into. Scenes without textures get a single
+/// white texel, so the array is never empty.
+pub struct TextureArray {
+    pub textures: Vec<GpuTexture>,
+    /// Index of the environment map
+    pub environment: Option<u32>,
+}
+
+impl TextureArray {
+    pub fn new(
+        instance: &ash::Instance,
+        physical_device: &vk::PhysicalDevice,
+        logical_device: &Rc<Device>,
+        allocator: &Rc<Allocator>,
+        command_pool: &vk::CommandPool,
+        queue: &vk::Queue,
+        geometry: &SceneGeometry,
+    ) -> engine::Result<Self> {
+        let environment_texture = geometry
+            .environment
+            .as_ref()
+            .map(|environment| (&environment.texture, true));
+        let mut textures: Vec<GpuTexture> = geometry
+            .textures
+            .iter()
+            .zip(color_textures(geometry))
+            .chain(environment_texture)
+            .map(|(texture, is_color)| {
+                GpuTexture::new(
+                    instance,
+                    physical_device,
+                    logical_device,
+                    allocator,
+                    command_pool,
+                    queue,
+                    texture,
+                    is_color,
+                )
+            })
+            .collect::<engine::Result<_>>()?;
+        let environment = environment_texture.map(|_| geometry.textures.len() as u32);
+
+        if textures.is_empty() {
+            let white = Texture {
+                name: Some("white".to_string()),
+                width: 1,
+                height: 1,
+                format: TextureFormat::Rgba8,
+                pixels: vec![u8::MAX; 4],
+                sampler: Sampler::default(),
+            };
+            textures.push(GpuTexture::new(
+                instance,
+                physical_device,
+                logical_device,
+                allocator,
+                command_pool,
+                queue,
+                &white,
+                false,
+            )?);
+        }
+
+        Ok(Self {
+            textures,
+            environment,
+        })
+    }
+
+    /// The number of descriptors in the array.
+    pub fn descriptor_count(&self) -> u32 {
+        self.textures.len() as u32
+    }
+
+    /// One image info per texture, for writing the array's descriptors.
+    pub fn image_infos(&self) -> Vec<vk::DescriptorImageInfo> {
+        self.textures
+            .iter()
+            .map(|texture| vk::DescriptorImageInfo {
+                sampler: texture.sampler.handle,
+                image_view: texture.image_view.handle,
+                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            })
+            .collect()
+    }
+}
+
+/// Whether each texture of `geometry` is read as a base color or emission,
+/// which glTF stores sRGB encoded, rather than as data.
+fn color_textures(geometry: &SceneGeometry) -> Vec<bool> {
+    let mut is_color = vec![false; geometry.textures.len()];
+    for material in &geometry.materials {
+        if let MaterialModel::MetallicRoughness {
+            base_color_texture,
+            emissive_texture,
+            ..
+        } = material.model
+        {
+            for texture in [base_color_texture, emissive_texture].into_iter().flatten() {
+                if let Some(is_color) = is_color.get_mut(texture) {
+                    *is_color = true;
+                }
+            }
+        }
+    }
+
+    is_color
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mip_chain_ends_at_one_texel() {
+        assert_eq!(mip_level_count(1, 1), 1);
+        assert_eq!(mip_level_count(2, 2), 2);
+        assert_eq!(mip_level_count(256, 256), 9);
+        // Rectangular and odd sizes round down at every level
+        assert_eq!(mip_level_count(300, 20), 9);
+        assert_eq!(mip_level_count(0, 0), 1);
+    }
+}
@@ -0,0 +1,13 @@
+//! A Vulkan path tracer for scenes in TOML or glTF, rendering with the ray
+//! tracing pipeline, a compute shader BVH tracer or the rasterizer.
+//!
+//! [`Renderer`] is the entry point. The modules below it are the building
+//! blocks it is made of, for applications that need more control.
+
+pub mod camera;
+pub mod engine;
+mod renderer;
+pub mod scene;
+pub mod utils;
+
+pub use renderer::{Renderer, RendererOptions};
@@ -1,157 +1,139 @@
 use winit::application::ApplicationHandler;
-use winit::event::{ElementState, KeyEvent, WindowEvent};
+use winit::event::{DeviceEvent, ElementState, KeyEvent, MouseButton, WindowEvent};
 use winit::event_loop::{ActiveEventLoop, EventLoop};
 use winit::keyboard::{Key, NamedKey};
-use winit::raw_window_handle::HasWindowHandle;
 use winit::window::{Theme, Window};
 
-use ash;
-use ash::vk;
+mod cli;
 
-pub mod engine;
-pub mod utils;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::Parser;
+use cli::Args;
+use vulkan_ray_tracer::camera;
+use vulkan_ray_tracer::engine;
+use vulkan_ray_tracer::engine::backend::Backend;
+use vulkan_ray_tracer::engine::path_tracing::PathTracingSettings;
+use vulkan_ray_tracer::scene::{self, mesh::SceneGeometry, Scene};
+use vulkan_ray_tracer::utils::image_export::{self, ExportError, ImageFileFormat};
+use vulkan_ray_tracer::Renderer;
 
 struct VulkanApp {
-    props: Option<VulkanAppProperties>,
-    is_debug_enabled: bool,
+    renderer: Option<Renderer>,
+    args: Args,
+    scene: Scene,
+    /// Handed to the renderer once the window exists
+    geometry: Option<SceneGeometry>,
+    /// The file the scene was loaded from, reloaded when it changes
+    scene_path: Option<PathBuf>,
+    scene_modified: Option<SystemTime>,
+    /// Why the event loop was stopped, if it was because of an error
+    error: Option<engine::Error>,
 }
 
 impl VulkanApp {
-    fn new(is_debug_enabled: bool) -> Self {
+    fn new(args: Args, scene: Scene, geometry: SceneGeometry) -> Self {
+        let scene_path = args.scene.clone();
+        let scene_modified = scene_path.as_deref().and_then(modified_time);
         VulkanApp {
-            props: None,
-            is_debug_enabled: is_debug_enabled,
+            renderer: None,
+            args,
+            scene,
+            geometry: Some(geometry),
+            scene_path,
+            scene_modified,
+            error: None,
+        }
+    }
+
+    fn init_vulkan(&mut self, event_loop: &ActiveEventLoop, window: Window) {
+        let Some(geometry) = self.geometry.take() else {
+            return;
+        };
+        match Renderer::new(window, &self.scene, geometry, &self.args.renderer_options()) {
+            Ok(renderer) => {
+                println!("Using the {:?} backend", renderer.backend());
+                self.renderer = Some(renderer);
+            }
+            Err(error) => self.fail(event_loop, error),
         }
     }
 
-    fn init_vulkan(&mut self, window: Window) {
-        let props = VulkanAppProperties::new(window, self.is_debug_enabled);
-        self.props = Some(props);
+    /// Releases the Vulkan resources and stops the event loop, after which
+    /// `main` reports `error`.
+    fn fail(&mut self, event_loop: &ActiveEventLoop, error: engine::Error) {
+        self.renderer = None;
+        self.error = Some(error);
+        event_loop.exit();
     }
 
-    fn init_window(event_loop: &ActiveEventLoop) -> Window {
+    fn init_window(event_loop: &ActiveEventLoop, scene: &Scene) -> engine::Result<Window> {
         let window_attributes = Window::default_attributes()
             .with_theme(Some(Theme::Dark))
-            .with_inner_size(winit::dpi::LogicalSize::new(800.0, 800.0))
+            .with_inner_size(winit::dpi::LogicalSize::new(
+                scene.render.width as f64,
+                scene.render.height as f64,
+            ))
             .with_title("Vulkan Ray Tracer");
-        event_loop.create_window(window_attributes).unwrap()
+        event_loop
+            .create_window(window_attributes)
+            .map_err(engine::Error::window("create window"))
     }
 
-    fn draw_frame(&mut self) {}
-}
-
-struct VulkanAppProperties {
-    _window: Window,
-    _entry: ash::Entry,
-    instance: ash::Instance,
-    is_debug_enabled: bool,
-    debug_messenger: vk::DebugUtilsMessengerEXT,
-    debug_utils_loader: ash::ext::debug_utils::Instance,
-    _physical_device: vk::PhysicalDevice,
-    logical_device: ash::Device,
-    _graphics_queue: vk::Queue,
-    _present_queue: vk::Queue,
-    _surface_loader: ash::khr::surface::Instance,
-    _surface: vk::SurfaceKHR,
-    _swap_chain: engine::swap_chain::SwapChain,
-}
-
-impl VulkanAppProperties {
-    // init_vulkan
-    fn new(window: Window, is_debug_enabled: bool) -> Self {
-        // Create an instance
-        let entry = ash::Entry::linked();
-        let instance = engine::instance::create_instance(&entry, is_debug_enabled);
-
-        // Setup the debug manager
-        let (debug_utils_loader, debug_messenger) =
-            utils::debug::setup_debug_utils(true, &entry, &instance);
-
-        // Create the surface
-        let raw_window_handle = window.window_handle().unwrap().as_raw();
-        let surface = engine::surface::create_surface(&entry, &instance, &raw_window_handle);
-        let surface_loader = ash::khr::surface::Instance::new(&entry, &instance);
-
-        // Create the physical device
-        let physical_device =
-            engine::physical_device::pick_physical_device(&instance, &surface, &surface_loader);
-
-        // Create the logical device
-        let logical_device = engine::logical_device::create_logical_device(
-            &physical_device,
-            &instance,
-            &surface,
-            &surface_loader,
-        );
-
-        let indices = engine::queue_families::find_queue_families(
-            &physical_device,
-            &instance,
-            &surface,
-            &surface_loader,
-        );
-        let graphics_queue =
-            unsafe { logical_device.get_device_queue(indices.graphics_family.unwrap(), 0) };
-        let present_queue =
-            unsafe { logical_device.get_device_queue(indices.present_family.unwrap(), 0) };
-
-        // Create swap chain and image views
-        let swap_chain = engine::swap_chain::SwapChain::new(
-            &physical_device,
-            &instance,
-            &logical_device,
-            &surface,
-            &surface_loader,
-            &window,
-        );
-
-        // Create graphics pipeline
-
-        VulkanAppProperties {
-            _window: window,
-            _entry: entry,
-            instance,
-            is_debug_enabled: is_debug_enabled,
-            debug_messenger,
-            debug_utils_loader,
-            _physical_device: physical_device,
-            logical_device,
-            _graphics_queue: graphics_queue,
-            _present_queue: present_queue,
-            _surface: surface,
-            _surface_loader: surface_loader,
-            _swap_chain: swap_chain,
+    fn draw_frame(&mut self, event_loop: &ActiveEventLoop) {
+        let Some(renderer) = self.renderer.as_mut() else {
+            return;
+        };
+        if let Err(error) = renderer.render_frame() {
+            self.fail(event_loop, error);
         }
     }
-}
-
-impl Drop for VulkanAppProperties {
-    fn drop(&mut self) {
-        unsafe {
-            println!("Destroying instance");
 
-            // Logical Device
-            // Would be better to call drop but I'm not sure how to do so since
-            // self is already &mut
-            self._swap_chain.cleanup(&self.logical_device);
-            self.logical_device.destroy_device(None);
+    /// Loads the scene file again if it was saved since it was last loaded.
+    /// A file that fails to load is reported and the previous scene kept.
+    fn reload_scene_if_changed(&mut self) {
+        let Some(path) = self.scene_path.as_deref() else {
+            return;
+        };
+        let modified = modified_time(path);
+        if modified == self.scene_modified {
+            return;
+        }
+        self.scene_modified = modified;
 
-            if self.is_debug_enabled {
-                self.debug_utils_loader
-                    .destroy_debug_utils_messenger(self.debug_messenger, None);
+        match scene::load(path) {
+            Ok((mut scene, geometry)) => {
+                self.args.apply_to(&mut scene.render);
+                match self.renderer.as_mut() {
+                    Some(renderer) => {
+                        if let Err(error) = renderer.load_scene(&scene, geometry) {
+                            report_error(&error);
+                            return;
+                        }
+                    }
+                    None => self.geometry = Some(geometry),
+                }
+                println!("Reloaded {}", path.display());
+                self.scene = scene;
             }
-
-            // Physical Device
-            self._surface_loader.destroy_surface(self._surface, None);
-            self.instance.destroy_instance(None);
+            Err(error) => eprintln!("{}", error),
         }
     }
 }
 
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
 impl ApplicationHandler for VulkanApp {
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {
-        let window = Self::init_window(event_loop);
-        self.init_vulkan(window);
+        match Self::init_window(event_loop, &self.scene) {
+            Ok(window) => self.init_vulkan(event_loop, window),
+            Err(error) => self.fail(event_loop, error),
+        }
     }
 
     fn window_event(
@@ -162,36 +144,258 @@ impl ApplicationHandler for VulkanApp {
     ) {
         self.main_loop(event_loop, event);
     }
+
+    fn device_event(
+        &mut self,
+        _event_loop: &ActiveEventLoop,
+        _device_id: winit::event::DeviceId,
+        event: DeviceEvent,
+    ) {
+        // Raw motion keeps coming when the cursor is locked in place
+        if let DeviceEvent::MouseMotion { delta } = event {
+            if let Some(renderer) = self.renderer.as_mut() {
+                renderer.camera_controller.handle_mouse_motion(delta);
+            }
+        }
+    }
+
+    fn about_to_wait(&mut self, _event_loop: &ActiveEventLoop) {
+        self.reload_scene_if_changed();
+
+        // Render continuously rather than only when the OS asks for a redraw
+        if let Some(renderer) = self.renderer.as_ref() {
+            renderer.request_redraw();
+        }
+    }
 }
 
 impl VulkanApp {
+    /// Applies `change` to the path tracing settings. The accumulation starts
+    /// over on the next frame if the change affects the image.
+    fn change_path_tracing(&mut self, change: impl FnOnce(&mut PathTracingSettings)) {
+        let Some(renderer) = self.renderer.as_mut() else {
+            return;
+        };
+        change(&mut renderer.path_tracing);
+        let settings = &renderer.path_tracing;
+        println!(
+            "Max bounces: {}, samples per frame: {}, Russian roulette: {}",
+            settings.max_bounces,
+            settings.samples_per_frame,
+            if settings.russian_roulette {
+                "on"
+            } else {
+                "off"
+            }
+        );
+    }
+
+    fn save_screenshot(&mut self) {
+        let Some(renderer) = self.renderer.as_mut() else {
+            return;
+        };
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let path = PathBuf::from(format!("screenshot-{}.png", timestamp));
+        match save_frame(renderer, &path) {
+            Ok(true) => println!("Saved screenshot to {}", path.display()),
+            Ok(false) => eprintln!(
+                "Nothing to save: the window is minimized, or the surface doesn't allow \
+                 copying from swapchain images"
+            ),
+            Err(error) => eprintln!("Failed to save screenshot: {}", error),
+        }
+    }
+
+    /// Movement keys are handled for as long as they are held, the others
+    /// when pressed.
+    fn handle_key(&mut self, event_loop: &ActiveEventLoop, event: KeyEvent) {
+        if let Some(renderer) = self.renderer.as_mut() {
+            if renderer
+                .camera_controller
+                .handle_key(event.physical_key, event.state)
+            {
+                return;
+            }
+        }
+        if event.state != ElementState::Pressed {
+            return;
+        }
+
+        match event.logical_key.as_ref() {
+            Key::Named(NamedKey::Escape) => {
+                // The first press frees a grabbed cursor
+                match self.renderer.as_mut() {
+                    Some(renderer) if renderer.camera_controller.is_cursor_grabbed() => {
+                        renderer.set_cursor_grabbed(false)
+                    }
+                    _ => event_loop.exit(),
+                }
+            }
+            Key::Named(NamedKey::Tab) => {
+                if let Some(renderer) = self.renderer.as_mut() {
+                    renderer.camera.toggle_orbit();
+                    println!("Camera mode: {:?}", renderer.camera.mode);
+                }
+            }
+            Key::Named(NamedKey::F12) => self.save_screenshot(),
+            Key::Character("]") => self.change_path_tracing(|settings| {
+                settings.max_bounces += 1;
+            }),
+            Key::Character("[") => self.change_path_tracing(|settings| {
+                settings.max_bounces = settings.max_bounces.saturating_sub(1);
+            }),
+            Key::Character(".") => self.change_path_tracing(|settings| {
+                settings.samples_per_frame += 1;
+            }),
+            Key::Character(",") => self.change_path_tracing(|settings| {
+                settings.samples_per_frame = settings.samples_per_frame.saturating_sub(1).max(1);
+            }),
+            Key::Character("r") => self.change_path_tracing(|settings| {
+                settings.russian_roulette = !settings.russian_roulette;
+            }),
+            _ => {}
+        }
+    }
+
     fn main_loop(&mut self, event_loop: &ActiveEventLoop, event: WindowEvent) {
         match event {
             WindowEvent::CloseRequested => event_loop.exit(),
-            WindowEvent::RedrawRequested => {
-                // self.window.as_ref().request_redraw();
-                self.draw_frame();
+            WindowEvent::RedrawRequested => self.draw_frame(event_loop),
+            WindowEvent::Resized(_) => {
+                if let Some(renderer) = self.renderer.as_mut() {
+                    renderer.resized();
+                }
             }
-            WindowEvent::KeyboardInput {
-                event:
-                    KeyEvent {
-                        logical_key: key,
-                        state: ElementState::Pressed,
-                        ..
-                    },
+            WindowEvent::KeyboardInput { event, .. } => self.handle_key(event_loop, event),
+            WindowEvent::MouseInput {
+                state: ElementState::Pressed,
+                button: MouseButton::Left,
                 ..
-            } => match key {
-                Key::Named(NamedKey::Escape) => event_loop.exit(),
-                _ => {}
-            },
+            } => {
+                if let Some(renderer) = self.renderer.as_mut() {
+                    renderer.set_cursor_grabbed(true);
+                }
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                if let Some(renderer) = self.renderer.as_mut() {
+                    renderer.camera.change_speed(camera::scroll_lines(delta));
+                    println!("Camera speed: {}", renderer.camera.speed);
+                }
+            }
+            // Keys released and the cursor moved while another window has
+            // focus aren't seen
+            WindowEvent::Focused(false) => {
+                if let Some(renderer) = self.renderer.as_mut() {
+                    renderer.camera_controller.release_keys();
+                    renderer.set_cursor_grabbed(false);
+                }
+            }
             _ => {}
         }
     }
 }
 
 pub fn main() {
-    let event_loop = EventLoop::new().unwrap();
-    let mut vulkan_app = VulkanApp::new(true);
+    let args = Args::parse();
+
+    if args.list_devices {
+        let listed = engine::instance::Instance::new(ash::Entry::linked(), false, None)
+            .and_then(|instance| engine::physical_device::device_list(&instance));
+        match listed {
+            Ok(list) => println!("{}", list),
+            Err(error) => exit_with_error(&error),
+        }
+        return;
+    }
+
+    let loaded = match args.scene.as_deref() {
+        Some(path) => scene::load(path),
+        None => {
+            let scene = Scene::default();
+            scene.load_geometry().map(|geometry| (scene, geometry))
+        }
+    };
+    let (mut scene, geometry) = match loaded {
+        Ok(loaded) => loaded,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    };
+    args.apply_to(&mut scene.render);
+
+    if args.headless {
+        let mut renderer = Renderer::headless(&scene, geometry, &args.renderer_options())
+            .unwrap_or_else(|error| exit_with_error(&error));
+        println!("Using the {:?} backend", renderer.backend());
+        if let Err(error) = renderer.render_to_completion() {
+            drop(renderer);
+            exit_with_error(&error);
+        }
+        if renderer.backend() != Backend::Rasterizer {
+            println!(
+                "Rendered {} samples per pixel",
+                renderer.accumulated_samples()
+            );
+        }
+
+        let error = match save_frame(&mut renderer, &args.output) {
+            Ok(true) => {
+                println!("Saved headless render to {}", args.output.display());
+                return;
+            }
+            Ok(false) => "there is no frame to read back".to_string(),
+            Err(error) => error.to_string(),
+        };
+        eprintln!("Failed to save headless render: {}", error);
+        drop(renderer);
+        std::process::exit(1);
+    }
+
+    let event_loop = EventLoop::new()
+        .map_err(engine::Error::window("create event loop"))
+        .unwrap_or_else(|error| exit_with_error(&error));
+    let mut vulkan_app = VulkanApp::new(args, scene, geometry);
+
+    let result = event_loop
+        .run_app(&mut vulkan_app)
+        .map_err(engine::Error::window("run event loop"));
+    if let Some(error) = vulkan_app.error.take().or(result.err()) {
+        exit_with_error(&error);
+    }
+}
+
+/// Writes the current frame of `renderer` to `path`, or returns false when
+/// there is no frame that can be read back. OpenEXR files get the averaged
+/// samples in linear floats rather than the tonemapped 8-bit frame.
+fn save_frame(renderer: &mut Renderer, path: &Path) -> Result<bool, ExportError> {
+    let accumulation = match ImageFileFormat::from_path(path) {
+        Some(ImageFileFormat::Exr) => renderer.read_back_accumulation()?,
+        _ => None,
+    };
+    let image = match accumulation {
+        Some(image) => Some(image),
+        None => renderer.read_back()?,
+    };
+    match image {
+        Some(image) => image_export::save_image(path, &image).map(|()| true),
+        None => Ok(false),
+    }
+}
+
+/// Prints `error` and what can be done about it.
+fn report_error(error: &engine::Error) {
+    eprintln!("{}", error);
+    if let Some(hint) = error.hint() {
+        eprintln!("Hint: {}", hint);
+    }
+}
 
-    let _ = event_loop.run_app(&mut vulkan_app);
+fn exit_with_error(error: &engine::Error) -> ! {
+    report_error(error);
+    std::process::exit(1);
 }
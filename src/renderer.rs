@@ -0,0 +1,844 @@
+//! The public entry point of the ray tracer: a [`Renderer`] draws a scene
+//! into a window or, headless, into an offscreen image that can be read
+//! back.
+
+use std::rc::Rc;
+use std::time::Instant;
+
+use ash::vk;
+use winit::raw_window_handle::{
+    HasDisplayHandle, HasWindowHandle, RawDisplayHandle, RawWindowHandle,
+};
+use winit::window::Window;
+
+use crate::camera::{Camera, CameraController};
+use crate::engine;
+use crate::engine::allocator::Allocator;
+use crate::engine::backend::{Backend, Tracer};
+use crate::engine::buffer::Buffer;
+use crate::engine::commands::CommandPool;
+use crate::engine::error::VkResultExt;
+use crate::engine::framebuffer::Framebuffer;
+use crate::engine::instance::Instance;
+use crate::engine::logical_device::Device;
+use crate::engine::path_tracing::{Accumulation, FrameConstants, PathTracingSettings};
+use crate::engine::physical_device::DeviceRequest;
+use crate::engine::pipeline::Pipeline;
+use crate::engine::render_pass::RenderPass;
+use crate::engine::surface::Surface;
+use crate::engine::swap_chain::SwapChain;
+use crate::scene::mesh::SceneGeometry;
+use crate::scene::Scene;
+use crate::utils::image_export::{self, ExportError, HostImage};
+
+/// How a [`Renderer`] sets up Vulkan.
+#[derive(Debug, Clone)]
+pub struct RendererOptions {
+    /// Enables the Khronos validation layer and the debug messenger
+    pub validation: bool,
+    /// Chosen from the device capabilities when `None`
+    pub backend: Option<Backend>,
+    /// The best scoring suitable device is used when `None`
+    pub device: Option<DeviceRequest>,
+    /// Falls back to FIFO when the surface doesn't support it. Mailbox is
+    /// used when available and `None`, without warning otherwise.
+    pub present_mode: Option<vk::PresentModeKHR>,
+    /// Prints the devices considered and the one picked
+    pub verbose: bool,
+}
+
+impl Default for RendererOptions {
+    fn default() -> Self {
+        Self {
+            validation: true,
+            backend: None,
+            device: None,
+            present_mode: None,
+            verbose: false,
+        }
+    }
+}
+
+/// Renders a scene with one of the backends, accumulating samples across
+/// frames for as long as the camera and the settings stay the same.
+///
+/// ```no_run
+/// use vulkan_ray_tracer::{Renderer, RendererOptions};
+///
+/// let (scene, geometry) = vulkan_ray_tracer::scene::load("scene.toml".as_ref())?;
+/// let mut renderer = Renderer::headless(&scene, geometry, &RendererOptions::default())?;
+/// renderer.render_to_completion()?;
+/// let image = renderer.read_back()?.expect("headless renders can be read back");
+/// vulkan_ray_tracer::utils::image_export::save_image("render.png".as_ref(), &image)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct Renderer {
+    /// What the tracer traces, moved to the shutter time of each frame
+    geometry: SceneGeometry,
+    instance: Rc<Instance>,
+    physical_device: vk::PhysicalDevice,
+    logical_device: Rc<Device>,
+    /// Backs every buffer and image, freed once the last of them is dropped
+    allocator: Rc<Allocator>,
+    graphics_queue: vk::Queue,
+    present_queue: Option<vk::Queue>,
+    /// Owns the surface of the window
+    swap_chain: Option<SwapChain>,
+    offscreen: Option<engine::offscreen::OffscreenTarget>,
+    render_pass: RenderPass,
+    graphics_pipeline: Pipeline,
+    /// One per swapchain image, or a single one for the offscreen image
+    framebuffers: Vec<Framebuffer>,
+    /// Frees `command_buffers` when it is dropped
+    command_pool: CommandPool,
+    command_buffers: Vec<vk::CommandBuffer>,
+    sync_objects: engine::sync::SyncObjects,
+    backend: Backend,
+    /// Replaces the rasterized draw when a ray tracing backend is in use
+    tracer: Option<Tracer>,
+    pub camera: Camera,
+    /// Moves `camera` with the keyboard and mouse
+    pub camera_controller: CameraController,
+    /// When the camera was last moved, to move it by the time since
+    last_camera_update: Instant,
+    /// Changes that affect the image start the accumulation over. Scenes
+    /// with motion take one sample per frame regardless of
+    /// `samples_per_frame`, see `frame_settings`.
+    pub path_tracing: PathTracingSettings,
+    accumulation: Accumulation,
+    current_frame: usize,
+    /// Set when the window reports a new size, since not every platform
+    /// returns `ERROR_OUT_OF_DATE_KHR` after a resize
+    framebuffer_resized: bool,
+    /// Last, so that it outlives the surface of the swapchain
+    window: Option<Window>,
+}
+
+impl Renderer {
+    /// Creates a renderer presenting to `window`, whose size the frames
+    /// follow.
+    pub fn new(
+        window: Window,
+        scene: &Scene,
+        geometry: SceneGeometry,
+        options: &RendererOptions,
+    ) -> engine::Result<Self> {
+        Self::create(Some(window), scene, geometry, options)
+    }
+
+    /// Creates a renderer without a surface or swapchain, which renders into
+    /// an offscreen image of the scene's render resolution instead.
+    pub fn headless(
+        scene: &Scene,
+        geometry: SceneGeometry,
+        options: &RendererOptions,
+    ) -> engine::Result<Self> {
+        Self::create(None, scene, geometry, options)
+    }
+
+    fn create(
+        window: Option<Window>,
+        scene: &Scene,
+        geometry: SceneGeometry,
+        options: &RendererOptions,
+    ) -> engine::Result<Self> {
+        let is_headless = window.is_none();
+        let is_debug_enabled = options.validation;
+        let requested_backend = options.backend;
+
+        // Create an instance, with the debug messenger when validating
+        let handles = window.as_ref().map(raw_handles).transpose()?;
+        let instance = Rc::new(Instance::new(
+            ash::Entry::linked(),
+            is_debug_enabled,
+            handles.as_ref().map(|(display_handle, _)| display_handle),
+        )?);
+
+        // Create the surface
+        let surface = handles
+            .as_ref()
+            .map(|(display_handle, window_handle)| {
+                Surface::new(&instance, display_handle, window_handle)
+            })
+            .transpose()?;
+        let surface_info = surface.as_ref().map(Surface::info);
+
+        // Create the physical device
+        let physical_device = engine::physical_device::pick_physical_device(
+            &instance,
+            surface_info,
+            requested_backend == Some(Backend::HardwareRayTracing),
+            options.device.as_ref(),
+            options.verbose,
+        )?;
+        let frame_usage = match surface_info {
+            Some((surface, surface_loader)) => {
+                engine::swap_chain::query_swap_chain_support(
+                    &physical_device,
+                    surface,
+                    surface_loader,
+                )?
+                .capabilities
+                .supported_usage_flags
+            }
+            // The offscreen image is made to be blitted into
+            None => vk::ImageUsageFlags::TRANSFER_DST,
+        };
+        let backend = Backend::choose(&physical_device, &instance, requested_backend, frame_usage)?;
+
+        // Create the logical device
+        let logical_device = Rc::new(engine::logical_device::create_logical_device(
+            &physical_device,
+            &instance,
+            surface_info,
+            backend.is_ray_tracing_enabled(),
+        )?);
+        let allocator = Rc::new(Allocator::new(&logical_device, &physical_device));
+
+        // Devices without the queue families needed aren't picked
+        let indices =
+            engine::queue_families::find_queue_families(&physical_device, &instance, surface_info);
+        let graphics_family = indices
+            .graphics_family
+            .expect("The picked device has no graphics queue family!");
+        let graphics_queue = unsafe { logical_device.get_device_queue(graphics_family, 0) };
+        let present_queue = indices
+            .present_family
+            .map(|family| unsafe { logical_device.get_device_queue(family, 0) });
+
+        // Create swap chain and image views, or the offscreen image when
+        // there is nothing to present to
+        let swap_chain = window
+            .as_ref()
+            .zip(surface)
+            .map(|(window, surface)| {
+                SwapChain::new(
+                    &physical_device,
+                    &logical_device,
+                    surface,
+                    window,
+                    options.present_mode,
+                )
+            })
+            .transpose()?;
+        let offscreen = if is_headless {
+            Some(engine::offscreen::OffscreenTarget::new(
+                &logical_device,
+                &allocator,
+                vk::Extent2D {
+                    width: scene.render.width,
+                    height: scene.render.height,
+                },
+            )?)
+        } else {
+            None
+        };
+
+        // Create graphics pipeline
+        let (image_format, final_layout, image_views, extent) = match (&swap_chain, &offscreen) {
+            (Some(swap_chain), _) => (
+                swap_chain.image_format,
+                vk::ImageLayout::PRESENT_SRC_KHR,
+                swap_chain
+                    .swap_chain_image_views
+                    .iter()
+                    .map(|image_view| image_view.handle)
+                    .collect(),
+                swap_chain.extent,
+            ),
+            (None, Some(offscreen)) => (
+                offscreen.image_format,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vec![offscreen.image_view.handle],
+                offscreen.extent,
+            ),
+            (None, None) => unreachable!(),
+        };
+        let render_pass =
+            engine::render_pass::create_render_pass(&logical_device, image_format, final_layout)?;
+        let graphics_pipeline =
+            engine::pipeline::create_graphics_pipeline(&logical_device, &render_pass.handle)?;
+        let framebuffers = engine::framebuffer::create_framebuffers(
+            &logical_device,
+            &render_pass.handle,
+            &image_views,
+            extent,
+        )?;
+
+        // Create command buffers and synchronization objects
+        let command_pool = CommandPool::new(&logical_device, graphics_family)?;
+        let command_buffers = engine::commands::create_command_buffers(
+            &logical_device,
+            &command_pool.handle,
+            engine::sync::MAX_FRAMES_IN_FLIGHT as u32,
+        )?;
+        let sync_objects = engine::sync::SyncObjects::new(&logical_device, image_views.len())?;
+
+        // Create the ray tracing pipeline and the scene it traces
+        let tracer = Tracer::new(
+            backend,
+            &instance,
+            &physical_device,
+            &logical_device,
+            &allocator,
+            &command_pool.handle,
+            &graphics_queue,
+            extent,
+            &geometry,
+        )?;
+
+        Ok(Renderer {
+            geometry,
+            instance,
+            physical_device,
+            logical_device,
+            allocator,
+            graphics_queue,
+            present_queue,
+            swap_chain,
+            offscreen,
+            render_pass,
+            graphics_pipeline,
+            framebuffers,
+            command_pool,
+            command_buffers,
+            sync_objects,
+            backend,
+            tracer,
+            camera: Camera::from_scene(&scene.camera().cloned().unwrap_or_default()),
+            camera_controller: CameraController::new(),
+            last_camera_update: Instant::now(),
+            path_tracing: PathTracingSettings::new(&scene.render),
+            accumulation: Accumulation::new(),
+            current_frame: 0,
+            framebuffer_resized: false,
+            window,
+        })
+    }
+
+    /// The window the renderer presents to, unless it is headless.
+    pub fn window(&self) -> Option<&Window> {
+        self.window.as_ref()
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Samples per pixel gathered since the accumulation last started over.
+    pub fn accumulated_samples(&self) -> u32 {
+        self.accumulation.accumulated_samples()
+    }
+
+    /// Whether the path tracer has gathered all the samples the settings ask
+    /// for. The rasterizer is complete after any frame.
+    pub fn is_complete(&self) -> bool {
+        self.tracer.is_none() || self.accumulation.is_complete(&self.path_tracing)
+    }
+
+    /// Notes that the window was resized, so the swapchain is recreated after
+    /// the next frame.
+    pub fn resized(&mut self) {
+        self.framebuffer_resized = true;
+    }
+
+    /// Renders a frame, which is presented to the window or, when headless,
+    /// left in the offscreen image.
+    pub fn render_frame(&mut self) -> engine::Result<()> {
+        if self.swap_chain.is_some() {
+            self.draw_frame(None).map(|_| ())
+        } else {
+            self.render_offscreen_frame()
+        }
+    }
+
+    /// Renders frames until [`Renderer::is_complete`].
+    pub fn render_to_completion(&mut self) -> engine::Result<()> {
+        loop {
+            self.render_frame()?;
+            if self.is_complete() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Renders one frame into the offscreen image, in its own submission so
+    /// that no single one runs long enough to trip the driver's timeout.
+    fn render_offscreen_frame(&mut self) -> engine::Result<()> {
+        // Only called when there is no swapchain, so there is an offscreen
+        // image
+        let offscreen = self
+            .offscreen
+            .as_ref()
+            .expect("Offscreen rendering requires a headless renderer!");
+        let (image, extent) = (offscreen.image.handle, offscreen.extent);
+
+        let settings = self.frame_settings();
+        let constants = self
+            .accumulation
+            .next_frame(self.camera.view(extent), &settings);
+        let command_buffer = engine::commands::begin_single_time_commands(
+            &self.logical_device,
+            &self.command_pool.handle,
+        )?;
+        // The previous frame was waited for, so any frame's buffers are free
+        self.record_motion(&command_buffer, self.current_frame, &constants);
+        self.record_frame(
+            &command_buffer,
+            &image,
+            &self.framebuffers[0].handle,
+            extent,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            &constants,
+        );
+        engine::commands::end_single_time_commands(
+            &self.logical_device,
+            &self.command_pool.handle,
+            &self.graphics_queue,
+            command_buffer,
+        )
+    }
+
+    /// Acquires a swapchain image, renders into it and queues it for
+    /// presentation. Returns whether a frame was presented, and if so
+    /// `capture` receives a copy of it.
+    fn draw_frame(&mut self, capture: Option<&Buffer>) -> engine::Result<bool> {
+        // A minimized window has a zero sized surface, which a swapchain
+        // can't be created for, so skip frames until it is restored
+        if self.is_minimized() {
+            return Ok(false);
+        }
+
+        let settings = self.frame_settings();
+        let Some(swap_chain) = self.swap_chain.as_ref() else {
+            return Ok(false);
+        };
+
+        let frame = self.current_frame;
+        let in_flight_fence = self.sync_objects.in_flight_fences[frame].handle;
+        let image_available_semaphore = self.sync_objects.image_available_semaphores[frame].handle;
+        let command_buffer = self.command_buffers[frame];
+
+        unsafe {
+            self.logical_device
+                .wait_for_fences(&[in_flight_fence], true, u64::MAX)
+                .context("wait for the in-flight fence")?;
+        }
+
+        let acquire_result = unsafe {
+            swap_chain.swap_chain_device.acquire_next_image(
+                swap_chain.swap_chain,
+                u64::MAX,
+                image_available_semaphore,
+                vk::Fence::null(),
+            )
+        };
+        // A suboptimal swapchain can still be presented to, so it is only
+        // recreated after this frame
+        let image_index = match acquire_result {
+            Ok((image_index, _is_suboptimal)) => image_index,
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                return self.recreate_swap_chain().map(|()| false)
+            }
+            Err(result) => {
+                return Err(engine::Error::Vulkan {
+                    operation: "acquire swap chain image",
+                    result,
+                })
+            }
+        };
+        let render_finished_semaphore =
+            self.sync_objects.render_finished_semaphores[image_index as usize].handle;
+        let now = Instant::now();
+        let time_step = (now - self.last_camera_update).as_secs_f32();
+        self.last_camera_update = now;
+        self.camera_controller.update(&mut self.camera, time_step);
+        let constants = self
+            .accumulation
+            .next_frame(self.camera.view(swap_chain.extent), &settings);
+
+        unsafe {
+            self.logical_device
+                .reset_fences(&[in_flight_fence])
+                .context("reset the in-flight fence")?;
+            self.logical_device
+                .reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())
+                .context("reset command buffer")?;
+
+            let begin_info = vk::CommandBufferBeginInfo::default();
+            self.logical_device
+                .begin_command_buffer(command_buffer, &begin_info)
+                .context("begin recording command buffer")?;
+        }
+        // The in-flight fence was waited for, so the frame's buffers are free
+        self.record_motion(&command_buffer, frame, &constants);
+        // Recording the motion doesn't touch the swapchain checked above
+        let swap_chain = self.swap_chain.as_ref().expect("The swapchain is gone!");
+        self.record_frame(
+            &command_buffer,
+            &swap_chain.swap_chain_images[image_index as usize],
+            &self.framebuffers[image_index as usize].handle,
+            swap_chain.extent,
+            vk::ImageLayout::PRESENT_SRC_KHR,
+            &constants,
+        );
+        // The image may only be read between acquiring and presenting it
+        if let Some(buffer) = capture {
+            engine::image::record_copy_image_to_buffer(
+                &self.logical_device,
+                &command_buffer,
+                &swap_chain.swap_chain_images[image_index as usize],
+                swap_chain.extent,
+                vk::ImageLayout::PRESENT_SRC_KHR,
+                &buffer.handle,
+            );
+        }
+        unsafe {
+            self.logical_device
+                .end_command_buffer(command_buffer)
+                .context("record command buffer")?;
+        }
+
+        let wait_semaphores = [image_available_semaphore];
+        let wait_stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
+        let signal_semaphores = [render_finished_semaphore];
+        let command_buffers = [command_buffer];
+        let submit_info = vk::SubmitInfo {
+            wait_semaphore_count: wait_semaphores.len() as u32,
+            p_wait_semaphores: wait_semaphores.as_ptr(),
+            p_wait_dst_stage_mask: wait_stages.as_ptr(),
+            command_buffer_count: command_buffers.len() as u32,
+            p_command_buffers: command_buffers.as_ptr(),
+            signal_semaphore_count: signal_semaphores.len() as u32,
+            p_signal_semaphores: signal_semaphores.as_ptr(),
+            ..Default::default()
+        };
+        unsafe {
+            self.logical_device
+                .queue_submit(self.graphics_queue, &[submit_info], in_flight_fence)
+                .context("submit draw command buffer")?;
+        }
+
+        let swap_chains = [swap_chain.swap_chain];
+        let image_indices = [image_index];
+        let present_info = vk::PresentInfoKHR {
+            wait_semaphore_count: signal_semaphores.len() as u32,
+            p_wait_semaphores: signal_semaphores.as_ptr(),
+            swapchain_count: swap_chains.len() as u32,
+            p_swapchains: swap_chains.as_ptr(),
+            p_image_indices: image_indices.as_ptr(),
+            ..Default::default()
+        };
+        let present_result = unsafe {
+            swap_chain.swap_chain_device.queue_present(
+                self.present_queue
+                    .expect("A renderer with a swapchain has no present queue!"),
+                &present_info,
+            )
+        };
+
+        self.current_frame = (self.current_frame + 1) % engine::sync::MAX_FRAMES_IN_FLIGHT;
+
+        let is_out_of_date = match present_result {
+            Ok(is_suboptimal) => is_suboptimal,
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => true,
+            Err(result) => {
+                return Err(engine::Error::Vulkan {
+                    operation: "present swap chain image",
+                    result,
+                })
+            }
+        };
+        if is_out_of_date || self.framebuffer_resized {
+            self.framebuffer_resized = false;
+            self.recreate_swap_chain()?;
+        }
+
+        Ok(true)
+    }
+
+    /// Records the commands producing a frame into `image`, with the ray
+    /// tracer when there is one and the graphics pipeline otherwise. The
+    /// image is left in `final_layout`.
+    fn record_frame(
+        &self,
+        command_buffer: &vk::CommandBuffer,
+        image: &vk::Image,
+        framebuffer: &vk::Framebuffer,
+        extent: vk::Extent2D,
+        final_layout: vk::ImageLayout,
+        constants: &FrameConstants,
+    ) {
+        match self.tracer.as_ref() {
+            Some(tracer) => tracer.record_command_buffer(
+                &self.logical_device,
+                command_buffer,
+                image,
+                final_layout,
+                constants,
+            ),
+            None => engine::pipeline::record_command_buffer(
+                &self.logical_device,
+                command_buffer,
+                &self.render_pass.handle,
+                framebuffer,
+                extent,
+                &self.graphics_pipeline.handle,
+            ),
+        }
+    }
+
+    fn is_minimized(&self) -> bool {
+        self.window.as_ref().is_some_and(|window| {
+            let size = window.inner_size();
+            size.width == 0 || size.height == 0
+        })
+    }
+
+    /// The settings the next frame is traced with. The instances of a scene
+    /// with motion are moved once per frame, so each sample needs a frame of
+    /// its own to be traced at a different time of the shutter interval.
+    fn frame_settings(&self) -> PathTracingSettings {
+        let mut settings = self.path_tracing;
+        if self.geometry.has_motion() {
+            settings.samples_per_frame = 1;
+        }
+        settings
+    }
+
+    /// Records moving the instances of a scene with motion to where they are
+    /// when the frame with `constants` is traced, using the buffers of frame
+    /// in flight `frame`. Each frame sees one instant of the shutter
+    /// interval, so the motion blurs as frames accumulate.
+    fn record_motion(
+        &mut self,
+        command_buffer: &vk::CommandBuffer,
+        frame: usize,
+        constants: &FrameConstants,
+    ) {
+        if !self.geometry.has_motion() || constants.samples_per_frame == 0 {
+            return;
+        }
+        let Some(tracer) = self.tracer.as_mut() else {
+            return;
+        };
+
+        let time = engine::path_tracing::shutter_time(self.camera.shutter, constants.frame_index);
+        tracer.record_set_time(
+            &self.logical_device,
+            command_buffer,
+            frame,
+            &self.geometry,
+            time,
+        );
+    }
+
+    /// Rebuilds the swapchain and everything sized or formatted after it.
+    fn recreate_swap_chain(&mut self) -> engine::Result<()> {
+        if self.is_minimized() {
+            return Ok(());
+        }
+        let (Some(window), Some(swap_chain)) = (&self.window, &mut self.swap_chain) else {
+            return Ok(());
+        };
+
+        unsafe { self.logical_device.device_wait_idle() }.context("wait for the device")?;
+
+        self.framebuffers.clear();
+        let old_format = swap_chain.image_format;
+        swap_chain.recreate(&self.physical_device, window)?;
+
+        // The surface format rarely changes, but when it does the render pass
+        // and the pipeline built against it are no longer compatible
+        if swap_chain.image_format != old_format {
+            self.render_pass = engine::render_pass::create_render_pass(
+                &self.logical_device,
+                swap_chain.image_format,
+                vk::ImageLayout::PRESENT_SRC_KHR,
+            )?;
+            self.graphics_pipeline = engine::pipeline::create_graphics_pipeline(
+                &self.logical_device,
+                &self.render_pass.handle,
+            )?;
+        }
+
+        let image_views: Vec<vk::ImageView> = swap_chain
+            .swap_chain_image_views
+            .iter()
+            .map(|image_view| image_view.handle)
+            .collect();
+        self.framebuffers = engine::framebuffer::create_framebuffers(
+            &self.logical_device,
+            &self.render_pass.handle,
+            &image_views,
+            swap_chain.extent,
+        )?;
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.resize(
+                &self.logical_device,
+                &self.allocator,
+                &self.command_pool.handle,
+                &self.graphics_queue,
+                swap_chain.extent,
+            )?;
+        }
+        self.accumulation.reset();
+        self.sync_objects.resize_render_finished_semaphores(
+            &self.logical_device,
+            swap_chain.swap_chain_image_views.len(),
+        )?;
+
+        Ok(())
+    }
+
+    /// Replaces the traced scene, camera and render settings, starting the
+    /// accumulation over. The current scene is kept if the new one can't be
+    /// traced. The render resolution of `scene` only applies to new
+    /// renderers.
+    pub fn load_scene(&mut self, scene: &Scene, geometry: SceneGeometry) -> engine::Result<()> {
+        let extent = match (&self.swap_chain, &self.offscreen) {
+            (Some(swap_chain), _) => swap_chain.extent,
+            (None, Some(offscreen)) => offscreen.extent,
+            (None, None) => unreachable!(),
+        };
+
+        unsafe { self.logical_device.device_wait_idle() }.context("wait for the device")?;
+        let tracer = Tracer::new(
+            self.backend,
+            &self.instance,
+            &self.physical_device,
+            &self.logical_device,
+            &self.allocator,
+            &self.command_pool.handle,
+            &self.graphics_queue,
+            extent,
+            &geometry,
+        )?;
+        self.tracer = tracer;
+        self.geometry = geometry;
+
+        self.camera = Camera::from_scene(&scene.camera().cloned().unwrap_or_default());
+        self.path_tracing = PathTracingSettings::new(&scene.render);
+        self.accumulation.reset();
+
+        Ok(())
+    }
+
+    /// Grabs or releases the cursor of the window. Mouse look works while it
+    /// is grabbed.
+    pub fn set_cursor_grabbed(&mut self, is_grabbed: bool) {
+        let Some(window) = self.window.as_ref() else {
+            return;
+        };
+        if is_grabbed {
+            self.camera_controller.grab_cursor(window);
+        } else {
+            self.camera_controller.release_cursor(window);
+        }
+    }
+
+    pub fn request_redraw(&self) {
+        if let Some(window) = self.window.as_ref() {
+            window.request_redraw();
+        }
+    }
+
+    /// Reads back the current frame, from the offscreen image when headless.
+    /// With a window another frame is drawn and copied before it is
+    /// presented, since presented images can't be read. Returns `None` when
+    /// no frame could be drawn, e.g. while minimized, or the surface doesn't
+    /// allow copying from swapchain images.
+    pub fn read_back(&mut self) -> Result<Option<HostImage>, ExportError> {
+        let (data, format, extent) = match (&self.offscreen, &self.swap_chain) {
+            (Some(offscreen), _) => {
+                unsafe { self.logical_device.device_wait_idle() }.context("wait for the device")?;
+                let data = offscreen.read_back(
+                    &self.logical_device,
+                    &self.allocator,
+                    &self.command_pool.handle,
+                    &self.graphics_queue,
+                )?;
+                (data, offscreen.image_format, offscreen.extent)
+            }
+            (None, Some(swap_chain)) => {
+                if !swap_chain
+                    .image_usage
+                    .contains(vk::ImageUsageFlags::TRANSFER_SRC)
+                {
+                    return Ok(None);
+                }
+
+                let (format, extent) = (swap_chain.image_format, swap_chain.extent);
+                let buffer =
+                    engine::image::create_read_back_buffer(&self.allocator, format, extent)?;
+                if !self.draw_frame(Some(&buffer))? {
+                    return Ok(None);
+                }
+                unsafe { self.logical_device.device_wait_idle() }.context("wait for the device")?;
+                let data = buffer
+                    .allocation
+                    .read(engine::image::packed_size(format, extent));
+                (data, format, extent)
+            }
+            (None, None) => unreachable!(),
+        };
+
+        let host_image = image_export::decode_pixels(&data, format, extent.width, extent.height)?;
+        Ok(Some(host_image))
+    }
+
+    /// Reads back the average of the accumulated samples in linear 32-bit
+    /// floats, before tonemapping. Returns `None` for the rasterizer, which
+    /// accumulates nothing, and before the first samples.
+    pub fn read_back_accumulation(&self) -> Result<Option<HostImage>, ExportError> {
+        let samples = self.accumulation.accumulated_samples();
+        let Some(tracer) = self.tracer.as_ref().filter(|_| samples > 0) else {
+            return Ok(None);
+        };
+        let accumulation_image = tracer.accumulation_image();
+
+        unsafe { self.logical_device.device_wait_idle() }.context("wait for the device")?;
+        let data = engine::image::read_back_image(
+            &self.logical_device,
+            &self.allocator,
+            &self.command_pool.handle,
+            &self.graphics_queue,
+            &accumulation_image.image.handle,
+            accumulation_image.format,
+            accumulation_image.extent,
+            vk::ImageLayout::GENERAL,
+        )?;
+
+        let extent = accumulation_image.extent;
+        let mut host_image = image_export::decode_pixels(
+            &data,
+            accumulation_image.format,
+            extent.width,
+            extent.height,
+        )?;
+        image_export::average_samples(&mut host_image, samples);
+        Ok(Some(host_image))
+    }
+}
+
+/// The display and window handles of `window`, which the instance and the
+/// surface are created for.
+fn raw_handles(window: &Window) -> engine::Result<(RawDisplayHandle, RawWindowHandle)> {
+    let display_handle = window
+        .display_handle()
+        .map_err(engine::Error::window("get display handle"))?;
+    let window_handle = window
+        .window_handle()
+        .map_err(engine::Error::window("get window handle"))?;
+    Ok((display_handle.as_raw(), window_handle.as_raw()))
+}
+
+impl Drop for Renderer {
+    fn drop(&mut self) {
+        // The fields are destroyed as they are dropped, each before the
+        // device, allocator or instance it holds on to, but none may be in
+        // use by frames still in flight. A lost device is torn down all the
+        // same.
+        let _ = unsafe { self.logical_device.device_wait_idle() };
+    }
+}
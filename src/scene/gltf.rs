@@ -0,0 +1,589 @@
+//! glTF 2.0 import, from `.gltf` files with external or embedded buffers and
+//! from binary `.glb` files.
+//!
+//! A whole file maps onto a `Scene`: every glTF mesh becomes a mesh whose
+//! primitives are merged into one `TriangleMesh`, every node with a mesh an
+//! object, and the metallic-roughness materials, textures, perspective
+//! cameras and `KHR_lights_punctual` lights their scene counterparts.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use ash::vk;
+use glam::{Mat4, Vec3};
+use gltf::camera::Projection;
+use gltf::khr_lights_punctual::Kind;
+use gltf::mesh::Mode;
+use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+
+use super::mesh::{MeshInstance, SceneGeometry, TriangleMesh, Vertex, NO_MATERIAL};
+use super::texture::{Sampler, Texture, TextureFormat};
+use super::{
+    Camera, Light, Material, MaterialModel, Mesh, Object, RenderSettings, Scene, SceneError,
+    Transform,
+};
+
+/// Converts the photometric intensities of glTF lights, in candela, to
+/// radiometric ones.
+const LUMENS_PER_WATT: f32 = 683.0;
+
+pub fn is_gltf(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            extension.eq_ignore_ascii_case("gltf") || extension.eq_ignore_ascii_case("glb")
+        })
+}
+
+/// Imports the default scene of a glTF file, or its first scene if none is
+/// marked as the default, together with all of its meshes.
+pub fn load_gltf(path: &Path) -> Result<(Scene, SceneGeometry), SceneError> {
+    let (document, buffers, images) =
+        gltf::import(path).map_err(|error| gltf_error(path, error))?;
+
+    let mesh_names = mesh_names(&document);
+    let meshes = document
+        .meshes()
+        .map(|mesh| load_mesh(&mesh, &buffers, path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut visitor = NodeVisitor {
+        scene: Scene {
+            render: RenderSettings::default(),
+            cameras: Vec::new(),
+            meshes: mesh_names
+                .into_iter()
+                .map(|name| Mesh {
+                    name,
+                    path: Some(path.to_path_buf()),
+                    primitive: None,
+                })
+                .collect(),
+            materials: load_materials(&document),
+            objects: Vec::new(),
+            lights: Vec::new(),
+            environment: None,
+            textures: document
+                .textures()
+                .map(|texture| load_texture(&texture, &images))
+                .collect(),
+        },
+        instances: Vec::new(),
+        first_aspect_ratio: None,
+    };
+
+    let root = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or_else(|| invalid(path, "the file has no scenes".to_string()))?;
+    for node in root.nodes() {
+        visitor.visit(&node, Mat4::IDENTITY);
+    }
+
+    let NodeVisitor {
+        mut scene,
+        instances,
+        first_aspect_ratio,
+    } = visitor;
+
+    // Match the render to the proportions of the camera it's seen through
+    if let Some(aspect_ratio) = first_aspect_ratio {
+        let width = (scene.render.height as f32 * aspect_ratio).round() as u32;
+        scene.render.width = width.max(1);
+    }
+
+    scene.validate().map_err(|message| invalid(path, message))?;
+    for (mesh, triangle_mesh) in scene.meshes.iter().zip(&meshes) {
+        if triangle_mesh.triangle_count() == 0 {
+            return Err(invalid(
+                path,
+                format!("mesh \"{}\" has no triangles", mesh.name),
+            ));
+        }
+    }
+
+    let materials = scene.materials.clone();
+    let textures = scene.textures.clone();
+    let lights = scene.lights.clone();
+    Ok((
+        scene,
+        SceneGeometry {
+            meshes,
+            instances,
+            materials,
+            textures,
+            environment: None,
+            lights,
+        },
+    ))
+}
+
+/// Loads the mesh called `name` from a glTF file, with the names `load_gltf`
+/// gives meshes, together with the materials its material ids refer to and
+/// the textures those refer to.
+pub fn load_gltf_mesh(
+    path: &Path,
+    name: &str,
+) -> Result<(TriangleMesh, Vec<Material>, Vec<Texture>), SceneError> {
+    let (document, buffers, images) =
+        gltf::import(path).map_err(|error| gltf_error(path, error))?;
+
+    let index = mesh_names(&document)
+        .iter()
+        .position(|mesh_name| mesh_name == name)
+        .ok_or_else(|| invalid(path, format!("the file has no mesh \"{}\"", name)))?;
+    let mesh = document.meshes().nth(index).expect("named above");
+    let textures = document
+        .textures()
+        .map(|texture| load_texture(&texture, &images))
+        .collect();
+
+    Ok((
+        load_mesh(&mesh, &buffers, path)?,
+        load_materials(&document),
+        textures,
+    ))
+}
+
+/// Walks the node hierarchy, collecting what the nodes place in the world.
+struct NodeVisitor {
+    scene: Scene,
+    instances: Vec<MeshInstance>,
+    first_aspect_ratio: Option<f32>,
+}
+
+impl NodeVisitor {
+    fn visit(&mut self, node: &gltf::Node, parent_transform: Mat4) {
+        let transform = parent_transform * Mat4::from_cols_array_2d(&node.transform().matrix());
+        let position = transform.transform_point3(Vec3::ZERO);
+        // Cameras and lights point down their local -Z axis
+        let direction = transform.transform_vector3(Vec3::NEG_Z).normalize();
+
+        if let Some(mesh) = node.mesh() {
+            self.instances.push(MeshInstance {
+                mesh: mesh.index(),
+                transform,
+                end_transform: None,
+                material: None,
+            });
+            self.scene.objects.push(Object {
+                mesh: self.scene.meshes[mesh.index()].name.clone(),
+                // The meshes carry their materials per triangle
+                material: None,
+                transform: Transform::from_matrix(&transform),
+                end_transform: None,
+            });
+        }
+
+        // Orthographic cameras have no equivalent and are skipped
+        if let Some(Projection::Perspective(perspective)) =
+            node.camera().as_ref().map(gltf::Camera::projection)
+        {
+            if self.scene.cameras.is_empty() {
+                self.first_aspect_ratio = perspective.aspect_ratio();
+            }
+            self.scene.cameras.push(Camera {
+                name: node
+                    .camera()
+                    .and_then(|camera| camera.name().map(str::to_string)),
+                position: position.to_array(),
+                look_at: (position + direction).to_array(),
+                up: transform.transform_vector3(Vec3::Y).normalize().to_array(),
+                fov_degrees: perspective.yfov().to_degrees(),
+                ..Camera::default()
+            });
+        }
+
+        if let Some(light) = node.light() {
+            let color = light.color();
+            let intensity = light.intensity();
+            self.scene.lights.push(match light.kind() {
+                // Already in lux
+                Kind::Directional => Light::Directional {
+                    direction: direction.to_array(),
+                    color,
+                    intensity,
+                },
+                Kind::Point => Light::Point {
+                    position: position.to_array(),
+                    color,
+                    intensity: intensity / LUMENS_PER_WATT,
+                },
+                Kind::Spot {
+                    inner_cone_angle,
+                    outer_cone_angle,
+                } => Light::Spot {
+                    position: position.to_array(),
+                    direction: direction.to_array(),
+                    color,
+                    intensity: intensity / LUMENS_PER_WATT,
+                    inner_angle_degrees: inner_cone_angle.to_degrees(),
+                    outer_angle_degrees: outer_cone_angle.to_degrees(),
+                },
+            });
+        }
+
+        for child in node.children() {
+            self.visit(&child, transform);
+        }
+    }
+}
+
+/// Names for the meshes in file order, made unique since glTF names are
+/// optional and may repeat.
+fn mesh_names(document: &gltf::Document) -> Vec<String> {
+    unique_names(
+        document
+            .meshes()
+            .map(|mesh| mesh.name().map(str::to_string)),
+        "mesh",
+    )
+}
+
+fn unique_names(names: impl Iterator<Item = Option<String>>, kind: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    names
+        .enumerate()
+        .map(|(index, name)| {
+            let name = name.unwrap_or_else(|| format!("{}{}", kind, index));
+            if seen.insert(name.clone()) {
+                name
+            } else {
+                format!("{}#{}", name, index)
+            }
+        })
+        .collect()
+}
+
+/// Merges the triangle primitives of a mesh. Material ids index the file's
+/// materials.
+fn load_mesh(
+    mesh: &gltf::Mesh,
+    buffers: &[gltf::buffer::Data],
+    path: &Path,
+) -> Result<TriangleMesh, SceneError> {
+    let mut triangle_mesh = TriangleMesh::default();
+    for primitive in mesh.primitives() {
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+        let Some(positions) = reader.read_positions() else {
+            continue;
+        };
+        let positions = positions.collect::<Vec<_>>();
+        let normals = reader.read_normals().map(Iterator::collect::<Vec<_>>);
+        let uvs = reader
+            .read_tex_coords(0)
+            .map(|uvs| uvs.into_f32().collect::<Vec<_>>());
+
+        let indices = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..positions.len() as u32).collect::<Vec<_>>(),
+        };
+        let indices = match primitive.mode() {
+            Mode::Triangles => indices,
+            Mode::TriangleStrip => (0..indices.len().saturating_sub(2))
+                .flat_map(|first| {
+                    // Every other triangle is flipped to keep the winding
+                    let [a, b, c] = [0, 1, 2].map(|corner| indices[first + corner]);
+                    if first % 2 == 0 {
+                        [a, b, c]
+                    } else {
+                        [b, a, c]
+                    }
+                })
+                .collect(),
+            Mode::TriangleFan => (1..indices.len().saturating_sub(1))
+                .flat_map(|second| [indices[0], indices[second], indices[second + 1]])
+                .collect(),
+            // Points and lines have no area for rays to hit
+            Mode::Points | Mode::Lines | Mode::LineLoop | Mode::LineStrip => continue,
+        };
+        if indices.len() % 3 != 0
+            || indices
+                .iter()
+                .any(|&index| index as usize >= positions.len())
+        {
+            return Err(invalid(
+                path,
+                format!(
+                    "a primitive of mesh {} has indices that don't form triangles",
+                    mesh.index()
+                ),
+            ));
+        }
+
+        let material = primitive
+            .material()
+            .index()
+            .map_or(NO_MATERIAL, |index| index as u32);
+        let mut part = TriangleMesh {
+            vertices: positions
+                .iter()
+                .enumerate()
+                .map(|(index, &position)| Vertex {
+                    position,
+                    normal: normals
+                        .as_ref()
+                        .and_then(|normals| normals.get(index).copied())
+                        .unwrap_or_default(),
+                    uv: uvs
+                        .as_ref()
+                        .and_then(|uvs| uvs.get(index).copied())
+                        .unwrap_or_default(),
+                })
+                .collect(),
+            material_ids: vec![material; indices.len() / 3],
+            indices,
+        };
+        if normals.is_none() {
+            part.generate_normals();
+        }
+        triangle_mesh.append(part);
+    }
+
+    Ok(triangle_mesh)
+}
+
+/// The metallic-roughness parameters of every material, in file order.
+/// Texture indices refer to `Scene::textures`, which holds the textures in
+/// file order as well.
+fn load_materials(document: &gltf::Document) -> Vec<Material> {
+    let names = unique_names(
+        document
+            .materials()
+            .map(|material| material.name().map(str::to_string)),
+        "material",
+    );
+
+    document
+        .materials()
+        .zip(names)
+        .map(|(material, name)| {
+            let pbr = material.pbr_metallic_roughness();
+            let [red, green, blue, _alpha] = pbr.base_color_factor();
+            let emissive_strength = material.emissive_strength().unwrap_or(1.0);
+            let texture_index =
+                |info: Option<gltf::texture::Info>| info.map(|info| info.texture().index());
+
+            Material {
+                name,
+                model: MaterialModel::MetallicRoughness {
+                    base_color: [red, green, blue],
+                    metallic: pbr.metallic_factor(),
+                    roughness: pbr.roughness_factor(),
+                    emissive: material
+                        .emissive_factor()
+                        .map(|channel| channel * emissive_strength),
+                    base_color_texture: texture_index(pbr.base_color_texture()),
+                    metallic_roughness_texture: texture_index(pbr.metallic_roughness_texture()),
+                    normal_texture: material
+                        .normal_texture()
+                        .map(|normal| normal.texture().index()),
+                    emissive_texture: texture_index(material.emissive_texture()),
+                },
+            }
+        })
+        .collect()
+}
+
+fn load_texture(texture: &gltf::Texture, images: &[gltf::image::Data]) -> Texture {
+    let image = &images[texture.source().index()];
+    Texture {
+        name: texture
+            .name()
+            .or_else(|| texture.source().name())
+            .map(str::to_string),
+        width: image.width,
+        height: image.height,
+        format: TextureFormat::Rgba8,
+        pixels: to_rgba8(image),
+        sampler: load_sampler(&texture.sampler()),
+    }
+}
+
+/// Expands grey images to RGB and adds opaque alpha where it's missing. Wider
+/// channels are cut to 8 bits, with float channels clamped to 0 to 1.
+fn to_rgba8(image: &gltf::image::Data) -> Vec<u8> {
+    use gltf::image::Format;
+
+    let (channel_count, channel_size) = match image.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        Format::R32G32B32FLOAT => (3, 4),
+        Format::R32G32B32A32FLOAT => (4, 4),
+    };
+    // The importer stores wide channels in native byte order
+    let channel = |bytes: &[u8]| match bytes {
+        [value] => *value,
+        [low, high] => (u16::from_ne_bytes([*low, *high]) >> 8) as u8,
+        _ => {
+            let value = f32::from_ne_bytes(bytes.try_into().expect("4 byte channel"));
+            (value.clamp(0.0, 1.0) * 255.0).round() as u8
+        }
+    };
+
+    image
+        .pixels
+        .chunks_exact(channel_count * channel_size)
+        .flat_map(|pixel| {
+            let channels = pixel
+                .chunks_exact(channel_size)
+                .map(channel)
+                .collect::<Vec<_>>();
+            match channels[..] {
+                [grey] => [grey, grey, grey, u8::MAX],
+                [grey, alpha] => [grey, grey, grey, alpha],
+                [red, green, blue] => [red, green, blue, u8::MAX],
+                [red, green, blue, alpha] => [red, green, blue, alpha],
+                _ => unreachable!("images have one to four channels"),
+            }
+        })
+        .collect()
+}
+
+fn load_sampler(sampler: &gltf::texture::Sampler) -> Sampler {
+    let defaults = Sampler::default();
+    let mag_filter = match sampler.mag_filter() {
+        Some(MagFilter::Nearest) => vk::Filter::NEAREST,
+        Some(MagFilter::Linear) => vk::Filter::LINEAR,
+        None => defaults.mag_filter,
+    };
+    let (min_filter, mipmap_mode, use_mipmaps) = match sampler.min_filter() {
+        Some(MinFilter::Nearest) => (vk::Filter::NEAREST, vk::SamplerMipmapMode::NEAREST, false),
+        Some(MinFilter::Linear) => (vk::Filter::LINEAR, vk::SamplerMipmapMode::NEAREST, false),
+        Some(MinFilter::NearestMipmapNearest) => {
+            (vk::Filter::NEAREST, vk::SamplerMipmapMode::NEAREST, true)
+        }
+        Some(MinFilter::LinearMipmapNearest) => {
+            (vk::Filter::LINEAR, vk::SamplerMipmapMode::NEAREST, true)
+        }
+        Some(MinFilter::NearestMipmapLinear) => {
+            (vk::Filter::NEAREST, vk::SamplerMipmapMode::LINEAR, true)
+        }
+        Some(MinFilter::LinearMipmapLinear) => {
+            (vk::Filter::LINEAR, vk::SamplerMipmapMode::LINEAR, true)
+        }
+        None => (
+            defaults.min_filter,
+            defaults.mipmap_mode,
+            defaults.use_mipmaps,
+        ),
+    };
+    let address_mode = |wrapping| match wrapping {
+        WrappingMode::ClampToEdge => vk::SamplerAddressMode::CLAMP_TO_EDGE,
+        WrappingMode::MirroredRepeat => vk::SamplerAddressMode::MIRRORED_REPEAT,
+        WrappingMode::Repeat => vk::SamplerAddressMode::REPEAT,
+    };
+
+    Sampler {
+        mag_filter,
+        min_filter,
+        mipmap_mode,
+        use_mipmaps,
+        address_mode_u: address_mode(sampler.wrap_s()),
+        address_mode_v: address_mode(sampler.wrap_t()),
+    }
+}
+
+fn gltf_error(path: &Path, error: gltf::Error) -> SceneError {
+    SceneError::Gltf {
+        path: path.to_path_buf(),
+        error: Box::new(error),
+    }
+}
+
+fn invalid(path: &Path, message: String) -> SceneError {
+    SceneError::Invalid {
+        path: path.to_path_buf(),
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    #[test]
+    fn imports_node_hierarchy_cameras_and_lights() {
+        let (scene, geometry) = load_gltf(&fixture("hierarchy.gltf")).unwrap();
+
+        assert_eq!(scene.meshes[0].name, "tri");
+        assert_eq!(geometry.meshes[0].triangle_count(), 1);
+        assert_eq!(geometry.meshes[0].material_ids, vec![0]);
+        // Normals are generated when the file has none
+        assert_eq!(geometry.meshes[0].vertices[0].normal, [0.0, 0.0, 1.0]);
+
+        // The child's scale applies before its parent's translation
+        let transform = geometry.instances[0].transform;
+        let corner = transform.transform_point3(Vec3::X);
+        assert!(corner.abs_diff_eq(Vec3::new(3.0, 0.0, 0.0), 1e-6));
+        assert_eq!(scene.objects[0].mesh, "tri");
+
+        let camera = scene.camera().unwrap();
+        assert_eq!(camera.position, [0.0, 0.0, 5.0]);
+        assert_eq!(camera.look_at, [0.0, 0.0, 4.0]);
+        assert!((camera.fov_degrees - 0.8f32.to_degrees()).abs() < 1e-4);
+        assert_eq!(scene.render.width, 1200);
+
+        match scene.lights[0] {
+            Light::Spot {
+                position,
+                direction,
+                intensity,
+                ..
+            } => {
+                assert_eq!(position, [0.0, 3.0, 0.0]);
+                assert_eq!(direction, [0.0, 0.0, -1.0]);
+                assert!((intensity - 1.0).abs() < 1e-6);
+            }
+            ref light => panic!("expected a spot light, got {:?}", light),
+        }
+    }
+
+    #[test]
+    fn imports_metallic_roughness_materials() {
+        let (scene, _) = load_gltf(&fixture("hierarchy.gltf")).unwrap();
+
+        assert_eq!(scene.materials[0].name, "red");
+        match scene.materials[0].model {
+            MaterialModel::MetallicRoughness {
+                base_color,
+                metallic,
+                roughness,
+                base_color_texture,
+                ..
+            } => {
+                assert_eq!(base_color, [1.0, 0.0, 0.0]);
+                assert_eq!(metallic, 0.5);
+                // The glTF default
+                assert_eq!(roughness, 1.0);
+                assert_eq!(base_color_texture, None);
+            }
+            ref model => panic!("expected metallic-roughness, got {:?}", model),
+        }
+    }
+
+    #[test]
+    fn loads_single_mesh_by_name() {
+        let (mesh, materials, textures) =
+            load_gltf_mesh(&fixture("hierarchy.gltf"), "tri").unwrap();
+        assert_eq!(mesh.triangle_count(), 1);
+        assert_eq!(materials[mesh.material_ids[0] as usize].name, "red");
+        assert!(textures.is_empty());
+
+        let error = load_gltf_mesh(&fixture("hierarchy.gltf"), "missing").unwrap_err();
+        assert!(matches!(error, SceneError::Invalid { .. }));
+    }
+}
@@ -0,0 +1,253 @@
+use ash::vk;
+use glam::{Mat4, Vec3};
+
+use crate::engine::acceleration_structure::TriangleGeometry;
+use crate::engine::bvh::Triangle;
+use crate::engine::geometry::{TRIANGLE_INDICES, TRIANGLE_VERTICES};
+use crate::scene::texture::Texture;
+use crate::scene::{EnvironmentMap, Light, Material, Primitive};
+
+/// Material id of triangles that have no material assigned.
+pub const NO_MATERIAL: u32 = u32::MAX;
+
+/// A vertex as uploaded to the vertex buffer. The position comes first, so
+/// acceleration structure builds can read it with a stride of the whole
+/// vertex.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Vertex {
+    pub position: [f32; 3],
+    pub normal: [f32; 3],
+    pub uv: [f32; 2],
+}
+
+/// An indexed triangle mesh in host memory.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TriangleMesh {
+    pub vertices: Vec<Vertex>,
+    /// Three per triangle
+    pub indices: Vec<u32>,
+    /// One per triangle, indexing the materials of the file the mesh came
+    /// from, or `SceneGeometry::materials` once loaded into a scene. Triangles
+    /// without a material have `NO_MATERIAL`.
+    pub material_ids: Vec<u32>,
+}
+
+impl TriangleMesh {
+    pub fn from_primitive(primitive: Primitive) -> Self {
+        match primitive {
+            Primitive::Triangle => {
+                let mut mesh = Self {
+                    vertices: TRIANGLE_VERTICES
+                        .iter()
+                        .map(|&position| Vertex {
+                            position,
+                            normal: [0.0; 3],
+                            uv: [0.0; 2],
+                        })
+                        .collect(),
+                    indices: TRIANGLE_INDICES.to_vec(),
+                    material_ids: vec![NO_MATERIAL],
+                };
+                mesh.generate_normals();
+                mesh
+            }
+        }
+    }
+
+    /// Two triangles spanning the parallelogram with edges `edge_u` and
+    /// `edge_v` from `corner`, facing along their cross product and textured
+    /// from 0 to 1 along each edge. The triangles have no material.
+    pub fn parallelogram(corner: Vec3, edge_u: Vec3, edge_v: Vec3) -> Self {
+        let normal = edge_u.cross(edge_v).normalize_or_zero().to_array();
+        let vertex = |u: f32, v: f32| Vertex {
+            position: (corner + u * edge_u + v * edge_v).to_array(),
+            normal,
+            uv: [u, v],
+        };
+        Self {
+            vertices: vec![
+                vertex(0.0, 0.0),
+                vertex(1.0, 0.0),
+                vertex(1.0, 1.0),
+                vertex(0.0, 1.0),
+            ],
+            indices: vec![0, 1, 2, 0, 2, 3],
+            material_ids: vec![NO_MATERIAL; 2],
+        }
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    /// Replaces all normals with the area-weighted average of the normals of
+    /// the triangles sharing each vertex.
+    pub fn generate_normals(&mut self) {
+        let mut normals = vec![Vec3::ZERO; self.vertices.len()];
+        for triangle in self.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|corner| triangle[corner] as usize);
+            let position = |index: usize| Vec3::from_array(self.vertices[index].position);
+            // The cross product's length is twice the triangle's area
+            let normal = (position(b) - position(a)).cross(position(c) - position(a));
+            for index in [a, b, c] {
+                normals[index] += normal;
+            }
+        }
+
+        for (vertex, normal) in self.vertices.iter_mut().zip(normals) {
+            vertex.normal = normal.normalize_or_zero().to_array();
+        }
+    }
+
+    /// Adds the triangles of `other` after those of this mesh.
+    pub fn append(&mut self, other: TriangleMesh) {
+        let first_vertex = self.vertices.len() as u32;
+        self.vertices.extend(other.vertices);
+        self.indices
+            .extend(other.indices.iter().map(|index| first_vertex + index));
+        self.material_ids.extend(other.material_ids);
+    }
+
+    /// Shifts the material ids by `offset`, for when the materials they index
+    /// are appended to a longer list.
+    pub fn offset_material_ids(&mut self, offset: u32) {
+        for material_id in self.material_ids.iter_mut() {
+            if *material_id != NO_MATERIAL {
+                *material_id += offset;
+            }
+        }
+    }
+
+    /// How the mesh is laid out in the vertex and index buffers, for the
+    /// bottom-level acceleration structure build.
+    pub fn geometry(&self) -> TriangleGeometry {
+        TriangleGeometry {
+            vertex_count: self.vertices.len() as u32,
+            vertex_stride: std::mem::size_of::<Vertex>() as vk::DeviceSize,
+            index_count: self.indices.len() as u32,
+            is_opaque: true,
+        }
+    }
+
+    /// The triangles moved by `transform`, in index order.
+    pub fn transformed_triangles(&self, transform: &Mat4) -> impl Iterator<Item = Triangle> + '_ {
+        let transform = *transform;
+        self.indices.chunks_exact(3).map(move |triangle| {
+            let [v0, v1, v2] = [0, 1, 2].map(|corner| {
+                let position = self.vertices[triangle[corner] as usize].position;
+                transform.transform_point3(Vec3::from_array(position))
+            });
+            Triangle::new(v0, v1, v2)
+        })
+    }
+
+    /// The texture coordinates of the corners of each triangle, in index
+    /// order.
+    pub fn triangle_uvs(&self) -> impl Iterator<Item = [[f32; 2]; 3]> + '_ {
+        self.indices
+            .chunks_exact(3)
+            .map(|triangle| [0, 1, 2].map(|corner| self.vertices[triangle[corner] as usize].uv))
+    }
+}
+
+/// The meshes of a scene in host memory, the objects placing them, the
+/// materials of both, the textures of the materials and the environment.
+#[derive(Debug, Clone)]
+pub struct SceneGeometry {
+    /// In the order of `Scene::meshes`, followed by one per area light
+    pub meshes: Vec<TriangleMesh>,
+    pub instances: Vec<MeshInstance>,
+    /// `Scene::materials`, followed by the materials of the mesh files
+    pub materials: Vec<Material>,
+    /// `Scene::textures`, followed by the textures of the mesh files
+    pub textures: Vec<Texture>,
+    pub environment: Option<EnvironmentMap>,
+    /// The point, spot and directional lights. Area lights are emissive
+    /// meshes at the end of `meshes` instead.
+    pub lights: Vec<Light>,
+}
+
+impl SceneGeometry {
+    /// Whether any instance moves while the shutter is open.
+    pub fn has_motion(&self) -> bool {
+        self.instances
+            .iter()
+            .any(|instance| instance.end_transform.is_some())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeshInstance {
+    /// Index into `SceneGeometry::meshes`
+    pub mesh: usize,
+    /// Where the instance is at time 0
+    pub transform: Mat4,
+    /// Where a moving instance is at time 1
+    pub end_transform: Option<Mat4>,
+    /// Index into `SceneGeometry::materials`, replacing the materials of the
+    /// mesh's triangles
+    pub material: Option<usize>,
+}
+
+impl MeshInstance {
+    /// The transform at `time`, between 0 and 1. Moving instances are
+    /// interpolated by their scale, rotation and translation, so they turn
+    /// rather than shrink through a rotation.
+    pub fn transform_at(&self, time: f32) -> Mat4 {
+        let Some(end_transform) = self.end_transform else {
+            return self.transform;
+        };
+        let (start_scale, start_rotation, start_translation) =
+            self.transform.to_scale_rotation_translation();
+        let (end_scale, end_rotation, end_translation) =
+            end_transform.to_scale_rotation_translation();
+        Mat4::from_scale_rotation_translation(
+            start_scale.lerp(end_scale, time),
+            start_rotation.slerp(end_rotation, time),
+            start_translation.lerp(end_translation, time),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Quat;
+
+    use super::*;
+
+    #[test]
+    fn moving_instances_turn_between_their_transforms() {
+        let instance = MeshInstance {
+            mesh: 0,
+            transform: Mat4::from_scale(Vec3::splat(2.0)),
+            end_transform: Some(Mat4::from_scale_rotation_translation(
+                Vec3::splat(2.0),
+                Quat::from_rotation_y(2.0),
+                Vec3::new(4.0, 0.0, 0.0),
+            )),
+            material: None,
+        };
+        assert!(instance
+            .transform_at(0.0)
+            .abs_diff_eq(instance.transform, 1e-6));
+        assert!(instance
+            .transform_at(1.0)
+            .abs_diff_eq(instance.end_transform.unwrap(), 1e-5));
+
+        // Halfway it has turned half as far and kept its size
+        let halfway = instance.transform_at(0.5);
+        let expected = Mat4::from_scale_rotation_translation(
+            Vec3::splat(2.0),
+            Quat::from_rotation_y(1.0),
+            Vec3::new(2.0, 0.0, 0.0),
+        );
+        assert!(halfway.abs_diff_eq(expected, 1e-5));
+
+        let still = MeshInstance {
+            end_transform: None,
+            ..instance
+        };
+        assert_eq!(still.transform_at(0.7), still.transform);
+    }
+}
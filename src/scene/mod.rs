@@ -0,0 +1,889 @@
+//! Declarative scene files.
+//!
+//! Scenes are written in TOML and describe the render settings, cameras,
+//! meshes, materials, the objects placing meshes in the world and the lights.
+//! Every section is optional; `scenes/triangle.toml` is a complete example.
+//! glTF files are imported as whole scenes instead, see `load`.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use ash::vk;
+use glam::{EulerRot, Mat4, Quat, Vec3};
+use serde::Deserialize;
+
+pub mod gltf;
+pub mod mesh;
+pub mod obj;
+pub mod texture;
+
+use mesh::{MeshInstance, SceneGeometry, TriangleMesh};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scene {
+    #[serde(default)]
+    pub render: RenderSettings,
+    /// The first camera is the one rendered from
+    #[serde(default)]
+    pub cameras: Vec<Camera>,
+    #[serde(default)]
+    pub meshes: Vec<Mesh>,
+    #[serde(default)]
+    pub materials: Vec<Material>,
+    #[serde(default)]
+    pub objects: Vec<Object>,
+    #[serde(default)]
+    pub lights: Vec<Light>,
+    /// Lights rays that leave the scene, in place of the default sky
+    #[serde(default)]
+    pub environment: Option<Environment>,
+    /// Images referenced by materials. Scene files can't declare textures,
+    /// only glTF imports bring them.
+    #[serde(skip)]
+    pub textures: Vec<texture::Texture>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    /// The path tracer stops accumulating once it reaches this many samples,
+    /// and headless renders take this many
+    pub samples_per_pixel: u32,
+    /// Scenes with moving objects take one sample per frame instead, since
+    /// the objects move between frames
+    pub samples_per_frame: u32,
+    pub max_bounces: u32,
+    /// Randomly ends paths that carry little light from
+    /// `russian_roulette_depth` bounces on
+    pub russian_roulette: bool,
+    pub russian_roulette_depth: u32,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            width: 800,
+            height: 800,
+            samples_per_pixel: 256,
+            samples_per_frame: 1,
+            max_bounces: 4,
+            russian_roulette: true,
+            russian_roulette_depth: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Camera {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub position: [f32; 3],
+    pub look_at: [f32; 3],
+    #[serde(default = "default_up")]
+    pub up: [f32; 3],
+    /// Vertical field of view
+    #[serde(default = "default_fov_degrees")]
+    pub fov_degrees: f32,
+    /// Lens diameter, zero for a pinhole camera
+    #[serde(default)]
+    pub aperture: f32,
+    #[serde(default = "default_focus_distance")]
+    pub focus_distance: f32,
+    /// Blades of a polygonal aperture, or zero for a round one
+    #[serde(default)]
+    pub aperture_blades: u32,
+    #[serde(default)]
+    pub aperture_rotation_degrees: f32,
+    /// When the shutter opens and closes, between time 0 and 1 of the
+    /// objects' motion
+    #[serde(default = "default_shutter")]
+    pub shutter: [f32; 2],
+}
+
+impl Default for Camera {
+    /// Looking at the origin along +Z.
+    fn default() -> Self {
+        Self {
+            name: None,
+            position: [0.0, 0.0, -1.0],
+            look_at: [0.0, 0.0, 0.0],
+            up: default_up(),
+            fov_degrees: default_fov_degrees(),
+            aperture: 0.0,
+            focus_distance: default_focus_distance(),
+            aperture_blades: 0,
+            aperture_rotation_degrees: 0.0,
+            shutter: default_shutter(),
+        }
+    }
+}
+
+fn default_up() -> [f32; 3] {
+    [0.0, 1.0, 0.0]
+}
+
+fn default_fov_degrees() -> f32 {
+    60.0
+}
+
+fn default_focus_distance() -> f32 {
+    1.0
+}
+
+fn default_shutter() -> [f32; 2] {
+    [0.0, 1.0]
+}
+
+/// Geometry referenced by name from objects. Either loaded from the OBJ file
+/// at `path`, relative to the scene file, or one of the built-in primitives.
+/// A glTF `path` refers to the mesh of that file named `name`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mesh {
+    pub name: String,
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub primitive: Option<Primitive>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Primitive {
+    /// The triangle of `engine::geometry`
+    Triangle,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Material {
+    pub name: String,
+    #[serde(flatten)]
+    pub model: MaterialModel,
+}
+
+/// How a surface scatters light, chosen with the `type` key.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum MaterialModel {
+    Lambert {
+        albedo: [f32; 3],
+    },
+    /// A GGX microfacet metal
+    Conductor {
+        color: [f32; 3],
+        roughness: f32,
+    },
+    /// Glass-like refraction with index of refraction `ior`
+    Dielectric {
+        ior: f32,
+        #[serde(default)]
+        roughness: f32,
+    },
+    Emissive {
+        color: [f32; 3],
+        strength: f32,
+    },
+    /// The glTF metallic-roughness model. Textures index `Scene::textures`;
+    /// the metallic-roughness texture holds roughness in green and metalness
+    /// in blue, both scaling the factors.
+    MetallicRoughness {
+        base_color: [f32; 3],
+        metallic: f32,
+        roughness: f32,
+        #[serde(default)]
+        emissive: [f32; 3],
+        #[serde(default)]
+        base_color_texture: Option<usize>,
+        #[serde(default)]
+        metallic_roughness_texture: Option<usize>,
+        #[serde(default)]
+        normal_texture: Option<usize>,
+        #[serde(default)]
+        emissive_texture: Option<usize>,
+    },
+}
+
+impl MaterialModel {
+    /// A Lambertian surface whose albedo is scaled by a texture, as the
+    /// metallic-roughness model without metalness.
+    pub fn textured_lambert(albedo: [f32; 3], texture: usize) -> Self {
+        Self::MetallicRoughness {
+            base_color: albedo,
+            metallic: 0.0,
+            roughness: 1.0,
+            emissive: [0.0; 3],
+            base_color_texture: Some(texture),
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            emissive_texture: None,
+        }
+    }
+
+    /// Shifts the texture indices by `offset`, for when the textures they
+    /// index are appended to a longer list.
+    pub fn offset_texture_indices(&mut self, offset: usize) {
+        if let Self::MetallicRoughness {
+            base_color_texture,
+            metallic_roughness_texture,
+            normal_texture,
+            emissive_texture,
+            ..
+        } = self
+        {
+            for texture in [
+                base_color_texture,
+                metallic_roughness_texture,
+                normal_texture,
+                emissive_texture,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                *texture += offset;
+            }
+        }
+    }
+}
+
+/// A mesh placed in the world.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Object {
+    pub mesh: String,
+    /// Objects without a material use a white Lambertian surface
+    #[serde(default)]
+    pub material: Option<String>,
+    #[serde(default)]
+    pub transform: Transform,
+    /// Where the object has moved to at time 1, blurring it over the time
+    /// the shutter is open. `transform` is where it is at time 0.
+    #[serde(default)]
+    pub end_transform: Option<Transform>,
+}
+
+/// Scale, then rotation about X, Y and Z in that order, then translation.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Transform {
+    pub translation: [f32; 3],
+    pub rotation_degrees: [f32; 3],
+    pub scale: [f32; 3],
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: [0.0; 3],
+            rotation_degrees: [0.0; 3],
+            scale: [1.0; 3],
+        }
+    }
+}
+
+impl Transform {
+    pub fn matrix(&self) -> Mat4 {
+        let [x, y, z] = self.rotation_degrees.map(f32::to_radians);
+        Mat4::from_scale_rotation_translation(
+            Vec3::from_array(self.scale),
+            Quat::from_euler(EulerRot::ZYX, z, y, x),
+            Vec3::from_array(self.translation),
+        )
+    }
+
+    /// The closest transform to `matrix`; shear is lost.
+    pub fn from_matrix(matrix: &Mat4) -> Self {
+        let (scale, rotation, translation) = matrix.to_scale_rotation_translation();
+        let (z, y, x) = rotation.to_euler(EulerRot::ZYX);
+        Self {
+            translation: translation.to_array(),
+            rotation_degrees: [x, y, z].map(f32::to_degrees),
+            scale: scale.to_array(),
+        }
+    }
+}
+
+/// A light source, chosen with the `type` key. `intensity` is in watts per
+/// steradian for point and spot lights, in lux for directional lights and
+/// the radiance of area lights, which glow on both faces.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Light {
+    Point {
+        position: [f32; 3],
+        #[serde(default = "default_light_color")]
+        color: [f32; 3],
+        intensity: f32,
+    },
+    Directional {
+        /// The direction the light travels in
+        direction: [f32; 3],
+        #[serde(default = "default_light_color")]
+        color: [f32; 3],
+        intensity: f32,
+    },
+    Spot {
+        position: [f32; 3],
+        direction: [f32; 3],
+        #[serde(default = "default_light_color")]
+        color: [f32; 3],
+        intensity: f32,
+        /// Full intensity inside this angle from `direction`
+        inner_angle_degrees: f32,
+        /// No light outside this angle from `direction`
+        outer_angle_degrees: f32,
+    },
+    /// A parallelogram spanned by two edges from a corner, loaded as an
+    /// emissive mesh
+    Area {
+        corner: [f32; 3],
+        edge_u: [f32; 3],
+        edge_v: [f32; 3],
+        #[serde(default = "default_light_color")]
+        color: [f32; 3],
+        intensity: f32,
+    },
+}
+
+fn default_light_color() -> [f32; 3] {
+    [1.0; 3]
+}
+
+/// An equirectangular image of the light arriving from every direction. Its
+/// top row looks straight up (+Y) and its columns go around from +X towards
+/// +Z.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Environment {
+    /// Relative to the scene file, usually a Radiance HDR image
+    pub path: PathBuf,
+    /// Scales the radiance of the image
+    #[serde(default = "default_environment_intensity")]
+    pub intensity: f32,
+    /// Turns the image about the up axis, e.g. to move the sun
+    #[serde(default)]
+    pub rotation_degrees: f32,
+}
+
+fn default_environment_intensity() -> f32 {
+    1.0
+}
+
+impl Environment {
+    /// Decodes the image. It's read without mip levels and wraps around
+    /// horizontally only.
+    pub fn load(&self) -> Result<EnvironmentMap, SceneError> {
+        let mut texture = texture::load_texture(&self.path)?;
+        texture.sampler.use_mipmaps = false;
+        texture.sampler.address_mode_v = vk::SamplerAddressMode::CLAMP_TO_EDGE;
+        Ok(EnvironmentMap {
+            texture,
+            intensity: self.intensity,
+            rotation_degrees: self.rotation_degrees,
+        })
+    }
+}
+
+/// An `Environment` with its image in memory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvironmentMap {
+    pub texture: texture::Texture,
+    pub intensity: f32,
+    pub rotation_degrees: f32,
+}
+
+impl Default for Scene {
+    /// The triangle the renderer draws without a scene file, seen head-on.
+    fn default() -> Self {
+        Self {
+            render: RenderSettings::default(),
+            cameras: vec![Camera::default()],
+            meshes: vec![Mesh {
+                name: "triangle".to_string(),
+                path: None,
+                primitive: Some(Primitive::Triangle),
+            }],
+            materials: Vec::new(),
+            objects: vec![Object {
+                mesh: "triangle".to_string(),
+                material: None,
+                transform: Transform::default(),
+                end_transform: None,
+            }],
+            lights: Vec::new(),
+            environment: None,
+            textures: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        error: Box<toml::de::Error>,
+    },
+    Invalid {
+        path: PathBuf,
+        message: String,
+    },
+    Obj(obj::ObjError),
+    Gltf {
+        path: PathBuf,
+        error: Box<::gltf::Error>,
+    },
+    Image {
+        path: PathBuf,
+        error: Box<image::ImageError>,
+    },
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, error } => {
+                write!(f, "Can't read scene {}: {}", path.display(), error)
+            }
+            // The TOML error already points at the offending line
+            Self::Parse { path, error } => {
+                write!(f, "Can't parse scene {}:\n{}", path.display(), error)
+            }
+            Self::Invalid { path, message } => {
+                write!(f, "Invalid scene {}: {}", path.display(), message)
+            }
+            Self::Obj(error) => write!(f, "Can't load mesh: {}", error),
+            Self::Gltf { path, error } => {
+                write!(f, "Can't load glTF file {}: {}", path.display(), error)
+            }
+            Self::Image { path, error } => {
+                write!(f, "Can't load texture {}: {}", path.display(), error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+impl From<obj::ObjError> for SceneError {
+    fn from(error: obj::ObjError) -> Self {
+        Self::Obj(error)
+    }
+}
+
+/// Loads a scene and its meshes, importing glTF files by their `.gltf` or
+/// `.glb` extension and reading anything else as a scene file.
+pub fn load(path: &Path) -> Result<(Scene, SceneGeometry), SceneError> {
+    if gltf::is_gltf(path) {
+        return gltf::load_gltf(path);
+    }
+
+    let scene = Scene::load(path)?;
+    let geometry = scene.load_geometry()?;
+    Ok((scene, geometry))
+}
+
+impl Scene {
+    /// Reads, parses and validates a scene file. Mesh and environment paths
+    /// in the loaded scene are resolved against the directory of the file.
+    pub fn load(path: &Path) -> Result<Self, SceneError> {
+        let source = std::fs::read_to_string(path).map_err(|error| SceneError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        let mut scene = Self::parse(&source, path)?;
+
+        let directory = path.parent().unwrap_or(Path::new(""));
+        for mesh in scene.meshes.iter_mut() {
+            if let Some(mesh_path) = mesh.path.as_mut() {
+                *mesh_path = directory.join(&*mesh_path);
+            }
+        }
+        if let Some(environment) = scene.environment.as_mut() {
+            environment.path = directory.join(&environment.path);
+        }
+
+        Ok(scene)
+    }
+
+    /// Parses and validates scene file contents. `path` is only used in
+    /// error messages.
+    pub fn parse(source: &str, path: &Path) -> Result<Self, SceneError> {
+        let scene: Scene = toml::from_str(source).map_err(|error| SceneError::Parse {
+            path: path.to_path_buf(),
+            error: Box::new(error),
+        })?;
+        scene.validate().map_err(|message| SceneError::Invalid {
+            path: path.to_path_buf(),
+            message,
+        })?;
+
+        Ok(scene)
+    }
+
+    /// Loads every mesh into memory and resolves the objects to mesh and
+    /// material indices. The materials of mesh files are appended to those of
+    /// the scene, named after the mesh, and the textures they use to those of
+    /// the scene. Diffuse maps of OBJ materials are decoded once per file, and
+    /// the environment image is decoded too. Area lights become emissive
+    /// meshes after those of the scene, the other lights are passed on.
+    pub fn load_geometry(&self) -> Result<SceneGeometry, SceneError> {
+        let mut meshes = Vec::with_capacity(self.meshes.len());
+        let mut materials = self.materials.clone();
+        let mut textures = self.textures.clone();
+        let mut texture_paths: HashMap<PathBuf, usize> = HashMap::new();
+        for mesh in &self.meshes {
+            let (mut triangle_mesh, mesh_materials) = match (&mesh.path, mesh.primitive) {
+                (Some(path), _) if gltf::is_gltf(path) => {
+                    let (triangle_mesh, mut mesh_materials, mesh_textures) =
+                        gltf::load_gltf_mesh(path, &mesh.name)?;
+                    for material in mesh_materials.iter_mut() {
+                        material.model.offset_texture_indices(textures.len());
+                    }
+                    textures.extend(mesh_textures);
+                    (triangle_mesh, mesh_materials)
+                }
+                (Some(path), _) => {
+                    let model = obj::load_obj(path)?;
+                    let mut mesh_materials = Vec::with_capacity(model.materials.len());
+                    for material in &model.materials {
+                        let mut material_model = material.to_material_model();
+                        if let (MaterialModel::Lambert { albedo }, Some(texture_path)) =
+                            (&material_model, &material.diffuse_texture)
+                        {
+                            let texture = match texture_paths.get(texture_path) {
+                                Some(&texture) => texture,
+                                None => {
+                                    textures.push(texture::load_texture(texture_path)?);
+                                    texture_paths.insert(texture_path.clone(), textures.len() - 1);
+                                    textures.len() - 1
+                                }
+                            };
+                            material_model = MaterialModel::textured_lambert(*albedo, texture);
+                        }
+                        mesh_materials.push(Material {
+                            name: format!("{}/{}", mesh.name, material.name),
+                            model: material_model,
+                        });
+                    }
+                    (model.mesh, mesh_materials)
+                }
+                (None, Some(primitive)) => (TriangleMesh::from_primitive(primitive), Vec::new()),
+                (None, None) => unreachable!("validated when parsing"),
+            };
+            if triangle_mesh.triangle_count() == 0 {
+                return Err(SceneError::Invalid {
+                    path: mesh.path.clone().unwrap_or_default(),
+                    message: format!("mesh \"{}\" has no triangles", mesh.name),
+                });
+            }
+            triangle_mesh.offset_material_ids(materials.len() as u32);
+            materials.extend(mesh_materials);
+            meshes.push(triangle_mesh);
+        }
+
+        let mut instances: Vec<MeshInstance> = self
+            .objects
+            .iter()
+            .map(|object| MeshInstance {
+                mesh: self
+                    .meshes
+                    .iter()
+                    .position(|mesh| mesh.name == object.mesh)
+                    .expect("validated when parsing"),
+                transform: object.transform.matrix(),
+                end_transform: object.end_transform.as_ref().map(Transform::matrix),
+                material: object.material.as_ref().map(|name| {
+                    self.materials
+                        .iter()
+                        .position(|material| &material.name == name)
+                        .expect("validated when parsing")
+                }),
+            })
+            .collect();
+
+        let mut lights = Vec::with_capacity(self.lights.len());
+        for (index, light) in self.lights.iter().enumerate() {
+            let Light::Area {
+                corner,
+                edge_u,
+                edge_v,
+                color,
+                intensity,
+            } = *light
+            else {
+                lights.push(light.clone());
+                continue;
+            };
+            instances.push(MeshInstance {
+                mesh: meshes.len(),
+                transform: Mat4::IDENTITY,
+                end_transform: None,
+                material: Some(materials.len()),
+            });
+            meshes.push(TriangleMesh::parallelogram(
+                Vec3::from_array(corner),
+                Vec3::from_array(edge_u),
+                Vec3::from_array(edge_v),
+            ));
+            materials.push(Material {
+                name: format!("area light {}", index),
+                model: MaterialModel::Emissive {
+                    color,
+                    strength: intensity,
+                },
+            });
+        }
+
+        let environment = self
+            .environment
+            .as_ref()
+            .map(Environment::load)
+            .transpose()?;
+
+        Ok(SceneGeometry {
+            meshes,
+            instances,
+            materials,
+            textures,
+            environment,
+            lights,
+        })
+    }
+
+    pub fn camera(&self) -> Option<&Camera> {
+        self.cameras.first()
+    }
+
+    pub fn mesh(&self, name: &str) -> Option<&Mesh> {
+        self.meshes.iter().find(|mesh| mesh.name == name)
+    }
+
+    pub fn material(&self, name: &str) -> Option<&Material> {
+        self.materials.iter().find(|material| material.name == name)
+    }
+
+    /// Checks what the file format can't express: references between
+    /// sections, unique names and value ranges.
+    fn validate(&self) -> Result<(), String> {
+        let render = &self.render;
+        if render.width == 0 || render.height == 0 {
+            return Err(format!(
+                "render resolution {}x{} has no pixels",
+                render.width, render.height
+            ));
+        }
+        if render.samples_per_pixel == 0 || render.samples_per_frame == 0 {
+            return Err(
+                "render.samples_per_pixel and render.samples_per_frame must be at least 1"
+                    .to_string(),
+            );
+        }
+
+        for (index, camera) in self.cameras.iter().enumerate() {
+            if !(camera.fov_degrees > 0.0 && camera.fov_degrees < 180.0) {
+                return Err(format!(
+                    "camera {} has a field of view of {} degrees, expected between 0 and 180",
+                    index, camera.fov_degrees
+                ));
+            }
+            if camera.position == camera.look_at {
+                return Err(format!("camera {} looks at its own position", index));
+            }
+            if camera.up == [0.0; 3] {
+                return Err(format!("camera {} has a zero up vector", index));
+            }
+            if camera.aperture < 0.0 || camera.focus_distance <= 0.0 {
+                return Err(format!(
+                    "camera {} needs a non-negative aperture and a positive focus distance",
+                    index
+                ));
+            }
+            if camera.aperture_blades != 0 && camera.aperture_blades < 3 {
+                return Err(format!(
+                    "camera {} has {} aperture blades, expected 0 for a round aperture or at least 3",
+                    index, camera.aperture_blades
+                ));
+            }
+            let [open, close] = camera.shutter;
+            if !(0.0 <= open && open <= close && close <= 1.0) {
+                return Err(format!(
+                    "camera {} has a shutter interval of [{}, {}], expected within [0, 1]",
+                    index, open, close
+                ));
+            }
+        }
+
+        if self.objects.is_empty() {
+            return Err("there are no objects to render".to_string());
+        }
+
+        check_unique_names("mesh", self.meshes.iter().map(|mesh| &mesh.name))?;
+        for mesh in &self.meshes {
+            match (&mesh.path, &mesh.primitive) {
+                (None, None) => {
+                    return Err(format!(
+                        "mesh \"{}\" needs either a path or a primitive",
+                        mesh.name
+                    ))
+                }
+                (Some(_), Some(_)) => {
+                    return Err(format!(
+                        "mesh \"{}\" has both a path and a primitive",
+                        mesh.name
+                    ))
+                }
+                _ => {}
+            }
+        }
+
+        check_unique_names(
+            "material",
+            self.materials.iter().map(|material| &material.name),
+        )?;
+        for material in &self.materials {
+            let is_valid = match material.model {
+                MaterialModel::Lambert { .. } => true,
+                MaterialModel::Conductor { roughness, .. } => (0.0..=1.0).contains(&roughness),
+                MaterialModel::Dielectric { ior, roughness } => {
+                    ior > 0.0 && (0.0..=1.0).contains(&roughness)
+                }
+                MaterialModel::Emissive { strength, .. } => strength >= 0.0,
+                MaterialModel::MetallicRoughness {
+                    metallic,
+                    roughness,
+                    ..
+                } => (0.0..=1.0).contains(&metallic) && (0.0..=1.0).contains(&roughness),
+            };
+            if !is_valid {
+                return Err(format!(
+                    "material \"{}\" is out of range: roughness and metallic must be within 0 to 1, ior positive and strength non-negative",
+                    material.name
+                ));
+            }
+            if let MaterialModel::MetallicRoughness {
+                base_color_texture,
+                metallic_roughness_texture,
+                normal_texture,
+                emissive_texture,
+                ..
+            } = material.model
+            {
+                let textures = [
+                    base_color_texture,
+                    metallic_roughness_texture,
+                    normal_texture,
+                    emissive_texture,
+                ];
+                if let Some(texture) = textures
+                    .into_iter()
+                    .flatten()
+                    .find(|&texture| texture >= self.textures.len())
+                {
+                    return Err(format!(
+                        "material \"{}\" uses texture {}, but there are {} textures",
+                        material.name,
+                        texture,
+                        self.textures.len()
+                    ));
+                }
+            }
+        }
+
+        for (index, object) in self.objects.iter().enumerate() {
+            if self.mesh(&object.mesh).is_none() {
+                return Err(format!(
+                    "object {} uses mesh \"{}\", which is not defined",
+                    index, object.mesh
+                ));
+            }
+            if let Some(material) = object.material.as_ref() {
+                if self.material(material).is_none() {
+                    return Err(format!(
+                        "object {} uses material \"{}\", which is not defined",
+                        index, material
+                    ));
+                }
+            }
+        }
+
+        if let Some(environment) = self.environment.as_ref() {
+            if environment.intensity.is_nan() || environment.intensity < 0.0 {
+                return Err(format!(
+                    "environment intensity {} must be non-negative",
+                    environment.intensity
+                ));
+            }
+        }
+
+        for (index, light) in self.lights.iter().enumerate() {
+            if let Light::Point { intensity, .. }
+            | Light::Directional { intensity, .. }
+            | Light::Spot { intensity, .. } = light
+            {
+                if intensity.is_nan() || *intensity < 0.0 {
+                    return Err(format!(
+                        "light {} has intensity {}, expected non-negative",
+                        index, intensity
+                    ));
+                }
+            }
+            if let Light::Spot {
+                inner_angle_degrees,
+                outer_angle_degrees,
+                ..
+            } = light
+            {
+                if !(0.0 <= *inner_angle_degrees
+                    && inner_angle_degrees <= outer_angle_degrees
+                    && *outer_angle_degrees <= 90.0)
+                {
+                    return Err(format!(
+                        "spot light {} needs 0 <= inner_angle_degrees <= outer_angle_degrees <= 90",
+                        index
+                    ));
+                }
+            }
+            if let Light::Area {
+                edge_u,
+                edge_v,
+                intensity,
+                ..
+            } = light
+            {
+                let area = Vec3::from_array(*edge_u)
+                    .cross(Vec3::from_array(*edge_v))
+                    .length();
+                if !(area > 0.0 && *intensity >= 0.0) {
+                    return Err(format!(
+                        "area light {} needs edges spanning an area and a non-negative intensity",
+                        index
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn check_unique_names<'a>(
+    kind: &str,
+    names: impl Iterator<Item = &'a String>,
+) -> Result<(), String> {
+    let mut seen = HashSet::new();
+    for name in names {
+        if !seen.insert(name) {
+            return Err(format!("{} \"{}\" is defined more than once", kind, name));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,462 @@
+//! Wavefront OBJ and MTL import.
+//!
+//! Supports positions, texture coordinates and normals, polygonal faces with
+//! any of the `v`, `v/vt`, `v//vn` and `v/vt/vn` corner forms (including
+//! negative indices), `usemtl` and `mtllib`. Other statements, such as
+//! groups and smoothing groups, are ignored.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::scene::mesh::{TriangleMesh, Vertex, NO_MATERIAL};
+use crate::scene::MaterialModel;
+
+/// A material from an MTL file, with the values of its statements or the
+/// MTL defaults.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MtlMaterial {
+    pub name: String,
+    /// `Kd`
+    pub diffuse: [f32; 3],
+    /// `Ks`
+    pub specular: [f32; 3],
+    /// `Ke`
+    pub emission: [f32; 3],
+    /// `Ns`, the Phong exponent
+    pub shininess: f32,
+    /// `Ni`
+    pub ior: f32,
+    /// `d`, or one minus `Tr`
+    pub dissolve: f32,
+    pub illumination_model: u32,
+    /// `map_Kd`, relative to the directory of the MTL file
+    pub diffuse_texture: Option<PathBuf>,
+}
+
+impl MtlMaterial {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            diffuse: [0.8; 3],
+            specular: [0.0; 3],
+            emission: [0.0; 3],
+            shininess: 0.0,
+            ior: 1.0,
+            dissolve: 1.0,
+            illumination_model: 2,
+            diffuse_texture: None,
+        }
+    }
+
+    /// The closest of the renderer's material models. Emission wins, then
+    /// transparency or a refracting illumination model makes a dielectric, and
+    /// a specular color a conductor whose roughness comes from `Ns`.
+    pub fn to_material_model(&self) -> MaterialModel {
+        if self.emission.iter().any(|&channel| channel > 0.0) {
+            let strength = self.emission.iter().cloned().fold(0.0, f32::max);
+            return MaterialModel::Emissive {
+                color: self.emission.map(|channel| channel / strength),
+                strength,
+            };
+        }
+
+        // Phong exponent to GGX roughness, as alpha = sqrt(2 / (Ns + 2))
+        let roughness = (2.0 / (self.shininess.max(0.0) + 2.0)).sqrt().sqrt();
+        if self.dissolve < 1.0 || matches!(self.illumination_model, 4 | 6 | 7) {
+            MaterialModel::Dielectric {
+                ior: if self.ior > 1.0 { self.ior } else { 1.5 },
+                roughness: 0.0,
+            }
+        } else if self.illumination_model == 3 || self.specular.iter().any(|&channel| channel > 0.5)
+        {
+            MaterialModel::Conductor {
+                color: self.specular,
+                roughness,
+            }
+        } else {
+            MaterialModel::Lambert {
+                albedo: self.diffuse,
+            }
+        }
+    }
+}
+
+/// The contents of an OBJ file as one mesh, with the materials its triangles
+/// refer to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjModel {
+    pub mesh: TriangleMesh,
+    pub materials: Vec<MtlMaterial>,
+}
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io {
+        path: PathBuf,
+        error: std::io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            Self::Parse {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", path.display(), line, message),
+        }
+    }
+}
+
+impl std::error::Error for ObjError {}
+
+fn read_file(path: &Path) -> Result<String, ObjError> {
+    std::fs::read_to_string(path).map_err(|error| ObjError::Io {
+        path: path.to_path_buf(),
+        error,
+    })
+}
+
+/// Loads an OBJ file and the MTL files it references, which are looked up
+/// next to it.
+pub fn load_obj(path: &Path) -> Result<ObjModel, ObjError> {
+    let source = read_file(path)?;
+    let directory = path.parent().unwrap_or(Path::new(""));
+    parse_obj(&source, path, |name| {
+        let mtl_path = directory.join(name);
+        let source = read_file(&mtl_path)?;
+        parse_mtl(&source, &mtl_path)
+    })
+}
+
+/// Parses OBJ source. `load_mtl` is called with the file name of each
+/// `mtllib`, and `path` is only used in error messages.
+pub fn parse_obj(
+    source: &str,
+    path: &Path,
+    mut load_mtl: impl FnMut(&str) -> Result<Vec<MtlMaterial>, ObjError>,
+) -> Result<ObjModel, ObjError> {
+    let mut positions: Vec<[f32; 3]> = Vec::new();
+    let mut uvs: Vec<[f32; 2]> = Vec::new();
+    let mut normals: Vec<[f32; 3]> = Vec::new();
+
+    let mut materials: Vec<MtlMaterial> = Vec::new();
+    let mut current_material = NO_MATERIAL;
+
+    let mut mesh = TriangleMesh::default();
+    // Vertices are shared between faces when all their indices match
+    let mut vertex_indices: HashMap<(usize, Option<usize>, Option<usize>), u32> = HashMap::new();
+    let mut has_missing_normals = false;
+
+    for (line_index, line) in source.lines().enumerate() {
+        let error = |message: String| ObjError::Parse {
+            path: path.to_path_buf(),
+            line: line_index + 1,
+            message,
+        };
+
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        let arguments: Vec<&str> = tokens.collect();
+
+        match keyword {
+            "v" => positions.push(parse_floats(&arguments).map_err(error)?),
+            "vt" => {
+                let [u, v] = parse_floats(&arguments).map_err(error)?;
+                // OBJ puts the origin at the bottom left, Vulkan at the top left
+                uvs.push([u, 1.0 - v]);
+            }
+            "vn" => normals.push(parse_floats(&arguments).map_err(error)?),
+            "f" => {
+                if arguments.len() < 3 {
+                    return Err(error(format!(
+                        "face has {} corners, expected at least 3",
+                        arguments.len()
+                    )));
+                }
+
+                let mut corners = Vec::with_capacity(arguments.len());
+                for corner in &arguments {
+                    let key = parse_corner(corner, positions.len(), uvs.len(), normals.len())
+                        .map_err(error)?;
+                    has_missing_normals |= key.2.is_none();
+                    let index = *vertex_indices.entry(key).or_insert_with(|| {
+                        let (position, uv, normal) = key;
+                        mesh.vertices.push(Vertex {
+                            position: positions[position],
+                            normal: normal.map_or([0.0; 3], |normal| normals[normal]),
+                            uv: uv.map_or([0.0; 2], |uv| uvs[uv]),
+                        });
+                        mesh.vertices.len() as u32 - 1
+                    });
+                    corners.push(index);
+                }
+
+                // Fan triangulation, which is exact for the convex polygons
+                // OBJ exporters write
+                for i in 1..corners.len() - 1 {
+                    mesh.indices
+                        .extend_from_slice(&[corners[0], corners[i], corners[i + 1]]);
+                    mesh.material_ids.push(current_material);
+                }
+            }
+            "usemtl" => {
+                let name = arguments.join(" ");
+                current_material = match materials.iter().position(|m| m.name == name) {
+                    Some(index) => index as u32,
+                    // Keep going with defaults when the MTL file lacks it
+                    None => {
+                        materials.push(MtlMaterial::new(&name));
+                        materials.len() as u32 - 1
+                    }
+                };
+            }
+            "mtllib" => {
+                for name in &arguments {
+                    for material in load_mtl(name)? {
+                        if !materials.iter().any(|m| m.name == material.name) {
+                            materials.push(material);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if has_missing_normals {
+        generate_missing_normals(&mut mesh, &vertex_indices);
+    }
+
+    Ok(ObjModel { mesh, materials })
+}
+
+/// Gives vertices without a normal in the file the smooth normal of their
+/// position, averaged over every face using that position.
+fn generate_missing_normals(
+    mesh: &mut TriangleMesh,
+    vertex_indices: &HashMap<(usize, Option<usize>, Option<usize>), u32>,
+) {
+    let mut generated = mesh.clone();
+    generated.generate_normals();
+
+    // Sum the generated normals of all vertices sharing a position
+    let mut position_normals: HashMap<usize, glam::Vec3> = HashMap::new();
+    for (&(position, _, _), &index) in vertex_indices {
+        let normal = glam::Vec3::from_array(generated.vertices[index as usize].normal);
+        *position_normals.entry(position).or_default() += normal;
+    }
+
+    for (&(position, _, normal), &index) in vertex_indices {
+        if normal.is_none() {
+            mesh.vertices[index as usize].normal =
+                position_normals[&position].normalize_or_zero().to_array();
+        }
+    }
+}
+
+fn parse_floats<const N: usize>(arguments: &[&str]) -> Result<[f32; N], String> {
+    if arguments.len() < N {
+        return Err(format!("expected {} numbers, found {}", N, arguments.len()));
+    }
+
+    let mut values = [0.0; N];
+    for (value, argument) in values.iter_mut().zip(arguments) {
+        *value = argument
+            .parse()
+            .map_err(|_| format!("\"{}\" is not a number", argument))?;
+    }
+
+    Ok(values)
+}
+
+/// Resolves a face corner like `3/1/2`, `3//2` or `-1` into zero-based
+/// position, texture coordinate and normal indices.
+fn parse_corner(
+    corner: &str,
+    position_count: usize,
+    uv_count: usize,
+    normal_count: usize,
+) -> Result<(usize, Option<usize>, Option<usize>), String> {
+    let mut parts = corner.split('/');
+    let resolve = |part: Option<&str>, count: usize, kind: &str| -> Result<Option<usize>, String> {
+        let Some(part) = part.filter(|part| !part.is_empty()) else {
+            return Ok(None);
+        };
+        let index: i64 = part
+            .parse()
+            .map_err(|_| format!("\"{}\" is not a valid {} index", part, kind))?;
+        // Indices are one-based, negative ones count back from the end
+        let resolved = if index > 0 {
+            index - 1
+        } else {
+            count as i64 + index
+        };
+        if index == 0 || resolved < 0 || resolved >= count as i64 {
+            return Err(format!(
+                "{} index {} is out of range, {} are defined",
+                kind, index, count
+            ));
+        }
+        Ok(Some(resolved as usize))
+    };
+
+    let position = resolve(parts.next(), position_count, "position")?
+        .ok_or_else(|| format!("face corner \"{}\" has no position", corner))?;
+    let uv = resolve(parts.next(), uv_count, "texture coordinate")?;
+    let normal = resolve(parts.next(), normal_count, "normal")?;
+
+    Ok((position, uv, normal))
+}
+
+/// Parses MTL source. Texture paths are resolved against the directory of
+/// `path`, which is also used in error messages.
+pub fn parse_mtl(source: &str, path: &Path) -> Result<Vec<MtlMaterial>, ObjError> {
+    let directory = path.parent().unwrap_or(Path::new(""));
+    let mut materials: Vec<MtlMaterial> = Vec::new();
+
+    for (line_index, line) in source.lines().enumerate() {
+        let error = |message: String| ObjError::Parse {
+            path: path.to_path_buf(),
+            line: line_index + 1,
+            message,
+        };
+
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        let arguments: Vec<&str> = tokens.collect();
+
+        if keyword == "newmtl" {
+            materials.push(MtlMaterial::new(&arguments.join(" ")));
+            continue;
+        }
+        let Some(material) = materials.last_mut() else {
+            return Err(error(format!("\"{}\" before any newmtl", keyword)));
+        };
+
+        match keyword {
+            "Kd" => material.diffuse = parse_floats(&arguments).map_err(error)?,
+            "Ks" => material.specular = parse_floats(&arguments).map_err(error)?,
+            "Ke" => material.emission = parse_floats(&arguments).map_err(error)?,
+            "Ns" => material.shininess = parse_floats::<1>(&arguments).map_err(error)?[0],
+            "Ni" => material.ior = parse_floats::<1>(&arguments).map_err(error)?[0],
+            "d" => material.dissolve = parse_floats::<1>(&arguments).map_err(error)?[0],
+            "Tr" => material.dissolve = 1.0 - parse_floats::<1>(&arguments).map_err(error)?[0],
+            "illum" => {
+                material.illumination_model = arguments
+                    .first()
+                    .and_then(|argument| argument.parse().ok())
+                    .ok_or_else(|| error("illum needs an integer".to_string()))?;
+            }
+            // Options such as -bm come before the file name
+            "map_Kd" => {
+                material.diffuse_texture = arguments.last().map(|name| directory.join(name));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(materials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    #[test]
+    fn loads_quad_with_materials() {
+        let model = load_obj(&fixture("quad.obj")).unwrap();
+        let mesh = &model.mesh;
+
+        // Two triangles sharing the diagonal
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3]);
+        assert_eq!(mesh.material_ids, vec![0, 0]);
+        assert_eq!(mesh.vertices[2].uv, [1.0, 0.0]);
+        assert!(mesh
+            .vertices
+            .iter()
+            .all(|vertex| vertex.normal == [0.0, 0.0, 1.0]));
+
+        assert_eq!(model.materials.len(), 1);
+        let material = &model.materials[0];
+        assert_eq!(material.name, "red");
+        assert_eq!(material.diffuse, [0.8, 0.1, 0.1]);
+        assert_eq!(material.diffuse_texture, Some(fixture("textures/red.png")));
+        assert_eq!(
+            material.to_material_model(),
+            MaterialModel::Lambert {
+                albedo: [0.8, 0.1, 0.1]
+            }
+        );
+    }
+
+    #[test]
+    fn triangulates_and_generates_normals() {
+        let model = load_obj(&fixture("pyramid.obj")).unwrap();
+        let mesh = &model.mesh;
+
+        // A square base fanned into two triangles and four sides
+        assert_eq!(mesh.triangle_count(), 6);
+        assert_eq!(mesh.material_ids[..2], [NO_MATERIAL, NO_MATERIAL]);
+        assert_eq!(mesh.material_ids[2..], [0, 0, 0, 0]);
+        assert_eq!(model.materials[0].name, "gold");
+
+        for vertex in &mesh.vertices {
+            let normal = glam::Vec3::from_array(vertex.normal);
+            assert!((normal.length() - 1.0).abs() < 1e-5);
+        }
+        // The apex normal points straight up, as the sides are symmetric
+        let apex = mesh
+            .vertices
+            .iter()
+            .find(|vertex| vertex.position == [0.0, 1.0, 0.0])
+            .unwrap();
+        let normal = glam::Vec3::from_array(apex.normal);
+        assert!(normal.abs_diff_eq(glam::Vec3::Y, 1e-5));
+    }
+
+    #[test]
+    fn resolves_negative_indices() {
+        let source = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\n";
+        let model = parse_obj(source, Path::new("relative.obj"), |_| Ok(Vec::new())).unwrap();
+        assert_eq!(model.mesh.indices, vec![0, 1, 2]);
+        assert_eq!(model.mesh.vertices[1].position, [1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn reports_line_of_bad_index() {
+        let source = "v 0 0 0\nv 1 0 0\nf 1 2 3\n";
+        let error = parse_obj(source, Path::new("broken.obj"), |_| Ok(Vec::new())).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "broken.obj:3: position index 3 is out of range, 2 are defined"
+        );
+    }
+
+    #[test]
+    fn reports_missing_mtl() {
+        let error = load_obj(&fixture("missing_mtl.obj")).unwrap_err();
+        assert!(matches!(error, ObjError::Io { path, .. } if path.ends_with("missing.mtl")));
+    }
+}
@@ -0,0 +1,145 @@
+use std::path::Path;
+
+use ash::vk;
+
+use super::SceneError;
+
+/// A decoded image in host memory, with the sampler materials read it with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Texture {
+    pub name: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    pub format: TextureFormat,
+    /// Tightly packed rows of `format` texels, top row first
+    pub pixels: Vec<u8>,
+    pub sampler: Sampler,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextureFormat {
+    /// 8-bit RGBA
+    Rgba8,
+    /// 32-bit float RGBA in native byte order, for high dynamic range images
+    Rgba32Float,
+}
+
+impl TextureFormat {
+    pub fn texel_size(&self) -> usize {
+        match self {
+            Self::Rgba8 => 4,
+            Self::Rgba32Float => 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sampler {
+    pub mag_filter: vk::Filter,
+    pub min_filter: vk::Filter,
+    pub mipmap_mode: vk::SamplerMipmapMode,
+    /// Whether the minification filter reads from mip levels at all
+    pub use_mipmaps: bool,
+    pub address_mode_u: vk::SamplerAddressMode,
+    pub address_mode_v: vk::SamplerAddressMode,
+}
+
+impl Default for Sampler {
+    /// Trilinear filtering with repeating texture coordinates.
+    fn default() -> Self {
+        Self {
+            mag_filter: vk::Filter::LINEAR,
+            min_filter: vk::Filter::LINEAR,
+            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+            use_mipmaps: true,
+            address_mode_u: vk::SamplerAddressMode::REPEAT,
+            address_mode_v: vk::SamplerAddressMode::REPEAT,
+        }
+    }
+}
+
+/// Decodes a PNG, JPEG or Radiance HDR file, told apart by their contents.
+/// HDR images keep their float values, everything else is converted to 8-bit
+/// RGBA. The texture is named after the file and uses the default sampler.
+pub fn load_texture(path: &Path) -> Result<Texture, SceneError> {
+    let image_error = |error| SceneError::Image {
+        path: path.to_path_buf(),
+        error: Box::new(error),
+    };
+    let image = image::ImageReader::open(path)
+        .and_then(image::ImageReader::with_guessed_format)
+        .map_err(|error| image_error(image::ImageError::IoError(error)))?
+        .decode()
+        .map_err(image_error)?;
+
+    let (format, pixels) = match image {
+        image::DynamicImage::ImageRgb32F(_) | image::DynamicImage::ImageRgba32F(_) => (
+            TextureFormat::Rgba32Float,
+            image
+                .to_rgba32f()
+                .into_raw()
+                .into_iter()
+                .flat_map(f32::to_ne_bytes)
+                .collect(),
+        ),
+        _ => (TextureFormat::Rgba8, image.to_rgba8().into_raw()),
+    };
+
+    Ok(Texture {
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned()),
+        width: image.width(),
+        height: image.height(),
+        format,
+        pixels,
+        sampler: Sampler::default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    #[test]
+    fn decodes_png_to_rgba8() {
+        let texture = load_texture(&fixture("textures/red.png")).unwrap();
+        assert_eq!((texture.width, texture.height), (2, 2));
+        assert_eq!(texture.format, TextureFormat::Rgba8);
+        assert_eq!(texture.pixels.len(), 2 * 2 * 4);
+        assert_eq!(texture.pixels[..4], [204, 26, 26, 255]);
+        assert_eq!(texture.name.as_deref(), Some("red.png"));
+    }
+
+    #[test]
+    fn decodes_hdr_to_float() {
+        // Two uncompressed RGBE pixels, 1.0 and 2.0 in every channel
+        let mut contents = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 2\n".to_vec();
+        contents.extend([128, 128, 128, 129, 128, 128, 128, 130]);
+        let path = std::env::temp_dir().join("vulkan_ray_tracer_decodes_hdr.hdr");
+        std::fs::write(&path, contents).unwrap();
+
+        let texture = load_texture(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(texture.format, TextureFormat::Rgba32Float);
+        let texels: Vec<f32> = texture
+            .pixels
+            .chunks_exact(4)
+            .map(|bytes| f32::from_ne_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(texels, [1.0, 1.0, 1.0, 1.0, 2.0, 2.0, 2.0, 1.0]);
+    }
+
+    #[test]
+    fn reports_missing_files() {
+        let error = load_texture(&fixture("textures/missing.png")).unwrap_err();
+        assert!(matches!(error, SceneError::Image { .. }));
+    }
+}
@@ -1,5 +1,8 @@
 use ash::vk;
 
+use crate::engine;
+use crate::engine::error::VkResultExt;
+
 use std::ffi::{c_void, CStr};
 
 // pub struct ValidationInfo {
@@ -86,18 +89,16 @@ pub fn setup_debug_utils(
     is_debug_enabled: bool,
     entry: &ash::Entry,
     instance: &ash::Instance,
-) -> (ash::ext::debug_utils::Instance, vk::DebugUtilsMessengerEXT) {
+) -> engine::Result<(ash::ext::debug_utils::Instance, vk::DebugUtilsMessengerEXT)> {
     let debug_utils_loader = ash::ext::debug_utils::Instance::new(entry, instance);
     if !is_debug_enabled {
-        return (debug_utils_loader, vk::DebugUtilsMessengerEXT::null());
+        return Ok((debug_utils_loader, vk::DebugUtilsMessengerEXT::null()));
     }
 
     let create_info = populate_debug_messenger_create_info();
-    let debug_messenger = unsafe {
-        debug_utils_loader
-            .create_debug_utils_messenger(&create_info, None)
-            .unwrap()
-    };
+    let debug_messenger =
+        unsafe { debug_utils_loader.create_debug_utils_messenger(&create_info, None) }
+            .context("create debug messenger")?;
 
-    (debug_utils_loader, debug_messenger)
+    Ok((debug_utils_loader, debug_messenger))
 }
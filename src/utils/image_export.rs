@@ -0,0 +1,421 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use ash::vk;
+
+use crate::engine;
+
+/// A frame read back from the device, converted to linear RGBA floats.
+pub struct HostImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[f32; 4]>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFileFormat {
+    Png,
+    Ppm,
+    Exr,
+}
+
+impl ImageFileFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "png" => Some(Self::Png),
+            "ppm" => Some(Self::Ppm),
+            "exr" => Some(Self::Exr),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io(std::io::Error),
+    Png(png::EncodingError),
+    Exr(exr::error::Error),
+    UnknownFileFormat(String),
+    UnsupportedPixelFormat(vk::Format),
+    /// The image couldn't be copied from the device
+    ReadBack(engine::Error),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "{}", error),
+            Self::Png(error) => write!(f, "PNG encoding failed: {}", error),
+            Self::Exr(error) => write!(f, "OpenEXR encoding failed: {}", error),
+            Self::UnknownFileFormat(path) => write!(
+                f,
+                "Can't tell the image format of {}, expected .png, .ppm or .exr",
+                path
+            ),
+            Self::UnsupportedPixelFormat(format) => {
+                write!(f, "Can't export images in format {:?}", format)
+            }
+            Self::ReadBack(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+impl From<std::io::Error> for ExportError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+impl From<engine::Error> for ExportError {
+    fn from(error: engine::Error) -> Self {
+        Self::ReadBack(error)
+    }
+}
+
+impl From<png::EncodingError> for ExportError {
+    fn from(error: png::EncodingError) -> Self {
+        Self::Png(error)
+    }
+}
+
+impl From<exr::error::Error> for ExportError {
+    fn from(error: exr::error::Error) -> Self {
+        Self::Exr(error)
+    }
+}
+
+pub fn is_srgb_format(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::B8G8R8A8_SRGB | vk::Format::R8G8B8A8_SRGB | vk::Format::A8B8G8R8_SRGB_PACK32
+    )
+}
+
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Converts texels read back from an image of `format` into linear RGBA.
+///
+/// The 8-bit `_SRGB` formats (as picked by `choose_swap_surface_format`) were
+/// encoded by the hardware on write, so they are decoded here. `_UNORM` and
+/// float formats already hold the linear values the shaders produced.
+pub fn decode_pixels(
+    data: &[u8],
+    format: vk::Format,
+    width: u32,
+    height: u32,
+) -> Result<HostImage, ExportError> {
+    let pixels = match format {
+        vk::Format::R8G8B8A8_UNORM
+        | vk::Format::R8G8B8A8_SRGB
+        | vk::Format::B8G8R8A8_UNORM
+        | vk::Format::B8G8R8A8_SRGB => {
+            let is_bgr = matches!(
+                format,
+                vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB
+            );
+            let decode = |byte: u8| {
+                let value = byte as f32 / 255.0;
+                if is_srgb_format(format) {
+                    srgb_to_linear(value)
+                } else {
+                    value
+                }
+            };
+
+            data.chunks_exact(4)
+                .map(|texel| {
+                    let (r, b) = if is_bgr {
+                        (texel[2], texel[0])
+                    } else {
+                        (texel[0], texel[2])
+                    };
+                    // Alpha is always stored linearly
+                    [
+                        decode(r),
+                        decode(texel[1]),
+                        decode(b),
+                        texel[3] as f32 / 255.0,
+                    ]
+                })
+                .collect()
+        }
+        vk::Format::R32G32B32A32_SFLOAT => data
+            .chunks_exact(16)
+            .map(|texel| {
+                let channel = |i: usize| {
+                    f32::from_le_bytes([
+                        texel[4 * i],
+                        texel[4 * i + 1],
+                        texel[4 * i + 2],
+                        texel[4 * i + 3],
+                    ])
+                };
+                [channel(0), channel(1), channel(2), channel(3)]
+            })
+            .collect(),
+        _ => return Err(ExportError::UnsupportedPixelFormat(format)),
+    };
+
+    Ok(HostImage {
+        width,
+        height,
+        pixels,
+    })
+}
+
+/// Turns a running sum of `samples` samples per pixel, as the accumulation
+/// image holds, into their average.
+pub fn average_samples(image: &mut HostImage, samples: u32) {
+    let scale = 1.0 / samples.max(1) as f32;
+    for [r, g, b, a] in image.pixels.iter_mut() {
+        *r *= scale;
+        *g *= scale;
+        *b *= scale;
+        *a = 1.0;
+    }
+}
+
+/// Writes `image` to `path`, choosing the file format from the extension.
+/// PNG and PPM are 8-bit sRGB, OpenEXR keeps the linear 32-bit floats.
+pub fn save_image(path: &Path, image: &HostImage) -> Result<(), ExportError> {
+    match ImageFileFormat::from_path(path) {
+        Some(ImageFileFormat::Png) => write_png(path, image),
+        Some(ImageFileFormat::Ppm) => write_ppm(path, image),
+        Some(ImageFileFormat::Exr) => write_exr(path, image),
+        None => Err(ExportError::UnknownFileFormat(path.display().to_string())),
+    }
+}
+
+fn encode_srgb8(value: f32) -> u8 {
+    (linear_to_srgb(value.clamp(0.0, 1.0)) * 255.0).round() as u8
+}
+
+fn write_png(path: &Path, image: &HostImage) -> Result<(), ExportError> {
+    let writer = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(writer, image.width, image.height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual);
+
+    let data: Vec<u8> = image
+        .pixels
+        .iter()
+        .flat_map(|[r, g, b, a]| {
+            [
+                encode_srgb8(*r),
+                encode_srgb8(*g),
+                encode_srgb8(*b),
+                (a.clamp(0.0, 1.0) * 255.0).round() as u8,
+            ]
+        })
+        .collect();
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+
+    Ok(())
+}
+
+fn write_ppm(path: &Path, image: &HostImage) -> Result<(), ExportError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write!(writer, "P6\n{} {}\n255\n", image.width, image.height)?;
+
+    let data: Vec<u8> = image
+        .pixels
+        .iter()
+        .flat_map(|[r, g, b, _]| [encode_srgb8(*r), encode_srgb8(*g), encode_srgb8(*b)])
+        .collect();
+    writer.write_all(&data)?;
+
+    Ok(())
+}
+
+fn write_exr(path: &Path, image: &HostImage) -> Result<(), ExportError> {
+    let width = image.width as usize;
+    exr::prelude::write_rgba_file(path, width, image.height as usize, |x, y| {
+        let [r, g, b, a] = image.pixels[y * width + x];
+        (r, g, b, a)
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("vulkan_ray_tracer_{}", name))
+    }
+
+    fn assert_close(actual: [f32; 4], expected: [f32; 4]) {
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!(
+                (actual - expected).abs() < 5e-3,
+                "{:?} != {:?}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_rgba_and_bgra_unorm() {
+        let data = [255, 0, 51, 128];
+        let rgba = decode_pixels(&data, vk::Format::R8G8B8A8_UNORM, 1, 1).unwrap();
+        assert_close(rgba.pixels[0], [1.0, 0.0, 0.2, 128.0 / 255.0]);
+        let bgra = decode_pixels(&data, vk::Format::B8G8R8A8_UNORM, 1, 1).unwrap();
+        assert_close(bgra.pixels[0], [0.2, 0.0, 1.0, 128.0 / 255.0]);
+    }
+
+    #[test]
+    fn decodes_srgb_but_not_alpha() {
+        let data = [188, 255, 0, 188];
+        let image = decode_pixels(&data, vk::Format::R8G8B8A8_SRGB, 1, 1).unwrap();
+        let half = srgb_to_linear(188.0 / 255.0);
+        assert!((half - 0.5).abs() < 0.01);
+        assert_close(image.pixels[0], [half, 1.0, 0.0, 188.0 / 255.0]);
+
+        let image = decode_pixels(&data, vk::Format::B8G8R8A8_SRGB, 1, 1).unwrap();
+        assert_close(image.pixels[0], [0.0, 1.0, half, 188.0 / 255.0]);
+    }
+
+    #[test]
+    fn decodes_float_texels() {
+        let data: Vec<u8> = [0.25f32, 2.0, 8.5, 1.0]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        let image = decode_pixels(&data, vk::Format::R32G32B32A32_SFLOAT, 1, 1).unwrap();
+        assert_eq!(image.pixels, [[0.25, 2.0, 8.5, 1.0]]);
+    }
+
+    #[test]
+    fn rejects_unknown_pixel_formats() {
+        let result = decode_pixels(&[0; 4], vk::Format::R8_UNORM, 1, 1);
+        assert!(matches!(
+            result,
+            Err(ExportError::UnsupportedPixelFormat(vk::Format::R8_UNORM))
+        ));
+    }
+
+    #[test]
+    fn srgb_conversions_round_trip() {
+        for value in [0.0, 0.002, 0.01, 0.2, 0.5, 1.0] {
+            assert!((srgb_to_linear(linear_to_srgb(value)) - value).abs() < 1e-5);
+        }
+        assert_eq!(encode_srgb8(-1.0), 0);
+        assert_eq!(encode_srgb8(0.5), 188);
+        assert_eq!(encode_srgb8(4.0), 255);
+    }
+
+    #[test]
+    fn averages_accumulated_samples() {
+        let mut image = HostImage {
+            width: 1,
+            height: 1,
+            pixels: vec![[4.0, 2.0, 0.0, 1.0]],
+        };
+        average_samples(&mut image, 4);
+        assert_eq!(image.pixels, [[1.0, 0.5, 0.0, 1.0]]);
+    }
+
+    fn test_image() -> HostImage {
+        HostImage {
+            width: 2,
+            height: 1,
+            pixels: vec![[1.0, 0.5, 0.0, 1.0], [0.0, 0.2, 1.0, 0.5]],
+        }
+    }
+
+    #[test]
+    fn writes_ppm() {
+        let path = temp_path("writes_ppm.ppm");
+        save_image(&path, &test_image()).unwrap();
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let header = b"P6\n2 1\n255\n";
+        assert_eq!(&contents[..header.len()], header);
+        let texels = &contents[header.len()..];
+        let image = decode_pixels(
+            &[
+                texels[..3].to_vec(),
+                vec![255],
+                texels[3..].to_vec(),
+                vec![255],
+            ]
+            .concat(),
+            vk::Format::R8G8B8A8_SRGB,
+            2,
+            1,
+        )
+        .unwrap();
+        assert_close(image.pixels[0], [1.0, 0.5, 0.0, 1.0]);
+        assert_close(image.pixels[1], [0.0, 0.2, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn png_round_trips() {
+        let path = temp_path("png_round_trips.png");
+        save_image(&path, &test_image()).unwrap();
+        let decoder = png::Decoder::new(File::open(&path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((info.width, info.height), (2, 1));
+        assert_eq!(info.color_type, png::ColorType::Rgba);
+        let image = decode_pixels(&data, vk::Format::R8G8B8A8_SRGB, 2, 1).unwrap();
+        assert_close(image.pixels[0], [1.0, 0.5, 0.0, 1.0]);
+        assert_close(image.pixels[1], [0.0, 0.2, 1.0, 0.5]);
+    }
+
+    #[test]
+    fn exr_keeps_values_above_one() {
+        let path = temp_path("exr_keeps_values_above_one.exr");
+        let image = HostImage {
+            width: 1,
+            height: 1,
+            pixels: vec![[4.5, 0.25, 0.0, 1.0]],
+        };
+        save_image(&path, &image).unwrap();
+        let read = exr::prelude::read_first_rgba_layer_from_file(
+            &path,
+            |_, _| Vec::new(),
+            |pixels: &mut Vec<[f32; 4]>, _, (r, g, b, a): (f32, f32, f32, f32)| {
+                pixels.push([r, g, b, a])
+            },
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.layer_data.channel_data.pixels, [[4.5, 0.25, 0.0, 1.0]]);
+    }
+
+    #[test]
+    fn rejects_unknown_extensions() {
+        let result = save_image(Path::new("render.bmp"), &test_image());
+        assert!(matches!(result, Err(ExportError::UnknownFileFormat(_))));
+    }
+}
@@ -1,3 +1,4 @@
 pub mod debug;
+pub mod image_export;
 pub mod platforms;
 pub mod required;
@@ -1,48 +1,40 @@
-#[cfg(target_os = "windows")]
-use ash::khr::win32_surface;
-#[cfg(all(unix, not(target_os = "android"), not(target_os = "macos")))]
-use ash::khr::xlib_surface;
-#[cfg(target_os = "macos")]
-use ash::mvk::macos_surface;
+use winit::raw_window_handle::RawDisplayHandle;
 
 use ash::ext::debug_utils;
-use ash::khr::surface;
-// use ash::vk;
+use ash::khr::{surface, wayland_surface, win32_surface, xcb_surface, xlib_surface};
+use ash::mvk::macos_surface;
 
-#[cfg(target_os = "windows")]
-pub fn get_required_extensions() -> Vec<*const i8> {
-    vec![
-        debug_utils::NAME.as_ptr(),
-        surface::NAME.as_ptr(),
-        win32_surface::NAME.as_ptr(),
-    ]
-}
+use crate::engine;
 
-#[cfg(all(unix, not(target_os = "android"), not(target_os = "macos")))]
-pub fn get_required_extensions() -> Vec<*const i8> {
-    vec![
-        debug_utils::NAME.as_ptr(),
-        surface::NAME.as_ptr(),
-        xlib_surface::NAME.as_ptr(),
-    ]
-}
+/// The instance extensions for presenting to windows on `display_handle`.
+/// A Linux build can run on Wayland or X11, so the surface extension is
+/// picked from the display the window was opened on rather than at compile
+/// time. Headless rendering never creates a surface, so only the debug utils
+/// extension is needed in that case.
+pub fn get_required_extensions(
+    display_handle: Option<&RawDisplayHandle>,
+) -> engine::Result<Vec<*const i8>> {
+    let Some(display_handle) = display_handle else {
+        return Ok(vec![debug_utils::NAME.as_ptr()]);
+    };
+
+    let surface_extension = match display_handle {
+        RawDisplayHandle::Windows(_) => win32_surface::NAME,
+        RawDisplayHandle::Wayland(_) => wayland_surface::NAME,
+        RawDisplayHandle::Xcb(_) => xcb_surface::NAME,
+        RawDisplayHandle::Xlib(_) => xlib_surface::NAME,
+        RawDisplayHandle::AppKit(_) => macos_surface::NAME,
+        _ => {
+            return Err(engine::Error::UnsupportedWindow(format!(
+                "{:?}",
+                display_handle
+            )))
+        }
+    };
 
-#[cfg(target_os = "macos")]
-pub fn get_required_extensions() -> Vec<*const i8> {
-    vec![
+    Ok(vec![
         debug_utils::NAME.as_ptr(),
         surface::NAME.as_ptr(),
-        macos_surface::NAME.as_ptr(),
-    ]
+        surface_extension.as_ptr(),
+    ])
 }
-
-// #[cfg(target_os = "windows")]
-// pub fn create_surface(window: &winit::window::Window) -> vk::SurfaceKHR {
-//     use std::os::raw::c_void;
-//     use std::ptr;
-//     use winapi::shared::windef::HWND;
-//     use winapi::um::libloaderapi::GetModuleHandleW;
-//     use winit::platform::windows::WindowExtWindows;
-
-//     let hwnd = window.hwnd() as HWND;
-// }
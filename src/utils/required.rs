@@ -2,12 +2,38 @@ use std::ffi::CStr;
 
 use ash::vk;
 
-pub fn get_required_extensions() -> Vec<*const i8> {
-    unsafe { vec![CStr::from_bytes_with_nul_unchecked(b"VK_KHR_swapchain\0").as_ptr()] }
+/// Device extensions the renderer needs. Headless rendering never creates a
+/// swapchain, so it doesn't need `VK_KHR_swapchain`.
+pub fn get_required_extensions(is_headless: bool, is_ray_tracing_enabled: bool) -> Vec<*const i8> {
+    get_required_extensions_cstr(is_headless, is_ray_tracing_enabled)
+        .iter()
+        .map(|extension| extension.as_ptr())
+        .collect()
 }
 
-pub fn get_required_extensions_cstr() -> Vec<&'static CStr> {
-    vec![vk::KHR_SWAPCHAIN_NAME]
+pub fn get_required_extensions_cstr(
+    is_headless: bool,
+    is_ray_tracing_enabled: bool,
+) -> Vec<&'static CStr> {
+    let mut extensions = vec![];
+    if !is_headless {
+        extensions.push(vk::KHR_SWAPCHAIN_NAME);
+    }
+    if is_ray_tracing_enabled {
+        extensions.extend(get_ray_tracing_extensions_cstr());
+    }
+
+    extensions
+}
+
+/// Device extensions needed by the hardware ray tracing backend.
+pub fn get_ray_tracing_extensions_cstr() -> Vec<&'static CStr> {
+    vec![
+        vk::KHR_ACCELERATION_STRUCTURE_NAME,
+        vk::KHR_RAY_TRACING_PIPELINE_NAME,
+        vk::KHR_BUFFER_DEVICE_ADDRESS_NAME,
+        vk::KHR_DEFERRED_HOST_OPERATIONS_NAME,
+    ]
 }
 
 pub fn get_required_layers() -> Vec<*const i8> {